@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS gtfs.transfer_patterns;
+DROP TABLE IF EXISTS gtfs.transfer_patterns_meta;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.transfer_patterns (
+    chateau text NOT NULL,
+    source_stop_id text NOT NULL,
+    node_id integer NOT NULL,
+    parent_node_id integer,
+    stop_id text NOT NULL,
+    arrive_by_walking boolean NOT NULL,
+    is_target boolean NOT NULL,
+    PRIMARY KEY (chateau, source_stop_id, node_id)
+);
+
+CREATE TABLE gtfs.transfer_patterns_meta (
+    chateau text NOT NULL PRIMARY KEY,
+    computed_unix_time_ms bigint NOT NULL,
+    window_start bigint NOT NULL,
+    window_end bigint NOT NULL,
+    source_stops_count integer NOT NULL,
+    nodes_count bigint NOT NULL
+);
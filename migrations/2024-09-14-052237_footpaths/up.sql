@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.footpaths (
+    chateau text NOT NULL,
+    from_stop_id text NOT NULL,
+    to_stop_id text NOT NULL,
+    distance_metres real NOT NULL,
+    duration_seconds integer NOT NULL,
+    computed_unix_time_ms bigint NOT NULL,
+    PRIMARY KEY (chateau, from_stop_id, to_stop_id)
+);
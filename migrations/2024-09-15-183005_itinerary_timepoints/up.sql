@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE gtfs.itinerary_pattern ADD COLUMN timepoint boolean;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.aspen_snapshots (
+    chateau text NOT NULL PRIMARY KEY,
+    created_unix_time_ms bigint NOT NULL,
+    snapshot bytea NOT NULL
+);
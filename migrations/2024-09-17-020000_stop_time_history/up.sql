@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- Final realtime arrival and departure of each trip at each stop, one partition per service day.
+-- Partitions are created by Aspen as it archives each day.
+CREATE TABLE gtfs.stop_time_history (
+    service_date date NOT NULL,
+    chateau text NOT NULL,
+    trip_id text NOT NULL,
+    route_id text,
+    stop_id text NOT NULL,
+    stop_sequence integer NOT NULL,
+    arrival_time bigint,
+    arrival_delay integer,
+    departure_time bigint,
+    departure_delay integer,
+    recorded_unix_time_ms bigint NOT NULL,
+    PRIMARY KEY (service_date, chateau, trip_id, stop_id, stop_sequence)
+) PARTITION BY RANGE (service_date);
+
+CREATE INDEX stop_time_history_route ON gtfs.stop_time_history (chateau, route_id, service_date);
+CREATE INDEX stop_time_history_stop ON gtfs.stop_time_history (chateau, stop_id, service_date);
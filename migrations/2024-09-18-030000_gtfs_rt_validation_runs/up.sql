@@ -0,0 +1,16 @@
+-- Your SQL goes here
+-- GTFS-realtime validation results, one row per feed type each time Aspen validates a feed.
+CREATE TABLE gtfs.gtfs_rt_validation_runs (
+    realtime_feed_id text NOT NULL,
+    feed_type text NOT NULL,
+    checked_unix_time_ms bigint NOT NULL,
+    chateau text NOT NULL,
+    header_timestamp bigint,
+    entity_count integer NOT NULL,
+    error_count integer NOT NULL,
+    warning_count integer NOT NULL,
+    notices jsonb NOT NULL,
+    PRIMARY KEY (realtime_feed_id, feed_type, checked_unix_time_ms)
+);
+
+CREATE INDEX gtfs_rt_validation_runs_chateau ON gtfs.gtfs_rt_validation_runs (chateau, checked_unix_time_ms);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- Last fetches of each realtime feed by Alpenrose, and last ingestions by Aspen, as JSON arrays oldest first.
+CREATE TABLE gtfs.realtime_feed_health (
+    realtime_feed_id text NOT NULL PRIMARY KEY,
+    fetches_updated_ms bigint,
+    last_success_ms bigint,
+    recent_fetches jsonb,
+    ingests_updated_ms bigint,
+    recent_ingests jsonb
+);
@@ -0,0 +1,20 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS gtfs.gtfs_errors_chateau_idx;
+
+ALTER TABLE gtfs.gtfs_errors DROP CONSTRAINT gtfs_errors_pkey;
+
+-- only the first rule of each attempt fits the old primary key
+DELETE FROM gtfs.gtfs_errors a
+    USING gtfs.gtfs_errors b
+    WHERE a.onestop_feed_id = b.onestop_feed_id
+    AND a.attempt_id = b.attempt_id
+    AND a.rule > b.rule;
+
+ALTER TABLE gtfs.gtfs_errors
+    DROP COLUMN rule,
+    DROP COLUMN severity,
+    DROP COLUMN count,
+    DROP COLUMN sample_ids,
+    DROP COLUMN checked_unix_time_ms;
+
+ALTER TABLE gtfs.gtfs_errors ADD PRIMARY KEY (onestop_feed_id, attempt_id);
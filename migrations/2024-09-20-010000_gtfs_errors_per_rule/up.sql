@@ -0,0 +1,22 @@
+-- Your SQL goes here
+-- one row per broken rule of each ingestion attempt, instead of one row per attempt
+ALTER TABLE gtfs.gtfs_errors DROP CONSTRAINT gtfs_errors_pkey;
+
+-- rows written before rules existed are kept as a single error of an unknown rule
+ALTER TABLE gtfs.gtfs_errors
+    ADD COLUMN rule text NOT NULL DEFAULT 'unknown',
+    ADD COLUMN severity text NOT NULL DEFAULT 'error',
+    ADD COLUMN count integer NOT NULL DEFAULT 1,
+    ADD COLUMN sample_ids jsonb NOT NULL DEFAULT '[]'::jsonb,
+    ADD COLUMN checked_unix_time_ms bigint NOT NULL DEFAULT 0;
+
+ALTER TABLE gtfs.gtfs_errors
+    ALTER COLUMN rule DROP DEFAULT,
+    ALTER COLUMN severity DROP DEFAULT,
+    ALTER COLUMN count DROP DEFAULT,
+    ALTER COLUMN sample_ids DROP DEFAULT,
+    ALTER COLUMN checked_unix_time_ms DROP DEFAULT;
+
+ALTER TABLE gtfs.gtfs_errors ADD PRIMARY KEY (onestop_feed_id, attempt_id, rule);
+
+CREATE INDEX gtfs_errors_chateau_idx ON gtfs.gtfs_errors (chateau);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE gtfs.ingested_static ADD COLUMN file_hashes jsonb;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS gtfs.fare_rules;
+DROP TABLE IF EXISTS gtfs.fare_attributes;
+DROP TABLE IF EXISTS gtfs.networks;
+DROP TABLE IF EXISTS gtfs.stop_areas;
+DROP TABLE IF EXISTS gtfs.areas;
+DROP TABLE IF EXISTS gtfs.fare_transfer_rules;
+DROP TABLE IF EXISTS gtfs.fare_leg_rules;
+DROP TABLE IF EXISTS gtfs.fare_products;
+DROP TABLE IF EXISTS gtfs.fare_media;
@@ -0,0 +1,113 @@
+-- Your SQL goes here
+-- Fares v2 and legacy fares, kept per ingestion attempt like the rest of the schedule
+-- Rules without an id of their own are keyed by their row in the file
+
+CREATE TABLE gtfs.fare_media (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    fare_media_id text NOT NULL,
+    fare_media_name text,
+    fare_media_type smallint NOT NULL,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, fare_media_id)
+);
+
+CREATE TABLE gtfs.fare_products (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    row_index integer NOT NULL,
+    fare_product_id text NOT NULL,
+    fare_product_name text,
+    fare_media_id text,
+    amount double precision NOT NULL,
+    currency text NOT NULL,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, row_index)
+);
+
+CREATE TABLE gtfs.fare_leg_rules (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    row_index integer NOT NULL,
+    leg_group_id text,
+    network_id text,
+    from_area_id text,
+    to_area_id text,
+    from_timeframe_group_id text,
+    to_timeframe_group_id text,
+    fare_product_id text NOT NULL,
+    rule_priority integer,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, row_index)
+);
+
+CREATE TABLE gtfs.fare_transfer_rules (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    row_index integer NOT NULL,
+    from_leg_group_id text,
+    to_leg_group_id text,
+    transfer_count integer,
+    duration_limit integer,
+    duration_limit_type smallint,
+    fare_transfer_type smallint NOT NULL,
+    fare_product_id text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, row_index)
+);
+
+CREATE TABLE gtfs.areas (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    area_id text NOT NULL,
+    area_name text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, area_id)
+);
+
+CREATE TABLE gtfs.stop_areas (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    area_id text NOT NULL,
+    stop_id text NOT NULL,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, area_id, stop_id)
+);
+
+-- route_ids come from route_networks.txt and the network_id column of routes.txt
+CREATE TABLE gtfs.networks (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    network_id text NOT NULL,
+    network_name text,
+    route_ids text[] NOT NULL,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, network_id)
+);
+
+CREATE TABLE gtfs.fare_attributes (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    fare_id text NOT NULL,
+    price double precision NOT NULL,
+    currency_type text NOT NULL,
+    payment_method smallint NOT NULL,
+    transfers smallint,
+    agency_id text,
+    transfer_duration integer,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, fare_id)
+);
+
+CREATE TABLE gtfs.fare_rules (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    row_index integer NOT NULL,
+    fare_id text NOT NULL,
+    route_id text,
+    origin_id text,
+    destination_id text,
+    contains_id text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, row_index)
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS gtfs.levels;
+DROP TABLE IF EXISTS gtfs.pathways;
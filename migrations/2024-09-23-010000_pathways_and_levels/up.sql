@@ -0,0 +1,32 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.pathways (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    pathway_id text NOT NULL,
+    from_stop_id text NOT NULL,
+    to_stop_id text NOT NULL,
+    pathway_mode smallint NOT NULL,
+    is_bidirectional boolean NOT NULL,
+    length real,
+    traversal_time integer,
+    stair_count integer,
+    max_slope real,
+    min_width real,
+    signposted_as text,
+    reversed_signposted_as text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, pathway_id)
+);
+
+CREATE INDEX pathways_from_stop_idx ON gtfs.pathways (onestop_feed_id, attempt_id, from_stop_id);
+CREATE INDEX pathways_to_stop_idx ON gtfs.pathways (onestop_feed_id, attempt_id, to_stop_id);
+
+CREATE TABLE gtfs.levels (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    level_id text NOT NULL,
+    level_index double precision NOT NULL,
+    level_name text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, level_id)
+);
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE gtfs.itinerary_pattern
+    DROP COLUMN start_pickup_drop_off_window_since_start,
+    DROP COLUMN end_pickup_drop_off_window_since_start,
+    DROP COLUMN pickup_booking_rule_id,
+    DROP COLUMN drop_off_booking_rule_id;
+
+DROP TABLE IF EXISTS gtfs.booking_rules;
+DROP TABLE IF EXISTS gtfs.flex_locations;
@@ -0,0 +1,41 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.flex_locations (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    location_id text NOT NULL,
+    stop_name text,
+    stop_desc text,
+    geometry GEOMETRY(MULTIPOLYGON, 4326) NOT NULL,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, location_id)
+);
+
+CREATE INDEX flex_locations_geometry_idx ON gtfs.flex_locations USING GIST (geometry);
+
+CREATE TABLE gtfs.booking_rules (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    booking_rule_id text NOT NULL,
+    booking_type smallint NOT NULL,
+    prior_notice_duration_min integer,
+    prior_notice_duration_max integer,
+    prior_notice_last_day integer,
+    prior_notice_last_time text,
+    prior_notice_start_day integer,
+    prior_notice_start_time text,
+    prior_notice_service_id text,
+    message text,
+    pickup_message text,
+    drop_off_message text,
+    phone_number text,
+    info_url text,
+    booking_url text,
+    chateau text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, booking_rule_id)
+);
+
+ALTER TABLE gtfs.itinerary_pattern
+    ADD COLUMN start_pickup_drop_off_window_since_start integer,
+    ADD COLUMN end_pickup_drop_off_window_since_start integer,
+    ADD COLUMN pickup_booking_rule_id text,
+    ADD COLUMN drop_off_booking_rule_id text;
@@ -1,4 +1,4 @@
-use catenary::duration_since_unix_epoch;
+use catenary::alpenrose::recorder::Recorder;
 use catenary::get_node_for_realtime_feed_id;
 use prost::Message;
 
@@ -7,6 +7,7 @@ pub async fn fetch_amtrak_data(
     feed_id: &str,
     gtfs: &gtfs_structures::Gtfs,
     client: &reqwest::Client,
+    recorder: &Option<Recorder>,
 ) {
     let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
 
@@ -24,23 +25,17 @@ pub async fn fetch_amtrak_data(
                 .await
                 .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    data.chateau_id.clone(),
-                    String::from(feed_id),
+            let tarpc_send_to_aspen = super::send_to_aspen(
+                &aspen_client,
+                super::custom_fetch(
+                    &data.chateau_id,
+                    feed_id,
                     Some(vehicle_data),
                     Some(trip_data),
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    Some(200),
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+                ),
+                recorder,
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
@@ -1,4 +1,4 @@
-use catenary::duration_since_unix_epoch;
+use catenary::alpenrose::recorder::Recorder;
 use catenary::get_node_for_realtime_feed_id;
 use prost::Message;
 
@@ -7,6 +7,7 @@ pub async fn fetch_chicago_data(
     feed_id: &str,
     client: &reqwest::Client,
     trips_content: &str,
+    recorder: &Option<Recorder>,
 ) {
     let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
 
@@ -23,23 +24,17 @@ pub async fn fetch_chicago_data(
                     .await
                     .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    worker_metadata.chateau_id.clone(),
-                    String::from(feed_id),
+            let tarpc_send_to_aspen = super::send_to_aspen(
+                &aspen_client,
+                super::custom_fetch(
+                    &worker_metadata.chateau_id,
+                    feed_id,
                     Some(chicago_rt_data.vehicle_positions.encode_to_vec()),
                     None,
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    None,
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+                ),
+                recorder,
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
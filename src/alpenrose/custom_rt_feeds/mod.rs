@@ -1,3 +1,6 @@
+use catenary::alpenrose::recorder::{RecordedFetch, Recorder};
+use catenary::aspen::lib::AspenRpcClient;
+
 pub mod amtrak;
 //pub mod anteater_express;
 pub mod chicagotransit;
@@ -6,3 +9,59 @@ pub mod tlms;
 pub mod uci;
 pub mod uk;
 pub mod viarail;
+
+/// Sends a fetch to Aspen, recording it first when recording is enabled
+pub async fn send_to_aspen(
+    aspen_client: &AspenRpcClient,
+    fetch: RecordedFetch,
+    recorder: &Option<Recorder>,
+) -> Result<bool, tarpc::client::RpcError> {
+    if let Some(recorder) = recorder {
+        recorder.record(&fetch);
+    }
+
+    aspen_client
+        .from_alpenrose(
+            tarpc::context::current(),
+            fetch.chateau_id,
+            fetch.realtime_feed_id,
+            fetch.vehicles,
+            fetch.trips,
+            fetch.alerts,
+            fetch.has_vehicles,
+            fetch.has_trips,
+            fetch.has_alerts,
+            fetch.vehicles_response_code,
+            fetch.trips_response_code,
+            fetch.alerts_response_code,
+            fetch.time_of_submission_ms,
+        )
+        .await
+}
+
+/// A custom feed is fetched in one go, without response codes of its own
+pub fn custom_fetch(
+    chateau_id: &str,
+    feed_id: &str,
+    vehicles: Option<Vec<u8>>,
+    trips: Option<Vec<u8>>,
+) -> RecordedFetch {
+    RecordedFetch {
+        chateau_id: chateau_id.to_string(),
+        realtime_feed_id: feed_id.to_string(),
+        vehicles_response_code: vehicles.as_ref().map(|_| 200),
+        trips_response_code: trips.as_ref().map(|_| 200),
+        vehicles,
+        trips,
+        alerts: None,
+        has_vehicles: true,
+        has_trips: true,
+        has_alerts: false,
+        alerts_response_code: None,
+        time_of_submission_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
+        vehicles_error: None,
+        trips_error: None,
+        alerts_error: None,
+        sent_to_aspen: true,
+    }
+}
@@ -1,3 +1,4 @@
+use catenary::alpenrose::recorder::Recorder;
 use prost::Message;
 use serde::{Deserialize, Serialize};
 
@@ -11,6 +12,7 @@ pub async fn fetch_mta_lirr_data(
     etcd: &mut etcd_client::Client,
     feed_id: &str,
     client: &reqwest::Client,
+    recorder: &Option<Recorder>,
 ) {
     let fetch_url =
         "https://backend-unified.mylirr.org/locations?geometry=TRACK_TURF&railroad=LIRR";
@@ -43,6 +45,7 @@ pub async fn fetch_mta_lirr_data(
                     lirr_vehicle_position_bytes,
                     lirr_trip_updates_bytes,
                     feed_id,
+                    recorder,
                 )
                 .await;
             }
@@ -54,6 +57,7 @@ pub async fn fetch_mta_metronorth_data(
     etcd: &mut etcd_client::Client,
     feed_id: &str,
     client: &reqwest::Client,
+    recorder: &Option<Recorder>,
 ) {
     let fetch_url = "https://backend-unified.mylirr.org/locations?geometry=TRACK_TURF&railroad=MNR";
 
@@ -85,6 +89,7 @@ pub async fn fetch_mta_metronorth_data(
                     mnr_vehicle_position_bytes,
                     mnr_trip_updates_bytes,
                     feed_id,
+                    recorder,
                 )
                 .await;
             }
@@ -387,6 +392,7 @@ pub async fn send_mta_rail_to_aspen(
     vehicle_position: Vec<u8>,
     trip_updates: Vec<u8>,
     feed_id: &str,
+    recorder: &Option<Recorder>,
 ) {
     let fetch_assigned_node_meta = catenary::get_node_for_realtime_feed_id(etcd, feed_id).await;
 
@@ -397,23 +403,17 @@ pub async fn send_mta_rail_to_aspen(
             .await
             .unwrap();
 
-        let tarpc_send_to_aspen = aspen_client
-            .from_alpenrose(
-                tarpc::context::current(),
-                data.chateau_id.clone(),
-                String::from(feed_id),
+        let tarpc_send_to_aspen = super::send_to_aspen(
+            &aspen_client,
+            super::custom_fetch(
+                &data.chateau_id,
+                feed_id,
                 Some(vehicle_position),
                 Some(trip_updates),
-                None,
-                true,
-                true,
-                false,
-                Some(200),
-                Some(200),
-                None,
-                catenary::duration_since_unix_epoch().as_millis() as u64,
-            )
-            .await;
+            ),
+            recorder,
+        )
+        .await;
 
         match tarpc_send_to_aspen {
             Ok(_) => {
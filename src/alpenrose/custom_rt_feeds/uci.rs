@@ -1,9 +1,13 @@
-use catenary::duration_since_unix_epoch;
+use catenary::alpenrose::recorder::Recorder;
 use catenary::get_node_for_realtime_feed_id;
 use prost::Message;
 use zotgtfs::get_gtfs_rt;
 
-pub async fn fetch_uci_data(etcd: &mut etcd_client::Client, feed_id: &str) {
+pub async fn fetch_uci_data(
+    etcd: &mut etcd_client::Client,
+    feed_id: &str,
+    recorder: &Option<Recorder>,
+) {
     let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
 
     if let Some(data) = fetch_assigned_node_meta {
@@ -19,23 +23,17 @@ pub async fn fetch_uci_data(etcd: &mut etcd_client::Client, feed_id: &str) {
                 .await
                 .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    data.chateau_id.clone(),
-                    String::from(feed_id),
+            let tarpc_send_to_aspen = super::send_to_aspen(
+                &aspen_client,
+                super::custom_fetch(
+                    &data.chateau_id,
+                    feed_id,
                     Some(vehicle_data.clone()),
                     Some(vehicle_data),
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    Some(200),
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+                ),
+                recorder,
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
@@ -0,0 +1,103 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Measured cost of fetching each feed, used by the leader to balance feeds between workers.
+// Every worker keeps a moving average of the time and payload size of its feeds, and publishes it to etcd
+// under /alpenrose_feed_costs/, attached to its lease.
+
+use catenary::bounded_load::smoothed;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Feeds which were never measured are assumed to cost this much per fetch
+const DEFAULT_FETCH_MS: f64 = 500.0;
+
+/// Downloading and forwarding this many bytes counts as much as a millisecond of fetching
+const BYTES_PER_MS: f64 = 10_000.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FeedCost {
+    pub fetch_ms: f64,
+    pub payload_bytes: f64,
+}
+
+impl FeedCost {
+    pub fn observe(&mut self, fetch_ms: f64, payload_bytes: f64) {
+        self.fetch_ms = smoothed(self.fetch_ms, fetch_ms);
+        self.payload_bytes = smoothed(self.payload_bytes, payload_bytes);
+    }
+}
+
+pub fn record_fetch(
+    costs: &DashMap<String, FeedCost>,
+    feed_id: &str,
+    fetch_ms: f64,
+    payload_bytes: f64,
+) {
+    costs
+        .entry(feed_id.to_string())
+        .and_modify(|cost| cost.observe(fetch_ms, payload_bytes))
+        .or_insert(FeedCost {
+            fetch_ms,
+            payload_bytes,
+        });
+}
+
+/// Work per second spent on a feed, fetching it every fetch_interval_ms
+pub fn feed_weight(cost: Option<&FeedCost>, fetch_interval_ms: Option<i32>) -> f64 {
+    let work_per_fetch = match cost {
+        Some(cost) => cost.fetch_ms + cost.payload_bytes / BYTES_PER_MS,
+        None => DEFAULT_FETCH_MS,
+    };
+
+    let fetch_interval_ms = fetch_interval_ms.unwrap_or(1_000).max(1) as f64;
+
+    // never zero, so every feed counts towards the load of its worker
+    work_per_fetch.max(1.0) / fetch_interval_ms
+}
+
+pub async fn publish_feed_costs(
+    etcd: &mut etcd_client::Client,
+    costs: &DashMap<String, FeedCost>,
+    etcd_lease_id: i64,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let costs = costs
+        .iter()
+        .map(|cost| (cost.key().clone(), *cost.value()))
+        .collect::<Vec<(String, FeedCost)>>();
+
+    for (feed_id, cost) in costs {
+        etcd.put(
+            format!("/alpenrose_feed_costs/{}", feed_id).as_str(),
+            bincode::serialize(&cost).unwrap(),
+            Some(etcd_client::PutOptions::new().with_lease(etcd_lease_id)),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_feed_costs(
+    etcd: &mut etcd_client::Client,
+) -> Result<std::collections::HashMap<String, FeedCost>, Box<dyn std::error::Error + Sync + Send>> {
+    Ok(etcd
+        .get(
+            "/alpenrose_feed_costs/",
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await?
+        .take_kvs()
+        .into_iter()
+        .filter_map(|kv| {
+            Some((
+                kv.key_str().ok()?.replace("/alpenrose_feed_costs/", ""),
+                bincode::deserialize::<FeedCost>(kv.value()).ok()?,
+            ))
+        })
+        .collect())
+}
@@ -0,0 +1,282 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Conditional requests and backoff for realtime feeds.
+// ETag and Last-Modified of the last response are sent back, so unchanged feeds answer 304 and cost the agency almost nothing.
+// Feeds which are rate limited, erroring or timing out are fetched exponentially less often, or as told by Retry-After.
+
+use crate::single_fetch_time::UrlType;
+use dashmap::DashMap;
+use reqwest::header::{
+    HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::Response;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest wait between fetches of a failing feed
+pub const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Longest Retry-After which is honoured, so a bad header cannot stop a feed for days
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug, Default)]
+pub struct CacheValidators {
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeedBackoff {
+    pub consecutive_failures: u32,
+    pub retry_at: Instant,
+}
+
+pub type ValidatorsPerUrl = DashMap<(String, UrlType), CacheValidators>;
+
+/// Adds If-None-Match and If-Modified-Since from the last successful response
+pub fn make_conditional(
+    request: Option<reqwest::Request>,
+    feed_id: &str,
+    url_type: UrlType,
+    validators: &ValidatorsPerUrl,
+) -> Option<reqwest::Request> {
+    let mut request = request?;
+
+    if let Some(validators) = validators.get(&(feed_id.to_string(), url_type)) {
+        if let Some(etag) = &validators.etag {
+            request.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+        }
+
+        if let Some(last_modified) = &validators.last_modified {
+            request
+                .headers_mut()
+                .insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    Some(request)
+}
+
+/// ETag and Last-Modified of a 200 response
+pub fn validators_of_response(response: &Response) -> CacheValidators {
+    CacheValidators {
+        etag: response.headers().get(ETAG).cloned(),
+        last_modified: response.headers().get(LAST_MODIFIED).cloned(),
+    }
+}
+
+/// Keeps the validators for the next request, only once the data of the response has reached Aspen,
+/// otherwise the next fetch would get a 304 and the data would never be sent
+pub fn save_validators(
+    feed_id: &str,
+    url_type: UrlType,
+    new_validators: CacheValidators,
+    validators: &ValidatorsPerUrl,
+) {
+    let key = (feed_id.to_string(), url_type);
+
+    match new_validators.etag.is_some() || new_validators.last_modified.is_some() {
+        true => {
+            validators.insert(key, new_validators);
+        }
+        false => {
+            validators.remove(&key);
+        }
+    }
+}
+
+pub fn is_failure_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+/// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let date = SystemTime::UNIX_EPOCH + Duration::from_secs(date.timestamp().max(0) as u64);
+
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+pub fn retry_after_of_response(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()))
+}
+
+/// Doubles the fetch interval for every failure in a row, and never retries before the server asked,
+/// up to MAX_RETRY_AFTER
+pub fn backoff_duration(
+    consecutive_failures: u32,
+    fetch_interval: Duration,
+    retry_after: Option<Duration>,
+) -> Duration {
+    let exponential = fetch_interval
+        .saturating_mul(2u32.saturating_pow(consecutive_failures))
+        .min(MAX_BACKOFF);
+
+    match retry_after {
+        Some(retry_after) => exponential.max(retry_after.min(MAX_RETRY_AFTER)),
+        None => exponential,
+    }
+}
+
+pub fn register_failure(
+    backoff_per_feed: &DashMap<String, FeedBackoff>,
+    feed_id: &str,
+    fetch_interval: Duration,
+    retry_after: Option<Duration>,
+) -> Duration {
+    let consecutive_failures = backoff_per_feed
+        .get(feed_id)
+        .map(|backoff| backoff.consecutive_failures + 1)
+        .unwrap_or(1);
+
+    if let Some(retry_after) = retry_after.filter(|retry_after| *retry_after > MAX_RETRY_AFTER) {
+        eprintln!(
+            "{} asked to retry after {:?}, retrying after {:?} instead",
+            feed_id, retry_after, MAX_RETRY_AFTER
+        );
+    }
+
+    let wait = backoff_duration(consecutive_failures, fetch_interval, retry_after);
+
+    backoff_per_feed.insert(
+        feed_id.to_string(),
+        FeedBackoff {
+            consecutive_failures,
+            retry_at: Instant::now() + wait,
+        },
+    );
+
+    wait
+}
+
+/// True while the feed is waiting out its backoff
+pub fn is_backing_off(backoff_per_feed: &DashMap<String, FeedBackoff>, feed_id: &str) -> bool {
+    backoff_per_feed
+        .get(feed_id)
+        .is_some_and(|backoff| Instant::now() < backoff.retry_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_seconds_and_dates() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1445412480);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        // Wed, 21 Oct 2015 07:28:00 GMT is 1445412480
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn saved_validators_make_the_next_request_conditional() {
+        let validators = ValidatorsPerUrl::new();
+        let request = || {
+            Some(reqwest::Request::new(
+                reqwest::Method::GET,
+                "https://example.com/rt".parse().unwrap(),
+            ))
+        };
+
+        save_validators(
+            "f-test~rt",
+            UrlType::TripUpdates,
+            CacheValidators {
+                etag: Some(HeaderValue::from_static("\"abc\"")),
+                last_modified: None,
+            },
+            &validators,
+        );
+
+        let conditional =
+            make_conditional(request(), "f-test~rt", UrlType::TripUpdates, &validators).unwrap();
+        assert_eq!(
+            conditional.headers().get(IF_NONE_MATCH),
+            Some(&HeaderValue::from_static("\"abc\""))
+        );
+
+        // other urls of the feed are not affected
+        let other = make_conditional(request(), "f-test~rt", UrlType::Alerts, &validators).unwrap();
+        assert!(other.headers().get(IF_NONE_MATCH).is_none());
+
+        // a response without validators clears them
+        save_validators(
+            "f-test~rt",
+            UrlType::TripUpdates,
+            CacheValidators::default(),
+            &validators,
+        );
+
+        let unconditional =
+            make_conditional(request(), "f-test~rt", UrlType::TripUpdates, &validators).unwrap();
+        assert!(unconditional.headers().get(IF_NONE_MATCH).is_none());
+    }
+
+    #[test]
+    fn backoff_grows_and_respects_retry_after() {
+        let interval = Duration::from_secs(1);
+
+        assert_eq!(backoff_duration(1, interval, None), Duration::from_secs(2));
+        assert_eq!(backoff_duration(3, interval, None), Duration::from_secs(8));
+        assert_eq!(backoff_duration(40, interval, None), MAX_BACKOFF);
+        assert_eq!(
+            backoff_duration(1, interval, Some(Duration::from_secs(30))),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            backoff_duration(1, interval, Some(Duration::from_secs(86400 * 7))),
+            MAX_RETRY_AFTER
+        );
+    }
+
+    #[test]
+    fn failures_in_a_row_are_counted_per_feed() {
+        let backoff_per_feed = DashMap::new();
+        let interval = Duration::from_secs(1);
+
+        assert_eq!(
+            register_failure(&backoff_per_feed, "f-a~rt", interval, None),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            register_failure(&backoff_per_feed, "f-a~rt", interval, None),
+            Duration::from_secs(4)
+        );
+        assert_eq!(
+            register_failure(&backoff_per_feed, "f-b~rt", interval, None),
+            Duration::from_secs(2)
+        );
+
+        assert!(is_backing_off(&backoff_per_feed, "f-a~rt"));
+        assert!(!is_backing_off(&backoff_per_feed, "f-c~rt"));
+
+        // missing feeds and bad requests are not made better by waiting
+        assert!(is_failure_status(429));
+        assert!(is_failure_status(503));
+        assert!(!is_failure_status(404));
+        assert!(!is_failure_status(304));
+    }
+}
@@ -1,9 +1,12 @@
+use crate::feed_cost::{feed_weight, get_feed_costs};
 use crate::get_feed_metadata::get_feed_metadata;
+use crate::rendezvous::assign_feeds;
 use crate::RealtimeFeedFetch;
 use catenary::fast_hash;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use dmfr_dataset_reader::read_folders;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
@@ -72,54 +75,142 @@ pub async fn perform_leader_job(
 
         //Time to reassign!
 
-        // divide feeds between worker nodes
+        // divide feeds between worker nodes, weighted by how expensive each feed is to fetch
 
-        // feed id -> List of realtime fetch instructions
-        let mut assignments: BTreeMap<String, HashMap<String, RealtimeFeedFetch>> = BTreeMap::new();
+        let feed_costs = get_feed_costs(etcd).await?;
 
-        for (index, (feed_id, realtime_instructions)) in feeds_map.iter().enumerate() {
-            let node_to_assign = &workers_list[index % workers_list.len()];
+        let weighted_feeds = feeds_map
+            .iter()
+            .map(|(feed_id, realtime_instructions)| {
+                (
+                    feed_id.clone(),
+                    feed_weight(
+                        feed_costs.get(feed_id),
+                        realtime_instructions.fetch_interval_ms,
+                    ),
+                )
+            })
+            .collect::<Vec<(String, f64)>>();
+
+        // (worker id, feed id) -> serialised instructions currently in etcd
+        let previous_assignments = etcd
+            .get(
+                "/alpenrose_assignments/",
+                Some(etcd_client::GetOptions::new().with_prefix()),
+            )
+            .await?
+            .take_kvs()
+            .into_iter()
+            .filter_map(|kv| {
+                let key = kv.key_str().ok()?.replace("/alpenrose_assignments/", "");
+                let (worker_id, feed_id) = key.split_once('/')?;
+
+                Some((
+                    (worker_id.to_string(), feed_id.to_string()),
+                    kv.value().to_vec(),
+                ))
+            })
+            .collect::<HashMap<(String, String), Vec<u8>>>();
+
+        let previous_feed_to_worker = previous_assignments
+            .keys()
+            .map(|(worker_id, feed_id)| (feed_id.clone(), worker_id.clone()))
+            .collect::<BTreeMap<String, String>>();
+
+        let feed_to_worker = assign_feeds(&weighted_feeds, &workers_list, &previous_feed_to_worker);
+
+        // worker id -> feed id -> realtime fetch instructions
+        let mut assignments: BTreeMap<String, HashMap<String, RealtimeFeedFetch>> = BTreeMap::new();
 
-            //append to list
+        for (feed_id, worker_id) in feed_to_worker.iter() {
             assignments
-                .entry(node_to_assign.to_string())
-                .and_modify(|instructions| {
-                    instructions.insert(feed_id.clone(), realtime_instructions.clone());
-                })
-                .or_insert({
-                    let mut map = HashMap::new();
-                    map.insert(feed_id.clone(), realtime_instructions.clone());
-                    map
-                });
+                .entry(worker_id.clone())
+                .or_default()
+                .insert(feed_id.clone(), feeds_map[feed_id].clone());
         }
 
         //lock it so you can't change it anymore
         let assignments = assignments;
 
+        let mut changed_workers: BTreeSet<String> = BTreeSet::new();
+
+        // stop the old worker first, so a moved feed is not fetched twice
+        for (worker_id, feed_id) in previous_assignments.keys() {
+            if feed_to_worker.get(feed_id) != Some(worker_id) {
+                let delete_assignment = etcd
+                    .delete(
+                        format!("/alpenrose_assignments/{}/{}", worker_id, feed_id).as_str(),
+                        None,
+                    )
+                    .await;
+
+                match delete_assignment {
+                    Ok(_) => {
+                        changed_workers.insert(worker_id.clone());
+                    }
+                    Err(err) => eprintln!("{:#?}", err),
+                }
+            }
+        }
+
+        let mut moved_feeds: usize = 0;
+
         for (worker_id, instructions_hashmap) in assignments.iter() {
             let lease_option = etcd_client::PutOptions::new()
                 .with_lease(*fetch_workers_hashmap.get(worker_id).unwrap());
 
             for (feed_id, realtime_instruction) in instructions_hashmap {
+                let serialised_instruction = bincode::serialize(&realtime_instruction).unwrap();
+
+                let previous = previous_assignments.get(&(worker_id.clone(), feed_id.clone()));
+
+                // unchanged assignments are left alone
+                if previous == Some(&serialised_instruction) {
+                    continue;
+                }
+
+                if previous.is_none() {
+                    moved_feeds += 1;
+                }
+
                 let set_assignment = etcd
                     .put(
                         format!("/alpenrose_assignments/{}/{}", worker_id, feed_id).as_str(),
-                        bincode::serialize(&realtime_instruction).unwrap(),
+                        serialised_instruction,
                         Some(lease_option.clone()),
                     )
                     .await;
 
-                if let Err(err) = &set_assignment {
-                    eprintln!("{:#?}", err)
+                match set_assignment {
+                    Ok(_) => {
+                        changed_workers.insert(worker_id.clone());
+                    }
+                    Err(err) => eprintln!("{:#?}", err),
                 }
             }
-            //update the last updated time
+        }
+
+        println!(
+            "Assigned {} feeds to {} workers, {} feeds moved",
+            feed_to_worker.len(),
+            workers_list.len(),
+            moved_feeds
+        );
+
+        //update the last updated time of workers whose feeds changed
+
+        for worker_id in changed_workers.iter() {
+            let lease_id = match fetch_workers_hashmap.get(worker_id) {
+                Some(lease_id) => *lease_id,
+                // the worker is gone, its keys expire with its lease
+                None => continue,
+            };
 
             let set_metadata_updated_time = etcd
                 .put(
                     format!("/alpenrose_assignments_last_updated/{}", worker_id).as_str(),
                     bincode::serialize(&catenary::duration_since_unix_epoch().as_millis()).unwrap(),
-                    Some(lease_option),
+                    Some(etcd_client::PutOptions::new().with_lease(lease_id)),
                 )
                 .await;
 
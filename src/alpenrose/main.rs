@@ -47,8 +47,11 @@ use std::time::Instant;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 mod custom_rt_feeds;
+mod feed_cost;
+mod fetch_policy;
 pub mod get_feed_metadata;
 mod leader_job;
+mod rendezvous;
 use std::io;
 use zip::ZipArchive;
 mod single_fetch_time;
@@ -99,6 +102,25 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
     let last_fetch_per_feed: Arc<DashMap<String, Instant>> = Arc::new(DashMap::new());
 
+    let validators_per_url: Arc<fetch_policy::ValidatorsPerUrl> = Arc::new(DashMap::new());
+    let backoff_per_feed: Arc<DashMap<String, fetch_policy::FeedBackoff>> =
+        Arc::new(DashMap::new());
+
+    let feed_costs: Arc<DashMap<String, feed_cost::FeedCost>> = Arc::new(DashMap::new());
+    let mut last_feed_costs_publish: Option<Instant> = None;
+
+    let fetch_histories: Arc<DashMap<String, catenary::feed_health::FetchHistory>> =
+        Arc::new(DashMap::new());
+    let mut last_fetch_histories_save: Option<Instant> = None;
+
+    let recorder = Arc::new(catenary::alpenrose::recorder::Recorder::from_env(
+        this_worker_id.as_str(),
+    ));
+
+    if recorder.is_some() {
+        println!("Recording fetched feeds");
+    }
+
     //make client for reqwest
     //allow various compression algorithms to be used during the download process, as enabled in Cargo.toml
     let client = reqwest::ClientBuilder::new()
@@ -353,8 +375,62 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
                 Arc::clone(&chicago_trips_str),
                 &etcd_urls,
                 &etcd_connection_options,
+                Arc::clone(&recorder),
+                Arc::clone(&validators_per_url),
+                Arc::clone(&backoff_per_feed),
+                Arc::clone(&feed_costs),
+                Arc::clone(&fetch_histories),
             )
             .await?;
+
+            //tell the leader how expensive the feeds of this worker are
+
+            if last_feed_costs_publish
+                .map(|last_publish| last_publish.elapsed() >= feed_cost::PUBLISH_INTERVAL)
+                .unwrap_or(true)
+            {
+                last_feed_costs_publish = Some(Instant::now());
+
+                let assigned_feeds = assignments_for_this_worker.read().await;
+
+                // removed feeds and feeds moved to another worker are measured by their new owner
+                feed_costs.retain(|feed_id, _| assigned_feeds.contains_key(feed_id));
+
+                drop(assigned_feeds);
+
+                if let Err(err) =
+                    feed_cost::publish_feed_costs(&mut etcd, &feed_costs, etcd_lease_id).await
+                {
+                    eprintln!("Could not publish feed costs: {:#?}", err);
+                }
+            }
+
+            //save the recent fetches of this worker's feeds for the feed health api
+
+            if last_fetch_histories_save
+                .map(|last_save| last_save.elapsed() >= catenary::feed_health::FLUSH_INTERVAL)
+                .unwrap_or(true)
+            {
+                last_fetch_histories_save = Some(Instant::now());
+
+                let assigned_feeds = assignments_for_this_worker.read().await;
+
+                // feeds moved to another worker are theirs to report now
+                fetch_histories.retain(|feed_id, _| assigned_feeds.contains_key(feed_id));
+
+                drop(assigned_feeds);
+
+                let histories = fetch_histories
+                    .iter()
+                    .map(|history| (history.key().clone(), history.value().clone()))
+                    .collect::<Vec<(String, catenary::feed_health::FetchHistory)>>();
+
+                if let Err(err) =
+                    catenary::feed_health::save_fetch_histories(&arc_conn_pool, histories).await
+                {
+                    eprintln!("Could not save fetch histories: {:#?}", err);
+                }
+            }
         } else {
             //revoke the lease
 
@@ -0,0 +1,356 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Records every response Alpenrose receives, and the payloads it sends to Aspen,
+// so processing bugs can be reproduced offline with alpenrose_replay.
+// Recording is enabled by setting ALPENROSE_RECORD_DIR. A new gzip file is started every hour,
+// holding length prefixed bincode records in the order they were fetched.
+// Fetches are queued to a writer thread, so the fetch loop never waits on the disk.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+const RECORDING_FILE_SECS: u64 = 3600;
+
+/// Fetches waiting for the writer, beyond which new fetches are dropped instead of waited for
+const RECORDING_QUEUE_LENGTH: usize = 1024;
+
+/// One fetch of a feed. When it was sent to Aspen, the fields are the arguments of the `from_alpenrose` call.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedFetch {
+    pub chateau_id: String,
+    pub realtime_feed_id: String,
+    pub vehicles: Option<Vec<u8>>,
+    pub trips: Option<Vec<u8>>,
+    pub alerts: Option<Vec<u8>>,
+    pub has_vehicles: bool,
+    pub has_trips: bool,
+    pub has_alerts: bool,
+    pub vehicles_response_code: Option<u16>,
+    pub trips_response_code: Option<u16>,
+    pub alerts_response_code: Option<u16>,
+    pub time_of_submission_ms: u64,
+    // requests which got no response, such as timeouts
+    pub vehicles_error: Option<String>,
+    pub trips_error: Option<String>,
+    pub alerts_error: Option<String>,
+    /// False for fetches Aspen never saw, such as failures, rate limits and 304 Not Modified
+    pub sent_to_aspen: bool,
+}
+
+struct OpenRecording {
+    file_start_secs: u64,
+    encoder: GzEncoder<File>,
+}
+
+struct RecordingWriter {
+    directory: PathBuf,
+    worker_id: String,
+    current: Option<OpenRecording>,
+}
+
+impl RecordingWriter {
+    fn write(
+        &mut self,
+        fetch: &RecordedFetch,
+    ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        let bytes = bincode::serialize(fetch)?;
+
+        let file_start_secs =
+            (fetch.time_of_submission_ms / 1000) / RECORDING_FILE_SECS * RECORDING_FILE_SECS;
+
+        if self
+            .current
+            .as_ref()
+            .map(|recording| recording.file_start_secs != file_start_secs)
+            .unwrap_or(true)
+        {
+            self.finish()?;
+
+            std::fs::create_dir_all(&self.directory)?;
+
+            // several workers can record into the same directory
+            let path = self.directory.join(format!(
+                "alpenrose-{}-{}.bin.gz",
+                file_start_secs, self.worker_id
+            ));
+
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+
+            self.current = Some(OpenRecording {
+                file_start_secs,
+                encoder: GzEncoder::new(file, Compression::default()),
+            });
+        }
+
+        let encoder = &mut self.current.as_mut().unwrap().encoder;
+
+        encoder.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        encoder.write_all(&bytes)?;
+        // readable up to here even if Alpenrose is killed
+        encoder.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        if let Some(previous) = self.current.take() {
+            previous.encoder.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Recorder {
+    sender: SyncSender<RecordedFetch>,
+    writer: JoinHandle<()>,
+}
+
+impl Recorder {
+    pub fn from_env(worker_id: &str) -> Option<Recorder> {
+        let directory = std::env::var("ALPENROSE_RECORD_DIR").ok()?;
+
+        Some(Recorder::new(PathBuf::from(directory), worker_id))
+    }
+
+    pub fn new(directory: PathBuf, worker_id: &str) -> Recorder {
+        let (sender, receiver) = sync_channel::<RecordedFetch>(RECORDING_QUEUE_LENGTH);
+
+        let mut recording_writer = RecordingWriter {
+            directory,
+            worker_id: worker_id.to_string(),
+            current: None,
+        };
+
+        let writer = std::thread::spawn(move || {
+            for fetch in receiver {
+                if let Err(err) = recording_writer.write(&fetch) {
+                    eprintln!("{}: Error recording fetch: {}", fetch.realtime_feed_id, err);
+                }
+            }
+
+            if let Err(err) = recording_writer.finish() {
+                eprintln!("Error closing recording: {}", err);
+            }
+        });
+
+        Recorder { sender, writer }
+    }
+
+    /// Queues the fetch for the writer without waiting
+    pub fn record(&self, fetch: &RecordedFetch) {
+        match self.sender.try_send(fetch.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(fetch)) => {
+                eprintln!(
+                    "{}: Recording is behind, fetch not recorded",
+                    fetch.realtime_feed_id
+                );
+            }
+            Err(TrySendError::Disconnected(fetch)) => {
+                eprintln!(
+                    "{}: Recording writer stopped, fetch not recorded",
+                    fetch.realtime_feed_id
+                );
+            }
+        }
+    }
+
+    /// Writes every queued fetch and closes the file
+    pub fn close(self) {
+        drop(self.sender);
+
+        if self.writer.join().is_err() {
+            eprintln!("Recording writer panicked");
+        }
+    }
+}
+
+/// Reads every record of a recording file, which has one gzip member per time it was opened.
+/// A record cut off at the end of the file, from a worker which was stopped, is ignored.
+pub fn read_recording(
+    path: &Path,
+) -> Result<Vec<RecordedFetch>, Box<dyn std::error::Error + Sync + Send>> {
+    let mut decoder = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    let mut fetches = vec![];
+
+    loop {
+        let mut length = [0u8; 8];
+
+        match decoder.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut bytes = vec![0u8; u64::from_le_bytes(length) as usize];
+
+        match decoder.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        fetches.push(bincode::deserialize::<RecordedFetch>(&bytes)?);
+    }
+
+    Ok(fetches)
+}
+
+/// Every record of a file, or of all recording files in a directory, sorted by submission time
+pub fn read_recordings(
+    path: &Path,
+) -> Result<Vec<RecordedFetch>, Box<dyn std::error::Error + Sync + Send>> {
+    let mut fetches = vec![];
+
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+
+            if entry_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("alpenrose-") && name.ends_with(".bin.gz"))
+            {
+                fetches.extend(read_recording(&entry_path)?);
+            }
+        }
+    } else {
+        fetches.extend(read_recording(path)?);
+    }
+
+    fetches.sort_by_key(|fetch| fetch.time_of_submission_ms);
+
+    Ok(fetches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(time_of_submission_ms: u64) -> RecordedFetch {
+        RecordedFetch {
+            chateau_id: String::from("c"),
+            realtime_feed_id: String::from("f-test~rt"),
+            vehicles: Some(vec![1, 2, 3]),
+            trips: None,
+            alerts: None,
+            has_vehicles: true,
+            has_trips: false,
+            has_alerts: false,
+            vehicles_response_code: Some(200),
+            trips_response_code: None,
+            alerts_response_code: None,
+            time_of_submission_ms,
+            vehicles_error: None,
+            trips_error: None,
+            alerts_error: None,
+            sent_to_aspen: true,
+        }
+    }
+
+    #[test]
+    fn records_are_read_back_in_order() {
+        let directory =
+            std::env::temp_dir().join(format!("alpenrose-recorder-test-{}", uuid::Uuid::new_v4()));
+        let recorder = Recorder::new(directory.clone(), "worker");
+
+        // the second record starts a new file, an hour later
+        recorder.record(&fetch(3_600_000 + 5));
+        recorder.record(&fetch(7_200_000 + 1));
+        recorder.record(&fetch(3_600_000 + 2));
+
+        recorder.close();
+
+        let fetches = read_recordings(&directory).unwrap();
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(
+            fetches
+                .iter()
+                .map(|fetch| fetch.time_of_submission_ms)
+                .collect::<Vec<u64>>(),
+            vec![3_600_002, 3_600_005, 7_200_001]
+        );
+        assert_eq!(fetches[0], fetch(3_600_002));
+    }
+
+    #[test]
+    fn failed_fetches_are_recorded_with_their_errors() {
+        let directory =
+            std::env::temp_dir().join(format!("alpenrose-recorder-test-{}", uuid::Uuid::new_v4()));
+        let recorder = Recorder::new(directory.clone(), "worker");
+
+        let failed = RecordedFetch {
+            chateau_id: String::from("c"),
+            realtime_feed_id: String::from("f-test~rt"),
+            vehicles: None,
+            trips: None,
+            alerts: None,
+            has_vehicles: true,
+            has_trips: true,
+            has_alerts: false,
+            vehicles_response_code: Some(304),
+            trips_response_code: None,
+            alerts_response_code: None,
+            time_of_submission_ms: 3_600_000,
+            vehicles_error: None,
+            trips_error: Some(String::from("operation timed out")),
+            alerts_error: None,
+            sent_to_aspen: false,
+        };
+
+        recorder.record(&failed);
+        recorder.close();
+
+        let fetches = read_recordings(&directory).unwrap();
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(fetches, vec![failed]);
+    }
+
+    #[test]
+    fn reopened_files_are_read_whole_and_cut_off_records_are_ignored() {
+        let directory =
+            std::env::temp_dir().join(format!("alpenrose-recorder-test-{}", uuid::Uuid::new_v4()));
+
+        // a restarted worker appends a second gzip member to the file of the hour
+        for time_of_submission_ms in [3_600_001, 3_600_002] {
+            let recorder = Recorder::new(directory.clone(), "worker");
+            recorder.record(&fetch(time_of_submission_ms));
+            recorder.close();
+        }
+
+        // a worker killed while writing leaves a record shorter than its length
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(directory.join("alpenrose-3600-worker.bin.gz"))
+            .unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(&100u64.to_le_bytes()).unwrap();
+        encoder.write_all(&[1, 2, 3]).unwrap();
+        encoder.finish().unwrap();
+
+        std::fs::write(directory.join("notes.txt"), "not a recording").unwrap();
+
+        let fetches = read_recordings(&directory);
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(fetches.unwrap(), vec![fetch(3_600_001), fetch(3_600_002)]);
+    }
+}
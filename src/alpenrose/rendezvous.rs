@@ -0,0 +1,171 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Assigns feeds to workers with rendezvous (highest random weight) hashing and bounded loads.
+// Each feed prefers workers in an order fixed by hashing the feed and worker ids together,
+// so a worker joining or leaving only moves the feeds it takes or gives up.
+// Loads are bounded by catenary::bounded_load, so expensive feeds spill over to their next preferred worker.
+
+use catenary::bounded_load::assign_bounded;
+use catenary::fast_hash;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+pub fn rendezvous_score(worker_id: &str, feed_id: &str) -> u64 {
+    fast_hash(&(worker_id, feed_id))
+}
+
+/// Workers from most to least preferred for the feed
+pub fn preferred_workers<'a>(feed_id: &str, workers: &'a [String]) -> Vec<&'a String> {
+    let mut ranked = workers.iter().collect::<Vec<&String>>();
+
+    ranked.sort_by_key(|worker_id| {
+        std::cmp::Reverse((rendezvous_score(worker_id, feed_id), *worker_id))
+    });
+
+    ranked
+}
+
+/// feed id -> worker id, for feeds given as (feed id, weight).
+/// Feeds stay on their worker in `previous` while it is alive and under the load bound,
+/// so changing feed weights do not shuffle feeds around.
+pub fn assign_feeds(
+    feeds: &[(String, f64)],
+    workers: &[String],
+    previous: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    // workers without feeds in the previous assignment just joined
+    let previous_workers = previous.values().collect::<BTreeSet<&String>>();
+
+    assign_bounded(
+        feeds,
+        workers,
+        &BTreeMap::new(),
+        |feed_id| {
+            // a joining worker takes the feeds it is the first choice for, as plain rendezvous hashing would move
+            let joining_first_choice = preferred_workers(feed_id, workers)
+                .first()
+                .copied()
+                .filter(|worker_id| !previous_workers.contains(worker_id));
+
+            joining_first_choice
+                .into_iter()
+                .chain(previous.get(feed_id))
+                .collect()
+        },
+        |feed_id| preferred_workers(feed_id, workers),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::bounded_load::LOAD_FACTOR;
+
+    #[test]
+    fn only_feeds_of_the_changed_worker_move() {
+        let feeds = (0..200)
+            .map(|i| (format!("f-{}~rt", i), 1.0))
+            .collect::<Vec<(String, f64)>>();
+        let workers = (0..4).map(|i| format!("w{}", i)).collect::<Vec<String>>();
+
+        let before = assign_feeds(&feeds, &workers, &BTreeMap::new());
+
+        let mut more_workers = workers.clone();
+        more_workers.push(String::from("w4"));
+
+        let after = assign_feeds(&feeds, &more_workers, &before);
+
+        let moved = before
+            .iter()
+            .filter(|(feed_id, worker_id)| after[*feed_id] != **worker_id)
+            .count();
+
+        // the new worker takes about a fifth, and no more than its capacity
+        assert!(moved <= (200.0 * LOAD_FACTOR / 5.0) as usize + 5);
+        assert!(after.values().any(|worker_id| worker_id == "w4"));
+
+        for worker_id in &workers {
+            let load = after.values().filter(|w| *w == worker_id).count();
+            assert!(load as f64 <= 200.0 * LOAD_FACTOR / 5.0);
+        }
+    }
+
+    fn unit_feeds(count: usize) -> Vec<(String, f64)> {
+        (0..count)
+            .map(|i| (format!("f-{}~rt", i), 1.0))
+            .collect::<Vec<(String, f64)>>()
+    }
+
+    #[test]
+    fn feeds_stay_on_their_worker_while_it_has_room() {
+        let workers = vec![String::from("w0"), String::from("w1"), String::from("w2")];
+
+        // the opposite of what hashing alone would pick for some feeds
+        let previous = unit_feeds(6)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (feed_id, _))| (feed_id, workers[i / 2].clone()))
+            .collect::<BTreeMap<String, String>>();
+
+        // costs changed, but every worker still fits under the bound
+        let reweighted = unit_feeds(6)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (feed_id, _))| (feed_id, 1.0 + (i % 2) as f64 * 0.1))
+            .collect::<Vec<(String, f64)>>();
+
+        assert_eq!(assign_feeds(&reweighted, &workers, &previous), previous);
+    }
+
+    #[test]
+    fn overloaded_worker_sheds_only_what_does_not_fit() {
+        let workers = vec![String::from("w0"), String::from("w1"), String::from("w2")];
+        let feeds = unit_feeds(8);
+
+        let mut previous = BTreeMap::new();
+
+        for (feed_id, _) in feeds.iter().take(6) {
+            previous.insert(feed_id.clone(), String::from("w0"));
+        }
+
+        previous.insert(feeds[6].0.clone(), String::from("w1"));
+        previous.insert(feeds[7].0.clone(), String::from("w2"));
+
+        let after = assign_feeds(&feeds, &workers, &previous);
+
+        // capacity is 1.25 * 8 / 3, so w0 keeps three feeds and the others keep theirs
+        assert_eq!(
+            after
+                .iter()
+                .filter(|(feed_id, worker_id)| previous[*feed_id] == **worker_id)
+                .count(),
+            5
+        );
+        assert_eq!(after[&feeds[6].0], "w1");
+        assert_eq!(after[&feeds[7].0], "w2");
+
+        for worker_id in &workers {
+            assert!(after.values().filter(|w| *w == worker_id).count() <= 3);
+        }
+    }
+
+    #[test]
+    fn feeds_of_a_removed_worker_are_spread_out() {
+        let workers = (0..4).map(|i| format!("w{}", i)).collect::<Vec<String>>();
+        let feeds = unit_feeds(100);
+
+        let before = assign_feeds(&feeds, &workers, &BTreeMap::new());
+        let after = assign_feeds(&feeds, &workers[..3], &before);
+
+        // only the feeds of the removed worker move
+        for (feed_id, worker_id) in before.iter() {
+            if worker_id != "w3" {
+                assert_eq!(&after[feed_id], worker_id);
+            }
+        }
+
+        assert!(after.values().all(|worker_id| worker_id != "w3"));
+    }
+}
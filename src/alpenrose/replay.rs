@@ -0,0 +1,151 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Sends feeds recorded by Alpenrose to Aspen again, at the recorded pace or faster.
+// Without --aspen, each feed goes to the Aspen worker assigned to it in etcd, like Alpenrose does.
+
+use catenary::alpenrose::recorder;
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::get_node_for_realtime_feed_id;
+use clap::Parser;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+struct Flags {
+    /// A recording file, or a directory of recordings
+    #[clap(long)]
+    recording: PathBuf,
+    /// 1 replays at the recorded pace, 10 ten times faster, and 0 as fast as Aspen accepts the data
+    #[clap(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Send every feed to this Aspen worker
+    #[clap(long)]
+    aspen: Option<SocketAddr>,
+    /// Only replay this chateau
+    #[clap(long)]
+    chateau: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let flags: Flags = Flags::parse();
+
+    let fetches = recorder::read_recordings(&flags.recording)?
+        .into_iter()
+        // failures and 304s are recorded for debugging, but Aspen never saw them
+        .filter(|fetch| fetch.sent_to_aspen)
+        .filter(|fetch| {
+            flags
+                .chateau
+                .as_ref()
+                .map(|chateau| &fetch.chateau_id == chateau)
+                .unwrap_or(true)
+        })
+        .collect::<Vec<recorder::RecordedFetch>>();
+
+    let first_submission_ms = match fetches.first() {
+        Some(fetch) => fetch.time_of_submission_ms,
+        None => {
+            println!("Nothing to replay");
+            return Ok(());
+        }
+    };
+
+    println!("Replaying {} fetches", fetches.len());
+
+    let mut etcd = match flags.aspen {
+        Some(_) => None,
+        None => {
+            let etcd_urls_original =
+                std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
+            let etcd_urls = etcd_urls_original.split(',').collect::<Vec<&str>>();
+
+            let etcd_connection_options = match (
+                std::env::var("ETCD_USERNAME"),
+                std::env::var("ETCD_PASSWORD"),
+            ) {
+                (Ok(username), Ok(password)) => {
+                    Some(etcd_client::ConnectOptions::new().with_user(username, password))
+                }
+                _ => None,
+            };
+
+            Some(etcd_client::Client::connect(&etcd_urls, etcd_connection_options).await?)
+        }
+    };
+
+    let mut aspen_clients: HashMap<SocketAddr, AspenRpcClient> = HashMap::new();
+
+    let replay_start = Instant::now();
+
+    for fetch in fetches {
+        if flags.speed > 0.0 {
+            let offset_ms =
+                (fetch.time_of_submission_ms - first_submission_ms) as f64 / flags.speed;
+
+            tokio::time::sleep_until(
+                (replay_start + Duration::from_millis(offset_ms as u64)).into(),
+            )
+            .await;
+        }
+
+        let socket = match (flags.aspen, etcd.as_mut()) {
+            (Some(socket), _) => socket,
+            (None, Some(etcd)) => {
+                match get_node_for_realtime_feed_id(etcd, &fetch.realtime_feed_id).await {
+                    Some(data) => data.socket,
+                    None => {
+                        eprintln!("{} was not assigned to a worker", fetch.realtime_feed_id);
+                        continue;
+                    }
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+
+        if !aspen_clients.contains_key(&socket) {
+            let aspen_client = catenary::aspen::lib::spawn_aspen_client_from_ip(&socket).await?;
+            aspen_clients.insert(socket, aspen_client);
+        }
+
+        let aspen_client = aspen_clients.get(&socket).unwrap();
+
+        let feed_id = fetch.realtime_feed_id.clone();
+
+        let sent = aspen_client
+            .from_alpenrose(
+                tarpc::context::current(),
+                fetch.chateau_id,
+                fetch.realtime_feed_id,
+                fetch.vehicles,
+                fetch.trips,
+                fetch.alerts,
+                fetch.has_vehicles,
+                fetch.has_trips,
+                fetch.has_alerts,
+                fetch.vehicles_response_code,
+                fetch.trips_response_code,
+                fetch.alerts_response_code,
+                fetch.time_of_submission_ms,
+            )
+            .await;
+
+        match sent {
+            Ok(_) => println!("{}: replayed to {}", feed_id, socket),
+            Err(e) => {
+                eprintln!("{}: Error sending data to {}: {}", feed_id, socket, e);
+                // reconnect for the next fetch
+                aspen_clients.remove(&socket);
+            }
+        }
+    }
+
+    println!("Replay finished in {:?}", replay_start.elapsed());
+
+    Ok(())
+}
@@ -1,7 +1,14 @@
+use crate::feed_cost::{record_fetch, FeedCost};
+use crate::fetch_policy::{
+    is_backing_off, is_failure_status, make_conditional, register_failure, retry_after_of_response,
+    save_validators, validators_of_response, CacheValidators, FeedBackoff, ValidatorsPerUrl,
+};
 use crate::KeyFormat;
 use crate::RealtimeFeedFetch;
 use catenary::ahash_fast_hash;
+use catenary::alpenrose::recorder::{RecordedFetch, Recorder};
 use catenary::duration_since_unix_epoch;
+use catenary::feed_health::{FetchHistory, FetchRecord};
 use catenary::get_node_for_realtime_feed_id;
 use dashmap::DashMap;
 use futures::StreamExt;
@@ -12,6 +19,7 @@ use scc::HashMap as SccHashMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
 use tokio::sync::RwLock;
 
@@ -36,6 +44,11 @@ async fn cleanup_response(
     feed_id: &str,
     hashes_of_data: Arc<SccHashMap<(String, UrlType), u64>>,
 ) -> Option<Vec<u8>> {
+    //unchanged since the last fetch, the response has no body
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return None;
+    }
+
     match response.bytes().await {
         Ok(bytes_pre) => {
             let bytes = bytes_pre.as_ref().to_vec();
@@ -73,6 +86,11 @@ pub async fn single_fetch_time(
     chicago_text_str: Arc<Option<String>>,
     etcd_urls: &Vec<&str>,
     etcd_connection_options: &Option<etcd_client::ConnectOptions>,
+    recorder: Arc<Option<Recorder>>,
+    validators: Arc<ValidatorsPerUrl>,
+    backoff_per_feed: Arc<DashMap<String, FeedBackoff>>,
+    feed_costs: Arc<DashMap<String, FeedCost>>,
+    fetch_histories: Arc<DashMap<String, FetchHistory>>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let start = Instant::now();
 
@@ -87,6 +105,11 @@ pub async fn single_fetch_time(
         let amtrak_gtfs = Arc::clone(&amtrak_gtfs);
         let chicago_text_str = chicago_text_str.clone();
         let etcd_urls = etcd_urls.clone();
+        let recorder = Arc::clone(&recorder);
+        let validators = Arc::clone(&validators);
+        let backoff_per_feed = Arc::clone(&backoff_per_feed);
+        let feed_costs = Arc::clone(&feed_costs);
+        let fetch_histories = Arc::clone(&fetch_histories);
 
         async move {
             let start = Instant::now();
@@ -106,25 +129,124 @@ pub async fn single_fetch_time(
                 }
             }
 
-            last_fetch_per_feed.insert(feed_id.clone(), Instant::now());
-
-            let vehicle_positions_request =
-                make_reqwest_for_url(UrlType::VehiclePositions, assignment, client.clone());
+            if is_backing_off(&backoff_per_feed, feed_id) {
+                return;
+            }
 
-            let trip_updates_request =
-                make_reqwest_for_url(UrlType::TripUpdates, assignment, client.clone());
+            last_fetch_per_feed.insert(feed_id.clone(), Instant::now());
 
-            let alerts_request = make_reqwest_for_url(UrlType::Alerts, assignment, client.clone());
+            let vehicle_positions_request = make_conditional(
+                make_reqwest_for_url(UrlType::VehiclePositions, assignment, client.clone()),
+                feed_id,
+                UrlType::VehiclePositions,
+                &validators,
+            );
+
+            let trip_updates_request = make_conditional(
+                make_reqwest_for_url(UrlType::TripUpdates, assignment, client.clone()),
+                feed_id,
+                UrlType::TripUpdates,
+                &validators,
+            );
+
+            let alerts_request = make_conditional(
+                make_reqwest_for_url(UrlType::Alerts, assignment, client.clone()),
+                feed_id,
+                UrlType::Alerts,
+                &validators,
+            );
 
             //run all requests concurrently
-            let vehicle_positions_future =
-                run_optional_req(vehicle_positions_request, client.clone());
-            let trip_updates_future = run_optional_req(trip_updates_request, client.clone());
-            let alerts_future = run_optional_req(alerts_request, client.clone());
+            let vehicle_positions_future = run_timed_req(vehicle_positions_request, client.clone());
+            let trip_updates_future = run_timed_req(trip_updates_request, client.clone());
+            let alerts_future = run_timed_req(alerts_request, client.clone());
 
             println!("{}: Fetching data", feed_id);
-            let (vehicle_positions_data, trip_updates_data, alerts_data) =
-                futures::join!(vehicle_positions_future, trip_updates_future, alerts_future,);
+            let (
+                (vehicle_positions_data, vehicle_positions_latency),
+                (trip_updates_data, trip_updates_latency),
+                (alerts_data, alerts_latency),
+            ) = futures::join!(vehicle_positions_future, trip_updates_future, alerts_future,);
+
+            let fetch_time_ms = duration_since_unix_epoch().as_millis() as u64;
+
+            //slow down for feeds which are rate limited, failing or timing out
+            let mut failed = false;
+            let mut retry_after: Option<Duration> = None;
+            let mut payload_bytes: u64 = 0;
+            //saved once the data is with Aspen
+            let mut received_validators: Vec<(UrlType, CacheValidators)> = vec![];
+
+            for (url_type, data, latency) in [
+                (
+                    UrlType::VehiclePositions,
+                    &vehicle_positions_data,
+                    vehicle_positions_latency,
+                ),
+                (
+                    UrlType::TripUpdates,
+                    &trip_updates_data,
+                    trip_updates_latency,
+                ),
+                (UrlType::Alerts, &alerts_data, alerts_latency),
+            ] {
+                if let Some(data) = data {
+                    fetch_histories
+                        .entry(feed_id.clone())
+                        .or_default()
+                        .push(FetchRecord {
+                            time_ms: fetch_time_ms,
+                            feed_type: url_type.feed_type_name().to_string(),
+                            http_status: data
+                                .as_ref()
+                                .ok()
+                                .map(|response| response.status().as_u16()),
+                            latency_ms: latency.as_millis() as u64,
+                            bytes: data
+                                .as_ref()
+                                .ok()
+                                .and_then(|response| response.content_length()),
+                            error: data.as_ref().err().map(|err| err.to_string()),
+                        });
+                }
+
+                match data {
+                    Some(Ok(response)) => {
+                        let status = response.status().as_u16();
+
+                        payload_bytes += response.content_length().unwrap_or(0);
+
+                        if status == 200 {
+                            received_validators.push((url_type, validators_of_response(response)));
+                        }
+
+                        if is_failure_status(status) {
+                            failed = true;
+                            retry_after = retry_after.max(retry_after_of_response(response));
+                        }
+                    }
+                    Some(Err(_)) => {
+                        failed = true;
+                    }
+                    None => {}
+                }
+            }
+
+            match failed {
+                true => {
+                    let wait = register_failure(
+                        &backoff_per_feed,
+                        feed_id,
+                        Duration::from_millis(fetch_interval_ms as u64),
+                        retry_after,
+                    );
+
+                    println!("{}: backing off for {:?}", feed_id, wait);
+                }
+                false => {
+                    backoff_per_feed.remove(feed_id);
+                }
+            }
 
             //send the data to aspen via tarpc
 
@@ -144,18 +266,46 @@ pub async fn single_fetch_time(
                     _ => None,
                 };
 
+                //lookup currently assigned realtime dataset in zookeeper
+                let fetch_assigned_node_meta =
+                    get_node_for_realtime_feed_id(&mut etcd, feed_id).await;
+
+                // every response is recorded, the payloads only when they are sent to aspen
+                let mut fetch = RecordedFetch {
+                    chateau_id: fetch_assigned_node_meta
+                        .as_ref()
+                        .map(|data| data.chateau_id.clone())
+                        .unwrap_or_default(),
+                    realtime_feed_id: feed_id.clone(),
+                    vehicles: None,
+                    trips: None,
+                    alerts: None,
+                    has_vehicles: assignment.realtime_vehicle_positions.is_some(),
+                    has_trips: assignment.realtime_trip_updates.is_some(),
+                    has_alerts: assignment.realtime_alerts.is_some(),
+                    vehicles_response_code: vehicle_positions_http_status,
+                    trips_response_code: trip_updates_http_status,
+                    alerts_response_code: alerts_http_status,
+                    time_of_submission_ms: fetch_time_ms,
+                    vehicles_error: request_error(&vehicle_positions_data),
+                    trips_error: request_error(&trip_updates_data),
+                    alerts_error: request_error(&alerts_data),
+                    sent_to_aspen: false,
+                };
+
                 if (vehicle_positions_http_status == Some(429))
                     || (trip_updates_http_status == Some(429))
                     || (alerts_http_status == Some(429))
                 {
                     println!("{}: 429 Rate limited", feed_id);
+
+                    if let Some(recorder) = recorder.as_ref() {
+                        recorder.record(&fetch);
+                    }
+
                     return;
                 }
 
-                //lookup currently assigned realtime dataset in zookeeper
-                let fetch_assigned_node_meta =
-                    get_node_for_realtime_feed_id(&mut etcd, feed_id).await;
-
                 match fetch_assigned_node_meta {
                     Some(data) => {
                         let worker_id = data.worker_id;
@@ -175,63 +325,72 @@ pub async fn single_fetch_time(
                                     || trip_updates_http_status == Some(200)
                                     || alerts_http_status == Some(200)
                                 {
-                                    let tarpc_send_to_aspen = aspen_client
-                                        .from_alpenrose(
-                                            tarpc::context::current(),
-                                            data.chateau_id.clone(),
-                                            feed_id.clone(),
-                                            match vehicle_positions_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::VehiclePositions,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            match trip_updates_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::TripUpdates,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            match alerts_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::Alerts,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            assignment.realtime_vehicle_positions.is_some(),
-                                            assignment.realtime_trip_updates.is_some(),
-                                            assignment.realtime_alerts.is_some(),
-                                            vehicle_positions_http_status,
-                                            trip_updates_http_status,
-                                            alerts_http_status,
-                                            duration_since_unix_epoch().as_millis() as u64,
-                                        )
-                                        .await;
+                                    fetch.vehicles = match vehicle_positions_data {
+                                        Some(Ok(response)) => {
+                                            cleanup_response(
+                                                response,
+                                                UrlType::VehiclePositions,
+                                                feed_id,
+                                                Arc::clone(&hashes_of_data),
+                                            )
+                                            .await
+                                        }
+                                        _ => None,
+                                    };
+                                    fetch.trips = match trip_updates_data {
+                                        Some(Ok(response)) => {
+                                            cleanup_response(
+                                                response,
+                                                UrlType::TripUpdates,
+                                                feed_id,
+                                                Arc::clone(&hashes_of_data),
+                                            )
+                                            .await
+                                        }
+                                        _ => None,
+                                    };
+                                    fetch.alerts = match alerts_data {
+                                        Some(Ok(response)) => {
+                                            cleanup_response(
+                                                response,
+                                                UrlType::Alerts,
+                                                feed_id,
+                                                Arc::clone(&hashes_of_data),
+                                            )
+                                            .await
+                                        }
+                                        _ => None,
+                                    };
+                                    fetch.time_of_submission_ms =
+                                        duration_since_unix_epoch().as_millis() as u64;
+                                    fetch.sent_to_aspen = true;
+
+                                    let tarpc_send_to_aspen = custom_rt_feeds::send_to_aspen(
+                                        &aspen_client,
+                                        fetch,
+                                        &recorder,
+                                    )
+                                    .await;
 
                                     match tarpc_send_to_aspen {
-                                        Ok(_) => {
+                                        Ok(accepted) => {
                                             println!(
                                         "feed {}|chateau {}: Successfully sent data sent to {}",
                                         feed_id, data.chateau_id, worker_id
                                     );
+
+                                            if accepted {
+                                                for (url_type, new_validators) in
+                                                    received_validators
+                                                {
+                                                    save_validators(
+                                                        feed_id,
+                                                        url_type,
+                                                        new_validators,
+                                                        &validators,
+                                                    );
+                                                }
+                                            }
                                         }
                                         Err(e) => {
                                             eprintln!(
@@ -241,16 +400,29 @@ pub async fn single_fetch_time(
                                         }
                                     }
                                 } else {
+                                    // 304 Not Modified also ends up here
                                     println!("{}: No data to send", feed_id);
+
+                                    if let Some(recorder) = recorder.as_ref() {
+                                        recorder.record(&fetch);
+                                    }
                                 }
                             }
                             Err(aspen_connection_error) => {
                                 eprintln!("aspen connection error: {:#?}", aspen_connection_error);
+
+                                if let Some(recorder) = recorder.as_ref() {
+                                    recorder.record(&fetch);
+                                }
                             }
                         };
                     }
                     None => {
                         eprintln!("{} was not assigned to a worker", feed_id);
+
+                        if let Some(recorder) = recorder.as_ref() {
+                            recorder.record(&fetch);
+                        }
                     }
                 }
             } else {
@@ -261,27 +433,36 @@ pub async fn single_fetch_time(
                             feed_id,
                             &amtrak_gtfs,
                             &client,
+                            &recorder,
                         )
                         .await;
                     }
                     "f-viarail~rt" => {
-                        custom_rt_feeds::viarail::fetch_via_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::viarail::fetch_via_data(
+                            &mut etcd, feed_id, &client, &recorder,
+                        )
+                        .await;
                     }
                     "f-mta~nyc~rt~lirr" => {
-                        custom_rt_feeds::mta::fetch_mta_lirr_data(&mut etcd, feed_id, &client)
-                            .await;
+                        custom_rt_feeds::mta::fetch_mta_lirr_data(
+                            &mut etcd, feed_id, &client, &recorder,
+                        )
+                        .await;
                     }
                     "f-mta~nyc~rt~mnr" => {
                         custom_rt_feeds::mta::fetch_mta_metronorth_data(
-                            &mut etcd, feed_id, &client,
+                            &mut etcd, feed_id, &client, &recorder,
                         )
                         .await;
                     }
                     "f-bus~dft~gov~uk~rt" => {
-                        custom_rt_feeds::uk::fetch_dft_bus_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::uk::fetch_dft_bus_data(
+                            &mut etcd, feed_id, &client, &recorder,
+                        )
+                        .await;
                     }
                     "f-uc~irvine~anteater~express~rt" => {
-                        custom_rt_feeds::uci::fetch_uci_data(&mut etcd, feed_id).await;
+                        custom_rt_feeds::uci::fetch_uci_data(&mut etcd, feed_id, &recorder).await;
                     }
                     "f-dp3-cta~rt" => match chicago_text_str.as_ref() {
                         Some(chicago_text_str) => {
@@ -290,13 +471,17 @@ pub async fn single_fetch_time(
                                 feed_id,
                                 &client,
                                 chicago_text_str.as_str(),
+                                &recorder,
                             )
                             .await;
                         }
                         None => {}
                     },
                     "f-tlms~rt" => {
-                        custom_rt_feeds::tlms::fetch_tlms_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::tlms::fetch_tlms_data(
+                            &mut etcd, feed_id, &client, &recorder,
+                        )
+                        .await;
                     }
                     _ => {}
                 }
@@ -305,6 +490,13 @@ pub async fn single_fetch_time(
             let duration = start.elapsed();
             let duration = duration.as_secs_f64();
             println!("{}: {:.2?}", feed_id, duration);
+
+            record_fetch(
+                &feed_costs,
+                feed_id,
+                duration * 1000.0,
+                payload_bytes as f64,
+            );
         }
     }))
     .buffer_unordered(20)
@@ -314,6 +506,15 @@ pub async fn single_fetch_time(
     Ok(())
 }
 
+fn request_error(
+    data: &Option<Result<Response, Box<dyn std::error::Error + Sync + Send>>>,
+) -> Option<String> {
+    match data {
+        Some(Err(err)) => Some(err.to_string()),
+        _ => None,
+    }
+}
+
 async fn run_optional_req(
     request: Option<reqwest::Request>,
     client: reqwest::Client,
@@ -324,6 +525,19 @@ async fn run_optional_req(
     }
 }
 
+async fn run_timed_req(
+    request: Option<reqwest::Request>,
+    client: reqwest::Client,
+) -> (
+    Option<Result<Response, Box<dyn std::error::Error + Sync + Send>>>,
+    Duration,
+) {
+    let start = Instant::now();
+    let response = run_optional_req(request, client).await;
+
+    (response, start.elapsed())
+}
+
 #[derive(Debug, Hash, Clone, Eq, PartialEq)]
 pub enum UrlType {
     VehiclePositions,
@@ -331,6 +545,16 @@ pub enum UrlType {
     Alerts,
 }
 
+impl UrlType {
+    pub fn feed_type_name(&self) -> &'static str {
+        match self {
+            UrlType::VehiclePositions => "vehicles",
+            UrlType::TripUpdates => "trips",
+            UrlType::Alerts => "alerts",
+        }
+    }
+}
+
 pub fn make_reqwest_for_url(
     url_type: UrlType,
     assignment: &RealtimeFeedFetch,
@@ -0,0 +1,64 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Compares the alerts of a chateau before and after an ingestion, so Spruce can forward only what changed.
+// Events are numbered per chateau, and a short log is kept for clients to catch up from their last sequence number.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::{AlertEventKind, AspenisedAlert, AspenisedAlertEvent};
+
+/// Number of alert events kept per chateau
+pub const MAX_ALERT_EVENTS_KEPT: usize = 1000;
+
+/// New, changed and removed alerts, numbered after `last_sequence`
+pub fn diff_alerts(
+    previous: &AHashMap<String, AspenisedAlert>,
+    current: &AHashMap<String, AspenisedAlert>,
+    last_sequence: u64,
+) -> Vec<AspenisedAlertEvent> {
+    let mut changes: Vec<(AlertEventKind, &String, &AspenisedAlert)> = vec![];
+
+    for (alert_id, alert) in current.iter() {
+        match previous.get(alert_id) {
+            None => changes.push((AlertEventKind::New, alert_id, alert)),
+            Some(previous_alert) if previous_alert != alert => {
+                changes.push((AlertEventKind::Changed, alert_id, alert))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (alert_id, alert) in previous.iter() {
+        if !current.contains_key(alert_id) {
+            changes.push((AlertEventKind::Removed, alert_id, alert));
+        }
+    }
+
+    // keep the numbering stable between runs with the same data
+    changes.sort_by(|a, b| a.1.cmp(b.1));
+
+    changes
+        .into_iter()
+        .enumerate()
+        .map(|(i, (kind, alert_id, alert))| AspenisedAlertEvent {
+            sequence: last_sequence + 1 + i as u64,
+            kind,
+            alert_id: alert_id.clone(),
+            alert: alert.clone(),
+        })
+        .collect()
+}
+
+/// Appends events to the log, dropping the oldest beyond `MAX_ALERT_EVENTS_KEPT`
+pub fn append_alert_events(
+    alert_events: &mut Vec<AspenisedAlertEvent>,
+    new_events: Vec<AspenisedAlertEvent>,
+) {
+    alert_events.extend(new_events);
+
+    if alert_events.len() > MAX_ALERT_EVENTS_KEPT {
+        let excess = alert_events.len() - MAX_ALERT_EVENTS_KEPT;
+        alert_events.drain(..excess);
+    }
+}
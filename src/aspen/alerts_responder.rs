@@ -1,3 +1,4 @@
+use catenary::aspen::lib::AlertEventsResponse;
 use catenary::aspen::lib::AlertsforManyStops;
 
 use catenary::aspen_dataset::AspenisedAlert;
@@ -131,3 +132,35 @@ pub fn get_alert_from_stop_ids(
 ) -> Option<AlertsforManyStops> {
     None
 }
+
+pub fn get_alert_events_since(
+    authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
+    chateau_id: &str,
+    after_sequence: Option<u64>,
+) -> Option<AlertEventsResponse> {
+    match authoritative_data_store.get(chateau_id) {
+        Some(aspenised_data) => {
+            let aspenised_data = aspenised_data.get();
+
+            let latest_sequence = aspenised_data.alert_event_sequence;
+
+            let after_sequence = match after_sequence {
+                Some(after_sequence) if after_sequence <= latest_sequence => after_sequence,
+                // numbering restarted because the chateau was loaded again, so everything is new to the caller
+                Some(_) => 0,
+                None => latest_sequence,
+            };
+
+            Some(AlertEventsResponse {
+                events: aspenised_data
+                    .alert_events
+                    .iter()
+                    .filter(|event| event.sequence > after_sequence)
+                    .cloned()
+                    .collect(),
+                latest_sequence,
+            })
+        }
+        None => None,
+    }
+}
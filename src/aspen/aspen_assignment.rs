@@ -7,8 +7,9 @@ use catenary::ChateauDataNoGeometry;
 use diesel::query_dsl::select_dsl::SelectDsl;
 use diesel::SelectableHelper;
 use diesel_async::RunQueryDsl;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -114,20 +115,127 @@ pub async fn assign_chateaus(
         } else {
             println!("Assigning tasks to workers....");
 
-            if let Some(chateau_list_lock) = chateau_list_lock.as_ref() {
-                for (index, (chateau_id, chateau)) in chateau_list_lock.chateaus.iter().enumerate()
+            //current owners, so moved chateaus can be handed off before the new assignment is visible
+            let mut previous_assignments: BTreeMap<String, ChateauMetadataEtcd> = BTreeMap::new();
+
+            let fetch_assignments_from_etcd = etcd
+                .get(
+                    "/aspen_assigned_chateaus/",
+                    Some(etcd_client::GetOptions::new().with_prefix()),
+                )
+                .await?;
+
+            for kv in fetch_assignments_from_etcd.kvs() {
+                if let (Ok(key), Ok(decoded_metadata)) = (
+                    kv.key_str(),
+                    bincode::deserialize::<ChateauMetadataEtcd>(kv.value()),
+                ) {
+                    previous_assignments.insert(
+                        key.trim_start_matches("/aspen_assigned_chateaus/")
+                            .to_string(),
+                        decoded_metadata,
+                    );
+                }
+            }
+
+            //workers shutting down are no longer assigned chateaus, but still hand theirs off
+            let mut draining_workers: BTreeSet<String> = BTreeSet::new();
+
+            let fetch_draining_workers_from_etcd = etcd
+                .get(
+                    "/aspen_draining_workers/",
+                    Some(etcd_client::GetOptions::new().with_prefix()),
+                )
+                .await?;
+
+            for kv in fetch_draining_workers_from_etcd.kvs() {
+                if let Ok(decoded_metadata) =
+                    bincode::deserialize::<AspenWorkerMetadataEtcd>(kv.value())
                 {
-                    // in the future, this should be rewritten to prevent full reshuffling of the entire assignment list
-                    // For example, taking only the orphened nodes and redistributing them
-                    // if a new node is added, carefully reassign the data
+                    draining_workers.insert(decoded_metadata.worker_id);
+                }
+            }
+
+            let chateau_list_lock_guard = chateau_list_lock;
+
+            if let Some(chateau_list_lock) = chateau_list_lock_guard.as_ref() {
+                //balance by the measured size of each chateau, keeping chateaus on their current worker where possible
 
-                    //for now this simply round robins the chateaus around
+                let chateau_costs = crate::chateau_load::get_chateau_costs(etcd).await?;
+                let pinned_chateaus = crate::chateau_load::get_pinned_chateaus(etcd).await?;
 
-                    let selected_aspen_worker_to_assign =
-                        workers_nodes_lock[index % workers_nodes_lock.len()].clone();
+                for (chateau_id, worker_id) in pinned_chateaus.iter() {
+                    if !workers_map.contains_key(worker_id) {
+                        println!(
+                            "{} is pinned to {}, which is not running",
+                            chateau_id, worker_id
+                        );
+                    }
+                }
 
-                    let worker_metadata =
-                        workers_map.get(&selected_aspen_worker_to_assign).unwrap();
+                let weighted_chateaus = chateau_list_lock
+                    .chateaus
+                    .iter()
+                    .map(|(chateau_id, chateau)| {
+                        (
+                            chateau_id.clone(),
+                            crate::chateau_load::chateau_weight(
+                                chateau_costs.get(chateau_id),
+                                chateau.realtime_feeds.len(),
+                            ),
+                        )
+                    })
+                    .collect::<Vec<(String, f64)>>();
+
+                let previous_owners = previous_assignments
+                    .iter()
+                    .map(|(chateau_id, metadata)| (chateau_id.clone(), metadata.worker_id.clone()))
+                    .collect::<BTreeMap<String, String>>();
+
+                let balanced_assignments = crate::chateau_load::balance_chateaus(
+                    &weighted_chateaus,
+                    &workers_nodes_lock,
+                    &previous_owners,
+                    &pinned_chateaus,
+                );
+
+                let worker_sockets = workers_map
+                    .iter()
+                    .map(|(worker_id, metadata)| (worker_id.clone(), metadata.socket))
+                    .collect::<BTreeMap<String, SocketAddr>>();
+
+                let planned_hand_offs = crate::chateau_handoff::plan_hand_offs(
+                    &chateau_list_lock.chateaus,
+                    &balanced_assignments,
+                    &previous_assignments,
+                    &worker_sockets,
+                    &draining_workers,
+                );
+
+                let chateaus_to_save = chateau_list_lock
+                    .chateaus
+                    .iter()
+                    .map(|(chateau_id, chateau)| {
+                        (
+                            chateau_id.clone(),
+                            chateau.realtime_feeds.clone(),
+                            balanced_assignments.get(chateau_id).unwrap().clone(),
+                        )
+                    })
+                    .collect::<Vec<(String, Vec<String>, String)>>();
+
+                let workers_count = workers_nodes_lock.len();
+
+                // hand offs can take seconds, so they run without holding the assignment locks
+                drop(chateau_list_lock_guard);
+                drop(workers_nodes_lock);
+
+                crate::chateau_handoff::run_hand_offs(&planned_hand_offs).await;
+
+                for (chateau_id, realtime_feeds, selected_aspen_worker_to_assign) in
+                    chateaus_to_save.iter()
+                {
+                    let worker_metadata = workers_map.get(selected_aspen_worker_to_assign).unwrap();
 
                     let assigned_chateau_data = ChateauMetadataEtcd {
                         worker_id: selected_aspen_worker_to_assign.clone(),
@@ -145,7 +253,7 @@ pub async fn assign_chateaus(
                         )
                         .await?;
 
-                    for realtime_feed_id in chateau.realtime_feeds.iter() {
+                    for realtime_feed_id in realtime_feeds.iter() {
                         let assigned_realtime_feed_data = RealtimeFeedMetadataEtcd {
                             worker_id: selected_aspen_worker_to_assign.clone(),
                             socket: worker_metadata.socket,
@@ -165,10 +273,19 @@ pub async fn assign_chateaus(
                     }
                 }
 
+                let moved_chateaus = balanced_assignments
+                    .iter()
+                    .filter(|(chateau_id, worker_id)| {
+                        previous_owners.get(*chateau_id) != Some(*worker_id)
+                    })
+                    .count();
+
+                println!("{} chateaus changed worker", moved_chateaus);
+
                 println!(
                     "Assigned {} chateaus across {} workers",
-                    chateau_list_lock.chateaus.len(),
-                    workers_nodes_lock.len()
+                    chateaus_to_save.len(),
+                    workers_count
                 );
             }
 
@@ -8,7 +8,9 @@ use std::collections::HashSet;
 use std::error::Error;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 
 use crate::import_alpenrose::new_rt_data;
@@ -21,7 +23,13 @@ pub async fn alpenrose_process_threads(
     alpenrosethreadcount: usize,
     chateau_queue_list: Arc<Mutex<HashSet<String>>>,
     lease_id_for_this_worker: i64,
+    processing_times: Arc<SccHashMap<String, f64>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let last_validated: Arc<SccHashMap<String, u64>> = Arc::new(SccHashMap::new());
+    let validation_permits = Arc::new(Semaphore::new(
+        crate::rt_validation::MAX_CONCURRENT_VALIDATIONS,
+    ));
+
     let mut set: JoinSet<_> = (0usize..alpenrosethreadcount)
         .map(|i| {
             let alpenrose_to_process_queue = Arc::clone(&alpenrose_to_process_queue);
@@ -29,6 +37,9 @@ pub async fn alpenrose_process_threads(
             let authoritative_data_store = Arc::clone(&authoritative_data_store);
             let conn_pool = Arc::clone(&conn_pool);
             let chateau_queue_list = Arc::clone(&chateau_queue_list);
+            let processing_times = Arc::clone(&processing_times);
+            let last_validated = Arc::clone(&last_validated);
+            let validation_permits = Arc::clone(&validation_permits);
             async move {
                 alpenrose_loop_process_thread(
                     alpenrose_to_process_queue,
@@ -36,6 +47,9 @@ pub async fn alpenrose_process_threads(
                     authoritative_data_store,
                     conn_pool,
                     chateau_queue_list,
+                    processing_times,
+                    last_validated,
+                    validation_permits,
                 )
                 .await
             }
@@ -55,6 +69,9 @@ pub async fn alpenrose_loop_process_thread(
     authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
     conn_pool: Arc<CatenaryPostgresPool>,
     chateau_queue_list: Arc<Mutex<HashSet<String>>>,
+    processing_times: Arc<SccHashMap<String, f64>>,
+    last_validated: Arc<SccHashMap<String, u64>>,
+    validation_permits: Arc<Semaphore>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     loop {
         // println!("From-Alpenrose process thread");
@@ -67,6 +84,9 @@ pub async fn alpenrose_loop_process_thread(
 
             drop(chateau_queue_list);
 
+            let chateau_id = new_ingest_task.chateau_id.clone();
+            let processing_start = Instant::now();
+
             let rt_processed_status = new_rt_data(
                 Arc::clone(&authoritative_data_store),
                 Arc::clone(&authoritative_gtfs_rt_store),
@@ -81,6 +101,36 @@ pub async fn alpenrose_loop_process_thread(
                 Arc::clone(&conn_pool),
             )
             .await?;
+
+            crate::chateau_load::record_processing(
+                &processing_times,
+                &chateau_id,
+                processing_start.elapsed().as_secs_f64() * 1000.0,
+            );
+
+            //validation queries postgres, so it runs beside the processing loop
+            //when every permit is taken, the feed is validated on a later ingest instead
+            if let Ok(permit) = Arc::clone(&validation_permits).try_acquire_owned() {
+                let authoritative_gtfs_rt_store = Arc::clone(&authoritative_gtfs_rt_store);
+                let conn_pool = Arc::clone(&conn_pool);
+                let last_validated = Arc::clone(&last_validated);
+
+                tokio::spawn(async move {
+                    if let Err(err) = crate::rt_validation::validate_feed_if_due(
+                        chateau_id,
+                        feed_id.clone(),
+                        authoritative_gtfs_rt_store,
+                        conn_pool,
+                        last_validated,
+                    )
+                    .await
+                    {
+                        eprintln!("Could not validate {}: {}", feed_id, err);
+                    }
+
+                    drop(permit);
+                });
+            }
         } else {
             tokio::time::sleep(Duration::from_millis(10)).await;
         }
@@ -0,0 +1,537 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Moves the realtime state of a chateau from its previous worker to the new one when the leader reassigns it.
+// The leader asks the previous owner to send the state before the new assignment is written to etcd,
+// so Birch never reaches a worker which has no data for the chateau.
+// The previous owner keeps serving the chateau until it sees the new assignment in etcd, and only then drops its state.
+// A worker shutting down gracefully drains first: it leaves /aspen_workers/ for /aspen_draining_workers/,
+// so the leader moves its chateaus away and still asks it for their state.
+
+use ahash::AHashMap;
+use catenary::aspen::lib::{AspenWorkerMetadataEtcd, ChateauMetadataEtcd, ChateauStateHandoff};
+use catenary::aspen_dataset::{AspenisedData, GtfsRtType};
+use catenary::rt_recent_history::{RtCacheEntry, RtKey};
+use catenary::ChateauDataNoGeometry;
+use compact_str::CompactString;
+use gtfs_realtime::FeedMessage;
+use prost::Message;
+use scc::HashMap as SccHashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tarpc::context;
+
+/// Hand offs run concurrently while the leader holds a 10 second etcd lease, so they must finish well within it
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Longest a shutting down worker waits for its chateaus to be moved away
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn handoff_context() -> context::Context {
+    let mut ctx = context::current();
+    ctx.deadline = Instant::now() + HANDOFF_TIMEOUT;
+    ctx
+}
+
+pub fn export_chateau_state(
+    authoritative_data_store: &SccHashMap<String, AspenisedData>,
+    authoritative_gtfs_rt_store: &SccHashMap<(String, GtfsRtType), FeedMessage>,
+    trip_updates_history: &SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    chateau_id: &str,
+    realtime_feed_ids: &[String],
+) -> ChateauStateHandoff {
+    let mut gtfs_rt = vec![];
+    let mut history = vec![];
+
+    for realtime_feed_id in realtime_feed_ids {
+        for feed_type in [
+            GtfsRtType::VehiclePositions,
+            GtfsRtType::TripUpdates,
+            GtfsRtType::Alerts,
+        ] {
+            if let Some(message) =
+                authoritative_gtfs_rt_store.get(&(realtime_feed_id.clone(), feed_type))
+            {
+                gtfs_rt.push((
+                    realtime_feed_id.clone(),
+                    feed_type,
+                    message.get().encode_to_vec(),
+                ));
+            }
+        }
+
+        if let Some(entries) = trip_updates_history.get(realtime_feed_id.as_str()) {
+            history.push((
+                CompactString::from(realtime_feed_id.as_str()),
+                entries
+                    .get()
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.clone()))
+                    .collect(),
+            ));
+        }
+    }
+
+    ChateauStateHandoff {
+        aspenised_data: authoritative_data_store
+            .get(chateau_id)
+            .map(|data| data.get().clone()),
+        gtfs_rt,
+        trip_updates_history: history,
+    }
+}
+
+/// Sends the state of the chateau to its new owner, returns true if it was accepted
+pub async fn send_chateau_state(
+    new_owner: SocketAddr,
+    chateau_id: String,
+    state: ChateauStateHandoff,
+) -> bool {
+    let aspen_client = match catenary::aspen::lib::spawn_aspen_client_from_ip(&new_owner).await {
+        Ok(aspen_client) => aspen_client,
+        Err(err) => {
+            eprintln!("Could not connect to new owner of {}: {}", chateau_id, err);
+            return false;
+        }
+    };
+
+    match aspen_client
+        .receive_chateau_state(handoff_context(), chateau_id.clone(), state)
+        .await
+    {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            eprintln!("Could not hand off {}: {}", chateau_id, err);
+            false
+        }
+    }
+}
+
+/// Drops the state of a chateau once etcd shows it assigned to another worker, so it is not served from two workers
+pub fn remove_chateau_state(
+    authoritative_data_store: &SccHashMap<String, AspenisedData>,
+    authoritative_gtfs_rt_store: &SccHashMap<(String, GtfsRtType), FeedMessage>,
+    trip_updates_history: &SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    chateau_id: &str,
+    realtime_feed_ids: &[String],
+) {
+    authoritative_data_store.remove(chateau_id);
+
+    for realtime_feed_id in realtime_feed_ids {
+        for feed_type in [
+            GtfsRtType::VehiclePositions,
+            GtfsRtType::TripUpdates,
+            GtfsRtType::Alerts,
+        ] {
+            authoritative_gtfs_rt_store.remove(&(realtime_feed_id.clone(), feed_type));
+        }
+
+        trip_updates_history.remove(realtime_feed_id.as_str());
+    }
+}
+
+/// Chateaus which this worker held and which are now assigned elsewhere, tracked across assignment checks
+#[derive(Default)]
+pub struct MovedChateaus {
+    previously_assigned: BTreeMap<String, Vec<String>>,
+    /// chateau id -> realtime feed ids
+    moved_away: BTreeMap<String, Vec<String>>,
+}
+
+impl MovedChateaus {
+    /// Chateaus whose state should be dropped after this assignment check.
+    /// They stay listed while assigned elsewhere, so state re-created by an Alpenrose
+    /// which has not seen the new assignment yet is dropped as well.
+    pub fn update(
+        &mut self,
+        assigned: &BTreeMap<String, Vec<String>>,
+    ) -> Vec<(String, Vec<String>)> {
+        for (chateau_id, realtime_feed_ids) in self.previously_assigned.iter() {
+            if !assigned.contains_key(chateau_id) {
+                self.moved_away
+                    .insert(chateau_id.clone(), realtime_feed_ids.clone());
+            }
+        }
+
+        self.moved_away
+            .retain(|chateau_id, _| !assigned.contains_key(chateau_id));
+        self.previously_assigned = assigned.clone();
+
+        self.moved_away
+            .iter()
+            .map(|(chateau_id, realtime_feed_ids)| (chateau_id.clone(), realtime_feed_ids.clone()))
+            .collect()
+    }
+}
+
+/// Merges state from the previous owner, keeping anything this worker received more recently
+pub fn import_chateau_state(
+    authoritative_data_store: &SccHashMap<String, AspenisedData>,
+    authoritative_gtfs_rt_store: &SccHashMap<(String, GtfsRtType), FeedMessage>,
+    trip_updates_history: &SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    chateau_id: String,
+    state: ChateauStateHandoff,
+) {
+    if let Some(handed_off_data) = state.aspenised_data {
+        authoritative_data_store
+            .entry(chateau_id)
+            .and_modify(|data| {
+                if data.last_updated_time_ms < handed_off_data.last_updated_time_ms {
+                    *data = handed_off_data.clone();
+                }
+            })
+            .or_insert(handed_off_data);
+    }
+
+    for (realtime_feed_id, feed_type, bytes) in state.gtfs_rt {
+        match FeedMessage::decode(bytes.as_slice()) {
+            Ok(message) => {
+                authoritative_gtfs_rt_store
+                    .entry((realtime_feed_id, feed_type))
+                    .or_insert(message);
+            }
+            Err(err) => eprintln!(
+                "Could not decode handed off feed {}: {}",
+                realtime_feed_id, err
+            ),
+        }
+    }
+
+    for (realtime_feed_id, entries) in state.trip_updates_history {
+        let mut history_for_feed = trip_updates_history
+            .entry(realtime_feed_id)
+            .or_insert_with(AHashMap::new);
+        let history_for_feed = history_for_feed.get_mut();
+
+        for (key, entry) in entries {
+            history_for_feed.entry(key).or_insert(entry);
+        }
+    }
+}
+
+/// Called by the leader before moving a chateau away from `previous_owner`
+pub async fn request_hand_off(
+    previous_owner: SocketAddr,
+    new_owner: SocketAddr,
+    chateau_id: &str,
+    realtime_feed_ids: &[String],
+) -> bool {
+    let aspen_client = match catenary::aspen::lib::spawn_aspen_client_from_ip(&previous_owner).await
+    {
+        Ok(aspen_client) => aspen_client,
+        Err(err) => {
+            eprintln!(
+                "Could not connect to previous owner of {}: {}",
+                chateau_id, err
+            );
+            return false;
+        }
+    };
+
+    match aspen_client
+        .hand_off_chateau(
+            handoff_context(),
+            chateau_id.to_string(),
+            realtime_feed_ids.to_vec(),
+            new_owner,
+        )
+        .await
+    {
+        Ok(handed_off) => handed_off,
+        Err(err) => {
+            eprintln!("Hand off of {} failed: {}", chateau_id, err);
+            false
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedHandOff {
+    pub chateau_id: String,
+    pub previous_owner: SocketAddr,
+    pub new_owner: SocketAddr,
+    pub realtime_feed_ids: Vec<String>,
+}
+
+/// Chateaus moving to another worker while their previous owner is still running or draining
+pub fn plan_hand_offs(
+    chateaus: &BTreeMap<String, ChateauDataNoGeometry>,
+    balanced_assignments: &BTreeMap<String, String>,
+    previous_assignments: &BTreeMap<String, ChateauMetadataEtcd>,
+    worker_sockets: &BTreeMap<String, SocketAddr>,
+    draining_workers: &BTreeSet<String>,
+) -> Vec<PlannedHandOff> {
+    balanced_assignments
+        .iter()
+        .filter_map(|(chateau_id, worker_id)| {
+            let previous_owner = previous_assignments.get(chateau_id)?;
+
+            if &previous_owner.worker_id == worker_id {
+                return None;
+            }
+
+            if !worker_sockets.contains_key(&previous_owner.worker_id)
+                && !draining_workers.contains(&previous_owner.worker_id)
+            {
+                return None;
+            }
+
+            Some(PlannedHandOff {
+                chateau_id: chateau_id.clone(),
+                previous_owner: previous_owner.socket,
+                new_owner: *worker_sockets.get(worker_id)?,
+                realtime_feed_ids: chateaus
+                    .get(chateau_id)
+                    .map(|chateau| chateau.realtime_feeds.clone())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Runs every hand off at once, each bounded by the timeout
+pub async fn run_hand_offs(planned_hand_offs: &[PlannedHandOff]) {
+    let results = futures::future::join_all(planned_hand_offs.iter().map(|hand_off| async move {
+        let handed_off = tokio::time::timeout(
+            HANDOFF_TIMEOUT,
+            request_hand_off(
+                hand_off.previous_owner,
+                hand_off.new_owner,
+                &hand_off.chateau_id,
+                &hand_off.realtime_feed_ids,
+            ),
+        )
+        .await
+        .unwrap_or(false);
+
+        (hand_off.chateau_id.as_str(), handed_off)
+    }))
+    .await;
+
+    for (chateau_id, handed_off) in results {
+        if !handed_off {
+            println!("Moving {} without its state, hand off failed", chateau_id);
+        }
+    }
+}
+
+/// Takes the worker out of the assignable workers and waits until the leader has moved its chateaus away
+pub async fn drain_worker(
+    etcd: &mut etcd_client::Client,
+    worker_metadata: &AspenWorkerMetadataEtcd,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    etcd.put(
+        format!("/aspen_draining_workers/{}", worker_metadata.worker_id).as_str(),
+        bincode::serialize(worker_metadata).unwrap(),
+        Some(etcd_client::PutOptions::new().with_lease(worker_metadata.etcd_lease_id)),
+    )
+    .await?;
+
+    etcd.delete(
+        format!("/aspen_workers/{}", worker_metadata.worker_id).as_str(),
+        None,
+    )
+    .await?;
+
+    let drain_start = Instant::now();
+
+    while drain_start.elapsed() < DRAIN_TIMEOUT {
+        let assignments = etcd
+            .get(
+                "/aspen_assigned_chateaus/",
+                Some(etcd_client::GetOptions::new().with_prefix()),
+            )
+            .await?;
+
+        let still_assigned = assignments
+            .kvs()
+            .iter()
+            .filter_map(|kv| bincode::deserialize::<ChateauMetadataEtcd>(kv.value()).ok())
+            .filter(|metadata| metadata.worker_id == worker_metadata.worker_id)
+            .count();
+
+        if still_assigned == 0 {
+            println!("All chateaus moved away, shutting down");
+            return Ok(());
+        }
+
+        println!("Waiting for {} chateaus to be moved away", still_assigned);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    println!("Chateaus were not moved away in time, shutting down anyway");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn chateau(chateau_id: &str, realtime_feeds: &[&str]) -> (String, ChateauDataNoGeometry) {
+        (
+            chateau_id.to_string(),
+            ChateauDataNoGeometry {
+                chateau_id: chateau_id.to_string(),
+                static_feeds: vec![],
+                realtime_feeds: realtime_feeds.iter().map(|feed| feed.to_string()).collect(),
+            },
+        )
+    }
+
+    fn assigned(worker_id: &str, port: u16) -> ChateauMetadataEtcd {
+        ChateauMetadataEtcd {
+            worker_id: worker_id.to_string(),
+            socket: socket(port),
+        }
+    }
+
+    #[test]
+    fn only_moved_chateaus_with_a_reachable_owner_are_handed_off() {
+        let chateaus = BTreeMap::from([
+            chateau("stays", &["f-stays~rt"]),
+            chateau("moves", &["f-moves~rt"]),
+            chateau("owner_gone", &["f-owner_gone~rt"]),
+            chateau("new", &["f-new~rt"]),
+        ]);
+
+        let balanced_assignments = BTreeMap::from([
+            (String::from("stays"), String::from("a")),
+            (String::from("moves"), String::from("b")),
+            (String::from("owner_gone"), String::from("b")),
+            (String::from("new"), String::from("a")),
+        ]);
+
+        let previous_assignments = BTreeMap::from([
+            (String::from("stays"), assigned("a", 1)),
+            (String::from("moves"), assigned("a", 1)),
+            (String::from("owner_gone"), assigned("crashed", 3)),
+        ]);
+
+        let worker_sockets = BTreeMap::from([
+            (String::from("a"), socket(1)),
+            (String::from("b"), socket(2)),
+        ]);
+
+        let planned = plan_hand_offs(
+            &chateaus,
+            &balanced_assignments,
+            &previous_assignments,
+            &worker_sockets,
+            &BTreeSet::new(),
+        );
+
+        assert_eq!(
+            planned,
+            vec![PlannedHandOff {
+                chateau_id: String::from("moves"),
+                previous_owner: socket(1),
+                new_owner: socket(2),
+                realtime_feed_ids: vec![String::from("f-moves~rt")],
+            }]
+        );
+    }
+
+    #[test]
+    fn draining_worker_hands_off_its_chateaus() {
+        let chateaus = BTreeMap::from([chateau("metro", &["f-metro~rt"])]);
+
+        let balanced_assignments = BTreeMap::from([(String::from("metro"), String::from("a"))]);
+        let previous_assignments =
+            BTreeMap::from([(String::from("metro"), assigned("draining", 9))]);
+        let worker_sockets = BTreeMap::from([(String::from("a"), socket(1))]);
+
+        let planned = plan_hand_offs(
+            &chateaus,
+            &balanced_assignments,
+            &previous_assignments,
+            &worker_sockets,
+            &BTreeSet::from([String::from("draining")]),
+        );
+
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].previous_owner, socket(9));
+        assert_eq!(planned[0].new_owner, socket(1));
+    }
+
+    #[test]
+    fn handed_off_state_is_dropped_and_merged_without_overwriting() {
+        let gtfs_rt_store: SccHashMap<(String, GtfsRtType), FeedMessage> = SccHashMap::new();
+        let history: SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>> = SccHashMap::new();
+        let data_store: SccHashMap<String, AspenisedData> = SccHashMap::new();
+
+        let mut message = FeedMessage::default();
+        message.header.timestamp = Some(100);
+        let _ = gtfs_rt_store.insert(
+            (String::from("f-metro~rt"), GtfsRtType::TripUpdates),
+            message,
+        );
+        let _ = gtfs_rt_store.insert(
+            (String::from("f-other~rt"), GtfsRtType::TripUpdates),
+            FeedMessage::default(),
+        );
+
+        let feed_ids = vec![String::from("f-metro~rt")];
+
+        let state = export_chateau_state(&data_store, &gtfs_rt_store, &history, "metro", &feed_ids);
+
+        assert_eq!(state.gtfs_rt.len(), 1);
+
+        remove_chateau_state(&data_store, &gtfs_rt_store, &history, "metro", &feed_ids);
+
+        assert!(!gtfs_rt_store.contains(&(String::from("f-metro~rt"), GtfsRtType::TripUpdates)));
+        assert!(gtfs_rt_store.contains(&(String::from("f-other~rt"), GtfsRtType::TripUpdates)));
+
+        // the new owner already has a newer message, which is kept
+        let new_owner_store: SccHashMap<(String, GtfsRtType), FeedMessage> = SccHashMap::new();
+        let mut newer = FeedMessage::default();
+        newer.header.timestamp = Some(200);
+        let _ =
+            new_owner_store.insert((String::from("f-metro~rt"), GtfsRtType::TripUpdates), newer);
+
+        import_chateau_state(
+            &data_store,
+            &new_owner_store,
+            &history,
+            String::from("metro"),
+            state,
+        );
+
+        assert_eq!(
+            new_owner_store
+                .get(&(String::from("f-metro~rt"), GtfsRtType::TripUpdates))
+                .map(|message| message.get().header.timestamp),
+            Some(Some(200))
+        );
+    }
+
+    #[test]
+    fn state_is_dropped_once_etcd_shows_the_chateau_elsewhere() {
+        let mut moved_chateaus = MovedChateaus::default();
+
+        let both = BTreeMap::from([
+            (String::from("metro"), vec![String::from("f-metro~rt")]),
+            (String::from("bart"), vec![String::from("f-bart~rt")]),
+        ]);
+        let only_bart = BTreeMap::from([(String::from("bart"), vec![String::from("f-bart~rt")])]);
+
+        assert!(moved_chateaus.update(&both).is_empty());
+
+        let expected = vec![(String::from("metro"), vec![String::from("f-metro~rt")])];
+
+        assert_eq!(moved_chateaus.update(&only_bart), expected);
+        // still assigned elsewhere, so anything Alpenrose sent in the meantime is dropped again
+        assert_eq!(moved_chateaus.update(&only_bart), expected);
+
+        // metro came back to this worker
+        assert!(moved_chateaus.update(&both).is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Balances chateaus between Aspen workers by how much work they are.
+// Workers publish the entity count and processing time of their chateaus to /aspen_chateau_costs/.
+// The leader keeps every chateau on its current worker unless that worker is over its share,
+// and places new, orphaned and overflowing chateaus on the least loaded workers.
+// Operators can pin a chateau by writing a worker id to /aspen_pinned_chateaus/{chateau_id}.
+
+use catenary::aspen::lib::ChateauCostEtcd;
+use catenary::aspen_dataset::AspenisedData;
+use catenary::bounded_load::{assign_bounded, smoothed};
+use scc::HashMap as SccHashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Processing an entity is assumed to cost this much, on top of the measured processing time
+const MS_PER_ENTITY: f64 = 0.05;
+
+/// Weight of a realtime feed of a chateau which was never measured
+const DEFAULT_WEIGHT_PER_FEED: f64 = 50.0;
+
+pub fn record_processing(processing_times: &SccHashMap<String, f64>, chateau_id: &str, ms: f64) {
+    processing_times
+        .entry(chateau_id.to_string())
+        .and_modify(|average| *average = smoothed(*average, ms))
+        .or_insert(ms);
+}
+
+pub fn chateau_weight(cost: Option<&ChateauCostEtcd>, realtime_feed_count: usize) -> f64 {
+    match cost {
+        Some(cost) => (cost.processing_ms + cost.entity_count as f64 * MS_PER_ENTITY).max(1.0),
+        None => (realtime_feed_count as f64 * DEFAULT_WEIGHT_PER_FEED).max(1.0),
+    }
+}
+
+/// chateau id -> worker id
+///
+/// `chateaus` are (chateau id, weight), `previous` is the current owner of each chateau,
+/// and `pins` are the workers chosen by operators.
+pub fn balance_chateaus(
+    chateaus: &[(String, f64)],
+    workers: &[String],
+    previous: &BTreeMap<String, String>,
+    pins: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    // new, orphaned and overflowing chateaus go to the least loaded worker
+    assign_bounded(
+        chateaus,
+        workers,
+        pins,
+        |chateau_id| previous.get(chateau_id).into_iter().collect(),
+        |_| vec![],
+    )
+}
+
+pub async fn get_chateau_costs(
+    etcd: &mut etcd_client::Client,
+) -> Result<HashMap<String, ChateauCostEtcd>, Box<dyn std::error::Error + Sync + Send>> {
+    Ok(etcd
+        .get(
+            "/aspen_chateau_costs/",
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await?
+        .take_kvs()
+        .into_iter()
+        .filter_map(|kv| {
+            Some((
+                kv.key_str().ok()?.replace("/aspen_chateau_costs/", ""),
+                bincode::deserialize::<ChateauCostEtcd>(kv.value()).ok()?,
+            ))
+        })
+        .collect())
+}
+
+/// Pins are written by hand, so the value is the worker id as plain text
+pub async fn get_pinned_chateaus(
+    etcd: &mut etcd_client::Client,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Sync + Send>> {
+    Ok(etcd
+        .get(
+            "/aspen_pinned_chateaus/",
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await?
+        .take_kvs()
+        .into_iter()
+        .filter_map(|kv| {
+            Some((
+                kv.key_str().ok()?.replace("/aspen_pinned_chateaus/", ""),
+                kv.value_str().ok()?.trim().to_string(),
+            ))
+        })
+        .collect())
+}
+
+pub async fn publish_loop(
+    etcd_addresses: Arc<Vec<String>>,
+    etcd_connect_options: Arc<Option<etcd_client::ConnectOptions>>,
+    etcd_lease_id: i64,
+    authoritative_data_store: Arc<SccHashMap<String, AspenisedData>>,
+    processing_times: Arc<SccHashMap<String, f64>>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let mut costs: Vec<(String, ChateauCostEtcd)> = vec![];
+
+        authoritative_data_store
+            .scan_async(|chateau_id, data| {
+                costs.push((
+                    chateau_id.clone(),
+                    ChateauCostEtcd {
+                        entity_count: (data.vehicle_positions.len()
+                            + data.trip_updates.len()
+                            + data.aspenised_alerts.len())
+                            as u64,
+                        processing_ms: 0.0,
+                    },
+                ))
+            })
+            .await;
+
+        for (chateau_id, cost) in costs.iter_mut() {
+            if let Some(processing_ms) = processing_times.get_async(chateau_id).await {
+                cost.processing_ms = *processing_ms.get();
+            }
+        }
+
+        let etcd = etcd_client::Client::connect(
+            etcd_addresses.as_slice(),
+            etcd_connect_options.as_ref().to_owned(),
+        )
+        .await;
+
+        let mut etcd = match etcd {
+            Ok(etcd) => etcd,
+            Err(err) => {
+                eprintln!("Could not publish chateau costs: {:#?}", err);
+                continue;
+            }
+        };
+
+        for (chateau_id, cost) in costs {
+            let put = etcd
+                .put(
+                    format!("/aspen_chateau_costs/{}", chateau_id).as_str(),
+                    bincode::serialize(&cost).unwrap(),
+                    Some(etcd_client::PutOptions::new().with_lease(etcd_lease_id)),
+                )
+                .await;
+
+            if let Err(err) = put {
+                eprintln!("Could not publish cost of {}: {:#?}", chateau_id, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workers(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("w{}", i)).collect()
+    }
+
+    #[test]
+    fn heavy_chateaus_are_spread_and_others_stay() {
+        let chateaus = vec![
+            (String::from("nyc"), 100.0),
+            (String::from("la"), 100.0),
+            (String::from("small-a"), 5.0),
+            (String::from("small-b"), 5.0),
+        ];
+
+        // nyc and la start on the same worker
+        let previous = BTreeMap::from([
+            (String::from("nyc"), String::from("w0")),
+            (String::from("la"), String::from("w0")),
+            (String::from("small-a"), String::from("w1")),
+            (String::from("small-b"), String::from("w0")),
+        ]);
+
+        let assignments = balance_chateaus(&chateaus, &workers(2), &previous, &BTreeMap::new());
+
+        assert_ne!(assignments["nyc"], assignments["la"]);
+        assert_eq!(assignments["small-a"], "w1");
+        // of two chateaus of the same weight, the first by name keeps its worker
+        assert_eq!(assignments["la"], "w0");
+    }
+
+    #[test]
+    fn pins_win_over_stickiness() {
+        let chateaus = vec![(String::from("nyc"), 10.0), (String::from("la"), 10.0)];
+
+        let previous = BTreeMap::from([
+            (String::from("nyc"), String::from("w0")),
+            (String::from("la"), String::from("w1")),
+        ]);
+        let pins = BTreeMap::from([
+            (String::from("nyc"), String::from("w1")),
+            // a pin to a worker which is gone is ignored
+            (String::from("la"), String::from("w9")),
+        ]);
+
+        let assignments = balance_chateaus(&chateaus, &workers(2), &previous, &pins);
+
+        assert_eq!(assignments["nyc"], "w1");
+        // the pinned chateau filled w1, so la moves
+        assert_eq!(assignments["la"], "w0");
+    }
+
+    #[test]
+    fn chateau_heavier_than_its_share_still_stays_and_no_workers_get_nothing() {
+        let chateaus = vec![
+            (String::from("nyc"), 300.0),
+            (String::from("la"), 10.0),
+            (String::from("sf"), 10.0),
+        ];
+
+        let previous = BTreeMap::from([(String::from("nyc"), String::from("w1"))]);
+
+        let assignments = balance_chateaus(&chateaus, &workers(2), &previous, &BTreeMap::new());
+
+        // nyc is over 1.25 times the average on its own, but moving it would not help
+        assert_eq!(assignments["nyc"], "w1");
+        assert_eq!(assignments["la"], "w0");
+        assert_eq!(assignments["sf"], "w0");
+
+        assert!(balance_chateaus(&chateaus, &[], &previous, &BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn unmeasured_chateaus_are_weighed_by_feed_count() {
+        assert!((chateau_weight(None, 2) - 2.0 * DEFAULT_WEIGHT_PER_FEED).abs() < 1e-9);
+        // chateaus without realtime feeds still cost something
+        assert!((chateau_weight(None, 0) - 1.0).abs() < 1e-9);
+
+        let cost = ChateauCostEtcd {
+            entity_count: 1000,
+            processing_ms: 10.0,
+        };
+
+        assert!((chateau_weight(Some(&cost), 2) - 60.0).abs() < 1e-9);
+
+        let processing_times = SccHashMap::new();
+
+        record_processing(&processing_times, "nyc", 100.0);
+        record_processing(&processing_times, "nyc", 200.0);
+
+        let average = *processing_times.get("nyc").unwrap().get();
+
+        assert!((average - 120.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,720 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Archives the final realtime arrival and departure of each trip at each stop into gtfs.stop_time_history,
+// for on time performance reports.
+// Stop times are held in memory while the feed keeps updating them, and written once the vehicle has passed the stop.
+// Predictions which the feed never confirmed, because the trip update disappeared first, are dropped.
+
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen_dataset::{AspenisedData, AspenisedTripUpdate};
+use catenary::models::StopTimeHistoryRow;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use chrono::NaiveDate;
+use diesel::QueryDsl;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::RunQueryDsl;
+use scc::HashMap as SccHashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the agency timezones of each chateau are read again
+const TIMEZONES_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A stop time is final once its last realtime time is this far in the past
+pub const FINAL_AFTER_SECS: i64 = 300;
+
+/// Archived stop times are remembered this long, so later updates to them are not written again
+const ARCHIVED_KEYS_KEPT_SECS: i64 = 2 * 86400;
+
+/// Predictions are forgotten once their time is this far in the past without the vehicle passing the stop
+const UNCONFIRMED_KEPT_SECS: i64 = 3600;
+
+// GTFS-rt TripDescriptor.ScheduleRelationship
+const TRIP_CANCELED: i32 = 3;
+const TRIP_DELETED: i32 = 7;
+
+// GTFS-rt StopTimeUpdate.ScheduleRelationship
+const STOP_SKIPPED: i32 = 1;
+const STOP_NO_DATA: i32 = 2;
+
+/// Stored when the feed gives no stop sequence
+const UNKNOWN_STOP_SEQUENCE: i32 = -1;
+
+/// (service_date, chateau, trip_id, stop_id, stop_sequence), the primary key of the table
+type ArchiveKey = (NaiveDate, String, String, String, i32);
+
+fn archive_key(row: &StopTimeHistoryRow) -> ArchiveKey {
+    (
+        row.service_date,
+        row.chateau.clone(),
+        row.trip_id.clone(),
+        row.stop_id.clone(),
+        row.stop_sequence,
+    )
+}
+
+fn last_event_time(row: &StopTimeHistoryRow) -> Option<i64> {
+    row.departure_time.max(row.arrival_time)
+}
+
+/// The latest realtime data of a stop time, and whether the vehicle had passed the stop when it was recorded
+#[derive(Clone, Debug)]
+pub struct Observation {
+    pub row: StopTimeHistoryRow,
+    pub passed: bool,
+}
+
+/// Local date of the earliest realtime event of the trip, for trip updates without a start date.
+/// A trip running past midnight which first appears in the feed after midnight is filed under the next day.
+fn service_date_from_first_event(
+    trip_update: &AspenisedTripUpdate,
+    timezone: &chrono_tz::Tz,
+) -> Option<NaiveDate> {
+    let first_event_time = trip_update
+        .stop_time_update
+        .iter()
+        .flat_map(|stu| [stu.arrival.as_ref(), stu.departure.as_ref()])
+        .flatten()
+        .filter_map(|event| event.time)
+        .min()?;
+
+    Some(
+        chrono::DateTime::from_timestamp(first_event_time, 0)?
+            .with_timezone(timezone)
+            .date_naive(),
+    )
+}
+
+/// Stop times with a realtime time and delay in the trip updates of the chateau.
+/// Trip updates without a start date are dated by their first event in `timezone`, and skipped without one.
+pub fn observations(
+    chateau_id: &str,
+    data: &AspenisedData,
+    timezone: Option<&chrono_tz::Tz>,
+    recorded_unix_time_ms: i64,
+) -> Vec<Observation> {
+    let mut rows = vec![];
+
+    let recorded_unix_time = recorded_unix_time_ms / 1000;
+
+    // trip id -> the stop sequence the vehicle is at or heading to
+    let current_stop_sequences = data
+        .vehicle_positions
+        .values()
+        .filter_map(|vehicle_position| {
+            Some((
+                vehicle_position.trip.as_ref()?.trip_id.clone()?,
+                vehicle_position.current_stop_sequence?,
+            ))
+        })
+        .collect::<AHashMap<String, u32>>();
+
+    for trip_update in data.trip_updates.values() {
+        if matches!(
+            trip_update.trip.schedule_relationship,
+            Some(TRIP_CANCELED) | Some(TRIP_DELETED)
+        ) {
+            continue;
+        }
+
+        let service_date = match &trip_update.trip.start_date {
+            Some(start_date) => NaiveDate::parse_from_str(start_date, "%Y%m%d").ok(),
+            None => {
+                timezone.and_then(|timezone| service_date_from_first_event(trip_update, timezone))
+            }
+        };
+
+        let (trip_id, service_date) = match (&trip_update.trip.trip_id, service_date) {
+            (Some(trip_id), Some(service_date)) => (trip_id, service_date),
+            _ => continue,
+        };
+
+        for stu in trip_update.stop_time_update.iter() {
+            if matches!(
+                stu.schedule_relationship,
+                Some(STOP_SKIPPED) | Some(STOP_NO_DATA)
+            ) {
+                continue;
+            }
+
+            let stop_id = match &stu.stop_id {
+                Some(stop_id) => stop_id,
+                None => continue,
+            };
+
+            // only events from the feed which Aspen could compare against the schedule
+            let arrival = stu
+                .arrival
+                .as_ref()
+                .filter(|event| !event.predicted)
+                .and_then(|event| Some((event.time?, event.delay?)));
+            let departure = stu
+                .departure
+                .as_ref()
+                .filter(|event| !event.predicted)
+                .and_then(|event| Some((event.time?, event.delay?)));
+
+            if arrival.is_none() && departure.is_none() {
+                continue;
+            }
+
+            // the vehicle moved on to a later stop, or the feed reports a time which already happened
+            let passed_by_vehicle = match (current_stop_sequences.get(trip_id), stu.stop_sequence) {
+                (Some(current_stop_sequence), Some(stop_sequence)) => {
+                    *current_stop_sequence > stop_sequence
+                }
+                _ => false,
+            };
+            let passed_by_time = departure
+                .or(arrival)
+                .is_some_and(|(time, _)| time <= recorded_unix_time);
+
+            let row = StopTimeHistoryRow {
+                service_date,
+                chateau: chateau_id.to_string(),
+                trip_id: trip_id.clone(),
+                route_id: trip_update.trip.route_id.clone(),
+                stop_id: stop_id.to_string(),
+                stop_sequence: stu
+                    .stop_sequence
+                    .map(|stop_sequence| stop_sequence as i32)
+                    .unwrap_or(UNKNOWN_STOP_SEQUENCE),
+                arrival_time: arrival.map(|(time, _)| time),
+                arrival_delay: arrival.map(|(_, delay)| delay),
+                departure_time: departure.map(|(time, _)| time),
+                departure_delay: departure.map(|(_, delay)| delay),
+                recorded_unix_time_ms,
+            };
+
+            rows.push(Observation {
+                row,
+                passed: passed_by_vehicle || passed_by_time,
+            });
+        }
+    }
+
+    rows
+}
+
+#[derive(Default)]
+pub struct DelayArchiver {
+    /// Latest observation of each stop time which has not been written yet
+    pending: AHashMap<ArchiveKey, Observation>,
+    /// Written stop times and their last event time
+    archived: AHashMap<ArchiveKey, i64>,
+    partitions: AHashSet<NaiveDate>,
+}
+
+impl DelayArchiver {
+    pub fn observe(&mut self, observations: Vec<Observation>) {
+        for mut observation in observations {
+            let key = archive_key(&observation.row);
+
+            if self.archived.contains_key(&key) {
+                continue;
+            }
+
+            // once passed, a stop stays passed even if the vehicle position goes missing
+            if let Some(previous) = self.pending.get(&key) {
+                observation.passed |= previous.passed;
+            }
+
+            self.pending.insert(key, observation);
+        }
+    }
+
+    /// Removes and returns the passed stop times which the feed stopped updating.
+    /// They are only marked as archived by `mark_archived`, once written.
+    pub fn take_final(&mut self, now: i64) -> Vec<StopTimeHistoryRow> {
+        // predictions for stops the vehicle never confirmed passing
+        self.pending.retain(|_, observation| {
+            observation.passed
+                || last_event_time(&observation.row)
+                    .is_some_and(|time| time > now - UNCONFIRMED_KEPT_SECS)
+        });
+
+        let final_keys = self
+            .pending
+            .iter()
+            .filter(|(_, observation)| {
+                observation.passed
+                    && last_event_time(&observation.row)
+                        .is_some_and(|time| time < now - FINAL_AFTER_SECS)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<ArchiveKey>>();
+
+        final_keys
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .map(|observation| observation.row)
+            .collect()
+    }
+
+    /// Remembers the written stop times, so later updates to them are not written again
+    pub fn mark_archived(&mut self, rows: &[StopTimeHistoryRow], now: i64) {
+        for row in rows {
+            self.archived
+                .insert(archive_key(row), last_event_time(row).unwrap_or(now));
+        }
+
+        self.archived
+            .retain(|_, time| *time > now - ARCHIVED_KEYS_KEPT_SECS);
+    }
+
+    /// Puts stop times which could not be written back, unless the feed updated them since
+    pub fn requeue(&mut self, rows: Vec<StopTimeHistoryRow>) {
+        for row in rows {
+            self.pending
+                .entry(archive_key(&row))
+                .or_insert(Observation { row, passed: true });
+        }
+    }
+
+    async fn ensure_partition(
+        &mut self,
+        conn: &mut diesel_async::AsyncPgConnection,
+        service_date: NaiveDate,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.partitions.contains(&service_date) {
+            return Ok(());
+        }
+
+        let next_date = service_date.succ_opt().ok_or("service date out of range")?;
+
+        diesel::sql_query(format!(
+            "CREATE TABLE IF NOT EXISTS gtfs.stop_time_history_{} PARTITION OF gtfs.stop_time_history FOR VALUES FROM ('{}') TO ('{}')",
+            service_date.format("%Y%m%d"),
+            service_date,
+            next_date
+        ))
+        .execute(conn)
+        .await?;
+
+        self.partitions.insert(service_date);
+
+        Ok(())
+    }
+
+    pub async fn write(
+        &mut self,
+        conn_pool: &CatenaryPostgresPool,
+        rows: &[StopTimeHistoryRow],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let conn_pre = conn_pool.get().await;
+        let conn = &mut conn_pre?;
+
+        let service_dates = rows
+            .iter()
+            .map(|row| row.service_date)
+            .collect::<AHashSet<NaiveDate>>();
+
+        for service_date in service_dates {
+            self.ensure_partition(conn, service_date).await?;
+        }
+
+        // all or nothing, so a failed write can be retried in full
+        conn.transaction::<(), diesel::result::Error, _>(|conn| {
+            async move {
+                for rows_chunk in rows.chunks(1000) {
+                    diesel::insert_into(
+                        catenary::schema::gtfs::stop_time_history::dsl::stop_time_history,
+                    )
+                    .values(rows_chunk)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+                }
+
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// The timezone of the first agency of each chateau with a valid one
+async fn chateau_timezones(
+    conn_pool: &CatenaryPostgresPool,
+) -> Result<AHashMap<String, chrono_tz::Tz>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let agencies = catenary::schema::gtfs::agencies::dsl::agencies
+        .select((
+            catenary::schema::gtfs::agencies::dsl::chateau,
+            catenary::schema::gtfs::agencies::dsl::agency_timezone,
+        ))
+        .load::<(String, String)>(conn)
+        .await?;
+
+    let mut timezones = AHashMap::new();
+
+    for (chateau, agency_timezone) in agencies {
+        if let Ok(timezone) = agency_timezone.parse::<chrono_tz::Tz>() {
+            timezones.entry(chateau).or_insert(timezone);
+        }
+    }
+
+    Ok(timezones)
+}
+
+pub async fn archive_loop(
+    authoritative_data_store: Arc<SccHashMap<String, AspenisedData>>,
+    conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let mut archiver = DelayArchiver::default();
+    let mut interval = tokio::time::interval(ARCHIVE_INTERVAL);
+
+    let mut timezones = AHashMap::new();
+    let mut timezones_read_at: Option<std::time::Instant> = None;
+
+    loop {
+        interval.tick().await;
+
+        if timezones_read_at.map_or(true, |read_at| {
+            read_at.elapsed() >= TIMEZONES_REFRESH_INTERVAL
+        }) {
+            // keeps the previous timezones when the read fails
+            match chateau_timezones(&conn_pool).await {
+                Ok(new_timezones) => {
+                    timezones = new_timezones;
+                    timezones_read_at = Some(std::time::Instant::now());
+                }
+                Err(err) => {
+                    eprintln!("Could not read the agency timezones: {}", err);
+                }
+            }
+        }
+
+        let now = catenary::duration_since_unix_epoch();
+
+        let mut rows = vec![];
+        authoritative_data_store
+            .scan_async(|chateau_id, data| {
+                rows.extend(observations(
+                    chateau_id,
+                    data,
+                    timezones.get(chateau_id),
+                    now.as_millis() as i64,
+                ))
+            })
+            .await;
+
+        archiver.observe(rows);
+
+        let final_rows = archiver.take_final(now.as_secs() as i64);
+
+        match archiver.write(&conn_pool, &final_rows).await {
+            Ok(()) => {
+                if !final_rows.is_empty() {
+                    println!("Archived {} stop times", final_rows.len());
+                }
+
+                archiver.mark_archived(&final_rows, now.as_secs() as i64);
+            }
+            Err(err) => {
+                eprintln!(
+                    "Could not archive {} stop times, retrying next time: {}",
+                    final_rows.len(),
+                    err
+                );
+
+                archiver.requeue(final_rows);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::aspen_dataset::{
+        AspenRawTripInfo, AspenStopTimeEvent, AspenisedStopTimeUpdate, AspenisedTripUpdate,
+        AspenisedVehiclePosition, AspenisedVehicleTripInfo,
+    };
+
+    fn row(stop_id: &str, departure_time: i64) -> StopTimeHistoryRow {
+        StopTimeHistoryRow {
+            service_date: NaiveDate::from_ymd_opt(2024, 9, 17).unwrap(),
+            chateau: String::from("c"),
+            trip_id: String::from("t"),
+            route_id: None,
+            stop_id: String::from(stop_id),
+            stop_sequence: 1,
+            arrival_time: None,
+            arrival_delay: None,
+            departure_time: Some(departure_time),
+            departure_delay: Some(60),
+            recorded_unix_time_ms: 0,
+        }
+    }
+
+    fn passed(stop_id: &str, departure_time: i64) -> Observation {
+        Observation {
+            row: row(stop_id, departure_time),
+            passed: true,
+        }
+    }
+
+    fn predicted(stop_id: &str, departure_time: i64) -> Observation {
+        Observation {
+            row: row(stop_id, departure_time),
+            passed: false,
+        }
+    }
+
+    #[test]
+    fn stop_times_are_written_once_after_passing() {
+        let mut archiver = DelayArchiver::default();
+
+        archiver.observe(vec![passed("a", 1000), passed("b", 5000)]);
+        archiver.observe(vec![passed("a", 1100)]);
+
+        let now = 1100 + FINAL_AFTER_SECS + 1;
+        let final_rows = archiver.take_final(now);
+
+        assert_eq!(final_rows.len(), 1);
+        assert_eq!(final_rows[0].stop_id, "a");
+        assert_eq!(final_rows[0].departure_time, Some(1100));
+
+        archiver.mark_archived(&final_rows, now);
+
+        // the feed still reporting the passed stop does not archive it again
+        archiver.observe(vec![passed("a", 1200)]);
+
+        assert!(archiver
+            .take_final(10_000)
+            .iter()
+            .all(|row| row.stop_id == "b"));
+    }
+
+    #[test]
+    fn failed_writes_are_retried() {
+        let mut archiver = DelayArchiver::default();
+
+        archiver.observe(vec![passed("a", 1000)]);
+
+        let now = 1000 + FINAL_AFTER_SECS + 1;
+        let final_rows = archiver.take_final(now);
+
+        assert_eq!(final_rows.len(), 1);
+
+        // the write failed, so nothing is marked as archived
+        archiver.requeue(final_rows);
+
+        let retried_rows = archiver.take_final(now + 60);
+
+        assert_eq!(retried_rows.len(), 1);
+        assert_eq!(retried_rows[0].departure_time, Some(1000));
+    }
+
+    #[test]
+    fn requeue_keeps_newer_updates_from_the_feed() {
+        let mut archiver = DelayArchiver::default();
+
+        archiver.observe(vec![passed("a", 1000)]);
+
+        let final_rows = archiver.take_final(1000 + FINAL_AFTER_SECS + 1);
+
+        archiver.observe(vec![passed("a", 1030)]);
+        archiver.requeue(final_rows);
+
+        let retried_rows = archiver.take_final(1030 + FINAL_AFTER_SECS + 1);
+
+        assert_eq!(retried_rows.len(), 1);
+        assert_eq!(retried_rows[0].departure_time, Some(1030));
+    }
+
+    #[test]
+    fn unconfirmed_predictions_are_never_archived() {
+        let mut archiver = DelayArchiver::default();
+
+        // the trip update disappeared before the vehicle reached the stop
+        archiver.observe(vec![predicted("a", 1000)]);
+
+        assert!(archiver.take_final(1000 + FINAL_AFTER_SECS + 1).is_empty());
+        assert_eq!(archiver.pending.len(), 1);
+
+        assert!(archiver
+            .take_final(1000 + UNCONFIRMED_KEPT_SECS + 1)
+            .is_empty());
+        assert!(archiver.pending.is_empty());
+    }
+
+    #[test]
+    fn passing_is_kept_when_later_updates_lose_the_vehicle() {
+        let mut archiver = DelayArchiver::default();
+
+        archiver.observe(vec![passed("a", 1000)]);
+        archiver.observe(vec![predicted("a", 1010)]);
+
+        let final_rows = archiver.take_final(1010 + FINAL_AFTER_SECS + 1);
+
+        assert_eq!(final_rows.len(), 1);
+        assert_eq!(final_rows[0].departure_time, Some(1010));
+    }
+
+    fn stop_time_update(stop_sequence: u32, time: i64) -> AspenisedStopTimeUpdate {
+        AspenisedStopTimeUpdate {
+            stop_sequence: Some(stop_sequence),
+            stop_id: Some(format!("s{}", stop_sequence).into()),
+            arrival: None,
+            departure: Some(AspenStopTimeEvent {
+                delay: Some(120),
+                time: Some(time),
+                uncertainty: None,
+                predicted: false,
+            }),
+            departure_occupancy_status: None,
+            schedule_relationship: None,
+            stop_time_properties: None,
+            platform_string: None,
+        }
+    }
+
+    fn trip_update(stop_time_update: Vec<AspenisedStopTimeUpdate>) -> AspenisedTripUpdate {
+        AspenisedTripUpdate {
+            trip: AspenRawTripInfo {
+                trip_id: Some(String::from("t")),
+                route_id: Some(String::from("r")),
+                direction_id: None,
+                start_time: None,
+                start_date: Some(String::from("20240917")),
+                schedule_relationship: None,
+                modified_trip: None,
+            },
+            vehicle: None,
+            timestamp: None,
+            delay: None,
+            stop_time_update,
+            trip_properties: None,
+            trip_headsign: None,
+        }
+    }
+
+    fn chateau_data(
+        trip_update: AspenisedTripUpdate,
+        vehicle_positions: AHashMap<String, AspenisedVehiclePosition>,
+    ) -> AspenisedData {
+        AspenisedData {
+            vehicle_positions,
+            vehicle_routes_cache: AHashMap::new(),
+            vehicle_label_to_gtfs_id: AHashMap::new(),
+            trip_updates: AHashMap::from_iter([("tu".into(), trip_update)]),
+            trip_updates_lookup_by_trip_id_to_trip_update_ids: AHashMap::new(),
+            aspenised_alerts: AHashMap::new(),
+            impacted_routes_alerts: AHashMap::new(),
+            impacted_stops_alerts: AHashMap::new(),
+            impacted_trips_alerts: AHashMap::new(),
+            alert_events: vec![],
+            alert_event_sequence: 0,
+            last_updated_time_ms: 0,
+        }
+    }
+
+    #[test]
+    fn observations_tell_passed_stops_from_predictions() {
+        let trip_update = trip_update(vec![
+            stop_time_update(1, 900),
+            stop_time_update(2, 1100),
+            stop_time_update(3, 1300),
+        ]);
+
+        // the vehicle left stop 2 early, ahead of the prediction
+        let vehicle_position = AspenisedVehiclePosition {
+            trip: Some(AspenisedVehicleTripInfo {
+                trip_id: Some(String::from("t")),
+                trip_headsign: None,
+                route_id: None,
+                trip_short_name: None,
+                direction_id: None,
+                start_time: None,
+                start_date: None,
+                schedule_relationship: None,
+            }),
+            vehicle: None,
+            position: None,
+            timestamp: None,
+            route_type: 3,
+            current_stop_sequence: Some(3),
+            current_status: None,
+            congestion_level: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            position_estimated: false,
+        };
+
+        let data = chateau_data(
+            trip_update,
+            AHashMap::from_iter([(String::from("v"), vehicle_position)]),
+        );
+
+        let mut observations = observations("c", &data, None, 1000 * 1000);
+        observations.sort_by_key(|observation| observation.row.stop_sequence);
+
+        let passed = observations
+            .iter()
+            .map(|observation| (observation.row.stop_sequence, observation.passed))
+            .collect::<Vec<(i32, bool)>>();
+
+        assert_eq!(passed, vec![(1, true), (2, true), (3, false)]);
+    }
+
+    #[test]
+    fn times_predicted_by_aspen_are_not_observed() {
+        let mut propagated = stop_time_update(2, 1100);
+
+        if let Some(departure) = propagated.departure.as_mut() {
+            departure.predicted = true;
+        }
+
+        let data = chateau_data(
+            trip_update(vec![stop_time_update(1, 900), propagated]),
+            AHashMap::new(),
+        );
+
+        let observations = observations("c", &data, None, 1000 * 1000);
+
+        assert_eq!(observations.len(), 1);
+        assert_eq!(observations[0].row.stop_sequence, 1);
+    }
+
+    #[test]
+    fn trip_updates_without_a_start_date_are_dated_by_their_first_event() {
+        let mut trip_update = trip_update(vec![
+            stop_time_update(2, 1_726_560_000),
+            stop_time_update(1, 1_726_559_400),
+        ]);
+        trip_update.trip.start_date = None;
+
+        let data = chateau_data(trip_update, AHashMap::new());
+
+        // 2024-09-17 07:50 UTC is still the evening of the 16th in Los Angeles
+        let observations_with_timezone = observations(
+            "c",
+            &data,
+            Some(&chrono_tz::America::Los_Angeles),
+            1000 * 1000,
+        );
+
+        assert_eq!(observations_with_timezone.len(), 2);
+        assert!(observations_with_timezone.iter().all(|observation| {
+            observation.row.service_date == NaiveDate::from_ymd_opt(2024, 9, 16).unwrap()
+        }));
+
+        assert!(observations("c", &data, None, 1000 * 1000).is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Predicted times for every stop after the last one the feed gave realtime data for.
+// Many feeds only send the delay at the next stop, so the last known delay is carried forward.
+// Late vehicles only make up time where the schedule has slack, the dwell time scheduled at
+// timepoints and layovers, and early vehicles wait at timepoints until their scheduled departure.
+
+use catenary::aspen_dataset::{AspenStopTimeEvent, AspenisedStopTimeUpdate, AspenisedTripUpdate};
+use catenary::models::ItineraryPatternRow;
+use chrono::TimeZone;
+use compact_str::CompactString;
+
+// GTFS-rt StopTimeUpdate.ScheduleRelationship
+const STOP_SKIPPED: i32 = 1;
+const STOP_NO_DATA: i32 = 2;
+
+/// Shortest time a vehicle spends at a stop with scheduled dwell time, in seconds
+const MIN_DWELL_SECS: i64 = 20;
+
+/// Scheduled times of a stop relative to the start of the trip, following `StopDifference`
+#[derive(Clone, Debug)]
+pub struct ScheduledStop {
+    pub stop_id: CompactString,
+    pub gtfs_stop_sequence: u32,
+    pub arrival_offset: i64,
+    pub departure_offset: i64,
+    pub timepoint: bool,
+}
+
+pub fn scheduled_stops_from_itinerary(rows: &[ItineraryPatternRow]) -> Vec<ScheduledStop> {
+    let mut rows = rows.iter().collect::<Vec<&ItineraryPatternRow>>();
+
+    rows.sort_by_key(|row| row.stop_sequence);
+
+    let mut last_known_offset: i64 = 0;
+
+    rows.iter()
+        .map(|row| {
+            let arrival_offset = row
+                .arrival_time_since_start
+                .or(row.interpolated_time_since_start)
+                .or(row.departure_time_since_start)
+                .map(|x| x as i64)
+                .unwrap_or(last_known_offset);
+
+            let departure_offset = row
+                .departure_time_since_start
+                .or(row.interpolated_time_since_start)
+                .or(row.arrival_time_since_start)
+                .map(|x| x as i64)
+                .unwrap_or(arrival_offset);
+
+            last_known_offset = departure_offset;
+
+            // stops with times interpolated by Catenary are never timepoints
+            let has_exact_time =
+                row.arrival_time_since_start.is_some() || row.departure_time_since_start.is_some();
+
+            ScheduledStop {
+                stop_id: row.stop_id.clone(),
+                gtfs_stop_sequence: row.gtfs_stop_sequence,
+                arrival_offset,
+                departure_offset,
+                timepoint: has_exact_time && row.timepoint.unwrap_or(true),
+            }
+        })
+        .collect()
+}
+
+fn parse_gtfs_time(time: &str) -> Option<i64> {
+    let mut parts = time.split(':');
+
+    let hours = parts.next()?.parse::<i64>().ok()?;
+    let minutes = parts.next()?.parse::<i64>().ok()?;
+    let seconds = parts.next()?.parse::<i64>().ok()?;
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// GTFS times are measured from noon minus 12 hours, which is not midnight on days when the clocks change
+fn service_day_reference(date: chrono::NaiveDate, timezone: &chrono_tz::Tz) -> Option<i64> {
+    let noon = timezone
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+
+    Some(noon.timestamp() - 43200)
+}
+
+// frequency based trips carry their actual start time
+fn start_time_of_trip(trip_update: &AspenisedTripUpdate, scheduled_start_time: i64) -> i64 {
+    trip_update
+        .trip
+        .start_time
+        .as_deref()
+        .and_then(parse_gtfs_time)
+        .unwrap_or(scheduled_start_time)
+}
+
+/// The service day, as a GTFS date, of a trip which started at `trip_start`
+pub fn service_date_of_trip(
+    trip_update: &AspenisedTripUpdate,
+    scheduled_start_time: i64,
+    trip_start: i64,
+    timezone: &chrono_tz::Tz,
+) -> Option<String> {
+    let reference = trip_start - start_time_of_trip(trip_update, scheduled_start_time);
+
+    let noon = chrono::DateTime::from_timestamp(reference + 43200, 0)?.with_timezone(timezone);
+
+    Some(noon.format("%Y%m%d").to_string())
+}
+
+/// Unix time of the scheduled start of the trip described by the trip update.
+/// Without a start date, the service day whose schedule is closest to the realtime data (or to now) is picked.
+pub fn trip_start_unix_time(
+    trip_update: &AspenisedTripUpdate,
+    schedule: &[ScheduledStop],
+    scheduled_start_time: i64,
+    timezone: &chrono_tz::Tz,
+    now: i64,
+) -> Option<i64> {
+    let start_time = start_time_of_trip(trip_update, scheduled_start_time);
+
+    if let Some(start_date) = &trip_update.trip.start_date {
+        let date = chrono::NaiveDate::parse_from_str(start_date, "%Y%m%d").ok()?;
+
+        return Some(service_day_reference(date, timezone)? + start_time);
+    }
+
+    // a realtime time at a known stop, or else the current time compared against the middle of the trip
+    let (reference_time, reference_offset) = trip_update
+        .stop_time_update
+        .iter()
+        .find_map(|stu| {
+            let time = stu
+                .departure
+                .as_ref()
+                .and_then(|event| event.time)
+                .or(stu.arrival.as_ref().and_then(|event| event.time))?;
+
+            let position = schedule.iter().position(|scheduled| {
+                Some(scheduled.gtfs_stop_sequence) == stu.stop_sequence
+                    || Some(&scheduled.stop_id) == stu.stop_id.as_ref()
+            })?;
+
+            Some((time, schedule[position].departure_offset))
+        })
+        .unwrap_or((
+            now,
+            schedule.last().map(|x| x.arrival_offset).unwrap_or(0) / 2,
+        ));
+
+    let today = chrono::DateTime::from_timestamp(now, 0)?
+        .with_timezone(timezone)
+        .date_naive();
+
+    [today.pred_opt()?, today, today.succ_opt()?]
+        .into_iter()
+        .filter_map(|date| service_day_reference(date, timezone))
+        .map(|reference| reference + start_time)
+        .min_by_key(|trip_start| (trip_start + reference_offset - reference_time).abs())
+}
+
+/// Fills the missing time or delay of the event, returning the delay in seconds
+fn complete_event(event: &mut Option<AspenStopTimeEvent>, scheduled_time: i64) -> Option<i64> {
+    let event = event.as_mut()?;
+
+    match (event.time, event.delay) {
+        (Some(time), _) => {
+            let delay = time - scheduled_time;
+            event.delay = Some(delay as i32);
+            Some(delay)
+        }
+        (None, Some(delay)) => {
+            event.time = Some(scheduled_time + delay as i64);
+            Some(delay as i64)
+        }
+        (None, None) => None,
+    }
+}
+
+/// Late vehicles leave after the minimum dwell, using up any layover.
+/// Vehicles hold at timepoints and layovers instead of leaving early.
+fn departure_delay_after_dwell(scheduled: &ScheduledStop, arrival_delay: i64) -> i64 {
+    let scheduled_dwell = scheduled.departure_offset - scheduled.arrival_offset;
+
+    let departure_delay = arrival_delay + MIN_DWELL_SECS.min(scheduled_dwell) - scheduled_dwell;
+
+    match scheduled.timepoint || scheduled_dwell > 0 {
+        true => departure_delay.max(0),
+        false => departure_delay,
+    }
+}
+
+fn predicted_event(scheduled_time: i64, delay: i64) -> AspenStopTimeEvent {
+    AspenStopTimeEvent {
+        delay: Some(delay as i32),
+        time: Some(scheduled_time + delay),
+        uncertainty: None,
+        predicted: true,
+    }
+}
+
+/// Completes the stop time updates of the trip, and adds predictions for every stop after the first one with realtime data
+pub fn propagate_delays(
+    trip_update: &mut AspenisedTripUpdate,
+    schedule: &[ScheduledStop],
+    trip_start: i64,
+) {
+    // schedule position -> index in stop_time_update
+    let mut matched: Vec<Option<usize>> = vec![None; schedule.len()];
+    let mut search_from: usize = 0;
+
+    for (stu_idx, stu) in trip_update.stop_time_update.iter().enumerate() {
+        let position = stu
+            .stop_sequence
+            .and_then(|stop_sequence| {
+                schedule
+                    .iter()
+                    .position(|scheduled| scheduled.gtfs_stop_sequence == stop_sequence)
+            })
+            .or_else(|| {
+                let stop_id = stu.stop_id.as_ref()?;
+
+                schedule
+                    .iter()
+                    .skip(search_from)
+                    .position(|scheduled| scheduled.stop_id == *stop_id)
+                    .map(|position| position + search_from)
+            });
+
+        if let Some(position) = position {
+            matched[position] = Some(stu_idx);
+            search_from = position + 1;
+        }
+    }
+
+    let start_position = match matched.iter().position(|stu_idx| stu_idx.is_some()) {
+        Some(position) => position,
+        // only a trip level delay is known, which applies to the whole trip
+        None => match trip_update.delay {
+            Some(_) => 0,
+            None => return,
+        },
+    };
+
+    let mut current_delay: Option<i64> = trip_update.delay.map(|x| x as i64);
+    let mut predictions: Vec<AspenisedStopTimeUpdate> = vec![];
+
+    for (position, scheduled) in schedule.iter().enumerate().skip(start_position) {
+        let scheduled_arrival = trip_start + scheduled.arrival_offset;
+        let scheduled_departure = trip_start + scheduled.departure_offset;
+
+        match matched[position] {
+            Some(stu_idx) => {
+                let stu = &mut trip_update.stop_time_update[stu_idx];
+
+                stu.stop_sequence = stu.stop_sequence.or(Some(scheduled.gtfs_stop_sequence));
+                stu.stop_id = stu.stop_id.clone().or(Some(scheduled.stop_id.clone()));
+
+                match stu.schedule_relationship {
+                    Some(STOP_NO_DATA) => {
+                        current_delay = None;
+                    }
+                    // skipped stops keep the delay of the vehicle passing through
+                    Some(STOP_SKIPPED) => {}
+                    _ => {
+                        if let Some(delay) = complete_event(&mut stu.arrival, scheduled_arrival) {
+                            current_delay = Some(delay);
+                        }
+
+                        match complete_event(&mut stu.departure, scheduled_departure) {
+                            Some(delay) => {
+                                current_delay = Some(delay);
+                            }
+                            None => {
+                                if let (true, Some(arrival_delay)) =
+                                    (stu.departure.is_none(), current_delay)
+                                {
+                                    let departure_delay =
+                                        departure_delay_after_dwell(scheduled, arrival_delay);
+
+                                    stu.departure =
+                                        Some(predicted_event(scheduled_departure, departure_delay));
+                                    current_delay = Some(departure_delay);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            None => {
+                if let Some(arrival_delay) = current_delay {
+                    let departure_delay = departure_delay_after_dwell(scheduled, arrival_delay);
+
+                    predictions.push(AspenisedStopTimeUpdate {
+                        stop_sequence: Some(scheduled.gtfs_stop_sequence),
+                        stop_id: Some(scheduled.stop_id.clone()),
+                        arrival: Some(predicted_event(scheduled_arrival, arrival_delay)),
+                        departure: Some(predicted_event(scheduled_departure, departure_delay)),
+                        departure_occupancy_status: None,
+                        schedule_relationship: None,
+                        stop_time_properties: None,
+                        platform_string: None,
+                    });
+
+                    current_delay = Some(departure_delay);
+                }
+            }
+        }
+    }
+
+    if predictions.is_empty() {
+        return;
+    }
+
+    trip_update.stop_time_update.extend(predictions);
+
+    let position_of_stop_sequence = schedule
+        .iter()
+        .enumerate()
+        .map(|(position, scheduled)| (scheduled.gtfs_stop_sequence, position))
+        .collect::<ahash::AHashMap<u32, usize>>();
+
+    trip_update.stop_time_update.sort_by_key(|stu| {
+        stu.stop_sequence
+            .and_then(|stop_sequence| position_of_stop_sequence.get(&stop_sequence).copied())
+            .unwrap_or(usize::MAX)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::aspen_dataset::AspenRawTripInfo;
+
+    fn stop(sequence: u32, arrival: i64, departure: i64, timepoint: bool) -> ScheduledStop {
+        ScheduledStop {
+            stop_id: CompactString::new(format!("s{}", sequence)),
+            gtfs_stop_sequence: sequence,
+            arrival_offset: arrival,
+            departure_offset: departure,
+            timepoint,
+        }
+    }
+
+    fn trip_update(
+        stop_time_update: Vec<AspenisedStopTimeUpdate>,
+        delay: Option<i32>,
+    ) -> AspenisedTripUpdate {
+        AspenisedTripUpdate {
+            trip: AspenRawTripInfo {
+                trip_id: Some(String::from("t")),
+                route_id: None,
+                direction_id: None,
+                start_time: None,
+                start_date: None,
+                schedule_relationship: None,
+                modified_trip: None,
+            },
+            vehicle: None,
+            timestamp: None,
+            delay,
+            stop_time_update,
+            trip_properties: None,
+            trip_headsign: None,
+        }
+    }
+
+    fn stop_time_update(
+        stop_sequence: u32,
+        arrival_time: Option<i64>,
+        schedule_relationship: Option<i32>,
+    ) -> AspenisedStopTimeUpdate {
+        AspenisedStopTimeUpdate {
+            stop_sequence: Some(stop_sequence),
+            stop_id: None,
+            arrival: arrival_time.map(|time| AspenStopTimeEvent {
+                delay: None,
+                time: Some(time),
+                uncertainty: None,
+                predicted: false,
+            }),
+            departure: None,
+            departure_occupancy_status: None,
+            schedule_relationship,
+            stop_time_properties: None,
+            platform_string: None,
+        }
+    }
+
+    fn arrival_delays(trip_update: &AspenisedTripUpdate) -> Vec<Option<i32>> {
+        trip_update
+            .stop_time_update
+            .iter()
+            .map(|stu| stu.arrival.as_ref().and_then(|event| event.delay))
+            .collect()
+    }
+
+    #[test]
+    fn delay_is_absorbed_by_layover() {
+        let schedule = vec![
+            stop(1, 0, 0, true),
+            stop(2, 300, 300, false),
+            stop(3, 600, 1200, true),
+            stop(4, 1500, 1500, false),
+        ];
+
+        let trip_start = 1_000_000;
+
+        let mut trip_update = AspenisedTripUpdate {
+            trip: AspenRawTripInfo {
+                trip_id: Some(String::from("t")),
+                route_id: None,
+                direction_id: None,
+                start_time: None,
+                start_date: None,
+                schedule_relationship: None,
+                modified_trip: None,
+            },
+            vehicle: None,
+            timestamp: None,
+            delay: None,
+            stop_time_update: vec![AspenisedStopTimeUpdate {
+                stop_sequence: Some(2),
+                stop_id: None,
+                arrival: Some(AspenStopTimeEvent {
+                    delay: None,
+                    time: Some(trip_start + 300 + 240),
+                    uncertainty: None,
+                    predicted: false,
+                }),
+                departure: None,
+                departure_occupancy_status: None,
+                schedule_relationship: None,
+                stop_time_properties: None,
+                platform_string: None,
+            }],
+            trip_properties: None,
+            trip_headsign: None,
+        };
+
+        propagate_delays(&mut trip_update, &schedule, trip_start);
+
+        assert_eq!(trip_update.stop_time_update.len(), 3);
+
+        let layover = &trip_update.stop_time_update[1];
+
+        // 4 minutes late into a 10 minute layover still leaves on time
+        assert_eq!(layover.arrival.as_ref().unwrap().delay, Some(240));
+        assert_eq!(layover.departure.as_ref().unwrap().delay, Some(0));
+
+        // the feed gave the arrival at the layover, and everything after it is Aspen's estimate
+        assert!(!layover.arrival.as_ref().unwrap().predicted);
+        assert!(layover.departure.as_ref().unwrap().predicted);
+
+        let last = &trip_update.stop_time_update[2];
+
+        assert_eq!(last.arrival.as_ref().unwrap().time, Some(trip_start + 1500));
+        assert!(last.arrival.as_ref().unwrap().predicted);
+    }
+
+    #[test]
+    fn service_date_of_trip_after_midnight() {
+        let schedule = vec![stop(1, 0, 0, true)];
+        let timezone = chrono_tz::America::Los_Angeles;
+
+        let mut trip_update = AspenisedTripUpdate {
+            trip: AspenRawTripInfo {
+                trip_id: Some(String::from("t")),
+                route_id: None,
+                direction_id: None,
+                start_time: None,
+                start_date: Some(String::from("20240915")),
+                schedule_relationship: None,
+                modified_trip: None,
+            },
+            vehicle: None,
+            timestamp: None,
+            delay: None,
+            stop_time_update: vec![],
+            trip_properties: None,
+            trip_headsign: None,
+        };
+
+        // 25:30:00, which is the next calendar day
+        let start_time = 25 * 3600 + 1800;
+
+        let trip_start =
+            trip_start_unix_time(&trip_update, &schedule, start_time, &timezone, 0).unwrap();
+
+        trip_update.trip.start_date = None;
+
+        assert_eq!(
+            service_date_of_trip(&trip_update, start_time, trip_start, &timezone),
+            Some(String::from("20240915"))
+        );
+    }
+
+    #[test]
+    fn no_data_ends_the_prediction_and_skipped_stops_keep_the_delay() {
+        let schedule = vec![
+            stop(1, 0, 0, false),
+            stop(2, 300, 300, false),
+            stop(3, 600, 600, false),
+            stop(4, 900, 900, false),
+        ];
+
+        let trip_start = 1_000_000;
+
+        let mut trip_update = trip_update(
+            vec![
+                stop_time_update(1, Some(trip_start + 60), None),
+                stop_time_update(2, None, Some(STOP_SKIPPED)),
+                stop_time_update(3, None, Some(STOP_NO_DATA)),
+            ],
+            None,
+        );
+
+        propagate_delays(&mut trip_update, &schedule, trip_start);
+
+        // nothing is known after the stop without data, so the last stop gets no prediction
+        assert_eq!(trip_update.stop_time_update.len(), 3);
+        assert_eq!(arrival_delays(&trip_update), vec![Some(60), None, None]);
+
+        let first_departure = trip_update.stop_time_update[0].departure.as_ref().unwrap();
+
+        assert_eq!(first_departure.delay, Some(60));
+        assert!(first_departure.predicted);
+        assert_eq!(
+            trip_update.stop_time_update[1].stop_id.as_deref(),
+            Some("s2")
+        );
+    }
+
+    #[test]
+    fn trip_delay_alone_is_carried_to_every_stop() {
+        let schedule = vec![
+            stop(1, 0, 0, true),
+            stop(2, 600, 660, true),
+            stop(3, 1260, 1260, false),
+        ];
+
+        let trip_start = 1_000_000;
+
+        // late vehicles make up the 60 seconds scheduled at the timepoint, less the minimum dwell
+        let mut late = trip_update(vec![], Some(120));
+
+        propagate_delays(&mut late, &schedule, trip_start);
+
+        assert_eq!(arrival_delays(&late), vec![Some(120), Some(120), Some(80)]);
+        assert!(late
+            .stop_time_update
+            .iter()
+            .all(|stu| stu.arrival.as_ref().unwrap().predicted));
+
+        // early vehicles wait at the first timepoint
+        let mut early = trip_update(vec![], Some(-120));
+
+        propagate_delays(&mut early, &schedule, trip_start);
+
+        assert_eq!(arrival_delays(&early), vec![Some(-120), Some(0), Some(0)]);
+        assert_eq!(
+            early.stop_time_update[0].departure.as_ref().unwrap().time,
+            Some(trip_start)
+        );
+
+        // without any delay there is nothing to predict
+        let mut unknown = trip_update(vec![], None);
+
+        propagate_delays(&mut unknown, &schedule, trip_start);
+
+        assert!(unknown.stop_time_update.is_empty());
+    }
+}
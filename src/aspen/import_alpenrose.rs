@@ -3,6 +3,12 @@
 // Attribution cannot be removed
 
 extern crate catenary;
+use crate::alert_diff::{append_alert_events, diff_alerts};
+use crate::delay_calculation::{
+    propagate_delays, scheduled_stops_from_itinerary, service_date_of_trip, trip_start_unix_time,
+    ScheduledStop,
+};
+use crate::rail_location_interpolation::{interpolate_position, stop_passing_times};
 use ahash::{AHashMap, AHashSet};
 use catenary::aspen_dataset::*;
 use catenary::postgres_tools::CatenaryPostgresPool;
@@ -17,6 +23,7 @@ use scc::HashMap as SccHashMap;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -393,6 +400,98 @@ pub async fn new_rt_data(
         let itinerary_pattern_id_to_itinerary_pattern_meta =
             itinerary_pattern_id_to_itinerary_pattern_meta;
 
+        //stop by stop schedule of every itinerary, used to predict times after the last realtime stop
+        let itinerary_rows = catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
+            .filter(catenary::schema::gtfs::itinerary_pattern::dsl::chateau.eq(&chateau_id))
+            .filter(
+                catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern_id
+                    .eq_any(list_of_itinerary_patterns_to_lookup.iter()),
+            )
+            .select(catenary::models::ItineraryPatternRow::as_select())
+            .load::<catenary::models::ItineraryPatternRow>(conn)
+            .await?;
+
+        let mut itinerary_rows_by_id: AHashMap<String, Vec<catenary::models::ItineraryPatternRow>> =
+            AHashMap::new();
+
+        for row in itinerary_rows {
+            itinerary_rows_by_id
+                .entry(row.itinerary_pattern_id.clone())
+                .or_default()
+                .push(row);
+        }
+
+        let itinerary_schedules: AHashMap<String, Vec<ScheduledStop>> = itinerary_rows_by_id
+            .into_iter()
+            .map(|(itinerary_pattern_id, rows)| {
+                (itinerary_pattern_id, scheduled_stops_from_itinerary(&rows))
+            })
+            .collect();
+
+        //stops and shapes, to place trains of feeds that only publish trip updates
+        let estimate_vehicle_positions = this_chateau
+            .realtime_feeds
+            .iter()
+            .flatten()
+            .any(|feed_id| MAKE_VEHICLES_FEED_LIST.contains(&feed_id.as_str()));
+
+        let mut stop_points: AHashMap<String, geo::Point<f64>> = AHashMap::new();
+        let mut shape_linestrings: AHashMap<String, geo::LineString<f64>> = AHashMap::new();
+
+        if estimate_vehicle_positions {
+            let stop_ids_to_lookup = itinerary_schedules
+                .values()
+                .flatten()
+                .map(|scheduled_stop| scheduled_stop.stop_id.to_string())
+                .collect::<AHashSet<String>>();
+
+            let stops = catenary::schema::gtfs::stops::dsl::stops
+                .filter(catenary::schema::gtfs::stops::dsl::chateau.eq(&chateau_id))
+                .filter(
+                    catenary::schema::gtfs::stops::dsl::gtfs_id.eq_any(stop_ids_to_lookup.iter()),
+                )
+                .select(catenary::models::Stop::as_select())
+                .load::<catenary::models::Stop>(conn)
+                .await?;
+
+            for stop in stops {
+                if let Some(point) = &stop.point {
+                    stop_points.insert(stop.gtfs_id.clone(), geo::Point::new(point.x, point.y));
+                }
+            }
+
+            let shape_ids_to_lookup = itinerary_pattern_id_to_itinerary_pattern_meta
+                .values()
+                .filter_map(|itinerary_pattern| itinerary_pattern.shape_id.clone())
+                .collect::<AHashSet<String>>();
+
+            let shapes = catenary::schema::gtfs::shapes::dsl::shapes
+                .filter(catenary::schema::gtfs::shapes::dsl::chateau.eq(&chateau_id))
+                .filter(
+                    catenary::schema::gtfs::shapes::dsl::shape_id
+                        .eq_any(shape_ids_to_lookup.iter()),
+                )
+                .select(catenary::models::Shape::as_select())
+                .load::<catenary::models::Shape>(conn)
+                .await?;
+
+            for shape in shapes {
+                shape_linestrings.insert(
+                    shape.shape_id.clone(),
+                    geo::LineString::new(
+                        shape
+                            .linestring
+                            .points
+                            .iter()
+                            .map(|point| geo::coord! { x: point.x, y: point.y })
+                            .collect::<Vec<_>>(),
+                    ),
+                );
+            }
+        }
+
+        let mut trip_update_ids_to_estimate: Vec<CompactString> = vec![];
+
         let mut route_ids_to_insert = AHashSet::new();
 
         for realtime_feed_id in this_chateau.realtime_feeds.iter().flatten() {
@@ -492,7 +591,8 @@ pub async fn new_rt_data(
                             current_stop_sequence: vehicle_pos.current_stop_sequence,
                             occupancy_status: vehicle_pos.occupancy_status,
                             occupancy_percentage: vehicle_pos.occupancy_percentage,
-                            congestion_level: vehicle_pos.congestion_level
+                            congestion_level: vehicle_pos.congestion_level,
+                            position_estimated: false,
                         };
 
                         let pos_aspenised = vehicle_pos_supplement(
@@ -532,7 +632,7 @@ pub async fn new_rt_data(
                     if let Some(trip_update) = &trip_update_entity.trip_update {
                         let trip_id = trip_update.trip.trip_id.clone();
 
-                        let trip_update = AspenisedTripUpdate {
+                        let mut trip_update = AspenisedTripUpdate {
                             trip: trip_update.trip.clone().into(),
                             vehicle: trip_update.vehicle.clone().map(|x| x.into()),
                             trip_headsign: None,
@@ -544,16 +644,18 @@ pub async fn new_rt_data(
                                     stop_id: stu.stop_id.as_ref().map(|x| x.into()),
                                     arrival: stu.arrival.clone().map(|arrival| {
                                         AspenStopTimeEvent {
-                                            delay: None,
+                                            delay: arrival.delay,
                                             time: arrival.time,
                                             uncertainty: arrival.uncertainty,
+                                            predicted: false,
                                         }
                                     }),
                                     departure: stu.departure.clone().map(|departure| {
                                         AspenStopTimeEvent {
-                                            delay: None,
+                                            delay: departure.delay,
                                             time: departure.time,
                                             uncertainty: departure.uncertainty,
+                                            predicted: false,
                                         }
                                     }),
                                     platform_string: None,
@@ -570,12 +672,50 @@ pub async fn new_rt_data(
                             trip_properties: trip_update.trip_properties.clone().map(|x| x.into()),
                         };
 
+                        if let Some(compressed_trip) = trip_id
+                            .as_ref()
+                            .and_then(|trip_id| trip_id_to_trip.get(trip_id))
+                        {
+                            let schedule =
+                                itinerary_schedules.get(&compressed_trip.itinerary_pattern_id);
+                            let timezone = itinerary_pattern_id_to_itinerary_pattern_meta
+                                .get(&compressed_trip.itinerary_pattern_id)
+                                .and_then(|meta| chrono_tz::Tz::from_str(&meta.timezone).ok());
+
+                            if let (Some(schedule), Some(timezone)) = (schedule, timezone) {
+                                if let Some(trip_start) = trip_start_unix_time(
+                                    &trip_update,
+                                    schedule,
+                                    compressed_trip.start_time as i64,
+                                    &timezone,
+                                    catenary::duration_since_unix_epoch().as_secs() as i64,
+                                ) {
+                                    propagate_delays(&mut trip_update, schedule, trip_start);
+
+                                    //the delay archive needs the service day of every trip
+                                    if trip_update.trip.start_date.is_none() {
+                                        trip_update.trip.start_date = service_date_of_trip(
+                                            &trip_update,
+                                            compressed_trip.start_time as i64,
+                                            trip_start,
+                                            &timezone,
+                                        );
+                                    }
+                                }
+                            }
+                        }
+
                         if trip_id.is_some() {
                             trip_updates_lookup_by_trip_id_to_trip_update_ids
                                 .entry(trip_id.as_ref().unwrap().into())
                                 .or_insert(vec![CompactString::new(&trip_update_entity.id)]);
                         }
 
+                        if MAKE_VEHICLES_FEED_LIST.contains(&realtime_feed_id.as_str()) {
+                            trip_update_ids_to_estimate
+                                .push(CompactString::new(&trip_update_entity.id));
+                        }
+
                         trip_updates
                             .insert(CompactString::new(&trip_update_entity.id), trip_update);
                     }
@@ -624,6 +764,131 @@ pub async fn new_rt_data(
             }
         }
 
+        //place trains without gps between the stops they are travelling between
+
+        let trip_ids_with_positions = aspenised_vehicle_positions
+            .values()
+            .filter(|vehicle| vehicle.position.is_some())
+            .filter_map(|vehicle| vehicle.trip.as_ref().and_then(|trip| trip.trip_id.clone()))
+            .collect::<AHashSet<String>>();
+
+        let now = catenary::duration_since_unix_epoch().as_secs() as i64;
+
+        for trip_update_id in trip_update_ids_to_estimate.iter() {
+            let trip_update = match trip_updates.get(trip_update_id) {
+                Some(trip_update) => trip_update,
+                None => continue,
+            };
+
+            // cancelled or deleted trips have no train to show
+            if matches!(trip_update.trip.schedule_relationship, Some(3) | Some(7)) {
+                continue;
+            }
+
+            let trip_id = match &trip_update.trip.trip_id {
+                Some(trip_id) => trip_id,
+                None => continue,
+            };
+
+            if trip_ids_with_positions.contains(trip_id) {
+                continue;
+            }
+
+            let compressed_trip = match trip_id_to_trip.get(trip_id) {
+                Some(compressed_trip) => compressed_trip,
+                None => continue,
+            };
+
+            let (schedule, itinerary_pattern) = match (
+                itinerary_schedules.get(&compressed_trip.itinerary_pattern_id),
+                itinerary_pattern_id_to_itinerary_pattern_meta
+                    .get(&compressed_trip.itinerary_pattern_id),
+            ) {
+                (Some(schedule), Some(itinerary_pattern)) => (schedule, itinerary_pattern),
+                _ => continue,
+            };
+
+            let timezone = match chrono_tz::Tz::from_str(&itinerary_pattern.timezone) {
+                Ok(timezone) => timezone,
+                Err(_) => continue,
+            };
+
+            let trip_start = match trip_start_unix_time(
+                trip_update,
+                schedule,
+                compressed_trip.start_time as i64,
+                &timezone,
+                now,
+            ) {
+                Some(trip_start) => trip_start,
+                None => continue,
+            };
+
+            let passing_times = stop_passing_times(trip_update, schedule, trip_start);
+
+            let points = schedule
+                .iter()
+                .map(|scheduled_stop| stop_points.get(scheduled_stop.stop_id.as_str()).copied())
+                .collect::<Vec<Option<geo::Point<f64>>>>();
+
+            let shape = itinerary_pattern
+                .shape_id
+                .as_ref()
+                .and_then(|shape_id| shape_linestrings.get(shape_id));
+
+            let estimated_position =
+                match interpolate_position(schedule, &passing_times, &points, shape, now) {
+                    Some(estimated_position) => estimated_position,
+                    None => continue,
+                };
+
+            let route_id = trip_update
+                .trip
+                .route_id
+                .clone()
+                .unwrap_or_else(|| compressed_trip.route_id.clone());
+
+            aspenised_vehicle_positions.insert(
+                format!("{}-estimated", trip_update_id),
+                AspenisedVehiclePosition {
+                    trip: Some(AspenisedVehicleTripInfo {
+                        trip_id: Some(trip_id.clone()),
+                        trip_headsign: itinerary_pattern.trip_headsign.clone(),
+                        route_id: Some(route_id.clone()),
+                        trip_short_name: compressed_trip
+                            .trip_short_name
+                            .as_ref()
+                            .map(|x| x.to_string()),
+                        direction_id: trip_update.trip.direction_id,
+                        start_time: trip_update.trip.start_time.clone(),
+                        start_date: trip_update.trip.start_date.clone(),
+                        schedule_relationship: trip_update.trip.schedule_relationship,
+                    }),
+                    vehicle: trip_update.vehicle.clone(),
+                    position: Some(CatenaryRtVehiclePosition {
+                        latitude: estimated_position.latitude,
+                        longitude: estimated_position.longitude,
+                        bearing: estimated_position.bearing,
+                        odometer: None,
+                        speed: None,
+                    }),
+                    timestamp: trip_update.timestamp,
+                    route_type: route_id_to_route
+                        .get(&route_id)
+                        .map(|route| route.route_type)
+                        .unwrap_or(1),
+                    current_stop_sequence: Some(estimated_position.current_stop_sequence),
+                    current_status: Some(estimated_position.current_status),
+                    congestion_level: None,
+                    occupancy_status: None,
+                    occupancy_percentage: None,
+                    position_estimated: true,
+                },
+            );
+
+            route_ids_to_insert.insert(route_id);
+        }
+
         //insert the route cache
 
         for route_id in route_ids_to_insert.iter() {
@@ -659,6 +924,16 @@ pub async fn new_rt_data(
     match authoritative_data_store.entry(chateau_id.clone()) {
         scc::hash_map::Entry::Occupied(mut oe) => {
             let mut data = oe.get_mut();
+
+            let new_alert_events =
+                diff_alerts(&data.aspenised_alerts, &alerts, data.alert_event_sequence);
+            let alert_event_sequence = new_alert_events
+                .last()
+                .map(|event| event.sequence)
+                .unwrap_or(data.alert_event_sequence);
+            let mut alert_events = std::mem::take(&mut data.alert_events);
+            append_alert_events(&mut alert_events, new_alert_events);
+
             *data = AspenisedData {
                 vehicle_positions: aspenised_vehicle_positions,
                 vehicle_routes_cache: vehicle_routes_cache,
@@ -670,6 +945,8 @@ pub async fn new_rt_data(
                 impacted_stops_alerts: AHashMap::new(),
                 vehicle_label_to_gtfs_id: gtfs_vehicle_labels_to_ids,
                 impacted_trips_alerts: impact_trip_id_to_alert_ids,
+                alert_events,
+                alert_event_sequence,
                 last_updated_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
             }
         }
@@ -685,6 +962,9 @@ pub async fn new_rt_data(
                 impacted_stops_alerts: AHashMap::new(),
                 vehicle_label_to_gtfs_id: gtfs_vehicle_labels_to_ids,
                 impacted_trips_alerts: impact_trip_id_to_alert_ids,
+                // alerts already active when the chateau is first loaded are not announced as new
+                alert_events: vec![],
+                alert_event_sequence: 0,
                 last_updated_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
             });
         }
@@ -0,0 +1,120 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Remembers the entity count and header timestamp of the last feeds decoded from Alpenrose,
+// and saves them to gtfs.realtime_feed_health for the feed health api.
+
+use ahash::AHashSet;
+use catenary::aspen_dataset::GtfsRtType;
+use catenary::feed_health::{push_recent, save_ingest_histories, IngestRecord, FLUSH_INTERVAL};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use gtfs_realtime::FeedMessage;
+use scc::HashMap as SccHashMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+pub async fn record_ingest(
+    ingest_histories: &SccHashMap<String, VecDeque<IngestRecord>>,
+    realtime_feed_id: &str,
+    feed_type: GtfsRtType,
+    feed: &FeedMessage,
+) {
+    let record = IngestRecord {
+        time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
+        feed_type: crate::rt_validation::feed_type_name(feed_type).to_string(),
+        entity_count: feed.entity.len(),
+        header_timestamp: feed.header.timestamp,
+    };
+
+    push_recent(
+        ingest_histories
+            .entry_async(realtime_feed_id.to_string())
+            .await
+            .or_default()
+            .get_mut(),
+        record,
+    );
+}
+
+/// Realtime feeds of the chateaus assigned to this worker
+fn owned_feed_ids(assigned: &BTreeMap<String, Vec<String>>) -> AHashSet<String> {
+    assigned
+        .values()
+        .flat_map(|realtime_feed_ids| realtime_feed_ids.iter().cloned())
+        .collect()
+}
+
+/// Saves the ingest histories every flush, after forgetting the feeds the leader moved to other workers
+pub async fn save_loop(
+    ingest_histories: Arc<SccHashMap<String, VecDeque<IngestRecord>>>,
+    conn_pool: Arc<CatenaryPostgresPool>,
+    etcd_addresses: Arc<Vec<String>>,
+    etcd_connect_options: Arc<Option<etcd_client::ConnectOptions>>,
+    worker_id: Arc<String>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let assigned = match etcd_client::Client::connect(
+            etcd_addresses.as_slice(),
+            etcd_connect_options.as_ref().to_owned(),
+        )
+        .await
+        {
+            Ok(mut etcd) => crate::persistence::assigned_chateaus(&mut etcd, &worker_id).await,
+            Err(err) => Err(err),
+        };
+
+        match assigned {
+            Ok(assigned) => {
+                let owned_feed_ids = owned_feed_ids(&assigned);
+
+                ingest_histories
+                    .retain_async(|realtime_feed_id, _| owned_feed_ids.contains(realtime_feed_id))
+                    .await;
+            }
+            Err(err) => {
+                eprintln!("Could not read the chateaus of this worker: {}", err);
+            }
+        }
+
+        let mut histories: Vec<(String, VecDeque<IngestRecord>)> = vec![];
+
+        ingest_histories
+            .scan_async(|realtime_feed_id, ingests| {
+                histories.push((realtime_feed_id.clone(), ingests.clone()))
+            })
+            .await;
+
+        if let Err(err) = save_ingest_histories(&conn_pool, histories).await {
+            eprintln!("Could not save ingest histories: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owned_feeds_come_from_every_assigned_chateau() {
+        let assigned = BTreeMap::from([
+            (
+                String::from("metro"),
+                vec![String::from("f-metro-rt"), String::from("f-bus-rt")],
+            ),
+            (String::from("ferry"), vec![]),
+            (String::from("rail"), vec![String::from("f-rail-rt")]),
+        ]);
+
+        let owned = owned_feed_ids(&assigned);
+
+        assert_eq!(owned.len(), 3);
+        assert!(owned.contains("f-bus-rt"));
+        assert!(owned.contains("f-rail-rt"));
+        assert!(!owned.contains("f-moved-rt"));
+    }
+}
@@ -5,9 +5,11 @@
 /// This is the service definition. It looks a lot like a trait definition.
 /// It defines one RPC, hello, which takes one arg, name, and returns a String.
 use crate::aspen_dataset::*;
+use crate::rt_recent_history::{RtCacheEntry, RtKey};
 use crate::ChateauDataNoGeometry;
 use ahash::AHashMap;
 use ahash::AHashSet;
+use compact_str::CompactString;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
@@ -62,6 +64,12 @@ pub trait AspenRpc {
         existing_fasthash_of_routes: Option<u64>,
     ) -> Option<GetVehicleLocationsResponse>;
 
+    /// Vehicles currently running any of the trips, keyed by gtfs id
+    async fn get_vehicle_locations_from_trip_ids(
+        chateau_id: String,
+        trip_ids: Vec<String>,
+    ) -> Option<AHashMap<String, AspenisedVehiclePosition>>;
+
     async fn get_gtfs_rt(
         realtime_feed_id: String,
         feed_type: crate::aspen_dataset::GtfsRtType,
@@ -93,6 +101,39 @@ pub trait AspenRpc {
     ) -> Option<AlertsforManyStops>;
 
     async fn get_all_alerts(chateau_id: String) -> Option<HashMap<String, AspenisedAlert>>;
+
+    /// Alerts added, changed or removed after `after_sequence`.
+    /// Without a sequence number, only the latest sequence number is returned.
+    async fn get_alert_events_since(
+        chateau_id: String,
+        after_sequence: Option<u64>,
+    ) -> Option<AlertEventsResponse>;
+
+    /// Sent by the leader to the current owner of a chateau before reassigning it.
+    /// The owner sends its state for the chateau and realtime feeds to the new owner, and returns once it was accepted.
+    async fn hand_off_chateau(
+        chateau_id: String,
+        realtime_feed_ids: Vec<String>,
+        new_owner: SocketAddr,
+    ) -> bool;
+
+    /// State of a chateau sent by its previous owner.
+    /// Data newer than the handed off state is kept.
+    async fn receive_chateau_state(chateau_id: String, state: ChateauStateHandoff) -> bool;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChateauStateHandoff {
+    pub aspenised_data: Option<AspenisedData>,
+    /// Feed messages of the chateau's realtime feeds, encoded as protobuf
+    pub gtfs_rt: Vec<(String, GtfsRtType, Vec<u8>)>,
+    pub trip_updates_history: Vec<(CompactString, Vec<(RtKey, RtCacheEntry)>)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AlertEventsResponse {
+    pub events: Vec<AspenisedAlertEvent>,
+    pub latest_sequence: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -115,6 +156,14 @@ pub struct ChateauMetadataEtcd {
     pub socket: SocketAddr,
 }
 
+/// Measured size of a chateau, published by its worker under /aspen_chateau_costs/
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChateauCostEtcd {
+    pub entity_count: u64,
+    /// Moving average of the time spent processing one update from Alpenrose
+    pub processing_ms: f64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RealtimeFeedMetadataEtcd {
     pub worker_id: String,
@@ -50,7 +50,17 @@ use tokio::time;
 use uuid::Uuid;
 mod leader_thread;
 use leader_thread::aspen_leader_thread;
+mod alert_diff;
+mod chateau_handoff;
+mod chateau_load;
+mod delay_archive;
+mod delay_calculation;
 mod import_alpenrose;
+mod ingest_health;
+mod persistence;
+#[path = "rail-location-interpolation.rs"]
+mod rail_location_interpolation;
+mod rt_validation;
 use ahash::AHashMap;
 use catenary::aspen_dataset::GtfsRtType;
 use catenary::aspen_dataset::*;
@@ -65,8 +75,10 @@ use catenary::parse_gtfs_rt_message;
 use rand::Rng;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 mod alerts_responder;
 mod aspen_assignment;
+use catenary::feed_health::IngestRecord;
 use catenary::rt_recent_history::RtCacheEntry;
 use catenary::rt_recent_history::RtKey;
 use prost::Message;
@@ -86,7 +98,7 @@ pub struct AspenServer {
     pub addr: SocketAddr,
     pub worker_id: Arc<String>, // Worker Id for this instance of Aspen
     pub authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
-    // Backed up in redis as well, program can be shut down and restarted without data loss
+    // Snapshotted by the persistence module, program can be shut down and restarted without data loss
     pub authoritative_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
     pub conn_pool: Arc<CatenaryPostgresPool>,
     pub authoritative_trip_updates_by_gtfs_feed_history:
@@ -103,6 +115,7 @@ pub struct AspenServer {
     pub etcd_connect_options: Arc<Option<etcd_client::ConnectOptions>>,
     pub worker_etcd_lease_id: i64,
     pub timestamps_of_gtfs_rt: Arc<SccHashMap<(String, GtfsRtType), u64>>,
+    pub ingest_histories: Arc<SccHashMap<String, VecDeque<IngestRecord>>>,
 }
 
 impl AspenRpc for AspenServer {
@@ -341,6 +354,22 @@ impl AspenRpc for AspenServer {
 
             //  println!("Parsed FeedMessages for {}", realtime_feed_id);
 
+            for (feed_type, gtfs_rt) in [
+                (GtfsRtType::VehiclePositions, &vehicles_gtfs_rt),
+                (GtfsRtType::TripUpdates, &trips_gtfs_rt),
+                (GtfsRtType::Alerts, &alerts_gtfs_rt),
+            ] {
+                if let Some(gtfs_rt) = gtfs_rt {
+                    ingest_health::record_ingest(
+                        &self.ingest_histories,
+                        &realtime_feed_id,
+                        feed_type,
+                        gtfs_rt,
+                    )
+                    .await;
+                }
+            }
+
             let mut new_data = false;
 
             let hash_data_start = Instant::now();
@@ -498,6 +527,39 @@ impl AspenRpc for AspenServer {
         }
     }
 
+    async fn get_vehicle_locations_from_trip_ids(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        trip_ids: Vec<String>,
+    ) -> Option<AHashMap<String, AspenisedVehiclePosition>> {
+        match self.authoritative_data_store.get(&chateau_id) {
+            Some(aspenised_data) => {
+                let aspenised_data = aspenised_data.get();
+
+                let trip_ids = trip_ids.into_iter().collect::<AHashSet<String>>();
+
+                Some(
+                    aspenised_data
+                        .vehicle_positions
+                        .iter()
+                        .filter(|(_, vehicle_position)| {
+                            vehicle_position
+                                .trip
+                                .as_ref()
+                                .and_then(|trip| trip.trip_id.as_ref())
+                                .is_some_and(|trip_id| trip_ids.contains(trip_id))
+                        })
+                        .map(|(gtfs_id, vehicle_position)| {
+                            (gtfs_id.clone(), vehicle_position.clone())
+                        })
+                        .collect(),
+                )
+            }
+            None => None,
+        }
+    }
+
     async fn get_single_vehicle_location_from_gtfsid(
         self,
         _: context::Context,
@@ -620,6 +682,59 @@ impl AspenRpc for AspenServer {
             None => None,
         }
     }
+
+    async fn get_alert_events_since(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        after_sequence: Option<u64>,
+    ) -> Option<AlertEventsResponse> {
+        alerts_responder::get_alert_events_since(
+            Arc::clone(&self.authoritative_data_store),
+            &chateau_id,
+            after_sequence,
+        )
+    }
+
+    async fn hand_off_chateau(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        realtime_feed_ids: Vec<String>,
+        new_owner: SocketAddr,
+    ) -> bool {
+        let state = chateau_handoff::export_chateau_state(
+            &self.authoritative_data_store,
+            &self.authoritative_gtfs_rt_store,
+            &self.authoritative_trip_updates_by_gtfs_feed_history,
+            &chateau_id,
+            &realtime_feed_ids,
+        );
+
+        println!("Handing off {} to {}", chateau_id, new_owner);
+
+        // the state is kept and served until the assignment check sees the chateau in etcd on its new owner
+        chateau_handoff::send_chateau_state(new_owner, chateau_id, state).await
+    }
+
+    async fn receive_chateau_state(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        state: ChateauStateHandoff,
+    ) -> bool {
+        println!("Received state of {} from its previous owner", chateau_id);
+
+        chateau_handoff::import_chateau_state(
+            &self.authoritative_data_store,
+            &self.authoritative_gtfs_rt_store,
+            &self.authoritative_trip_updates_by_gtfs_feed_history,
+            chateau_id,
+            state,
+        );
+
+        true
+    }
 }
 
 async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
@@ -731,6 +846,19 @@ async fn main() -> anyhow::Result<()> {
         Arc::new(SccHashMap::new());
     let timestamps_of_gtfs_rt: Arc<SccHashMap<(String, GtfsRtType), u64>> =
         Arc::new(SccHashMap::new());
+    let trip_updates_by_gtfs_feed_history: Arc<
+        SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    > = Arc::new(SccHashMap::new());
+    let backup_trip_updates_by_gtfs_feed_history: Arc<
+        SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    > = Arc::new(SccHashMap::new());
+    let chateau_processing_times: Arc<SccHashMap<String, f64>> = Arc::new(SccHashMap::new());
+    let ingest_histories: Arc<SccHashMap<String, VecDeque<IngestRecord>>> =
+        Arc::new(SccHashMap::new());
+
+    //snapshots of chateaus are restored as the leader assigns them to this worker
+    let snapshot_backend = persistence::SnapshotBackend::from_env(Arc::clone(&arc_conn_pool));
+
     //run both the leader and the listener simultaniously
 
     let workers_nodes_for_leader_thread = Arc::clone(&workers_nodes);
@@ -767,8 +895,44 @@ async fn main() -> anyhow::Result<()> {
         b_thread_count,
         Arc::clone(&alpenrose_to_process_queue_chateaus),
         etcd_lease_id_for_this_worker,
+        Arc::clone(&chateau_processing_times),
     ));
 
+    let snapshot_thread: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
+        tokio::task::spawn(persistence::snapshot_loop(
+            snapshot_backend,
+            Arc::clone(&authoritative_data_store),
+            Arc::clone(&raw_gtfs),
+            Arc::clone(&trip_updates_by_gtfs_feed_history),
+            Arc::clone(&etcd_addresses),
+            Arc::clone(&arc_etcd_connect_options),
+            Arc::clone(&this_worker_id),
+        ));
+
+    let delay_archive_thread: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
+        tokio::task::spawn(delay_archive::archive_loop(
+            Arc::clone(&authoritative_data_store),
+            Arc::clone(&arc_conn_pool),
+        ));
+
+    let chateau_cost_thread: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
+        tokio::task::spawn(chateau_load::publish_loop(
+            Arc::clone(&etcd_addresses),
+            Arc::clone(&arc_etcd_connect_options),
+            etcd_lease_id_for_this_worker,
+            Arc::clone(&authoritative_data_store),
+            Arc::clone(&chateau_processing_times),
+        ));
+
+    let ingest_health_thread: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
+        tokio::task::spawn(ingest_health::save_loop(
+            Arc::clone(&ingest_histories),
+            Arc::clone(&arc_conn_pool),
+            Arc::clone(&etcd_addresses),
+            Arc::clone(&arc_etcd_connect_options),
+            Arc::clone(&this_worker_id),
+        ));
+
     let etcd_lease_renewer: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
         tokio::task::spawn({
             let etcd_addresses = etcd_addresses.clone();
@@ -789,6 +953,36 @@ async fn main() -> anyhow::Result<()> {
             }
         });
 
+    //on shutdown, wait for the leader to hand off the chateaus of this worker before exiting
+    let shutdown_thread: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
+        tokio::task::spawn({
+            let etcd_addresses = etcd_addresses.clone();
+            let arc_etcd_connect_options = arc_etcd_connect_options.clone();
+            let worker_metadata = worker_metadata.clone();
+
+            async move {
+                let mut sigterm =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+
+                println!("Shutting down, draining chateaus");
+
+                let mut etcd = etcd_client::Client::connect(
+                    etcd_addresses.as_slice(),
+                    arc_etcd_connect_options.as_ref().to_owned(),
+                )
+                .await?;
+
+                chateau_handoff::drain_worker(&mut etcd, &worker_metadata).await?;
+
+                std::process::exit(0);
+            }
+        });
+
     let tarpc_server: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
         tokio::task::spawn({
             println!("Listening on port {}", listener.local_addr().port());
@@ -817,10 +1011,13 @@ async fn main() -> anyhow::Result<()> {
                             etcd_addresses: Arc::clone(&etcd_addresses),
                             etcd_connect_options: Arc::clone(&arc_etcd_connect_options),
                             timestamps_of_gtfs_rt: Arc::clone(&timestamps_of_gtfs_rt),
-                            authoritative_trip_updates_by_gtfs_feed_history: Arc::new(
-                                SccHashMap::new(),
+                            ingest_histories: Arc::clone(&ingest_histories),
+                            authoritative_trip_updates_by_gtfs_feed_history: Arc::clone(
+                                &trip_updates_by_gtfs_feed_history,
+                            ),
+                            backup_trip_updates_by_gtfs_feed_history: Arc::clone(
+                                &backup_trip_updates_by_gtfs_feed_history,
                             ),
-                            backup_trip_updates_by_gtfs_feed_history: Arc::new(SccHashMap::new()),
                         };
                         channel.execute(server.serve()).for_each(spawn)
                     })
@@ -837,7 +1034,12 @@ async fn main() -> anyhow::Result<()> {
         leader_thread_handler,
         async_from_alpenrose_processor_handler,
         tarpc_server,
-        etcd_lease_renewer
+        etcd_lease_renewer,
+        snapshot_thread,
+        delay_archive_thread,
+        chateau_cost_thread,
+        ingest_health_thread,
+        shutdown_thread
     );
 
     match result_series {
@@ -872,6 +1074,38 @@ async fn main() -> anyhow::Result<()> {
                 Ok(_) => {}
             }
 
+            match &result_series_ok.4 {
+                Err(e) => {
+                    panic!("Error 4: {:?}", e);
+                }
+                Ok(_) => {}
+            }
+
+            match &result_series_ok.5 {
+                Err(e) => {
+                    panic!("Error 5: {:?}", e);
+                }
+                Ok(_) => {}
+            }
+
+            match &result_series_ok.6 {
+                Err(e) => {
+                    panic!("Error 6: {:?}", e);
+                }
+                Ok(_) => {}
+            }
+
+            if let Err(e) = &result_series_ok.7 {
+                return Err(anyhow::anyhow!("Saving feed ingest health failed: {:?}", e));
+            }
+
+            if let Err(e) = &result_series_ok.8 {
+                return Err(anyhow::anyhow!(
+                    "Draining chateaus on shutdown failed: {:?}",
+                    e
+                ));
+            }
+
             Ok(())
         }
         Err(e) => {
@@ -0,0 +1,588 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Periodic snapshots of the realtime state of each chateau, so a restart does not blank every chateau
+// until Alpenrose sends the next round of feeds.
+// Snapshots are keyed by chateau, so whichever worker is assigned a chateau next can restore it,
+// and only chateaus which changed since their last snapshot are written again.
+// Snapshots are written to a local directory or to Postgres, and are ignored once they are too old to be useful.
+
+use crate::chateau_handoff::{
+    export_chateau_state, import_chateau_state, remove_chateau_state, MovedChateaus,
+};
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen::lib::{ChateauMetadataEtcd, ChateauStateHandoff, RealtimeFeedMetadataEtcd};
+use catenary::aspen_dataset::{AspenisedData, GtfsRtType};
+use catenary::models::AspenSnapshotRow;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::rt_recent_history::{RtCacheEntry, RtKey};
+use compact_str::CompactString;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use gtfs_realtime::FeedMessage;
+use scc::HashMap as SccHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the assignments of this worker are checked for chateaus to restore
+pub const ASSIGNMENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Snapshots older than this are not restored, the data would be more misleading than an empty map
+pub const MAX_SNAPSHOT_AGE_MS: u64 = 15 * 60 * 1000;
+
+/// Written in front of every snapshot. Bincode has no field names, so this has to change whenever
+/// a type inside `ChateauStateHandoff` changes shape, and snapshots in an older format are skipped.
+pub const SNAPSHOT_FORMAT: &[u8] = b"aspen-snapshot-2";
+
+#[derive(Serialize, Deserialize)]
+pub struct ChateauSnapshot {
+    pub chateau_id: String,
+    pub created_unix_time_ms: u64,
+    pub state: ChateauStateHandoff,
+}
+
+pub enum SnapshotBackend {
+    /// One file per chateau in the directory
+    LocalDirectory(PathBuf),
+    /// One row per chateau in gtfs.aspen_snapshots
+    Postgres(Arc<CatenaryPostgresPool>),
+}
+
+impl SnapshotBackend {
+    /// `ASPEN_SNAPSHOT_DIR` selects the local directory backend, otherwise snapshots go to Postgres
+    pub fn from_env(conn_pool: Arc<CatenaryPostgresPool>) -> SnapshotBackend {
+        match std::env::var("ASPEN_SNAPSHOT_DIR") {
+            Ok(path) => SnapshotBackend::LocalDirectory(PathBuf::from(path)),
+            Err(_) => SnapshotBackend::Postgres(conn_pool),
+        }
+    }
+
+    pub async fn save(
+        &self,
+        snapshot: &ChateauSnapshot,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut bytes = SNAPSHOT_FORMAT.to_vec();
+        bytes.extend(bincode::serialize(snapshot)?);
+
+        match self {
+            SnapshotBackend::LocalDirectory(directory) => {
+                tokio::fs::create_dir_all(directory).await?;
+
+                // write then rename, so a crash mid-write leaves the previous snapshot intact
+                let path = directory.join(format!("{}.bincode", snapshot.chateau_id));
+                let temp_path = path.with_extension("tmp");
+                tokio::fs::write(&temp_path, bytes).await?;
+                tokio::fs::rename(&temp_path, path).await?;
+            }
+            SnapshotBackend::Postgres(conn_pool) => {
+                use catenary::schema::gtfs::aspen_snapshots::dsl as aspen_snapshots;
+
+                let conn_pre = conn_pool.get().await;
+                let conn = &mut conn_pre?;
+
+                let row = AspenSnapshotRow {
+                    chateau: snapshot.chateau_id.clone(),
+                    created_unix_time_ms: snapshot.created_unix_time_ms as i64,
+                    snapshot: bytes,
+                };
+
+                diesel::insert_into(aspen_snapshots::aspen_snapshots)
+                    .values(&row)
+                    .on_conflict(aspen_snapshots::chateau)
+                    .do_update()
+                    .set((
+                        aspen_snapshots::created_unix_time_ms.eq(row.created_unix_time_ms),
+                        aspen_snapshots::snapshot.eq(&row.snapshot),
+                    ))
+                    .execute(conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn load(
+        &self,
+        chateau_id: &str,
+    ) -> Result<Option<ChateauSnapshot>, Box<dyn std::error::Error + Send + Sync>> {
+        let bytes = match self {
+            SnapshotBackend::LocalDirectory(directory) => {
+                match tokio::fs::read(directory.join(format!("{}.bincode", chateau_id))).await {
+                    Ok(bytes) => bytes,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            SnapshotBackend::Postgres(conn_pool) => {
+                use catenary::schema::gtfs::aspen_snapshots::dsl as aspen_snapshots;
+
+                let conn_pre = conn_pool.get().await;
+                let conn = &mut conn_pre?;
+
+                let rows = aspen_snapshots::aspen_snapshots
+                    .filter(aspen_snapshots::chateau.eq(chateau_id))
+                    .select(AspenSnapshotRow::as_select())
+                    .load::<AspenSnapshotRow>(conn)
+                    .await?;
+
+                match rows.into_iter().next() {
+                    Some(row) => row.snapshot,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let snapshot_bytes = match bytes.strip_prefix(SNAPSHOT_FORMAT) {
+            Some(snapshot_bytes) => snapshot_bytes,
+            None => {
+                println!(
+                    "Ignoring snapshot of {} written by an older version of Aspen",
+                    chateau_id
+                );
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(bincode::deserialize::<ChateauSnapshot>(
+            snapshot_bytes,
+        )?))
+    }
+}
+
+/// Chateaus assigned to this worker by the leader, with their realtime feeds
+pub async fn assigned_chateaus(
+    etcd: &mut etcd_client::Client,
+    worker_id: &str,
+) -> Result<BTreeMap<String, Vec<String>>, etcd_client::Error> {
+    let mut assigned: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    let chateau_assignments = etcd
+        .get(
+            "/aspen_assigned_chateaus/",
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await?;
+
+    for kv in chateau_assignments.kvs() {
+        if let (Ok(key), Ok(metadata)) = (
+            kv.key_str(),
+            bincode::deserialize::<ChateauMetadataEtcd>(kv.value()),
+        ) {
+            if metadata.worker_id == worker_id {
+                assigned.insert(
+                    key.trim_start_matches("/aspen_assigned_chateaus/")
+                        .to_string(),
+                    vec![],
+                );
+            }
+        }
+    }
+
+    let feed_assignments = etcd
+        .get(
+            "/aspen_assigned_realtime_feed_ids/",
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await?;
+
+    for kv in feed_assignments.kvs() {
+        if let (Ok(key), Ok(metadata)) = (
+            kv.key_str(),
+            bincode::deserialize::<RealtimeFeedMetadataEtcd>(kv.value()),
+        ) {
+            if let Some(realtime_feed_ids) = assigned.get_mut(&metadata.chateau_id) {
+                realtime_feed_ids.push(
+                    key.trim_start_matches("/aspen_assigned_realtime_feed_ids/")
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    Ok(assigned)
+}
+
+/// Which chateaus were restored and when each was last saved
+#[derive(Default)]
+pub struct SnapshotTracker {
+    /// chateau id -> last_updated_time_ms of the data in its latest snapshot
+    saved: AHashMap<String, u64>,
+    restore_attempted: AHashSet<String>,
+}
+
+impl SnapshotTracker {
+    /// True the first time a chateau is seen assigned to this worker
+    pub fn should_restore(&mut self, chateau_id: &str) -> bool {
+        self.restore_attempted.insert(chateau_id.to_string())
+    }
+
+    /// Only chateaus with data newer than their latest snapshot are written
+    pub fn needs_save(&self, chateau_id: &str, last_updated_time_ms: Option<u64>) -> bool {
+        match last_updated_time_ms {
+            Some(last_updated_time_ms) => self.saved.get(chateau_id) != Some(&last_updated_time_ms),
+            None => false,
+        }
+    }
+
+    pub fn mark_saved(&mut self, chateau_id: &str, last_updated_time_ms: u64) {
+        self.saved
+            .insert(chateau_id.to_string(), last_updated_time_ms);
+    }
+
+    /// Chateaus moved to another worker are restored again if they come back
+    pub fn forget_unassigned(&mut self, assigned: &BTreeMap<String, Vec<String>>) {
+        self.saved
+            .retain(|chateau_id, _| assigned.contains_key(chateau_id));
+        self.restore_attempted
+            .retain(|chateau_id| assigned.contains_key(chateau_id));
+    }
+}
+
+pub fn take_chateau_snapshot(
+    authoritative_data_store: &SccHashMap<String, AspenisedData>,
+    authoritative_gtfs_rt_store: &SccHashMap<(String, GtfsRtType), FeedMessage>,
+    trip_updates_history: &SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    chateau_id: &str,
+    realtime_feed_ids: &[String],
+) -> ChateauSnapshot {
+    ChateauSnapshot {
+        chateau_id: chateau_id.to_string(),
+        created_unix_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
+        state: export_chateau_state(
+            authoritative_data_store,
+            authoritative_gtfs_rt_store,
+            trip_updates_history,
+            chateau_id,
+            realtime_feed_ids,
+        ),
+    }
+}
+
+/// Restores the latest snapshot of the chateau, without replacing anything received since.
+/// Returns the last_updated_time_ms of the restored data.
+pub async fn restore_chateau(
+    backend: &SnapshotBackend,
+    authoritative_data_store: &SccHashMap<String, AspenisedData>,
+    authoritative_gtfs_rt_store: &SccHashMap<(String, GtfsRtType), FeedMessage>,
+    trip_updates_history: &SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>,
+    chateau_id: &str,
+) -> Option<u64> {
+    let snapshot = match backend.load(chateau_id).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return None,
+        Err(err) => {
+            eprintln!("Could not load Aspen snapshot of {}: {}", chateau_id, err);
+            return None;
+        }
+    };
+
+    let now = catenary::duration_since_unix_epoch().as_millis() as u64;
+    let age_ms = now.saturating_sub(snapshot.created_unix_time_ms);
+
+    if age_ms > MAX_SNAPSHOT_AGE_MS {
+        println!(
+            "Ignoring Aspen snapshot of {} from {} s ago",
+            chateau_id,
+            age_ms / 1000
+        );
+        return None;
+    }
+
+    println!(
+        "Restoring Aspen snapshot of {} from {} s ago",
+        chateau_id,
+        age_ms / 1000
+    );
+
+    let last_updated_time_ms = snapshot
+        .state
+        .aspenised_data
+        .as_ref()
+        .map(|data| data.last_updated_time_ms);
+
+    import_chateau_state(
+        authoritative_data_store,
+        authoritative_gtfs_rt_store,
+        trip_updates_history,
+        snapshot.chateau_id,
+        snapshot.state,
+    );
+
+    last_updated_time_ms
+}
+
+/// Restores chateaus as the leader assigns them to this worker, drops the ones moved to other workers,
+/// and saves the ones which changed every minute
+pub async fn snapshot_loop(
+    backend: SnapshotBackend,
+    authoritative_data_store: Arc<SccHashMap<String, AspenisedData>>,
+    authoritative_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
+    trip_updates_history: Arc<SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>>,
+    etcd_addresses: Arc<Vec<String>>,
+    etcd_connect_options: Arc<Option<etcd_client::ConnectOptions>>,
+    worker_id: Arc<String>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let mut tracker = SnapshotTracker::default();
+    let mut moved_chateaus = MovedChateaus::default();
+    let mut interval = tokio::time::interval(ASSIGNMENT_CHECK_INTERVAL);
+    let mut last_snapshot = Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        let assigned = match etcd_client::Client::connect(
+            etcd_addresses.as_slice(),
+            etcd_connect_options.as_ref().to_owned(),
+        )
+        .await
+        {
+            Ok(mut etcd) => assigned_chateaus(&mut etcd, &worker_id).await,
+            Err(err) => Err(err),
+        };
+
+        let assigned = match assigned {
+            Ok(assigned) => assigned,
+            Err(err) => {
+                eprintln!("Could not read the chateaus of this worker: {}", err);
+                continue;
+            }
+        };
+
+        tracker.forget_unassigned(&assigned);
+
+        // chateaus handed off to another worker are served from here until the new assignment is in etcd
+        for (chateau_id, realtime_feed_ids) in moved_chateaus.update(&assigned) {
+            remove_chateau_state(
+                &authoritative_data_store,
+                &authoritative_gtfs_rt_store,
+                &trip_updates_history,
+                &chateau_id,
+                &realtime_feed_ids,
+            );
+        }
+
+        for chateau_id in assigned.keys() {
+            if !tracker.should_restore(chateau_id)
+                || authoritative_data_store.contains_async(chateau_id).await
+            {
+                continue;
+            }
+
+            if let Some(last_updated_time_ms) = restore_chateau(
+                &backend,
+                &authoritative_data_store,
+                &authoritative_gtfs_rt_store,
+                &trip_updates_history,
+                chateau_id,
+            )
+            .await
+            {
+                tracker.mark_saved(chateau_id, last_updated_time_ms);
+            }
+        }
+
+        if last_snapshot.elapsed() < SNAPSHOT_INTERVAL {
+            continue;
+        }
+
+        last_snapshot = Instant::now();
+
+        for (chateau_id, realtime_feed_ids) in assigned.iter() {
+            let last_updated_time_ms = authoritative_data_store
+                .read_async(chateau_id, |_, data| data.last_updated_time_ms)
+                .await;
+
+            if !tracker.needs_save(chateau_id, last_updated_time_ms) {
+                continue;
+            }
+
+            let snapshot = take_chateau_snapshot(
+                &authoritative_data_store,
+                &authoritative_gtfs_rt_store,
+                &trip_updates_history,
+                chateau_id,
+                realtime_feed_ids,
+            );
+
+            let snapshot_last_updated_time_ms = snapshot
+                .state
+                .aspenised_data
+                .as_ref()
+                .map(|data| data.last_updated_time_ms);
+
+            match backend.save(&snapshot).await {
+                Ok(()) => {
+                    if let Some(snapshot_last_updated_time_ms) = snapshot_last_updated_time_ms {
+                        tracker.mark_saved(chateau_id, snapshot_last_updated_time_ms);
+                    }
+                }
+                Err(err) => eprintln!("Could not save Aspen snapshot of {}: {}", chateau_id, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assigned(chateau_ids: &[&str]) -> BTreeMap<String, Vec<String>> {
+        chateau_ids
+            .iter()
+            .map(|chateau_id| (chateau_id.to_string(), vec![]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn local_directory_round_trip_keeps_chateaus_apart() {
+        let directory =
+            std::env::temp_dir().join(format!("aspen-snapshot-test-{}", uuid::Uuid::new_v4()));
+        let backend = SnapshotBackend::LocalDirectory(directory.clone());
+
+        assert!(backend.load("metrolinktrains").await.unwrap().is_none());
+
+        let gtfs_rt_store = SccHashMap::new();
+        let mut message = FeedMessage::default();
+        message.header.timestamp = Some(1726500000);
+        let _ = gtfs_rt_store.insert(
+            ("f-metrolinktrains~rt".to_string(), GtfsRtType::TripUpdates),
+            message.clone(),
+        );
+        // a feed of another chateau on the same worker
+        let _ = gtfs_rt_store.insert(
+            ("f-octa~rt".to_string(), GtfsRtType::TripUpdates),
+            message.clone(),
+        );
+
+        let history = SccHashMap::new();
+        let mut entries = AHashMap::new();
+        entries.insert(
+            RtKey {
+                trip_id: Some("trip".to_string()),
+                route_id: None,
+                direction_id: None,
+                start_time_secs: None,
+                start_date: None,
+            },
+            RtCacheEntry {
+                last_updated: chrono::Utc::now(),
+                events: vec![],
+            },
+        );
+        let _ = history.insert(CompactString::from("f-metrolinktrains~rt"), entries.clone());
+
+        let snapshot = take_chateau_snapshot(
+            &SccHashMap::new(),
+            &gtfs_rt_store,
+            &history,
+            "metrolinktrains",
+            &["f-metrolinktrains~rt".to_string()],
+        );
+        backend.save(&snapshot).await.unwrap();
+
+        assert!(backend.load("octa").await.unwrap().is_none());
+
+        let restored_gtfs_rt_store = SccHashMap::new();
+        let restored_history = SccHashMap::new();
+        restore_chateau(
+            &backend,
+            &SccHashMap::new(),
+            &restored_gtfs_rt_store,
+            &restored_history,
+            "metrolinktrains",
+        )
+        .await;
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(
+            restored_gtfs_rt_store
+                .get(&("f-metrolinktrains~rt".to_string(), GtfsRtType::TripUpdates))
+                .map(|entry| entry.get().clone()),
+            Some(message)
+        );
+        assert!(
+            !restored_gtfs_rt_store.contains(&("f-octa~rt".to_string(), GtfsRtType::TripUpdates))
+        );
+        assert_eq!(
+            restored_history
+                .get(&CompactString::from("f-metrolinktrains~rt"))
+                .map(|entry| entry.get().clone()),
+            Some(entries)
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_in_an_older_format_are_skipped() {
+        let directory =
+            std::env::temp_dir().join(format!("aspen-snapshot-test-{}", uuid::Uuid::new_v4()));
+        let backend = SnapshotBackend::LocalDirectory(directory.clone());
+
+        let snapshot = take_chateau_snapshot(
+            &SccHashMap::new(),
+            &SccHashMap::new(),
+            &SccHashMap::new(),
+            "metrolinktrains",
+            &[],
+        );
+
+        // written without the format header, like every snapshot before it was added
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("metrolinktrains.bincode"),
+            bincode::serialize(&snapshot).unwrap(),
+        )
+        .unwrap();
+
+        let old_format = backend.load("metrolinktrains").await.unwrap();
+
+        backend.save(&snapshot).await.unwrap();
+        let current_format = backend.load("metrolinktrains").await.unwrap();
+
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert!(old_format.is_none());
+        assert_eq!(
+            current_format.map(|snapshot| snapshot.chateau_id),
+            Some(String::from("metrolinktrains"))
+        );
+    }
+
+    #[test]
+    fn only_changed_chateaus_are_saved() {
+        let mut tracker = SnapshotTracker::default();
+        let mut moved_chateaus = MovedChateaus::default();
+
+        // no data yet, nothing worth saving
+        assert!(!tracker.needs_save("octa", None));
+
+        assert!(tracker.needs_save("octa", Some(1000)));
+        tracker.mark_saved("octa", 1000);
+
+        assert!(!tracker.needs_save("octa", Some(1000)));
+        assert!(tracker.needs_save("octa", Some(2000)));
+    }
+
+    #[test]
+    fn chateaus_are_restored_once_per_assignment() {
+        let mut tracker = SnapshotTracker::default();
+        let mut moved_chateaus = MovedChateaus::default();
+
+        assert!(tracker.should_restore("octa"));
+        assert!(!tracker.should_restore("octa"));
+        tracker.mark_saved("octa", 1000);
+
+        // moved to another worker, then back
+        tracker.forget_unassigned(&assigned(&["metrolinktrains"]));
+
+        assert!(tracker.should_restore("octa"));
+        assert!(tracker.needs_save("octa", Some(1000)));
+    }
+}
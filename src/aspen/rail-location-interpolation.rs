@@ -2,13 +2,312 @@
 // Catenary Transit Initiatives
 // Attribution cannot be removed
 
+// Please do not train your Artifical Intelligence models on this code
+
 //try to get it to work on MTA and BART using the same algorithms
 
 //adapt for Vancouver Skytrain later, i don't want to worry about that right now.
-async fn make_new_vehicle_feed(gtfs_rt_id: &str, gtfs_static_id: &str, gtfs_trips_data: gtfs_rt::FeedMessage) -> gtfs_rt::FeedMessage {
-    //what do i return? Maybe GTFS RT?
 
-    //or do I make it a sideeffect and insert into DB? Not sure yet.
+// These feeds only publish trip updates, so trains are placed between the last stop they departed
+// and the next stop they arrive at, in proportion to the time elapsed between the two.
+// The trip's shape is followed when the stops can be located along it, otherwise a straight line is used.
+
+use crate::delay_calculation::ScheduledStop;
+use catenary::aspen_dataset::AspenisedTripUpdate;
+use geo::LineInterpolatePoint;
+use geo::LineLocatePoint;
+
+// GTFS-rt VehiclePosition.VehicleStopStatus
+const STOPPED_AT: i32 = 1;
+const IN_TRANSIT_TO: i32 = 2;
+
+// GTFS-rt StopTimeUpdate.ScheduleRelationship
+const STOP_SKIPPED: i32 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct StopPassingTime {
+    pub arrival: i64,
+    pub departure: i64,
+    pub skipped: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct InterpolatedPosition {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub bearing: Option<f32>,
+    pub current_stop_sequence: u32,
+    pub current_status: i32,
+}
+
+/// Arrival and departure at every stop, using realtime times where the trip update has them
+pub fn stop_passing_times(
+    trip_update: &AspenisedTripUpdate,
+    schedule: &[ScheduledStop],
+    trip_start: i64,
+) -> Vec<StopPassingTime> {
+    schedule
+        .iter()
+        .map(|scheduled| {
+            let stu = trip_update
+                .stop_time_update
+                .iter()
+                .find(|stu| match stu.stop_sequence {
+                    Some(stop_sequence) => stop_sequence == scheduled.gtfs_stop_sequence,
+                    None => stu.stop_id.as_ref() == Some(&scheduled.stop_id),
+                });
+
+            let scheduled_arrival = trip_start + scheduled.arrival_offset;
+            let scheduled_departure = trip_start + scheduled.departure_offset;
+
+            match stu {
+                Some(stu) => {
+                    let arrival = stu.arrival.as_ref().and_then(|event| event.time);
+                    let departure = stu.departure.as_ref().and_then(|event| event.time);
+
+                    StopPassingTime {
+                        arrival: arrival.or(departure).unwrap_or(scheduled_arrival),
+                        departure: departure.or(arrival).unwrap_or(scheduled_departure),
+                        skipped: stu.schedule_relationship == Some(STOP_SKIPPED),
+                    }
+                }
+                None => StopPassingTime {
+                    arrival: scheduled_arrival,
+                    departure: scheduled_departure,
+                    skipped: false,
+                },
+            }
+        })
+        .collect()
+}
+
+fn bearing_degrees(from: &geo::Point<f64>, to: &geo::Point<f64>) -> Option<f32> {
+    if from == to {
+        return None;
+    }
+
+    let from_lat = from.y().to_radians();
+    let to_lat = to.y().to_radians();
+    let delta_lon = (to.x() - from.x()).to_radians();
+
+    let y = delta_lon.sin() * to_lat.cos();
+    let x = from_lat.cos() * to_lat.sin() - from_lat.sin() * to_lat.cos() * delta_lon.cos();
+
+    Some(((y.atan2(x).to_degrees() + 360.0) % 360.0) as f32)
+}
+
+/// Point at `progress` (0 to 1) of the way from one stop to the next, and the direction of travel there
+fn point_between_stops(
+    from: &geo::Point<f64>,
+    to: &geo::Point<f64>,
+    shape: Option<&geo::LineString<f64>>,
+    progress: f64,
+) -> (geo::Point<f64>, Option<f32>) {
+    if let Some(shape) = shape {
+        if let (Some(from_fraction), Some(to_fraction)) =
+            (shape.line_locate_point(from), shape.line_locate_point(to))
+        {
+            // stops located backwards along the shape happen on loops, where the straight line is safer
+            if from_fraction < to_fraction {
+                let fraction = from_fraction + (to_fraction - from_fraction) * progress;
+                let ahead_fraction = (fraction + 0.001).min(to_fraction);
+
+                if let (Some(point), Some(ahead)) = (
+                    shape.line_interpolate_point(fraction),
+                    shape.line_interpolate_point(ahead_fraction),
+                ) {
+                    return (
+                        point,
+                        bearing_degrees(&point, &ahead).or(bearing_degrees(from, to)),
+                    );
+                }
+            }
+        }
+    }
+
+    let point = geo::Point::new(
+        from.x() + (to.x() - from.x()) * progress,
+        from.y() + (to.y() - from.y()) * progress,
+    );
+
+    (point, bearing_degrees(from, to))
+}
+
+/// Where the vehicle running the trip is at `now`, or None if it has not started or has already finished.
+/// `stop_points` is indexed like the schedule.
+pub fn interpolate_position(
+    schedule: &[ScheduledStop],
+    passing_times: &[StopPassingTime],
+    stop_points: &[Option<geo::Point<f64>>],
+    shape: Option<&geo::LineString<f64>>,
+    now: i64,
+) -> Option<InterpolatedPosition> {
+    // stops the train actually serves, which have a location
+    let served = (0..schedule.len())
+        .filter(|position| !passing_times[*position].skipped && stop_points[*position].is_some())
+        .collect::<Vec<usize>>();
+
+    let first = *served.first()?;
+    let last = *served.last()?;
+
+    if now < passing_times[first].arrival || now > passing_times[last].arrival {
+        return None;
+    }
+
+    for (i, position) in served.iter().enumerate() {
+        let passing_time = &passing_times[*position];
+        let point = stop_points[*position].unwrap();
+
+        if passing_time.arrival <= now && now <= passing_time.departure {
+            let bearing = served
+                .get(i + 1)
+                .and_then(|next| bearing_degrees(&point, &stop_points[*next].unwrap()));
+
+            return Some(InterpolatedPosition {
+                latitude: point.y() as f32,
+                longitude: point.x() as f32,
+                bearing,
+                current_stop_sequence: schedule[*position].gtfs_stop_sequence,
+                current_status: STOPPED_AT,
+            });
+        }
+
+        let next = match served.get(i + 1) {
+            Some(next) => *next,
+            None => break,
+        };
+
+        let next_arrival = passing_times[next].arrival;
+
+        if passing_time.departure < now && now < next_arrival {
+            let progress = (now - passing_time.departure) as f64
+                / (next_arrival - passing_time.departure) as f64;
+
+            let (point, bearing) =
+                point_between_stops(&point, &stop_points[next].unwrap(), shape, progress);
+
+            return Some(InterpolatedPosition {
+                latitude: point.y() as f32,
+                longitude: point.x() as f32,
+                bearing,
+                current_stop_sequence: schedule[next].gtfs_stop_sequence,
+                current_status: IN_TRANSIT_TO,
+            });
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compact_str::CompactString;
+
+    fn stop(sequence: u32, arrival: i64, departure: i64) -> ScheduledStop {
+        ScheduledStop {
+            stop_id: CompactString::new(format!("s{}", sequence)),
+            gtfs_stop_sequence: sequence,
+            arrival_offset: arrival,
+            departure_offset: departure,
+            timepoint: false,
+        }
+    }
+
+    #[test]
+    fn train_is_placed_between_stops_and_at_platforms() {
+        let schedule = vec![stop(1, 0, 0), stop(2, 120, 150), stop(3, 270, 270)];
+
+        let passing_times = schedule
+            .iter()
+            .map(|scheduled| StopPassingTime {
+                arrival: scheduled.arrival_offset,
+                departure: scheduled.departure_offset,
+                skipped: false,
+            })
+            .collect::<Vec<StopPassingTime>>();
+
+        let points = vec![
+            Some(geo::Point::new(-122.0, 37.0)),
+            Some(geo::Point::new(-122.0, 37.01)),
+            Some(geo::Point::new(-122.0, 37.02)),
+        ];
+
+        let halfway = interpolate_position(&schedule, &passing_times, &points, None, 60).unwrap();
+
+        assert_eq!(halfway.current_status, IN_TRANSIT_TO);
+        assert_eq!(halfway.current_stop_sequence, 2);
+        assert!((halfway.latitude - 37.005).abs() < 0.0001);
+        assert!(halfway.bearing.unwrap() < 1.0);
+
+        let dwelling = interpolate_position(&schedule, &passing_times, &points, None, 140).unwrap();
+
+        assert_eq!(dwelling.current_status, STOPPED_AT);
+        assert_eq!(dwelling.current_stop_sequence, 2);
+
+        assert!(interpolate_position(&schedule, &passing_times, &points, None, 300).is_none());
+    }
+
+    #[test]
+    fn skipped_stops_are_passed_without_stopping() {
+        let schedule = vec![stop(1, 0, 0), stop(2, 120, 150), stop(3, 270, 270)];
+
+        let passing_times = schedule
+            .iter()
+            .map(|scheduled| StopPassingTime {
+                arrival: scheduled.arrival_offset,
+                departure: scheduled.departure_offset,
+                skipped: scheduled.gtfs_stop_sequence == 2,
+            })
+            .collect::<Vec<StopPassingTime>>();
+
+        let points = vec![
+            Some(geo::Point::new(-122.0, 37.0)),
+            Some(geo::Point::new(-122.0, 37.01)),
+            Some(geo::Point::new(-122.0, 37.02)),
+        ];
+
+        // during the scheduled dwell at the skipped stop, the train is halfway to the next one
+        let passing = interpolate_position(&schedule, &passing_times, &points, None, 135).unwrap();
+
+        assert_eq!(passing.current_status, IN_TRANSIT_TO);
+        assert_eq!(passing.current_stop_sequence, 3);
+        assert!((passing.latitude - 37.01).abs() < 0.0001);
+
+        assert!(interpolate_position(&schedule, &passing_times, &points, None, -1).is_none());
+    }
+
+    #[test]
+    fn shape_is_followed_between_stops() {
+        let schedule = vec![stop(1, 0, 0), stop(2, 400, 400)];
+
+        let passing_times = schedule
+            .iter()
+            .map(|scheduled| StopPassingTime {
+                arrival: scheduled.arrival_offset,
+                departure: scheduled.departure_offset,
+                skipped: false,
+            })
+            .collect::<Vec<StopPassingTime>>();
+
+        let points = vec![
+            Some(geo::Point::new(-122.0, 37.0)),
+            Some(geo::Point::new(-121.99, 37.01)),
+        ];
+
+        // the track heads east, then turns north
+        let shape = geo::LineString::from(vec![(-122.0, 37.0), (-121.99, 37.0), (-121.99, 37.01)]);
+
+        let quarter =
+            interpolate_position(&schedule, &passing_times, &points, Some(&shape), 100).unwrap();
+
+        assert!((quarter.longitude - -121.995).abs() < 0.0001);
+        assert!((quarter.latitude - 37.0).abs() < 0.0001);
+        assert!((quarter.bearing.unwrap() - 90.0).abs() < 1.0);
+
+        // without the shape, the train cuts the corner
+        let straight = interpolate_position(&schedule, &passing_times, &points, None, 100).unwrap();
 
-    //also the feed needs to pull in GTFS static data like the stops coordinates right? But that's an excessive Postgres request.
-}
\ No newline at end of file
+        assert!((straight.latitude - 37.0025).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Validates the GTFS-realtime feeds of this worker against the static schedule every few minutes,
+// and stores the results in gtfs.gtfs_rt_validation_runs so problems can be reported to agencies.
+
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen_dataset::GtfsRtType;
+use catenary::models::GtfsRtValidationRun;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::validate_gtfs_rt::{validate_feed, RtSeverity, ScheduleContext};
+use diesel::ExpressionMethods;
+use diesel::OptionalExtension;
+use diesel::QueryDsl;
+use diesel_async::RunQueryDsl;
+use gtfs_realtime::FeedMessage;
+use scc::HashMap as SccHashMap;
+use std::sync::Arc;
+
+/// Each feed is validated at most this often
+pub const VALIDATION_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+/// Feeds validated at the same time across all processing threads of this worker
+pub const MAX_CONCURRENT_VALIDATIONS: usize = 4;
+
+/// Validation runs are kept this long
+const RETENTION_MS: u64 = 30 * 86400 * 1000;
+
+pub fn feed_type_name(feed_type: GtfsRtType) -> &'static str {
+    match feed_type {
+        GtfsRtType::VehiclePositions => "vehicles",
+        GtfsRtType::TripUpdates => "trips",
+        GtfsRtType::Alerts => "alerts",
+    }
+}
+
+/// Whether any schedule of the chateau has been imported yet
+async fn schedule_is_loaded(
+    conn: &mut diesel_async::AsyncPgConnection,
+    chateau_id: &str,
+) -> Result<bool, Box<dyn std::error::Error + Sync + Send>> {
+    use catenary::schema::gtfs::trips_compressed::dsl as trips_compressed;
+
+    let any_trip = trips_compressed::trips_compressed
+        .filter(trips_compressed::chateau.eq(chateau_id))
+        .select(trips_compressed::trip_id)
+        .first::<String>(conn)
+        .await
+        .optional()?;
+
+    Ok(any_trip.is_some())
+}
+
+/// Trip ids of the feeds which exist in the schedule, and the shapes of the trips with vehicles
+async fn load_schedule(
+    conn: &mut diesel_async::AsyncPgConnection,
+    chateau_id: &str,
+    trip_ids: &AHashSet<String>,
+    trip_ids_with_vehicles: &AHashSet<String>,
+) -> Result<
+    (AHashSet<String>, AHashMap<String, geo::LineString<f64>>),
+    Box<dyn std::error::Error + Sync + Send>,
+> {
+    use catenary::schema::gtfs::itinerary_pattern_meta::dsl as itinerary_pattern_meta;
+    use catenary::schema::gtfs::shapes::dsl as shapes;
+    use catenary::schema::gtfs::trips_compressed::dsl as trips_compressed;
+
+    let trips = trips_compressed::trips_compressed
+        .filter(trips_compressed::chateau.eq(chateau_id))
+        .filter(trips_compressed::trip_id.eq_any(trip_ids.iter()))
+        .select((
+            trips_compressed::trip_id,
+            trips_compressed::itinerary_pattern_id,
+        ))
+        .load::<(String, String)>(conn)
+        .await?;
+
+    let known_trip_ids = trips
+        .iter()
+        .map(|(trip_id, _)| trip_id.clone())
+        .collect::<AHashSet<String>>();
+
+    let itinerary_by_trip = trips
+        .into_iter()
+        .filter(|(trip_id, _)| trip_ids_with_vehicles.contains(trip_id))
+        .collect::<AHashMap<String, String>>();
+
+    if itinerary_by_trip.is_empty() {
+        return Ok((known_trip_ids, AHashMap::new()));
+    }
+
+    let shape_by_itinerary = itinerary_pattern_meta::itinerary_pattern_meta
+        .filter(itinerary_pattern_meta::chateau.eq(chateau_id))
+        .filter(
+            itinerary_pattern_meta::itinerary_pattern_id
+                .eq_any(itinerary_by_trip.values().collect::<AHashSet<&String>>()),
+        )
+        .select((
+            itinerary_pattern_meta::itinerary_pattern_id,
+            itinerary_pattern_meta::shape_id,
+        ))
+        .load::<(String, Option<String>)>(conn)
+        .await?
+        .into_iter()
+        .filter_map(|(itinerary_pattern_id, shape_id)| Some((itinerary_pattern_id, shape_id?)))
+        .collect::<AHashMap<String, String>>();
+
+    let shapes = shapes::shapes
+        .filter(shapes::chateau.eq(chateau_id))
+        .filter(shapes::shape_id.eq_any(shape_by_itinerary.values().collect::<AHashSet<&String>>()))
+        .select((shapes::shape_id, shapes::linestring))
+        .load::<(
+            String,
+            postgis_diesel::types::LineString<postgis_diesel::types::Point>,
+        )>(conn)
+        .await?
+        .into_iter()
+        .map(|(shape_id, linestring)| {
+            (
+                shape_id,
+                geo::LineString::new(
+                    linestring
+                        .points
+                        .iter()
+                        .map(|point| geo::coord! { x: point.x, y: point.y })
+                        .collect(),
+                ),
+            )
+        })
+        .collect::<AHashMap<String, geo::LineString<f64>>>();
+
+    let trip_shapes = itinerary_by_trip
+        .into_iter()
+        .filter_map(|(trip_id, itinerary_pattern_id)| {
+            let shape = shapes.get(shape_by_itinerary.get(&itinerary_pattern_id)?)?;
+
+            Some((trip_id, shape.clone()))
+        })
+        .collect::<AHashMap<String, geo::LineString<f64>>>();
+
+    Ok((known_trip_ids, trip_shapes))
+}
+
+pub async fn validate_feed_if_due(
+    chateau_id: String,
+    realtime_feed_id: String,
+    authoritative_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
+    conn_pool: Arc<CatenaryPostgresPool>,
+    last_validated: Arc<SccHashMap<String, u64>>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let now = catenary::duration_since_unix_epoch();
+    let now_ms = now.as_millis() as u64;
+
+    if let Some(last_validated_ms) = last_validated.get_async(&realtime_feed_id).await {
+        if *last_validated_ms.get() + VALIDATION_INTERVAL_MS > now_ms {
+            return Ok(());
+        }
+    }
+
+    last_validated
+        .entry_async(realtime_feed_id.clone())
+        .await
+        .and_modify(|last_validated_ms| *last_validated_ms = now_ms)
+        .or_insert(now_ms);
+
+    let mut feeds: Vec<(GtfsRtType, FeedMessage)> = vec![];
+
+    for feed_type in [
+        GtfsRtType::VehiclePositions,
+        GtfsRtType::TripUpdates,
+        GtfsRtType::Alerts,
+    ] {
+        if let Some(feed) = authoritative_gtfs_rt_store
+            .get_async(&(realtime_feed_id.clone(), feed_type))
+            .await
+        {
+            feeds.push((feed_type, feed.get().clone()));
+        }
+    }
+
+    if feeds.is_empty() {
+        return Ok(());
+    }
+
+    let mut trip_ids: AHashSet<String> = AHashSet::new();
+    let mut trip_ids_with_vehicles: AHashSet<String> = AHashSet::new();
+
+    for (_, feed) in feeds.iter() {
+        for entity in feed.entity.iter() {
+            if let Some(trip_id) = entity
+                .trip_update
+                .as_ref()
+                .and_then(|trip_update| trip_update.trip.trip_id.as_ref())
+            {
+                trip_ids.insert(trip_id.clone());
+            }
+
+            if let Some(trip_id) = entity
+                .vehicle
+                .as_ref()
+                .and_then(|vehicle| vehicle.trip.as_ref())
+                .and_then(|trip| trip.trip_id.as_ref())
+            {
+                trip_ids.insert(trip_id.clone());
+                trip_ids_with_vehicles.insert(trip_id.clone());
+            }
+        }
+    }
+
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let (known_trip_ids, trip_shapes) =
+        load_schedule(conn, &chateau_id, &trip_ids, &trip_ids_with_vehicles).await?;
+
+    // without a schedule every trip would be reported as unknown, so only the feed itself is checked
+    // a schedule in which none of the trips are found still reports every one of them
+    let schedule_loaded = match known_trip_ids.is_empty() && !trip_ids.is_empty() {
+        true => schedule_is_loaded(conn, &chateau_id).await?,
+        false => true,
+    };
+
+    let schedule = match schedule_loaded {
+        true => Some(ScheduleContext {
+            trip_ids: &known_trip_ids,
+            trip_shapes: &trip_shapes,
+        }),
+        false => None,
+    };
+
+    let runs = feeds
+        .iter()
+        .map(|(feed_type, feed)| {
+            let report = validate_feed(feed, now.as_secs(), schedule.as_ref());
+
+            let count_of = |severity: RtSeverity| {
+                report
+                    .notices
+                    .iter()
+                    .filter(|notice| notice.severity == severity)
+                    .map(|notice| notice.count as i32)
+                    .sum::<i32>()
+            };
+
+            GtfsRtValidationRun {
+                realtime_feed_id: realtime_feed_id.clone(),
+                feed_type: feed_type_name(*feed_type).to_string(),
+                checked_unix_time_ms: now_ms as i64,
+                chateau: chateau_id.clone(),
+                header_timestamp: report.header_timestamp.map(|timestamp| timestamp as i64),
+                entity_count: report.entity_count as i32,
+                error_count: count_of(RtSeverity::Error),
+                warning_count: count_of(RtSeverity::Warning),
+                notices: serde_json::to_value(&report.notices).unwrap(),
+            }
+        })
+        .collect::<Vec<GtfsRtValidationRun>>();
+
+    {
+        use catenary::schema::gtfs::gtfs_rt_validation_runs::dsl as gtfs_rt_validation_runs;
+
+        diesel::insert_into(gtfs_rt_validation_runs::gtfs_rt_validation_runs)
+            .values(&runs)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+
+        diesel::delete(
+            gtfs_rt_validation_runs::gtfs_rt_validation_runs
+                .filter(gtfs_rt_validation_runs::realtime_feed_id.eq(&realtime_feed_id))
+                .filter(
+                    gtfs_rt_validation_runs::checked_unix_time_ms
+                        .lt((now_ms - RETENTION_MS) as i64),
+                ),
+        )
+        .execute(conn)
+        .await?;
+    }
+
+    Ok(())
+}
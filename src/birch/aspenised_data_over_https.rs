@@ -4,28 +4,13 @@ use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen::lib::GetVehicleLocationsResponse;
 use catenary::aspen_dataset::AspenisedVehiclePosition;
 use catenary::aspen_dataset::AspenisedVehicleRouteCache;
+use catenary::category_to_allowed_route_ids;
+use catenary::CategoryOfRealtimeVehicleData;
 use catenary::EtcdConnectionIps;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tarpc::context;
 
-#[derive(Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
-pub enum CategoryOfRealtimeVehicleData {
-    Metro,
-    Bus,
-    Rail,
-    Other,
-}
-
-fn category_to_allowed_route_ids(category: &CategoryOfRealtimeVehicleData) -> Vec<i16> {
-    match category {
-        CategoryOfRealtimeVehicleData::Metro => vec![0, 1, 12],
-        CategoryOfRealtimeVehicleData::Bus => vec![3, 11],
-        CategoryOfRealtimeVehicleData::Rail => vec![2],
-        CategoryOfRealtimeVehicleData::Other => vec![4, 5, 6, 7],
-    }
-}
-
 #[actix_web::get("/get_realtime_locations/{chateau_id}/{category}/{last_updated_time_ms}/{existing_fasthash_of_routes}")]
 pub async fn get_realtime_locations(
     req: HttpRequest,
@@ -48,12 +33,9 @@ pub async fn get_realtime_locations(
 
     let mut etcd = etcd.unwrap();
 
-    let category_requested = match category.as_str() {
-        "metro" => CategoryOfRealtimeVehicleData::Metro,
-        "bus" => CategoryOfRealtimeVehicleData::Bus,
-        "rail" => CategoryOfRealtimeVehicleData::Rail,
-        "other" => CategoryOfRealtimeVehicleData::Other,
-        _ => return HttpResponse::NotFound().body("Invalid category"),
+    let category_requested = match CategoryOfRealtimeVehicleData::from_path_str(&category) {
+        Some(category_requested) => category_requested,
+        None => return HttpResponse::NotFound().body("Invalid category"),
     };
 
     let existing_fasthash_of_routes = match existing_fasthash_of_routes {
@@ -10,15 +10,27 @@ use actix_web::web::Query;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Responder;
+use catenary::aspen::lib::ChateauMetadataEtcd;
+use catenary::aspen::lib::TripsSelectionResponse;
+use catenary::aspen_dataset::AspenRawTripInfo;
+use catenary::aspen_dataset::AspenisedStopTimeUpdate;
+use catenary::aspen_dataset::AspenisedTripUpdate;
+use catenary::gtfs_schedule_protobuf::protobuf_to_frequencies;
+use catenary::make_calendar_structure_from_pg_single_chateau;
+use catenary::models::{CompressedTrip, ItineraryPatternMeta, ItineraryPatternRow};
 use catenary::postgres_tools::CatenaryPostgresPool;
-use diesel::dsl::sql;
+use catenary::EtcdConnectionIps;
 use diesel::query_dsl::methods::FilterDsl;
 use diesel::query_dsl::methods::SelectDsl;
-use diesel::sql_types::Bool;
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
 use diesel_async::RunQueryDsl;
+use serde::Serialize;
 use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Deserialize, Clone, Debug)]
@@ -29,22 +41,74 @@ struct NearbyFromStops {
     departure_time: Option<u64>,
 }
 
+#[derive(Serialize, Clone, Debug)]
+pub struct DepartureBoard {
+    pub updated_time_ms: u64,
+    pub departures: Vec<DeparturesPerRoute>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeparturesPerRoute {
+    pub route_id: String,
+    pub chateau: String,
+    pub short_name: Option<String>,
+    pub long_name: Option<String>,
+    pub color: Option<String>,
+    pub text_color: Option<String>,
+    pub route_type: i16,
+    //headsign -> Sorted Vec of Departures
+    pub directions: BTreeMap<String, Vec<DepartureArrivalEvent>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DepartureArrivalEvent {
+    pub stop_id: String,
+    pub stop_lat: Option<f64>,
+    pub stop_lon: Option<f64>,
+    pub stop_code: Option<String>,
+    pub scheduled_departure_time_s: Option<u64>,
+    //optional if the service is the last stop
+    pub scheduled_arrival_time_s: Option<u64>,
+    pub actual_departure_time_s: Option<u64>,
+    pub actual_arrival_time_s: Option<u64>,
+    pub has_realtime_trips: bool,
+    pub trip: AspenRawTripInfo,
+    pub trip_short_name: Option<String>,
+    pub is_frequency: bool,
+    pub is_interpolated: bool,
+    pub cancelled: bool,
+    pub has_realtime_vehicles: bool,
+    pub platform: Option<String>,
+}
+
 #[actix_web::get("/departures_at_stop/")]
 pub async fn departures_at_stop(
     req: HttpRequest,
     query: Query<NearbyFromStops>,
-
     pool: web::Data<Arc<CatenaryPostgresPool>>,
+    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
+    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
 ) -> impl Responder {
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
+
     let conn = &mut conn_pre.unwrap();
 
-    let departure_time = match query.departure_time {
-        Some(departure_time) => departure_time,
-        None => catenary::duration_since_unix_epoch().as_secs(),
+    let departure_time_chrono = match query.departure_time {
+        Some(x) => match catenary::datetime_from_unix_seconds(x) {
+            Some(departure_time_chrono) => departure_time_chrono,
+            None => return HttpResponse::BadRequest().body("Invalid departure time"),
+        },
+        None => chrono::Utc::now(),
     };
 
+    let seek_back = chrono::TimeDelta::new(5400, 0).unwrap();
+
+    let seek_forward = chrono::TimeDelta::new(3600 * 12, 0).unwrap();
+
+    let window_start = (departure_time_chrono - seek_back).timestamp();
+    let window_end = (departure_time_chrono + seek_forward).timestamp();
+
     let stops: diesel::prelude::QueryResult<Vec<catenary::models::Stop>> =
         catenary::schema::gtfs::stops::dsl::stops
             .filter(catenary::schema::gtfs::stops::chateau.eq(query.chateau_id.clone()))
@@ -53,28 +117,87 @@ pub async fn departures_at_stop(
             .load::<catenary::models::Stop>(conn)
             .await;
 
-    let stops = stops.unwrap();
+    let stops = match stops {
+        Ok(stops) => stops,
+        Err(err) => {
+            return HttpResponse::InternalServerError().body(format!("{:#?}", err));
+        }
+    };
 
-    let stop = stops[0].clone();
+    let stop = match stops.first() {
+        Some(stop) => stop.clone(),
+        None => {
+            return HttpResponse::NotFound().body("Stop not found");
+        }
+    };
+
+    // a station has no departures of its own, so include all of the platforms under it
+
+    let mut stop_ids_to_search: BTreeSet<String> = BTreeSet::new();
+
+    stop_ids_to_search.insert(stop.gtfs_id.clone());
+
+    for child_id in stop.children_ids.iter().flatten() {
+        stop_ids_to_search.insert(child_id.clone());
+    }
+
+    let all_stops: Vec<catenary::models::Stop> = match stop_ids_to_search.len() > 1 {
+        true => {
+            let children = catenary::schema::gtfs::stops::dsl::stops
+                .filter(catenary::schema::gtfs::stops::chateau.eq(query.chateau_id.clone()))
+                .filter(catenary::schema::gtfs::stops::gtfs_id.eq_any(&stop_ids_to_search))
+                .select(catenary::models::Stop::as_select())
+                .load::<catenary::models::Stop>(conn)
+                .await;
+
+            match children {
+                Ok(children) => children,
+                Err(err) => {
+                    return HttpResponse::InternalServerError().body(format!("{:#?}", err));
+                }
+            }
+        }
+        false => vec![stop.clone()],
+    };
+
+    let stops_table = all_stops
+        .into_iter()
+        .map(|stop| (stop.gtfs_id.clone(), stop))
+        .collect::<HashMap<String, catenary::models::Stop>>();
 
     // search through itineraries
 
-    let itins: diesel::prelude::QueryResult<Vec<catenary::models::ItineraryPatternRow>> =
+    let itins: diesel::prelude::QueryResult<Vec<ItineraryPatternRow>> =
         catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
             .filter(catenary::schema::gtfs::itinerary_pattern::chateau.eq(query.chateau_id.clone()))
-            .filter(catenary::schema::gtfs::itinerary_pattern::stop_id.eq(query.stop_id.clone()))
-            .select(catenary::models::ItineraryPatternRow::as_select())
-            .load::<catenary::models::ItineraryPatternRow>(conn)
+            .filter(catenary::schema::gtfs::itinerary_pattern::stop_id.eq_any(&stop_ids_to_search))
+            .select(ItineraryPatternRow::as_select())
+            .load::<ItineraryPatternRow>(conn)
             .await;
 
-    let itins = itins.unwrap();
+    let itins = match itins {
+        Ok(itins) => itins,
+        Err(err) => {
+            return HttpResponse::InternalServerError().body(format!("{:#?}", err));
+        }
+    };
 
     let itins_ids = itins
         .iter()
         .map(|x| x.itinerary_pattern_id.clone())
-        .collect::<Vec<String>>();
+        .collect::<BTreeSet<String>>();
+
+    // an itinerary may visit the same stop more than once, such as a loop
+    let mut itins_table: HashMap<String, Vec<ItineraryPatternRow>> = HashMap::new();
 
-    let itin_meta: diesel::prelude::QueryResult<Vec<catenary::models::ItineraryPatternMeta>> =
+    for itin in itins {
+        itins_table
+            .entry(itin.itinerary_pattern_id.clone())
+            .or_default()
+            .push(itin);
+    }
+
+    let itin_meta: diesel::prelude::QueryResult<Vec<ItineraryPatternMeta>> =
         catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta
             .filter(
                 catenary::schema::gtfs::itinerary_pattern_meta::chateau
@@ -82,32 +205,467 @@ pub async fn departures_at_stop(
             )
             .filter(
                 catenary::schema::gtfs::itinerary_pattern_meta::itinerary_pattern_id
-                    .eq_any(itins_ids.clone()),
+                    .eq_any(&itins_ids),
             )
-            .select(catenary::models::ItineraryPatternMeta::as_select())
-            .load::<catenary::models::ItineraryPatternMeta>(conn)
+            .select(ItineraryPatternMeta::as_select())
+            .load::<ItineraryPatternMeta>(conn)
             .await;
 
-    let itin_meta = itin_meta.unwrap();
+    let itin_meta = match itin_meta {
+        Ok(itin_meta) => itin_meta
+            .into_iter()
+            .map(|meta| (meta.itinerary_pattern_id.clone(), meta))
+            .collect::<HashMap<String, ItineraryPatternMeta>>(),
+        Err(err) => {
+            return HttpResponse::InternalServerError().body(format!("{:#?}", err));
+        }
+    };
 
-    let trips: diesel::prelude::QueryResult<Vec<catenary::models::CompressedTrip>> =
+    let trips: diesel::prelude::QueryResult<Vec<CompressedTrip>> =
         catenary::schema::gtfs::trips_compressed::dsl::trips_compressed
             .filter(catenary::schema::gtfs::trips_compressed::chateau.eq(query.chateau_id.clone()))
             .filter(
-                catenary::schema::gtfs::trips_compressed::itinerary_pattern_id
-                    .eq_any(itins_ids.clone()),
+                catenary::schema::gtfs::trips_compressed::itinerary_pattern_id.eq_any(&itins_ids),
             )
-            .select(catenary::models::CompressedTrip::as_select())
-            .load::<catenary::models::CompressedTrip>(conn)
+            .select(CompressedTrip::as_select())
+            .load::<CompressedTrip>(conn)
             .await;
 
-    let trips = trips.unwrap();
+    let trips = match trips {
+        Ok(trips) => trips,
+        Err(err) => {
+            return HttpResponse::InternalServerError().body(format!("{:#?}", err));
+        }
+    };
+
+    let service_ids = trips
+        .iter()
+        .map(|x| x.service_id.to_string())
+        .collect::<BTreeSet<String>>();
+
+    let route_ids = trips
+        .iter()
+        .map(|x| x.route_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let calendar = catenary::schema::gtfs::calendar::dsl::calendar
+        .filter(catenary::schema::gtfs::calendar::dsl::chateau.eq(query.chateau_id.clone()))
+        .filter(catenary::schema::gtfs::calendar::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::Calendar::as_select())
+        .load::<catenary::models::Calendar>(conn)
+        .await;
+
+    let calendar_dates = catenary::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::chateau.eq(query.chateau_id.clone()))
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::CalendarDate::as_select())
+        .load::<catenary::models::CalendarDate>(conn)
+        .await;
+
+    let routes = catenary::schema::gtfs::routes::dsl::routes
+        .filter(catenary::schema::gtfs::routes::dsl::chateau.eq(query.chateau_id.clone()))
+        .filter(catenary::schema::gtfs::routes::dsl::route_id.eq_any(&route_ids))
+        .select(catenary::models::Route::as_select())
+        .load::<catenary::models::Route>(conn)
+        .await;
+
+    let (calendar, calendar_dates, routes) = match (calendar, calendar_dates, routes) {
+        (Ok(calendar), Ok(calendar_dates), Ok(routes)) => (calendar, calendar_dates, routes),
+        _ => {
+            return HttpResponse::InternalServerError()
+                .body("Could not fetch calendar, calendar dates, or routes");
+        }
+    };
+
+    let calendar_structure =
+        make_calendar_structure_from_pg_single_chateau(calendar, calendar_dates);
+
+    let routes_table = routes
+        .into_iter()
+        .map(|route| (route.route_id.clone(), route))
+        .collect::<HashMap<String, catenary::models::Route>>();
 
     //get the start of the trip and the offset for the current stop
 
     //look through time compressed and decompress the itineraries, using timezones and calendar calcs
 
+    // trip_id -> list of (service date, reference start of service date, start of the run, stop visit)
+    let mut valid_trips: HashMap<
+        String,
+        Vec<(
+            chrono::NaiveDate,
+            chrono::DateTime<chrono_tz::Tz>,
+            u32,
+            &CompressedTrip,
+            &ItineraryPatternRow,
+        )>,
+    > = HashMap::new();
+
+    for trip in trips.iter() {
+        let this_itin_list = match itins_table.get(&trip.itinerary_pattern_id) {
+            Some(this_itin_list) => this_itin_list,
+            None => continue,
+        };
+
+        let this_itin_meta = match itin_meta.get(&trip.itinerary_pattern_id) {
+            Some(this_itin_meta) => this_itin_meta,
+            None => continue,
+        };
+
+        let timezone = match chrono_tz::Tz::from_str(this_itin_meta.timezone.as_str()) {
+            Ok(timezone) => timezone,
+            Err(_) => continue,
+        };
+
+        let service = match calendar_structure.get(trip.service_id.as_str()) {
+            Some(service) => service,
+            None => continue,
+        };
+
+        let frequency: Option<catenary::gtfs_schedule_protobuf::GtfsFrequenciesProto> = trip
+            .frequencies
+            .as_ref()
+            .and_then(|data| prost::Message::decode(data.as_ref()).ok());
+
+        let freq_converted = frequency.map(|x| protobuf_to_frequencies(&x));
+
+        let trip_starts = match &freq_converted {
+            Some(frequencies) => frequency_trip_starts(frequencies),
+            None => vec![trip.start_time],
+        };
+
+        for itin_row in this_itin_list {
+            let time_since_start = match itin_row.departure_time_since_start {
+                Some(departure_time_since_start) => departure_time_since_start,
+                None => match itin_row.arrival_time_since_start {
+                    Some(arrival) => arrival,
+                    None => itin_row.interpolated_time_since_start.unwrap_or(0),
+                },
+            };
+
+            let t_to_find_schedule_for = catenary::TripToFindScheduleFor {
+                trip_id: trip.trip_id.clone(),
+                chateau: query.chateau_id.clone(),
+                timezone,
+                time_since_start_of_service_date: chrono::TimeDelta::new(
+                    trip.start_time as i64 + time_since_start as i64,
+                    0,
+                )
+                .unwrap(),
+                frequency: freq_converted.clone(),
+                itinerary_id: trip.itinerary_pattern_id.clone(),
+                direction_id: this_itin_meta
+                    .direction_pattern_id
+                    .clone()
+                    .unwrap_or_default(),
+            };
+
+            let dates = catenary::find_service_ranges(
+                service,
+                &t_to_find_schedule_for,
+                departure_time_chrono,
+                seek_back,
+                seek_forward,
+            );
+
+            for date in dates {
+                for trip_start in trip_starts.iter() {
+                    // a frequency based trip runs all day, so only the runs near the requested time are listed
+                    if freq_converted.is_some() {
+                        let stop_time =
+                            date.1.timestamp() + *trip_start as i64 + time_since_start as i64;
+
+                        if stop_time < window_start || stop_time > window_end {
+                            continue;
+                        }
+                    }
+
+                    valid_trips.entry(trip.trip_id.clone()).or_default().push((
+                        date.0,
+                        date.1,
+                        *trip_start,
+                        trip,
+                        itin_row,
+                    ));
+                }
+            }
+        }
+    }
+
     //look through gtfs-rt times and hydrate the itineraries
 
-    HttpResponse::Ok().body("Hello!")
+    let gtfs_trips_aspenised = fetch_trips_from_aspen(
+        &query.chateau_id,
+        valid_trips.keys().cloned().collect::<Vec<String>>(),
+        etcd_connection_ips.as_ref(),
+        etcd_connection_options.as_ref(),
+    )
+    .await;
+
+    let mut departures_per_route: BTreeMap<String, DeparturesPerRoute> = BTreeMap::new();
+
+    for (trip_id, trip_grouping) in valid_trips.iter() {
+        for (service_date, reference_start, trip_start, trip, itin_row) in trip_grouping {
+            let route = match routes_table.get(&trip.route_id) {
+                Some(route) => route,
+                None => continue,
+            };
+
+            let this_itin_meta = itin_meta.get(&trip.itinerary_pattern_id).unwrap();
+
+            // runs of a frequency based trip share the trip id, and are told apart by their start time
+            let run_start_time = match trip.frequencies.is_some() {
+                true => Some(gtfs_time_string(*trip_start)),
+                false => None,
+            };
+
+            let trip_update = gtfs_trips_aspenised.as_ref().and_then(|x| {
+                find_trip_update_for_service_date(
+                    x,
+                    trip_id,
+                    service_date,
+                    run_start_time.as_deref(),
+                )
+            });
+
+            let stop_time_update: Option<&AspenisedStopTimeUpdate> =
+                trip_update.and_then(|trip_update| {
+                    trip_update.stop_time_update.iter().find(|x| {
+                        x.stop_id.as_ref().map(|compare| compare.as_str())
+                            == Some(itin_row.stop_id.as_str())
+                    })
+                });
+
+            let start_of_trip = reference_start.timestamp() as u64 + *trip_start as u64;
+
+            let scheduled_arrival_time_s = itin_row
+                .arrival_time_since_start
+                .or(itin_row.interpolated_time_since_start)
+                .map(|x| start_of_trip + x as u64);
+
+            let scheduled_departure_time_s = itin_row
+                .departure_time_since_start
+                .or(itin_row.arrival_time_since_start)
+                .or(itin_row.interpolated_time_since_start)
+                .map(|x| start_of_trip + x as u64);
+
+            let stop_for_event = stops_table.get(&itin_row.stop_id);
+
+            let event = DepartureArrivalEvent {
+                stop_id: itin_row.stop_id.clone(),
+                stop_lat: stop_for_event
+                    .and_then(|s| s.point.as_ref())
+                    .map(|point| point.y),
+                stop_lon: stop_for_event
+                    .and_then(|s| s.point.as_ref())
+                    .map(|point| point.x),
+                stop_code: stop_for_event.and_then(|s| s.code.clone()),
+                scheduled_departure_time_s,
+                scheduled_arrival_time_s,
+                actual_departure_time_s: stop_time_update
+                    .and_then(|x| x.departure.as_ref())
+                    .and_then(|x| x.time)
+                    .map(|x| x as u64),
+                actual_arrival_time_s: stop_time_update
+                    .and_then(|x| x.arrival.as_ref())
+                    .and_then(|x| x.time)
+                    .map(|x| x as u64),
+                has_realtime_trips: trip_update.is_some(),
+                trip: match trip_update {
+                    Some(trip_update) => trip_update.trip.clone(),
+                    None => AspenRawTripInfo {
+                        trip_id: Some(trip_id.clone()),
+                        route_id: Some(trip.route_id.clone()),
+                        direction_id: trip.direction_id.map(|x| x as u32),
+                        start_time: run_start_time.clone(),
+                        start_date: Some(service_date.format("%Y%m%d").to_string()),
+                        schedule_relationship: None,
+                        modified_trip: None,
+                    },
+                },
+                trip_short_name: trip.trip_short_name.as_ref().map(|x| x.to_string()),
+                is_frequency: trip.frequencies.is_some(),
+                is_interpolated: itin_row.interpolated_time_since_start.is_some(),
+                cancelled: trip_update
+                    .map(|x| x.trip.schedule_relationship == Some(3))
+                    .unwrap_or(false),
+                has_realtime_vehicles: trip_update.map(|x| x.vehicle.is_some()).unwrap_or(false),
+                platform: match stop_time_update.and_then(|x| x.platform_string.clone()) {
+                    Some(platform) => Some(platform),
+                    None => stop_for_event.and_then(|s| s.platform_code.clone()),
+                },
+            };
+
+            let route_group = departures_per_route
+                .entry(route.route_id.clone())
+                .or_insert_with(|| DeparturesPerRoute {
+                    route_id: route.route_id.clone(),
+                    chateau: query.chateau_id.clone(),
+                    short_name: route.short_name.clone(),
+                    long_name: route.long_name.clone(),
+                    color: route.color.clone(),
+                    text_color: route.text_color.clone(),
+                    route_type: route.route_type,
+                    directions: BTreeMap::new(),
+                });
+
+            let headsign = match trip_update.and_then(|x| x.trip_headsign.as_ref()) {
+                Some(headsign) => headsign.to_string(),
+                None => this_itin_meta.trip_headsign.clone().unwrap_or_default(),
+            };
+
+            route_group
+                .directions
+                .entry(headsign)
+                .or_default()
+                .push(event);
+        }
+    }
+
+    let mut departures = departures_per_route.into_values().collect::<Vec<_>>();
+
+    for route_group in departures.iter_mut() {
+        for events in route_group.directions.values_mut() {
+            events.sort_by_key(|x| {
+                x.scheduled_departure_time_s
+                    .or(x.scheduled_arrival_time_s)
+                    .unwrap_or(0)
+            });
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-cache"))
+        .json(DepartureBoard {
+            updated_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
+            departures,
+        })
+}
+
+pub async fn fetch_trips_from_aspen(
+    chateau_id: &str,
+    trip_ids: Vec<String>,
+    etcd_connection_ips: &Arc<EtcdConnectionIps>,
+    etcd_connection_options: &Arc<Option<etcd_client::ConnectOptions>>,
+) -> Option<TripsSelectionResponse> {
+    let mut etcd = etcd_client::Client::connect(
+        etcd_connection_ips.ip_addresses.as_slice(),
+        etcd_connection_options.as_ref().to_owned(),
+    )
+    .await
+    .ok()?;
+
+    let etcd_data = etcd
+        .get(
+            format!("/aspen_assigned_chateaus/{}", chateau_id).as_str(),
+            None,
+        )
+        .await
+        .ok()?;
+
+    let chateau_metadata =
+        bincode::deserialize::<ChateauMetadataEtcd>(etcd_data.kvs().first()?.value()).ok()?;
+
+    let aspen_client = catenary::aspen::lib::spawn_aspen_client_from_ip(&chateau_metadata.socket)
+        .await
+        .ok()?;
+
+    aspen_client
+        .get_all_trips_with_ids(tarpc::context::current(), chateau_id.to_string(), trip_ids)
+        .await
+        .ok()
+        .flatten()
+}
+
+fn find_trip_update_for_service_date<'a>(
+    gtfs_trip_aspenised: &'a TripsSelectionResponse,
+    trip_id: &str,
+    service_date: &chrono::NaiveDate,
+    start_time: Option<&str>,
+) -> Option<&'a AspenisedTripUpdate> {
+    let trip_update_ids = gtfs_trip_aspenised
+        .trip_id_to_trip_update_ids
+        .get(trip_id)?;
+
+    let service_date_str = service_date.format("%Y%m%d").to_string();
+
+    trip_update_ids
+        .iter()
+        .filter_map(|x| gtfs_trip_aspenised.trip_updates.get(x))
+        .filter(
+            |trip_update| match (&trip_update.trip.start_time, start_time) {
+                (Some(update_start_time), Some(start_time)) => update_start_time == start_time,
+                _ => true,
+            },
+        )
+        .find(|trip_update| match &trip_update.trip.start_date {
+            Some(start_date) => *start_date == service_date_str,
+            None => true,
+        })
+}
+
+/// Start of every run of a frequency based trip, in seconds since the start of the service date
+fn frequency_trip_starts(frequencies: &[gtfs_structures::Frequency]) -> Vec<u32> {
+    let mut starts = frequencies
+        .iter()
+        .filter(|frequency| frequency.headway_secs > 0)
+        .flat_map(|frequency| {
+            (frequency.start_time..frequency.end_time).step_by(frequency.headway_secs as usize)
+        })
+        .collect::<Vec<u32>>();
+
+    starts.sort_unstable();
+    starts.dedup();
+
+    starts
+}
+
+/// Seconds since the start of the service date as a GTFS time, which may go past 24:00:00
+fn gtfs_time_string(seconds: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frequency(start_time: u32, end_time: u32, headway_secs: u32) -> gtfs_structures::Frequency {
+        gtfs_structures::Frequency {
+            start_time,
+            end_time,
+            headway_secs,
+            exact_times: None,
+        }
+    }
+
+    #[test]
+    fn frequencies_expand_into_runs_before_their_end_time() {
+        let starts = frequency_trip_starts(&[
+            frequency(6 * 3600, 6 * 3600 + 1800, 600),
+            frequency(6 * 3600 + 1800, 7 * 3600, 900),
+            frequency(8 * 3600, 9 * 3600, 0),
+        ]);
+
+        assert_eq!(
+            starts,
+            vec![
+                6 * 3600,
+                6 * 3600 + 600,
+                6 * 3600 + 1200,
+                6 * 3600 + 1800,
+                6 * 3600 + 2700,
+            ]
+        );
+    }
+
+    #[test]
+    fn run_start_times_keep_hours_past_midnight() {
+        assert_eq!(gtfs_time_string(6 * 3600 + 5 * 60 + 7), "06:05:07");
+        assert_eq!(gtfs_time_string(25 * 3600 + 30 * 60), "25:30:00");
+    }
 }
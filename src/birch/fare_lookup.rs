@@ -0,0 +1,343 @@
+// Copyright
+// Catenary Transit Initiatives
+// Fare lookup endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Prices a leg or a sequence of legs from the fares of the production attempt of each feed.
+// Consecutive legs in the same feed are priced together so their transfer rules apply.
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::fares::{FareLeg, FarePrice, FeedFares};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::Arc;
+
+const MAX_LEGS: usize = 16;
+
+#[derive(Deserialize, Clone, Debug)]
+struct FareLegQuery {
+    onestop_feed_id: String,
+    route_id: String,
+    from_stop_id: String,
+    to_stop_id: String,
+    /// Unix seconds
+    departure_time: Option<u64>,
+    /// Unix seconds
+    arrival_time: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct FareQuery {
+    legs: Vec<FareLegQuery>,
+    /// Prefer products sold on this fare media, such as a contactless card
+    fare_media_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedFarePrice {
+    pub onestop_feed_id: String,
+    /// Index of the first leg of this feed in the request
+    pub first_leg_index: usize,
+    pub leg_count: usize,
+    /// None when the feed publishes no fares
+    pub price: Option<FarePrice>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FareResponse {
+    pub currency: Option<String>,
+    /// None when any leg could not be priced
+    pub total: Option<f64>,
+    pub fares: Vec<FeedFarePrice>,
+}
+
+struct FeedFareData {
+    fares: FeedFares,
+    /// stop id -> (parent station, zone id)
+    stops: HashMap<String, (Option<String>, Option<String>)>,
+}
+
+async fn load_feed_fares(
+    conn: &mut AsyncPgConnection,
+    feed_id: &str,
+    attempt_id: &str,
+    stop_ids: &[String],
+) -> Result<FeedFareData, Box<dyn Error + Send + Sync>> {
+    use catenary::schema::gtfs::fare_attributes::dsl as fare_attributes;
+    use catenary::schema::gtfs::fare_leg_rules::dsl as fare_leg_rules;
+    use catenary::schema::gtfs::fare_products::dsl as fare_products;
+    use catenary::schema::gtfs::fare_rules::dsl as fare_rules;
+    use catenary::schema::gtfs::fare_transfer_rules::dsl as fare_transfer_rules;
+    use catenary::schema::gtfs::networks::dsl as networks;
+    use catenary::schema::gtfs::stop_areas::dsl as stop_areas;
+    use catenary::schema::gtfs::stops::dsl as stops;
+
+    let fare_products = fare_products::fare_products
+        .filter(fare_products::onestop_feed_id.eq(feed_id))
+        .filter(fare_products::attempt_id.eq(attempt_id))
+        .select(catenary::models::FareProduct::as_select())
+        .load::<catenary::models::FareProduct>(conn)
+        .await?;
+
+    let fare_leg_rules = fare_leg_rules::fare_leg_rules
+        .filter(fare_leg_rules::onestop_feed_id.eq(feed_id))
+        .filter(fare_leg_rules::attempt_id.eq(attempt_id))
+        .order(fare_leg_rules::row_index)
+        .select(catenary::models::FareLegRule::as_select())
+        .load::<catenary::models::FareLegRule>(conn)
+        .await?;
+
+    let fare_transfer_rules = fare_transfer_rules::fare_transfer_rules
+        .filter(fare_transfer_rules::onestop_feed_id.eq(feed_id))
+        .filter(fare_transfer_rules::attempt_id.eq(attempt_id))
+        .order(fare_transfer_rules::row_index)
+        .select(catenary::models::FareTransferRule::as_select())
+        .load::<catenary::models::FareTransferRule>(conn)
+        .await?;
+
+    let fare_attributes = fare_attributes::fare_attributes
+        .filter(fare_attributes::onestop_feed_id.eq(feed_id))
+        .filter(fare_attributes::attempt_id.eq(attempt_id))
+        .select(catenary::models::FareAttribute::as_select())
+        .load::<catenary::models::FareAttribute>(conn)
+        .await?;
+
+    let fare_rules = fare_rules::fare_rules
+        .filter(fare_rules::onestop_feed_id.eq(feed_id))
+        .filter(fare_rules::attempt_id.eq(attempt_id))
+        .select(catenary::models::FareRule::as_select())
+        .load::<catenary::models::FareRule>(conn)
+        .await?;
+
+    let networks = networks::networks
+        .filter(networks::onestop_feed_id.eq(feed_id))
+        .filter(networks::attempt_id.eq(attempt_id))
+        .select(catenary::models::Network::as_select())
+        .load::<catenary::models::Network>(conn)
+        .await?;
+
+    let stop_rows = stops::stops
+        .filter(stops::onestop_feed_id.eq(feed_id))
+        .filter(stops::attempt_id.eq(attempt_id))
+        .filter(stops::gtfs_id.eq_any(stop_ids))
+        .select((stops::gtfs_id, stops::parent_station, stops::zone_id))
+        .load::<(String, Option<String>, Option<String>)>(conn)
+        .await?;
+
+    let stops = stop_rows
+        .into_iter()
+        .map(|(stop_id, parent_station, zone_id)| (stop_id, (parent_station, zone_id)))
+        .collect::<HashMap<String, (Option<String>, Option<String>)>>();
+
+    // areas may list the platform or its station
+    let area_stop_ids = stop_ids
+        .iter()
+        .cloned()
+        .chain(
+            stops
+                .values()
+                .filter_map(|(parent_station, _)| parent_station.clone()),
+        )
+        .collect::<BTreeSet<String>>();
+
+    let stop_areas = stop_areas::stop_areas
+        .filter(stop_areas::onestop_feed_id.eq(feed_id))
+        .filter(stop_areas::attempt_id.eq(attempt_id))
+        .filter(stop_areas::stop_id.eq_any(area_stop_ids))
+        .select(catenary::models::StopArea::as_select())
+        .load::<catenary::models::StopArea>(conn)
+        .await?;
+
+    Ok(FeedFareData {
+        fares: FeedFares::new(
+            fare_products,
+            fare_leg_rules,
+            fare_transfer_rules,
+            fare_attributes,
+            fare_rules,
+            stop_areas,
+            networks,
+        ),
+        stops,
+    })
+}
+
+fn to_fare_leg(leg: &FareLegQuery, feed_fare_data: &FeedFareData) -> FareLeg {
+    let with_parent = |stop_id: &String| {
+        let mut stop_ids = vec![stop_id.clone()];
+
+        if let Some((Some(parent_station), _)) = feed_fare_data.stops.get(stop_id) {
+            stop_ids.push(parent_station.clone());
+        }
+
+        stop_ids
+    };
+
+    let zone_id = |stop_id: &String| {
+        feed_fare_data
+            .stops
+            .get(stop_id)
+            .and_then(|(_, zone_id)| zone_id.clone())
+    };
+
+    FareLeg {
+        route_id: leg.route_id.clone(),
+        from_stop_ids: with_parent(&leg.from_stop_id),
+        to_stop_ids: with_parent(&leg.to_stop_id),
+        from_zone_id: zone_id(&leg.from_stop_id),
+        to_zone_id: zone_id(&leg.to_stop_id),
+        departure_time: leg.departure_time,
+        arrival_time: leg.arrival_time,
+    }
+}
+
+#[actix_web::post("/fare")]
+pub async fn fare(
+    query: web::Json<FareQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    use catenary::schema::gtfs::ingested_static::dsl as ingested_static;
+
+    let query = query.into_inner();
+
+    if query.legs.is_empty() || query.legs.len() > MAX_LEGS {
+        return HttpResponse::BadRequest()
+            .body(format!("between 1 and {} legs are required", MAX_LEGS));
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    let feed_ids = query
+        .legs
+        .iter()
+        .map(|leg| leg.onestop_feed_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let production_attempts = ingested_static::ingested_static
+        .filter(ingested_static::onestop_feed_id.eq_any(&feed_ids))
+        .filter(ingested_static::production.eq(true))
+        .filter(ingested_static::deleted.eq(false))
+        .select(catenary::models::IngestedStatic::as_select())
+        .load::<catenary::models::IngestedStatic>(&mut conn)
+        .await;
+
+    let production_attempts = match production_attempts {
+        Ok(production_attempts) => production_attempts,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read ingested feeds");
+        }
+    };
+
+    // the newest attempt in production carries the current fares
+    let mut feed_id_to_attempt: HashMap<String, (i64, String)> = HashMap::new();
+
+    for attempt in production_attempts {
+        let newest = feed_id_to_attempt
+            .get(&attempt.onestop_feed_id)
+            .map(|(ingest_start, _)| attempt.ingest_start_unix_time_ms > *ingest_start)
+            .unwrap_or(true);
+
+        if newest {
+            feed_id_to_attempt.insert(
+                attempt.onestop_feed_id,
+                (attempt.ingest_start_unix_time_ms, attempt.attempt_id),
+            );
+        }
+    }
+
+    let mut feed_fare_data: HashMap<String, FeedFareData> = HashMap::new();
+
+    for feed_id in feed_ids.iter() {
+        let Some((_, attempt_id)) = feed_id_to_attempt.get(feed_id) else {
+            return HttpResponse::NotFound().body(format!("{} is not in production", feed_id));
+        };
+
+        let stop_ids = query
+            .legs
+            .iter()
+            .filter(|leg| leg.onestop_feed_id == *feed_id)
+            .flat_map(|leg| [leg.from_stop_id.clone(), leg.to_stop_id.clone()])
+            .collect::<Vec<String>>();
+
+        match load_feed_fares(&mut conn, feed_id, attempt_id, &stop_ids).await {
+            Ok(data) => {
+                feed_fare_data.insert(feed_id.clone(), data);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                return HttpResponse::InternalServerError().body("Could not read fares");
+            }
+        }
+    }
+
+    let mut fares: Vec<FeedFarePrice> = vec![];
+    let mut first_leg_index = 0;
+
+    for legs in query
+        .legs
+        .chunk_by(|a, b| a.onestop_feed_id == b.onestop_feed_id)
+    {
+        let feed_id = &legs[0].onestop_feed_id;
+        let data = &feed_fare_data[feed_id];
+
+        let fare_legs = legs
+            .iter()
+            .map(|leg| to_fare_leg(leg, data))
+            .collect::<Vec<FareLeg>>();
+
+        fares.push(FeedFarePrice {
+            onestop_feed_id: feed_id.clone(),
+            first_leg_index,
+            leg_count: legs.len(),
+            price: data
+                .fares
+                .price_legs(&fare_legs, query.fare_media_id.as_deref()),
+        });
+
+        first_leg_index += legs.len();
+    }
+
+    let currencies = fares
+        .iter()
+        .filter_map(|fare| fare.price.as_ref())
+        .filter_map(|price| price.currency.clone())
+        .collect::<BTreeSet<String>>();
+
+    let currency = match currencies.len() {
+        1 => currencies.into_iter().next(),
+        _ => None,
+    };
+
+    let total = match currency.is_some() {
+        true => fares
+            .iter()
+            .map(|fare| fare.price.as_ref().and_then(|price| price.total))
+            .sum::<Option<f64>>(),
+        false => None,
+    };
+
+    HttpResponse::Ok().json(FareResponse {
+        currency,
+        total,
+        fares,
+    })
+}
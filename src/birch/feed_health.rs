@@ -0,0 +1,287 @@
+// Copyright
+// Catenary Transit Initiatives
+// Feed health endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Health and freshness of every realtime feed, from the last fetches of Alpenrose and ingestions of Aspen.
+// A feed is stale when its newest data is older than stale_after_secs,
+// and failing when its last few requests all failed.
+
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::feed_health::{FetchRecord, IngestRecord};
+use catenary::models::RealtimeFeedHealthRow;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+const DEFAULT_STALE_AFTER_SECS: u64 = 300;
+
+/// A feed is failing when this many of its latest requests failed in a row
+const FAILING_AFTER_FETCHES: usize = 3;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FeedHealthFilter {
+    Stale,
+    Failing,
+    /// Stale or failing
+    Unhealthy,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct FeedHealthQuery {
+    chateau: Option<String>,
+    realtime_feed_id: Option<String>,
+    status: Option<FeedHealthFilter>,
+    stale_after_secs: Option<u64>,
+    /// Include the recent fetches and ingestions themselves
+    details: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedHealthSummary {
+    pub realtime_feed_id: String,
+    pub chateau: String,
+    pub stale: bool,
+    pub failing: bool,
+    pub last_success_ms: Option<i64>,
+    pub last_fetch_ms: Option<u64>,
+    /// Share of the recent requests which succeeded
+    pub success_ratio: Option<f64>,
+    pub average_latency_ms: Option<f64>,
+    /// feed type -> status of the latest request
+    pub last_http_status: BTreeMap<String, Option<u16>>,
+    /// feed type -> size of the latest response
+    pub last_bytes: BTreeMap<String, u64>,
+    /// feed type -> entities in the latest ingestion
+    pub entity_counts: BTreeMap<String, usize>,
+    /// Age of the newest header timestamp across feed types
+    pub header_age_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_fetches: Option<Vec<FetchRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_ingests: Option<Vec<IngestRecord>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedHealthResponse {
+    pub feed_count: usize,
+    pub stale_count: usize,
+    pub failing_count: usize,
+    pub feeds: Vec<FeedHealthSummary>,
+}
+
+fn summarise(
+    realtime_feed_id: String,
+    chateau: String,
+    row: Option<RealtimeFeedHealthRow>,
+    now_ms: u64,
+    stale_after_secs: u64,
+    details: bool,
+) -> FeedHealthSummary {
+    let (last_success_ms, fetches, ingests) = match row {
+        Some(row) => (
+            row.last_success_ms,
+            row.recent_fetches
+                .and_then(|fetches| serde_json::from_value::<Vec<FetchRecord>>(fetches).ok())
+                .unwrap_or_default(),
+            row.recent_ingests
+                .and_then(|ingests| serde_json::from_value::<Vec<IngestRecord>>(ingests).ok())
+                .unwrap_or_default(),
+        ),
+        None => (None, vec![], vec![]),
+    };
+
+    let mut last_http_status = BTreeMap::new();
+    let mut last_bytes = BTreeMap::new();
+
+    // oldest first, so later fetches overwrite earlier ones
+    for fetch in fetches.iter() {
+        last_http_status.insert(fetch.feed_type.clone(), fetch.http_status);
+
+        if let Some(bytes) = fetch.bytes {
+            last_bytes.insert(fetch.feed_type.clone(), bytes);
+        }
+    }
+
+    let mut entity_counts = BTreeMap::new();
+    let mut newest_header_timestamp: Option<u64> = None;
+
+    for ingest in ingests.iter() {
+        entity_counts.insert(ingest.feed_type.clone(), ingest.entity_count);
+        newest_header_timestamp = newest_header_timestamp.max(ingest.header_timestamp);
+    }
+
+    let header_age_secs =
+        newest_header_timestamp.map(|timestamp| (now_ms / 1000).saturating_sub(timestamp));
+
+    let success_ratio = match fetches.is_empty() {
+        true => None,
+        false => Some(
+            fetches.iter().filter(|fetch| fetch.is_success()).count() as f64 / fetches.len() as f64,
+        ),
+    };
+
+    let average_latency_ms = match fetches.is_empty() {
+        true => None,
+        false => Some(
+            fetches
+                .iter()
+                .map(|fetch| fetch.latency_ms as f64)
+                .sum::<f64>()
+                / fetches.len() as f64,
+        ),
+    };
+
+    let failing = fetches.is_empty()
+        || fetches
+            .iter()
+            .rev()
+            .take(FAILING_AFTER_FETCHES)
+            .all(|fetch| !fetch.is_success());
+
+    let stale_after_ms = stale_after_secs * 1000;
+
+    let stale = match header_age_secs {
+        Some(header_age_secs) => header_age_secs > stale_after_secs,
+        // feeds without a header timestamp are judged by their last successful request
+        None => last_success_ms
+            .map(|last_success_ms| now_ms.saturating_sub(last_success_ms as u64) > stale_after_ms)
+            .unwrap_or(true),
+    };
+
+    FeedHealthSummary {
+        realtime_feed_id,
+        chateau,
+        stale,
+        failing,
+        last_success_ms,
+        last_fetch_ms: fetches.last().map(|fetch| fetch.time_ms),
+        success_ratio,
+        average_latency_ms,
+        last_http_status,
+        last_bytes,
+        entity_counts,
+        header_age_secs,
+        recent_fetches: details.then_some(fetches),
+        recent_ingests: details.then_some(ingests),
+    }
+}
+
+#[actix_web::get("/feed_health")]
+pub async fn feed_health(
+    query: Query<FeedHealthQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    use catenary::schema::gtfs::realtime_feed_health::dsl as realtime_feed_health;
+    use catenary::schema::gtfs::realtime_feeds::dsl as realtime_feeds;
+
+    let query = query.into_inner();
+
+    let stale_after_secs = query.stale_after_secs.unwrap_or(DEFAULT_STALE_AFTER_SECS);
+    let details = query.details.unwrap_or(false);
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    let mut feeds_query = realtime_feeds::realtime_feeds
+        .select((realtime_feeds::onestop_feed_id, realtime_feeds::chateau))
+        .into_boxed();
+
+    if let Some(chateau) = &query.chateau {
+        feeds_query = feeds_query.filter(realtime_feeds::chateau.eq(chateau));
+    }
+
+    if let Some(realtime_feed_id) = &query.realtime_feed_id {
+        feeds_query = feeds_query.filter(realtime_feeds::onestop_feed_id.eq(realtime_feed_id));
+    }
+
+    let feeds = match feeds_query.load::<(String, String)>(&mut conn).await {
+        Ok(feeds) => feeds,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read realtime feeds");
+        }
+    };
+
+    let health_rows = realtime_feed_health::realtime_feed_health
+        .filter(
+            realtime_feed_health::realtime_feed_id
+                .eq_any(feeds.iter().map(|(realtime_feed_id, _)| realtime_feed_id)),
+        )
+        .select(RealtimeFeedHealthRow::as_select())
+        .load::<RealtimeFeedHealthRow>(&mut conn)
+        .await;
+
+    let mut health_rows = match health_rows {
+        Ok(health_rows) => health_rows
+            .into_iter()
+            .map(|row| (row.realtime_feed_id.clone(), row))
+            .collect::<HashMap<String, RealtimeFeedHealthRow>>(),
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read feed health");
+        }
+    };
+
+    let now_ms = catenary::duration_since_unix_epoch().as_millis() as u64;
+
+    let mut summaries = feeds
+        .into_iter()
+        .map(|(realtime_feed_id, chateau)| {
+            let row = health_rows.remove(&realtime_feed_id);
+
+            summarise(
+                realtime_feed_id,
+                chateau,
+                row,
+                now_ms,
+                stale_after_secs,
+                details,
+            )
+        })
+        .collect::<Vec<FeedHealthSummary>>();
+
+    let stale_count = summaries.iter().filter(|summary| summary.stale).count();
+    let failing_count = summaries.iter().filter(|summary| summary.failing).count();
+    let feed_count = summaries.len();
+
+    if let Some(status) = query.status {
+        summaries.retain(|summary| match status {
+            FeedHealthFilter::Stale => summary.stale,
+            FeedHealthFilter::Failing => summary.failing,
+            FeedHealthFilter::Unhealthy => summary.stale || summary.failing,
+        });
+    }
+
+    summaries.sort_by(|a, b| {
+        a.chateau
+            .cmp(&b.chateau)
+            .then_with(|| a.realtime_feed_id.cmp(&b.realtime_feed_id))
+    });
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=30"))
+        .json(FeedHealthResponse {
+            feed_count,
+            stale_count,
+            failing_count,
+            feeds: summaries,
+        })
+}
@@ -4,6 +4,7 @@ use catenary::aspen_dataset::AspenStopTimeEvent;
 use catenary::aspen_dataset::AspenisedAlert;
 use catenary::aspen_dataset::AspenisedVehicleDescriptor;
 use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::aspen_dataset::StopTimeRefresh;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::schema::gtfs::itinerary_pattern as itinerary_pattern_pg_schema;
 use catenary::schema::gtfs::itinerary_pattern_meta as itinerary_pattern_meta_pg_schema;
@@ -220,17 +221,6 @@ struct GtfsRtRefreshData {
     stoptimes: Vec<StopTimeRefresh>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-struct StopTimeRefresh {
-    pub stop_id: Option<compact_str::CompactString>,
-    pub rt_arrival: Option<AspenStopTimeEvent>,
-    pub rt_departure: Option<AspenStopTimeEvent>,
-    pub schedule_relationship: Option<i32>,
-    pub gtfs_stop_sequence: Option<u16>,
-    pub rt_platform_string: Option<String>,
-    pub departure_occupancy_status: Option<i32>,
-}
-
 #[derive(Deserialize, Serialize)]
 struct TripIntroductionInformation {
     pub stoptimes: Vec<StopTimeIntroduction>,
@@ -380,18 +370,7 @@ pub async fn get_trip_rt_update(
                         let stop_data: Vec<StopTimeRefresh> = rt_trip_update
                             .stop_time_update
                             .iter()
-                            .map(|stop_time_update| StopTimeRefresh {
-                                stop_id: stop_time_update.stop_id.clone(),
-                                rt_arrival: stop_time_update.arrival.clone(),
-                                rt_departure: stop_time_update.departure.clone(),
-                                schedule_relationship: stop_time_update.schedule_relationship,
-                                gtfs_stop_sequence: stop_time_update
-                                    .stop_sequence
-                                    .map(|x| x as u16),
-                                rt_platform_string: stop_time_update.platform_string.clone(),
-                                departure_occupancy_status: stop_time_update
-                                    .departure_occupancy_status,
-                            })
+                            .map(StopTimeRefresh::from)
                             .collect();
 
                         HttpResponse::Ok().json(ResponseForGtfsRtRefresh {
@@ -0,0 +1,158 @@
+// Copyright
+// Catenary Transit Initiatives
+// GTFS schedule errors endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Schedule validation results recorded by Maple for each ingestion attempt.
+// By default only the latest attempt of each feed is returned.
+
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::models::GtfsError;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Deserialize, Clone, Debug)]
+struct GtfsErrorsQuery {
+    onestop_feed_id: Option<String>,
+    chateau: Option<String>,
+    attempt_id: Option<String>,
+    /// Return every stored attempt instead of the latest one
+    all_attempts: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GtfsErrorNotice {
+    pub rule: String,
+    pub severity: String,
+    pub description: String,
+    pub count: i32,
+    pub sample_ids: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AttemptValidation {
+    pub attempt_id: String,
+    pub checked_unix_time_ms: i64,
+    pub file_hash: Option<String>,
+    pub chateau: String,
+    pub error_count: i64,
+    pub warning_count: i64,
+    pub notices: Vec<GtfsErrorNotice>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GtfsErrorsResponse {
+    /// onestop feed id -> attempts, newest first
+    pub feeds: BTreeMap<String, Vec<AttemptValidation>>,
+}
+
+#[actix_web::get("/gtfs_errors")]
+pub async fn gtfs_errors(
+    query: Query<GtfsErrorsQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    use catenary::schema::gtfs::gtfs_errors::dsl as gtfs_errors;
+
+    let query = query.into_inner();
+
+    if query.onestop_feed_id.is_none() && query.chateau.is_none() {
+        return HttpResponse::BadRequest().body("onestop_feed_id or chateau is required");
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    let mut errors_query = gtfs_errors::gtfs_errors
+        .order(gtfs_errors::checked_unix_time_ms.desc())
+        .select(GtfsError::as_select())
+        .into_boxed();
+
+    if let Some(onestop_feed_id) = &query.onestop_feed_id {
+        errors_query = errors_query.filter(gtfs_errors::onestop_feed_id.eq(onestop_feed_id));
+    }
+
+    if let Some(chateau) = &query.chateau {
+        errors_query = errors_query.filter(gtfs_errors::chateau.eq(chateau));
+    }
+
+    if let Some(attempt_id) = &query.attempt_id {
+        errors_query = errors_query.filter(gtfs_errors::attempt_id.eq(attempt_id));
+    }
+
+    let rows = match errors_query.load::<GtfsError>(&mut conn).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read gtfs errors");
+        }
+    };
+
+    let all_attempts = query.all_attempts.unwrap_or(false);
+
+    let mut feeds: BTreeMap<String, Vec<AttemptValidation>> = BTreeMap::new();
+
+    // rows are newest first, so the first attempt seen for a feed is the latest
+    for row in rows {
+        let attempts = feeds.entry(row.onestop_feed_id.clone()).or_default();
+
+        let attempt_index = match attempts
+            .iter()
+            .position(|attempt| attempt.attempt_id == row.attempt_id)
+        {
+            Some(attempt_index) => attempt_index,
+            None => {
+                if !all_attempts && !attempts.is_empty() {
+                    continue;
+                }
+
+                attempts.push(AttemptValidation {
+                    attempt_id: row.attempt_id.clone(),
+                    checked_unix_time_ms: row.checked_unix_time_ms,
+                    file_hash: row.file_hash.clone(),
+                    chateau: row.chateau.clone(),
+                    error_count: 0,
+                    warning_count: 0,
+                    notices: vec![],
+                });
+
+                attempts.len() - 1
+            }
+        };
+
+        let attempt = &mut attempts[attempt_index];
+
+        match row.severity.as_str() {
+            "error" => attempt.error_count += row.count as i64,
+            _ => attempt.warning_count += row.count as i64,
+        }
+
+        attempt.notices.push(GtfsErrorNotice {
+            rule: row.rule,
+            severity: row.severity,
+            description: row.error,
+            count: row.count,
+            sample_ids: serde_json::from_value::<Vec<String>>(row.sample_ids).unwrap_or_default(),
+        });
+    }
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=300"))
+        .json(GtfsErrorsResponse { feeds })
+}
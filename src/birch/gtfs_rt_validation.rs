@@ -0,0 +1,175 @@
+// Copyright
+// Catenary Transit Initiatives
+// GTFS-realtime validation endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Validation results of realtime feeds, recorded by Aspen every few minutes.
+// For each feed type, returns the latest run, the error and warning counts over time,
+// and how often each rule was broken in the requested window.
+
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::models::GtfsRtValidationRun;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::validate_gtfs_rt::RtNotice;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DEFAULT_HOURS: u64 = 24;
+const MAX_HOURS: u64 = 30 * 24;
+
+#[derive(Deserialize, Clone, Debug)]
+struct GtfsRtValidationQuery {
+    realtime_feed_id: Option<String>,
+    chateau: Option<String>,
+    hours: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ValidationRunPoint {
+    pub checked_unix_time_ms: i64,
+    pub error_count: i32,
+    pub warning_count: i32,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RuleSummary {
+    /// Validation runs in which the rule was broken
+    pub runs: u32,
+    /// Entities breaking the rule, summed over all runs
+    pub total_count: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LatestValidationRun {
+    pub checked_unix_time_ms: i64,
+    pub header_timestamp: Option<i64>,
+    pub entity_count: i32,
+    pub notices: Vec<RtNotice>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedTypeValidation {
+    pub latest: LatestValidationRun,
+    pub history: Vec<ValidationRunPoint>,
+    pub rules: BTreeMap<String, RuleSummary>,
+    pub run_count: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GtfsRtValidationResponse {
+    /// realtime feed id -> feed type (vehicles, trips or alerts) -> results
+    pub feeds: BTreeMap<String, BTreeMap<String, FeedTypeValidation>>,
+}
+
+#[actix_web::get("/gtfs_rt_validation")]
+pub async fn gtfs_rt_validation(
+    query: Query<GtfsRtValidationQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    use catenary::schema::gtfs::gtfs_rt_validation_runs::dsl as gtfs_rt_validation_runs;
+
+    let query = query.into_inner();
+
+    if query.realtime_feed_id.is_none() && query.chateau.is_none() {
+        return HttpResponse::BadRequest().body("realtime_feed_id or chateau is required");
+    }
+
+    let hours = query.hours.unwrap_or(DEFAULT_HOURS);
+
+    if hours == 0 || hours > MAX_HOURS {
+        return HttpResponse::BadRequest()
+            .body(format!("hours must be between 1 and {}", MAX_HOURS));
+    }
+
+    let since_ms =
+        catenary::duration_since_unix_epoch().as_millis() as i64 - (hours * 3600 * 1000) as i64;
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    let mut runs_query = gtfs_rt_validation_runs::gtfs_rt_validation_runs
+        .filter(gtfs_rt_validation_runs::checked_unix_time_ms.ge(since_ms))
+        .order(gtfs_rt_validation_runs::checked_unix_time_ms.asc())
+        .select(GtfsRtValidationRun::as_select())
+        .into_boxed();
+
+    if let Some(realtime_feed_id) = &query.realtime_feed_id {
+        runs_query =
+            runs_query.filter(gtfs_rt_validation_runs::realtime_feed_id.eq(realtime_feed_id));
+    }
+
+    if let Some(chateau) = &query.chateau {
+        runs_query = runs_query.filter(gtfs_rt_validation_runs::chateau.eq(chateau));
+    }
+
+    let runs = match runs_query.load::<GtfsRtValidationRun>(&mut conn).await {
+        Ok(runs) => runs,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read validation results");
+        }
+    };
+
+    let mut feeds: BTreeMap<String, BTreeMap<String, FeedTypeValidation>> = BTreeMap::new();
+
+    // runs are oldest first, so the last one seen is the latest
+    for run in runs {
+        let notices = serde_json::from_value::<Vec<RtNotice>>(run.notices).unwrap_or_default();
+
+        let latest = LatestValidationRun {
+            checked_unix_time_ms: run.checked_unix_time_ms,
+            header_timestamp: run.header_timestamp,
+            entity_count: run.entity_count,
+            notices: notices.clone(),
+        };
+
+        let validation = feeds
+            .entry(run.realtime_feed_id)
+            .or_default()
+            .entry(run.feed_type)
+            .or_insert_with(|| FeedTypeValidation {
+                latest: latest.clone(),
+                history: vec![],
+                rules: BTreeMap::new(),
+                run_count: 0,
+            });
+
+        validation.latest = latest;
+        validation.run_count += 1;
+        validation.history.push(ValidationRunPoint {
+            checked_unix_time_ms: run.checked_unix_time_ms,
+            error_count: run.error_count,
+            warning_count: run.warning_count,
+        });
+
+        for notice in notices {
+            let rule = validation
+                .rules
+                .entry(notice.rule.code().to_string())
+                .or_default();
+
+            rule.runs += 1;
+            rule.total_count += notice.count as u64;
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=60"))
+        .json(GtfsRtValidationResponse { feeds })
+}
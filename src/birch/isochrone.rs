@@ -0,0 +1,686 @@
+// Copyright
+// Catenary Transit Initiatives
+// Isochrone endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use crate::timetable_cache::{cached_timetable, TimetableCacheActixData, TimetableCacheKey};
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use ahash::AHashMap;
+use catenary::osm_extractor::footpaths::{walking_duration_secs, WALKING_SPEED_METRES_PER_SEC};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::prairie::raptor::{
+    profile_search, ProfileArrival, DEFAULT_MAX_TRANSFERS, MAX_MAX_TRANSFERS,
+};
+use catenary::prairie::timetable::Timetable;
+use diesel::dsl::sql;
+use diesel::query_dsl::methods::FilterDsl;
+use diesel::query_dsl::methods::SelectDsl;
+use diesel::sql_types::Bool;
+use diesel::SelectableHelper;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use geo::HaversineDestination;
+use geo::HaversineDistance;
+use geojson::{Feature, GeoJson, JsonValue};
+use serde::Deserialize;
+use sqlx::Row;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+const DEFAULT_MAX_DURATION_SECS: i64 = 1800;
+const MAX_MAX_DURATION_SECS: i64 = 3600 * 3;
+
+// the rider may leave at any time in the window, and the fastest of those journeys is kept
+const DEFAULT_DEPARTURE_WINDOW_SECS: i64 = 1800;
+const MAX_DEPARTURE_WINDOW_SECS: i64 = 3600 * 3;
+
+// how far the rider is willing to walk to the first stop, and from the last stop
+const ACCESS_WALK_METRES: f64 = 800.0;
+const EGRESS_WALK_METRES: f64 = 800.0;
+
+const CIRCLE_VERTICES: usize = 32;
+
+// a map asks for the geojson and many tiles of the same isochrone within a few seconds
+const CACHE_TTL: Duration = Duration::from_secs(120);
+const CACHE_MAX_ENTRIES: usize = 512;
+
+// every search is loaded with the longest window a query may ask for, so searches share timetables
+const TIMETABLE_HORIZON_SECS: i64 = MAX_DEPARTURE_WINDOW_SECS + MAX_MAX_DURATION_SECS;
+
+#[derive(Deserialize, Clone, Debug)]
+struct IsochroneQuery {
+    lat: f64,
+    lon: f64,
+    departure_time: Option<u64>,
+    departure_window_secs: Option<i64>,
+    max_duration_secs: Option<i64>,
+    max_transfers: Option<usize>,
+    // "points" returns one point per stop, "circles" the area walkable from each stop in the remaining time
+    shape: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ReachableStop {
+    chateau: String,
+    stop_id: String,
+    name: Option<String>,
+    lat: f64,
+    lon: f64,
+    // when to leave the point for the fastest journey
+    departure_time: i64,
+    arrival_time: i64,
+    travel_time_secs: i64,
+    // number of vehicles boarded to get here
+    trips: usize,
+}
+
+impl ReachableStop {
+    fn walk_radius_metres(&self, max_duration_secs: i64) -> f64 {
+        let remaining_secs = (max_duration_secs - self.travel_time_secs).max(0) as f64;
+
+        (remaining_secs * WALKING_SPEED_METRES_PER_SEC).min(EGRESS_WALK_METRES)
+    }
+}
+
+/// Everything the search depends on, so requests for the same isochrone share one search
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct IsochroneCacheKey {
+    // degrees times 10000, about 10 metres
+    lat_e4: i64,
+    lon_e4: i64,
+    departure_time: i64,
+    departure_window_secs: i64,
+    max_duration_secs: i64,
+    max_transfers: usize,
+}
+
+impl IsochroneCacheKey {
+    fn new(query: &IsochroneQuery, now: i64) -> Self {
+        // searches leaving now start on the minute, so every tile of a map shares them
+        // the handlers reject departure times outside of datetime_from_unix_seconds, so this fits
+        let departure_time = match query.departure_time {
+            Some(x) => x as i64,
+            None => now - now.rem_euclid(60),
+        };
+
+        IsochroneCacheKey {
+            lat_e4: (query.lat * 10_000.0).round() as i64,
+            lon_e4: (query.lon * 10_000.0).round() as i64,
+            departure_time,
+            departure_window_secs: query
+                .departure_window_secs
+                .unwrap_or(DEFAULT_DEPARTURE_WINDOW_SECS)
+                .clamp(0, MAX_DEPARTURE_WINDOW_SECS),
+            max_duration_secs: isochrone_max_duration(query),
+            max_transfers: query
+                .max_transfers
+                .unwrap_or(DEFAULT_MAX_TRANSFERS)
+                .min(MAX_MAX_TRANSFERS),
+        }
+    }
+}
+
+type IsochroneSearch = Arc<OnceCell<Arc<Vec<ReachableStop>>>>;
+
+/// Searches by key, filled in by whichever request asks first while the others wait on it
+#[derive(Default)]
+pub struct IsochroneCache {
+    searches: AHashMap<IsochroneCacheKey, (Instant, IsochroneSearch)>,
+}
+
+pub type IsochroneCacheActixData = Arc<Mutex<IsochroneCache>>;
+
+impl IsochroneCache {
+    fn search(&mut self, key: &IsochroneCacheKey, now: Instant) -> IsochroneSearch {
+        self.searches
+            .retain(|_, (created, _)| now.duration_since(*created) < CACHE_TTL);
+
+        if !self.searches.contains_key(key) && self.searches.len() >= CACHE_MAX_ENTRIES {
+            let oldest = self
+                .searches
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(oldest, _)| oldest.clone());
+
+            if let Some(oldest) = oldest {
+                self.searches.remove(&oldest);
+            }
+        }
+
+        Arc::clone(
+            &self
+                .searches
+                .entry(key.clone())
+                .or_insert_with(|| (now, Arc::new(OnceCell::new())))
+                .1,
+        )
+    }
+}
+
+async fn cached_isochrone(
+    pool: &CatenaryPostgresPool,
+    cache: &IsochroneCacheActixData,
+    timetable_cache: &TimetableCacheActixData,
+    query: &IsochroneQuery,
+) -> Result<(IsochroneCacheKey, Arc<Vec<ReachableStop>>), Box<dyn Error + Send + Sync>> {
+    let key = IsochroneCacheKey::new(
+        query,
+        catenary::duration_since_unix_epoch().as_secs() as i64,
+    );
+
+    let search = cache.lock().unwrap().search(&key, Instant::now());
+
+    // a failed search leaves the cell empty, so the next request tries again
+    let reachable_stops = search
+        .get_or_try_init(|| async {
+            let conn_pre = pool.get().await;
+            let conn = &mut conn_pre?;
+
+            compute_isochrone(conn, timetable_cache, query, &key)
+                .await
+                .map(Arc::new)
+        })
+        .await?;
+
+    Ok((key.clone(), Arc::clone(reachable_stops)))
+}
+
+/// Profile search over the departure window from every stop within walking distance of the point
+async fn compute_isochrone(
+    conn: &mut AsyncPgConnection,
+    timetable_cache: &TimetableCacheActixData,
+    query: &IsochroneQuery,
+    key: &IsochroneCacheKey,
+) -> Result<Vec<ReachableStop>, Box<dyn Error + Send + Sync>> {
+    let input_point = geo::Point::new(query.lon, query.lat);
+
+    let spatial_resolution_in_degs =
+        crate::nearby_departures::make_degree_length_as_distance_from_point(
+            &input_point,
+            ACCESS_WALK_METRES,
+        );
+
+    let where_query_for_stops = format!(
+        "ST_DWithin(gtfs.stops.point, 'SRID=4326;POINT({} {})', {}) AND allowed_spatial_query = TRUE",
+        query.lon, query.lat, spatial_resolution_in_degs
+    );
+
+    let nearby_stops = catenary::schema::gtfs::stops::dsl::stops
+        .filter(sql::<Bool>(&where_query_for_stops))
+        .select(catenary::models::Stop::as_select())
+        .load::<catenary::models::Stop>(conn)
+        .await?;
+
+    let chateaus = nearby_stops
+        .iter()
+        .map(|stop| stop.chateau.clone())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+
+    if chateaus.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // the last departure in the window still travels for the whole duration
+    let timetable_key =
+        TimetableCacheKey::new(&chateaus, key.departure_time, TIMETABLE_HORIZON_SECS);
+
+    let timetable = cached_timetable(conn, timetable_cache, &timetable_key).await?;
+
+    let origins = nearby_stops
+        .iter()
+        .filter_map(|stop| {
+            let point = stop.point.as_ref()?;
+            let stop_idx = timetable.stop_index(&stop.chateau, &stop.gtfs_id)?;
+
+            let walk_distance = geo::Point::new(point.x, point.y).haversine_distance(&input_point);
+
+            Some((stop_idx, walking_duration_secs(walk_distance) as i64))
+        })
+        .collect::<Vec<(usize, i64)>>();
+
+    let profile = profile_search(
+        &timetable,
+        &origins,
+        key.departure_time,
+        key.departure_time + key.departure_window_secs,
+        key.max_transfers,
+    );
+
+    Ok(reachable_stops(&timetable, &profile, key.max_duration_secs))
+}
+
+fn reachable_stops(
+    timetable: &Timetable,
+    profile: &[Option<ProfileArrival>],
+    max_duration_secs: i64,
+) -> Vec<ReachableStop> {
+    profile
+        .iter()
+        .enumerate()
+        .filter_map(|(stop_idx, arrival)| {
+            let arrival = (*arrival)?;
+
+            if arrival.travel_time_secs() > max_duration_secs {
+                return None;
+            }
+
+            let stop = &timetable.stops[stop_idx];
+
+            Some(ReachableStop {
+                chateau: stop.chateau.clone(),
+                stop_id: stop.stop_id.clone(),
+                name: stop.name.clone(),
+                lat: stop.lat?,
+                lon: stop.lon?,
+                departure_time: arrival.departure_time,
+                arrival_time: arrival.arrival_time,
+                travel_time_secs: arrival.travel_time_secs(),
+                trips: arrival.trips,
+            })
+        })
+        .collect::<Vec<ReachableStop>>()
+}
+
+fn isochrone_max_duration(query: &IsochroneQuery) -> i64 {
+    query
+        .max_duration_secs
+        .unwrap_or(DEFAULT_MAX_DURATION_SECS)
+        .clamp(0, MAX_MAX_DURATION_SECS)
+}
+
+fn walking_circle(center: &geo::Point<f64>, radius_metres: f64) -> geo::Polygon<f64> {
+    let mut coords = (0..CIRCLE_VERTICES)
+        .map(|i| {
+            let bearing = 360.0 * i as f64 / CIRCLE_VERTICES as f64;
+            center.haversine_destination(bearing, radius_metres).0
+        })
+        .collect::<Vec<geo::Coord<f64>>>();
+
+    coords.push(coords[0]);
+
+    geo::Polygon::new(geo::LineString::new(coords), vec![])
+}
+
+#[actix_web::get("/isochrone")]
+pub async fn isochrone(
+    query: Query<IsochroneQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    isochrone_cache: web::Data<IsochroneCacheActixData>,
+    timetable_cache: web::Data<TimetableCacheActixData>,
+) -> impl Responder {
+    if query
+        .departure_time
+        .is_some_and(|x| catenary::datetime_from_unix_seconds(x).is_none())
+    {
+        return HttpResponse::BadRequest().body("Invalid departure time");
+    }
+
+    let (key, reachable_stops) =
+        match cached_isochrone(&pool, &isochrone_cache, &timetable_cache, &query).await {
+            Ok(search) => search,
+            Err(err) => {
+                eprintln!("{:#?}", err);
+                return HttpResponse::InternalServerError().body("Could not compute isochrone");
+            }
+        };
+
+    let max_duration_secs = key.max_duration_secs;
+    let circles = query.shape.as_deref() == Some("circles");
+
+    let features = reachable_stops
+        .iter()
+        .map(|stop| {
+            let point = geo::Point::new(stop.lon, stop.lat);
+            let walk_radius_metres = stop.walk_radius_metres(max_duration_secs);
+
+            let value = match circles {
+                true => geojson::Value::from(&walking_circle(&point, walk_radius_metres)),
+                false => geojson::Value::from(&point),
+            };
+
+            let mut properties: serde_json::map::Map<String, JsonValue> =
+                serde_json::map::Map::new();
+
+            properties.insert(
+                String::from("chateau"),
+                serde_json::Value::String(stop.chateau.clone()),
+            );
+            properties.insert(
+                String::from("stop_id"),
+                serde_json::Value::String(stop.stop_id.clone()),
+            );
+            properties.insert(
+                String::from("name"),
+                match &stop.name {
+                    Some(name) => serde_json::Value::String(name.clone()),
+                    None => serde_json::Value::Null,
+                },
+            );
+            properties.insert(
+                String::from("departure_time"),
+                serde_json::Value::from(stop.departure_time),
+            );
+            properties.insert(
+                String::from("arrival_time"),
+                serde_json::Value::from(stop.arrival_time),
+            );
+            properties.insert(
+                String::from("travel_time_secs"),
+                serde_json::Value::from(stop.travel_time_secs),
+            );
+            properties.insert(String::from("trips"), serde_json::Value::from(stop.trips));
+            properties.insert(
+                String::from("walk_radius_metres"),
+                serde_json::Value::from(walk_radius_metres),
+            );
+
+            geojson::Feature {
+                bbox: None,
+                geometry: Some(geojson::Geometry {
+                    bbox: None,
+                    value,
+                    foreign_members: None,
+                }),
+                id: Some(geojson::feature::Id::String(format!(
+                    "{}/{}",
+                    stop.chateau, stop.stop_id
+                ))),
+                properties: Some(properties),
+                foreign_members: None,
+            }
+        })
+        .collect::<Vec<Feature>>();
+
+    let feature_collection = geojson::FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(GeoJson::from(feature_collection).to_string())
+}
+
+#[actix_web::get("/isochrone/{z}/{x}/{y}")]
+pub async fn isochrone_tile(
+    query: Query<IsochroneQuery>,
+    path: web::Path<(u8, u32, u32)>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    isochrone_cache: web::Data<IsochroneCacheActixData>,
+    timetable_cache: web::Data<TimetableCacheActixData>,
+) -> impl Responder {
+    if query
+        .departure_time
+        .is_some_and(|x| catenary::datetime_from_unix_seconds(x).is_none())
+    {
+        return HttpResponse::BadRequest().body("Invalid departure time");
+    }
+
+    let (z, x, y) = path.into_inner();
+
+    let sqlx_pool_ref = sqlx_pool.as_ref().as_ref();
+
+    // every tile of the map reuses the same search
+    let (key, reachable_stops) =
+        match cached_isochrone(&pool, &isochrone_cache, &timetable_cache, &query).await {
+            Ok(search) => search,
+            Err(err) => {
+                eprintln!("{:#?}", err);
+                return HttpResponse::InternalServerError().body("Could not compute isochrone");
+            }
+        };
+
+    let max_duration_secs = key.max_duration_secs;
+
+    // the search result never touches the database, so the rows are passed in as arrays and unnested
+    let query_str = "
+    SELECT
+    ST_AsMVT(q, 'data', 4096, 'geom')
+FROM (
+    SELECT
+        chateau,
+        stop_id,
+        name,
+        arrival_time,
+        travel_time_secs,
+        trips,
+        walk_radius_metres,
+        departure_time,
+        ST_AsMVTGeom(ST_Transform(ST_SetSRID(ST_MakePoint(lon, lat), 4326), 3857),
+        ST_TileEnvelope($1, $2, $3), 4096, 64, true) AS geom
+    FROM
+        UNNEST($4::text[], $5::text[], $6::text[], $7::float8[], $8::float8[], $9::int8[], $10::int8[], $11::int4[], $12::float8[], $13::int8[])
+        AS t(chateau, stop_id, name, lon, lat, arrival_time, travel_time_secs, trips, walk_radius_metres, departure_time)
+) q";
+
+    match sqlx::query(query_str)
+        .bind(z as i32)
+        .bind(x as i32)
+        .bind(y as i32)
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.chateau.clone())
+                .collect::<Vec<String>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.stop_id.clone())
+                .collect::<Vec<String>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.name.clone())
+                .collect::<Vec<Option<String>>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.lon)
+                .collect::<Vec<f64>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.lat)
+                .collect::<Vec<f64>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.arrival_time)
+                .collect::<Vec<i64>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.travel_time_secs)
+                .collect::<Vec<i64>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.trips as i32)
+                .collect::<Vec<i32>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.walk_radius_metres(max_duration_secs))
+                .collect::<Vec<f64>>(),
+        )
+        .bind(
+            reachable_stops
+                .iter()
+                .map(|stop| stop.departure_time)
+                .collect::<Vec<i64>>(),
+        )
+        .fetch_one(sqlx_pool_ref)
+        .await
+    {
+        Ok(mvt_result) => {
+            let mvt_bytes: Vec<u8> = mvt_result.get(0);
+
+            HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/x-protobuf"))
+                .insert_header(("Cache-Control", "no-cache"))
+                .body(mvt_bytes)
+        }
+        Err(err) => {
+            eprintln!("{:?}", err);
+            HttpResponse::InternalServerError().body("Failed to fetch from postgres!")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::prairie::timetable::TimetableStop;
+
+    fn query(departure_time: Option<u64>) -> IsochroneQuery {
+        IsochroneQuery {
+            lat: 34.05601,
+            lon: -118.23432,
+            departure_time,
+            departure_window_secs: None,
+            max_duration_secs: Some(3600 * 24),
+            max_transfers: None,
+            shape: None,
+        }
+    }
+
+    #[test]
+    fn searches_leaving_now_share_the_minute() {
+        let key = IsochroneCacheKey::new(&query(None), 1_726_500_059);
+
+        assert_eq!(key, IsochroneCacheKey::new(&query(None), 1_726_500_001));
+        assert_eq!(key.departure_time, 1_726_500_000);
+        assert_eq!(key.departure_window_secs, DEFAULT_DEPARTURE_WINDOW_SECS);
+        assert_eq!(key.max_duration_secs, MAX_MAX_DURATION_SECS);
+        assert_eq!((key.lat_e4, key.lon_e4), (340_560, -1_182_343));
+
+        // asking for more transfers than a search allows is clamped
+        let mut many_transfers = query(None);
+        many_transfers.max_transfers = Some(usize::MAX);
+        assert_eq!(
+            IsochroneCacheKey::new(&many_transfers, 0).max_transfers,
+            MAX_MAX_TRANSFERS
+        );
+
+        // an explicit departure time is searched as given
+        assert_eq!(
+            IsochroneCacheKey::new(&query(Some(1_726_500_059)), 0).departure_time,
+            1_726_500_059
+        );
+    }
+
+    #[test]
+    fn requests_for_the_same_isochrone_share_a_search() {
+        let mut cache = IsochroneCache::default();
+        let now = Instant::now();
+
+        let key = IsochroneCacheKey::new(&query(Some(1_726_500_000)), 0);
+        let other_key = IsochroneCacheKey::new(&query(Some(1_726_503_600)), 0);
+
+        let search = cache.search(&key, now);
+        search.set(Arc::new(vec![])).unwrap();
+
+        assert!(cache
+            .search(&key, now + Duration::from_secs(10))
+            .initialized());
+        assert!(!cache.search(&other_key, now).initialized());
+
+        // expired searches run again
+        assert!(!cache.search(&key, now + CACHE_TTL).initialized());
+    }
+
+    #[test]
+    fn full_cache_drops_the_oldest_search() {
+        let mut cache = IsochroneCache::default();
+        let now = Instant::now();
+
+        let keys = (0..=CACHE_MAX_ENTRIES as u64)
+            .map(|i| IsochroneCacheKey::new(&query(Some(1_726_500_000 + i * 60)), 0))
+            .collect::<Vec<IsochroneCacheKey>>();
+
+        for (i, key) in keys.iter().enumerate() {
+            cache
+                .search(key, now + Duration::from_millis(i as u64))
+                .set(Arc::new(vec![]))
+                .unwrap();
+        }
+
+        assert_eq!(cache.searches.len(), CACHE_MAX_ENTRIES);
+        assert!(!cache.searches.contains_key(&keys[0]));
+        assert!(cache.searches.contains_key(&keys[CACHE_MAX_ENTRIES]));
+    }
+
+    #[test]
+    fn stops_beyond_the_duration_or_without_a_location_are_left_out() {
+        let mut timetable = Timetable::default();
+
+        let stop = |stop_id: &str, located: bool| TimetableStop {
+            chateau: String::from("metro"),
+            stop_id: stop_id.to_string(),
+            name: None,
+            code: None,
+            platform_code: None,
+            parent_station: None,
+            lat: located.then_some(34.05),
+            lon: located.then_some(-118.23),
+        };
+
+        let near = timetable.add_stop(stop("near", true));
+        let far = timetable.add_stop(stop("far", true));
+        let unlocated = timetable.add_stop(stop("unlocated", false));
+        timetable.add_stop(stop("unreached", true));
+
+        let mut profile = vec![None; timetable.stops.len()];
+
+        profile[near] = Some(ProfileArrival {
+            departure_time: 1200,
+            arrival_time: 1500,
+            trips: 1,
+        });
+        profile[far] = Some(ProfileArrival {
+            departure_time: 0,
+            arrival_time: 1801,
+            trips: 2,
+        });
+        profile[unlocated] = Some(ProfileArrival {
+            departure_time: 0,
+            arrival_time: 60,
+            trips: 0,
+        });
+
+        let reachable = reachable_stops(&timetable, &profile, 1800);
+
+        assert_eq!(
+            reachable
+                .iter()
+                .map(|stop| (
+                    stop.stop_id.as_str(),
+                    stop.departure_time,
+                    stop.travel_time_secs
+                ))
+                .collect::<Vec<_>>(),
+            vec![("near", 1200, 300)]
+        );
+    }
+}
@@ -17,6 +17,7 @@ use catenary::aspen_dataset::AspenisedTripUpdate;
 use catenary::gtfs_schedule_protobuf::protobuf_to_frequencies;
 use catenary::make_weekdays;
 use catenary::maple_syrup::DirectionPattern;
+use catenary::models::BookingRule;
 use catenary::models::DirectionPatternRow;
 use catenary::models::ItineraryPatternMeta;
 use catenary::models::ItineraryPatternRowNearbyLookup;
@@ -25,7 +26,6 @@ use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::schema::gtfs::trips_compressed;
 use catenary::CalendarUnified;
 use catenary::EtcdConnectionIps;
-use chrono::TimeZone;
 use compact_str::CompactString;
 use diesel::dsl::sql;
 use diesel::dsl::sql_query;
@@ -34,6 +34,7 @@ use diesel::query_dsl::methods::SelectDsl;
 use diesel::sql_types::Bool;
 use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
+use diesel_async::AsyncPgConnection;
 use diesel_async::RunQueryDsl;
 use futures::stream::futures_unordered;
 use futures::stream::FuturesUnordered;
@@ -61,13 +62,6 @@ struct NearbyFromCoords {
     departure_time: Option<u64>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
-struct DeparturesFromStop {
-    chateau_id: String,
-    stop_id: String,
-    departure_time: Option<u64>,
-}
-
 #[derive(Deserialize, Serialize, Clone, Debug)]
 struct DeparturesDebug {
     stop_lookup_ms: u128,
@@ -185,6 +179,33 @@ pub struct StopOutput {
     pub url: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlexWindow {
+    pub trip_id: CompactString,
+    pub gtfs_schedule_start_day: chrono::NaiveDate,
+    /// Unix seconds, a pickup anywhere in the zone can be booked between start and end
+    pub start: u64,
+    pub end: u64,
+    pub tz: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlexRouteGroup {
+    pub chateau_id: String,
+    pub route_id: CompactString,
+    pub color: Option<CompactString>,
+    pub text_color: Option<CompactString>,
+    pub short_name: Option<CompactString>,
+    pub long_name: Option<String>,
+    pub route_type: i16,
+    pub location_id: String,
+    pub zone_name: Option<String>,
+    pub headsign: Option<String>,
+    /// How to call to book, when the feed publishes it
+    pub booking_rule: Option<BookingRule>,
+    pub windows: Vec<FlexWindow>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DepartingTripsDataAnswer {
     pub number_of_stops_searched_through: usize,
@@ -192,6 +213,8 @@ pub struct DepartingTripsDataAnswer {
     pub rail_and_other_limited_metres: f64,
     pub departures: Vec<DepartureRouteGroup>,
     pub stop: HashMap<String, HashMap<CompactString, StopOutput>>,
+    /// Call to book service in the GTFS-Flex zones containing the point
+    pub flex: Vec<FlexRouteGroup>,
     pub debug: DeparturesDebug,
 }
 
@@ -225,7 +248,10 @@ pub async fn nearby_from_coords(
     };
 
     let departure_time_chrono = match query.departure_time {
-        Some(x) => chrono::Utc.timestamp_opt(x.try_into().unwrap(), 0).unwrap(),
+        Some(x) => match catenary::datetime_from_unix_seconds(x) {
+            Some(departure_time_chrono) => departure_time_chrono,
+            None => return HttpResponse::BadRequest().body("Invalid departure time"),
+        },
         None => chrono::Utc::now(),
     };
 
@@ -254,6 +280,24 @@ pub async fn nearby_from_coords(
         false => -90.,
     };
 
+    // flex zones are looked up on their own connection while the fixed route departures are found
+    let flex_lookup = {
+        let pool = pool.get_ref().clone();
+
+        tokio::spawn(async move {
+            let mut flex_conn = pool.get().await?;
+
+            flex_departures_at_point(
+                &mut flex_conn,
+                &input_point,
+                departure_time_chrono,
+                seek_back,
+                seek_forward,
+            )
+            .await
+        })
+    };
+
     let mut rail_and_other_distance_limit = 3000;
 
     let mut bus_distance_limit = 3000;
@@ -690,6 +734,18 @@ pub async fn nearby_from_coords(
 
     let chateau_metadata = chateau_metadata;
 
+    let flex = match flex_lookup.await {
+        Ok(Ok(flex)) => flex,
+        Ok(Err(err)) => {
+            eprintln!("Could not look up flex zones: {}", err);
+            vec![]
+        }
+        Err(err) => {
+            eprintln!("Flex zone lookup did not finish: {}", err);
+            vec![]
+        }
+    };
+
     match calendar_structure {
         Err(err) => HttpResponse::InternalServerError().body("CANNOT FIND CALENDARS"),
         Ok(calendar_structure) => {
@@ -1052,6 +1108,7 @@ pub async fn nearby_from_coords(
                 rail_and_other_limited_metres: rail_and_other_distance_limit as f64,
                 departures: departures,
                 stop: stops_answer,
+                flex,
                 debug: DeparturesDebug {
                     stop_lookup_ms: end_stops_duration.as_millis(),
                     directions_ms: directions_lookup_duration.as_millis(),
@@ -1064,56 +1121,330 @@ pub async fn nearby_from_coords(
     }
 }
 
-fn make_calendar_structure_from_pg_single_chateau(
-    services_calendar_lookup_queries_to_perform: Vec<catenary::models::Calendar>,
-    services_calendar_dates_lookup_queries_to_perform: Vec<catenary::models::CalendarDate>,
-) -> BTreeMap<String, catenary::CalendarUnified> {
-    let mut calendar_structures: BTreeMap<String, catenary::CalendarUnified> = BTreeMap::new();
-
-    for calendar in services_calendar_lookup_queries_to_perform {
-        calendar_structures.insert(
-            calendar.service_id.clone(),
-            catenary::CalendarUnified {
-                id: calendar.service_id.clone(),
-                general_calendar: Some(catenary::GeneralCalendar {
-                    days: make_weekdays(&calendar),
-                    start_date: calendar.gtfs_start_date,
-                    end_date: calendar.gtfs_end_date,
-                }),
-                exceptions: None,
-            },
-        );
+// Demand responsive trips are not waited for at a stop, riders inside a zone call to book a pickup within a window
+async fn flex_departures_at_point(
+    conn: &mut AsyncPgConnection,
+    point: &geo::Point,
+    departure_time_chrono: chrono::DateTime<chrono::Utc>,
+    seek_back: chrono::TimeDelta,
+    seek_forward: chrono::TimeDelta,
+) -> Result<Vec<FlexRouteGroup>, Box<dyn std::error::Error + Sync + Send>> {
+    use catenary::schema::gtfs::booking_rules::dsl as booking_rules;
+    use catenary::schema::gtfs::calendar::dsl as calendar;
+    use catenary::schema::gtfs::calendar_dates::dsl as calendar_dates;
+    use catenary::schema::gtfs::flex_locations::dsl as flex_locations;
+    use catenary::schema::gtfs::itinerary_pattern::dsl as itinerary_pattern;
+    use catenary::schema::gtfs::itinerary_pattern_meta::dsl as itinerary_pattern_meta;
+    use catenary::schema::gtfs::routes::dsl as routes;
+
+    // zones of the attempts in production
+    let where_query_for_zones = format!(
+        "ST_Contains(gtfs.flex_locations.geometry, 'SRID=4326;POINT({} {})')
+        AND (gtfs.flex_locations.onestop_feed_id, gtfs.flex_locations.attempt_id) IN
+        (SELECT onestop_feed_id, attempt_id FROM gtfs.ingested_static WHERE production = TRUE AND deleted = FALSE)",
+        point.x(),
+        point.y()
+    );
+
+    let zones = flex_locations::flex_locations
+        .filter(sql::<Bool>(&where_query_for_zones))
+        .select((
+            flex_locations::attempt_id,
+            flex_locations::location_id,
+            flex_locations::stop_name,
+        ))
+        .load::<(String, String, Option<String>)>(conn)
+        .await?;
+
+    if zones.is_empty() {
+        return Ok(vec![]);
     }
 
-    for calendar_date in services_calendar_dates_lookup_queries_to_perform {
-        let exception_number = match calendar_date.exception_type {
-            1 => gtfs_structures::Exception::Added,
-            2 => gtfs_structures::Exception::Deleted,
-            _ => panic!("WHAT IS THIS!!!!!!"),
-        };
+    // attempt ids start with the feed id, so they are unique across feeds
+    let zone_names = zones
+        .iter()
+        .map(|(attempt_id, location_id, stop_name)| {
+            ((attempt_id.clone(), location_id.clone()), stop_name.clone())
+        })
+        .collect::<HashMap<(String, String), Option<String>>>();
 
-        match calendar_structures.entry(calendar_date.service_id.clone()) {
-            btree_map::Entry::Occupied(mut oe) => {
-                let mut calendar_unified = oe.get_mut();
-
-                if let Some(entry) = &mut calendar_unified.exceptions {
-                    entry.insert(calendar_date.gtfs_date, exception_number);
-                } else {
-                    calendar_unified.exceptions = Some(BTreeMap::from_iter([(
-                        calendar_date.gtfs_date,
-                        exception_number,
-                    )]));
+    let attempt_ids = zones
+        .iter()
+        .map(|(attempt_id, _, _)| attempt_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let location_ids = zones
+        .iter()
+        .map(|(_, location_id, _)| location_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let zone_rows = itinerary_pattern::itinerary_pattern
+        .filter(itinerary_pattern::attempt_id.eq_any(&attempt_ids))
+        .filter(itinerary_pattern::stop_id.eq_any(&location_ids))
+        .filter(itinerary_pattern::start_pickup_drop_off_window_since_start.is_not_null())
+        .select(ItineraryPatternRow::as_select())
+        .load::<ItineraryPatternRow>(conn)
+        .await?;
+
+    // the first visit of a trip to the zone is where it picks up
+    let mut pickup_rows: HashMap<(String, String), ItineraryPatternRow> = HashMap::new();
+
+    for row in zone_rows {
+        if !zone_names.contains_key(&(row.attempt_id.clone(), row.stop_id.to_string())) {
+            continue;
+        }
+
+        match pickup_rows.entry((row.attempt_id.clone(), row.itinerary_pattern_id.clone())) {
+            Entry::Occupied(mut oe) => {
+                if row.stop_sequence < oe.get().stop_sequence {
+                    oe.insert(row);
                 }
             }
-            btree_map::Entry::Vacant(mut ve) => {
-                ve.insert(CalendarUnified::empty_exception_from_calendar_date(
-                    &calendar_date,
-                ));
+            Entry::Vacant(ve) => {
+                ve.insert(row);
             }
         }
     }
 
-    calendar_structures
+    let itinerary_ids = pickup_rows
+        .keys()
+        .map(|(_, itinerary_pattern_id)| itinerary_pattern_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let metas = itinerary_pattern_meta::itinerary_pattern_meta
+        .filter(itinerary_pattern_meta::attempt_id.eq_any(&attempt_ids))
+        .filter(itinerary_pattern_meta::itinerary_pattern_id.eq_any(&itinerary_ids))
+        .select(ItineraryPatternMeta::as_select())
+        .load::<ItineraryPatternMeta>(conn)
+        .await?
+        .into_iter()
+        .map(|meta| {
+            (
+                (meta.attempt_id.clone(), meta.itinerary_pattern_id.clone()),
+                meta,
+            )
+        })
+        .collect::<HashMap<(String, String), ItineraryPatternMeta>>();
+
+    let trips = trips_compressed::dsl::trips_compressed
+        .filter(trips_compressed::dsl::attempt_id.eq_any(&attempt_ids))
+        .filter(trips_compressed::dsl::itinerary_pattern_id.eq_any(&itinerary_ids))
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await?;
+
+    let route_ids = trips
+        .iter()
+        .map(|trip| trip.route_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let routes_table = routes::routes
+        .filter(routes::attempt_id.eq_any(&attempt_ids))
+        .filter(routes::route_id.eq_any(&route_ids))
+        .select(catenary::models::Route::as_select())
+        .load::<catenary::models::Route>(conn)
+        .await?
+        .into_iter()
+        .map(|route| ((route.attempt_id.clone(), route.route_id.clone()), route))
+        .collect::<HashMap<(String, String), catenary::models::Route>>();
+
+    let booking_rule_ids = pickup_rows
+        .values()
+        .filter_map(|row| row.pickup_booking_rule_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let booking_rules_table = booking_rules::booking_rules
+        .filter(booking_rules::attempt_id.eq_any(&attempt_ids))
+        .filter(booking_rules::booking_rule_id.eq_any(&booking_rule_ids))
+        .select(BookingRule::as_select())
+        .load::<BookingRule>(conn)
+        .await?
+        .into_iter()
+        .map(|booking_rule| {
+            (
+                (
+                    booking_rule.attempt_id.clone(),
+                    booking_rule.booking_rule_id.clone(),
+                ),
+                booking_rule,
+            )
+        })
+        .collect::<HashMap<(String, String), BookingRule>>();
+
+    // calendars are grouped by chateau, like the fixed route departures
+    let chateaus = trips
+        .iter()
+        .map(|trip| trip.chateau.clone())
+        .collect::<BTreeSet<String>>();
+
+    let mut calendar_queries = vec![];
+    let mut calendar_dates_queries = vec![];
+
+    for chateau in chateaus.iter() {
+        let service_ids = trips
+            .iter()
+            .filter(|trip| trip.chateau == *chateau)
+            .map(|trip| trip.service_id.clone())
+            .collect::<BTreeSet<CompactString>>();
+
+        calendar_queries.push(
+            calendar::calendar
+                .filter(calendar::chateau.eq(chateau))
+                .filter(calendar::service_id.eq_any(&service_ids))
+                .select(catenary::models::Calendar::as_select())
+                .load::<catenary::models::Calendar>(conn)
+                .await,
+        );
+
+        calendar_dates_queries.push(
+            calendar_dates::calendar_dates
+                .filter(calendar_dates::chateau.eq(chateau))
+                .filter(calendar_dates::service_id.eq_any(&service_ids))
+                .select(catenary::models::CalendarDate::as_select())
+                .load::<catenary::models::CalendarDate>(conn)
+                .await,
+        );
+    }
+
+    let calendar_structure =
+        make_calendar_structure_from_pg(calendar_queries, calendar_dates_queries)?;
+
+    let mut flex_groups: BTreeMap<(String, String, String), FlexRouteGroup> = BTreeMap::new();
+
+    for trip in trips {
+        let itinerary_key = (trip.attempt_id.clone(), trip.itinerary_pattern_id.clone());
+
+        let (Some(row), Some(meta), Some(route)) = (
+            pickup_rows.get(&itinerary_key),
+            metas.get(&itinerary_key),
+            routes_table.get(&(trip.attempt_id.clone(), trip.route_id.clone())),
+        ) else {
+            continue;
+        };
+
+        let Some(window_start_since_start) = row.start_pickup_drop_off_window_since_start else {
+            continue;
+        };
+
+        let window_end_since_start = row
+            .end_pickup_drop_off_window_since_start
+            .unwrap_or(window_start_since_start);
+
+        let Ok(timezone) = chrono_tz::Tz::from_str(meta.timezone.as_str()) else {
+            continue;
+        };
+
+        let Some(service) = calendar_structure
+            .get(&trip.chateau)
+            .and_then(|calendar_in_chateau| calendar_in_chateau.get(trip.service_id.as_str()))
+        else {
+            continue;
+        };
+
+        let window_start = trip.start_time as i64 + window_start_since_start as i64;
+        let window_end = trip.start_time as i64 + window_end_since_start as i64;
+
+        let t_to_find_schedule_for = catenary::TripToFindScheduleFor {
+            trip_id: trip.trip_id.clone(),
+            chateau: trip.chateau.clone(),
+            timezone,
+            time_since_start_of_service_date: chrono::TimeDelta::new(window_start, 0).unwrap(),
+            frequency: None,
+            itinerary_id: trip.itinerary_pattern_id.clone(),
+            direction_id: meta.direction_pattern_id.clone().unwrap_or_default(),
+        };
+
+        let dates = catenary::find_service_ranges(
+            service,
+            &t_to_find_schedule_for,
+            departure_time_chrono,
+            seek_back,
+            seek_forward,
+        );
+
+        for (service_date, start, end) in bookable_flex_windows(
+            &dates,
+            window_start,
+            window_end,
+            departure_time_chrono,
+            seek_forward,
+        ) {
+            let location_id = row.stop_id.to_string();
+
+            let flex_group = flex_groups
+                .entry((
+                    trip.chateau.clone(),
+                    route.route_id.clone(),
+                    location_id.clone(),
+                ))
+                .or_insert_with(|| FlexRouteGroup {
+                    chateau_id: trip.chateau.clone(),
+                    route_id: (&route.route_id).into(),
+                    color: route.color.as_ref().map(|x| x.into()),
+                    text_color: route.text_color.as_ref().map(|x| x.into()),
+                    short_name: route.short_name.as_ref().map(|x| x.into()),
+                    long_name: route.long_name.clone(),
+                    route_type: route.route_type,
+                    zone_name: zone_names
+                        .get(&(trip.attempt_id.clone(), location_id.clone()))
+                        .cloned()
+                        .flatten(),
+                    location_id,
+                    headsign: meta.trip_headsign.clone(),
+                    booking_rule: row
+                        .pickup_booking_rule_id
+                        .as_ref()
+                        .and_then(|booking_rule_id| {
+                            booking_rules_table
+                                .get(&(trip.attempt_id.clone(), booking_rule_id.clone()))
+                                .cloned()
+                        }),
+                    windows: vec![],
+                });
+
+            flex_group.windows.push(FlexWindow {
+                trip_id: (&trip.trip_id).into(),
+                gtfs_schedule_start_day: service_date,
+                start: start as u64,
+                end: end as u64,
+                tz: timezone.name().to_string(),
+            });
+        }
+    }
+
+    let mut flex = flex_groups.into_values().collect::<Vec<FlexRouteGroup>>();
+
+    for flex_group in flex.iter_mut() {
+        flex_group.windows.sort_by_key(|window| window.start);
+    }
+
+    Ok(flex)
+}
+
+/// Unix seconds of the window on each service date, for the windows which are still open
+/// and open before the end of the search. Window times are since the start of the service date.
+fn bookable_flex_windows(
+    dates: &[(chrono::NaiveDate, chrono::DateTime<chrono_tz::Tz>)],
+    window_start: i64,
+    window_end: i64,
+    departure_time_chrono: chrono::DateTime<chrono::Utc>,
+    seek_forward: chrono::TimeDelta,
+) -> Vec<(chrono::NaiveDate, i64, i64)> {
+    dates
+        .iter()
+        .map(|(service_date, reference_start_of_service_date)| {
+            (
+                *service_date,
+                reference_start_of_service_date.timestamp() + window_start,
+                reference_start_of_service_date.timestamp() + window_end,
+            )
+        })
+        // windows which have closed, or open beyond the search, are not bookable now
+        .filter(|(_, start, end)| {
+            *end >= departure_time_chrono.timestamp()
+                && *start <= (departure_time_chrono + seek_forward).timestamp()
+        })
+        .collect()
 }
 
 fn make_calendar_structure_from_pg(
@@ -1216,7 +1547,7 @@ fn make_calendar_structure_from_pg(
     Ok(calendar_structures)
 }
 
-fn make_degree_length_as_distance_from_point(point: &geo::Point, distance_metres: f64) -> f64 {
+pub fn make_degree_length_as_distance_from_point(point: &geo::Point, distance_metres: f64) -> f64 {
     let direction = match point.x() > 0. {
         true => 90.,
         false => -90.,
@@ -1226,3 +1557,92 @@ fn make_degree_length_as_distance_from_point(point: &geo::Point, distance_metres
 
     f64::abs(distance_calc_point.x() - point.x())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn service_dates() -> Vec<(chrono::NaiveDate, chrono::DateTime<chrono_tz::Tz>)> {
+        [16, 17]
+            .into_iter()
+            .map(|day| {
+                (
+                    chrono::NaiveDate::from_ymd_opt(2024, 9, day).unwrap(),
+                    chrono_tz::America::Los_Angeles
+                        .with_ymd_and_hms(2024, 9, day, 0, 0, 0)
+                        .unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn closed_and_distant_windows_are_not_bookable() {
+        // 10:00 on the 16th in Los Angeles
+        let departure_time_chrono = chrono::Utc.with_ymd_and_hms(2024, 9, 16, 17, 0, 0).unwrap();
+
+        // 07:00 to 17:00 is open on the 16th, and the 17th opens beyond a 12 hour search
+        let windows = bookable_flex_windows(
+            &service_dates(),
+            7 * 3600,
+            17 * 3600,
+            departure_time_chrono,
+            chrono::TimeDelta::new(3600 * 12, 0).unwrap(),
+        );
+
+        assert_eq!(
+            windows,
+            vec![(
+                chrono::NaiveDate::from_ymd_opt(2024, 9, 16).unwrap(),
+                chrono::Utc
+                    .with_ymd_and_hms(2024, 9, 16, 14, 0, 0)
+                    .unwrap()
+                    .timestamp(),
+                chrono::Utc
+                    .with_ymd_and_hms(2024, 9, 17, 0, 0, 0)
+                    .unwrap()
+                    .timestamp(),
+            )]
+        );
+
+        // 06:00 to 09:00 has closed on the 16th, the 17th opens within a day long search
+        let windows = bookable_flex_windows(
+            &service_dates(),
+            6 * 3600,
+            9 * 3600,
+            departure_time_chrono,
+            chrono::TimeDelta::new(3600 * 24, 0).unwrap(),
+        );
+
+        assert_eq!(
+            windows
+                .iter()
+                .map(|(service_date, _, _)| *service_date)
+                .collect::<Vec<_>>(),
+            vec![chrono::NaiveDate::from_ymd_opt(2024, 9, 17).unwrap()]
+        );
+    }
+
+    #[test]
+    fn windows_past_midnight_stay_on_their_service_date() {
+        // 00:30 on the 17th, still inside the 22:00 to 25:00 window of the 16th
+        let departure_time_chrono = chrono::Utc.with_ymd_and_hms(2024, 9, 17, 7, 30, 0).unwrap();
+
+        let windows = bookable_flex_windows(
+            &service_dates(),
+            22 * 3600,
+            25 * 3600,
+            departure_time_chrono,
+            chrono::TimeDelta::new(3600, 0).unwrap(),
+        );
+
+        assert_eq!(
+            windows
+                .iter()
+                .map(|(service_date, _, _)| *service_date)
+                .collect::<Vec<_>>(),
+            vec![chrono::NaiveDate::from_ymd_opt(2024, 9, 16).unwrap()]
+        );
+    }
+}
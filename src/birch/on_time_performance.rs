@@ -0,0 +1,291 @@
+// Copyright
+// Catenary Transit Initiatives
+// On time performance endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Punctuality of a chateau's routes and stops over a range of service days, from the stop times archived by Aspen.
+// Departures count as on time between 1 minute early and 5 minutes late.
+// Counts, averages and percentiles are computed by Postgres, so only one row per route and stop is read.
+// The last stop of a trip has no departure, so its arrival is used instead.
+
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use chrono::NaiveDate;
+use diesel::sql_types::{BigInt, Date, Integer, Nullable, Text};
+use diesel::QueryableByName;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const ON_TIME_EARLIEST_SECS: i32 = -60;
+const ON_TIME_LATEST_SECS: i32 = 300;
+
+const DEFAULT_DAYS: i64 = 7;
+const MAX_DAYS: i64 = 62;
+
+// one row for the whole chateau, one per route and one per stop, aggregated by Postgres
+const PUNCTUALITY_QUERY: &str = "SELECT
+    GROUPING(route_id) = 0 AS by_route,
+    GROUPING(stop_id) = 0 AS by_stop,
+    route_id,
+    stop_id,
+    COUNT(*) AS observations,
+    COUNT(*) FILTER (WHERE delay < $4) AS early,
+    COUNT(*) FILTER (WHERE delay > $5) AS late,
+    AVG(delay)::float8 AS average_delay_secs,
+    percentile_cont(0.5) WITHIN GROUP (ORDER BY delay) AS median_delay_secs,
+    percentile_cont(0.9) WITHIN GROUP (ORDER BY delay) AS p90_delay_secs
+FROM (
+    SELECT route_id, stop_id, COALESCE(departure_delay, arrival_delay) AS delay
+    FROM gtfs.stop_time_history
+    WHERE chateau = $1
+        AND service_date >= $2
+        AND service_date <= $3
+        AND ($6::text IS NULL OR route_id = $6)
+        AND ($7::text IS NULL OR stop_id = $7)
+) AS delays
+WHERE delay IS NOT NULL
+GROUP BY GROUPING SETS ((), (route_id), (stop_id))";
+
+#[derive(Deserialize, Clone, Debug)]
+struct OnTimePerformanceQuery {
+    chateau: String,
+    route_id: Option<String>,
+    stop_id: Option<String>,
+    /// YYYY-MM-DD, inclusive
+    start_date: Option<NaiveDate>,
+    end_date: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct PunctualitySummary {
+    pub observations: u64,
+    pub early: u64,
+    pub on_time: u64,
+    pub late: u64,
+    pub on_time_ratio: Option<f64>,
+    pub average_delay_secs: Option<f64>,
+    pub median_delay_secs: Option<f64>,
+    pub p90_delay_secs: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OnTimePerformanceResponse {
+    pub chateau: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub overall: PunctualitySummary,
+    pub routes: BTreeMap<String, PunctualitySummary>,
+    pub stops: BTreeMap<String, PunctualitySummary>,
+}
+
+#[derive(QueryableByName, Clone, Debug)]
+struct PunctualityGroup {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    by_route: bool,
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    by_stop: bool,
+    #[diesel(sql_type = Nullable<Text>)]
+    route_id: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    stop_id: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    observations: i64,
+    #[diesel(sql_type = BigInt)]
+    early: i64,
+    #[diesel(sql_type = BigInt)]
+    late: i64,
+    #[diesel(sql_type = Nullable<diesel::sql_types::Double>)]
+    average_delay_secs: Option<f64>,
+    #[diesel(sql_type = Nullable<diesel::sql_types::Double>)]
+    median_delay_secs: Option<f64>,
+    #[diesel(sql_type = Nullable<diesel::sql_types::Double>)]
+    p90_delay_secs: Option<f64>,
+}
+
+impl PunctualityGroup {
+    fn summary(&self) -> PunctualitySummary {
+        let observations = self.observations.max(0) as u64;
+        let early = self.early.max(0) as u64;
+        let late = self.late.max(0) as u64;
+        let on_time = observations.saturating_sub(early + late);
+
+        PunctualitySummary {
+            observations,
+            early,
+            on_time,
+            late,
+            on_time_ratio: match observations {
+                0 => None,
+                _ => Some(on_time as f64 / observations as f64),
+            },
+            average_delay_secs: self.average_delay_secs,
+            median_delay_secs: self.median_delay_secs,
+            p90_delay_secs: self.p90_delay_secs,
+        }
+    }
+}
+
+/// Splits the grouping sets into the overall, per route and per stop summaries.
+/// Stop times without a route are only counted in the overall and per stop summaries.
+fn split_groups(
+    groups: &[PunctualityGroup],
+) -> (
+    PunctualitySummary,
+    BTreeMap<String, PunctualitySummary>,
+    BTreeMap<String, PunctualitySummary>,
+) {
+    let mut overall = PunctualitySummary::default();
+    let mut routes: BTreeMap<String, PunctualitySummary> = BTreeMap::new();
+    let mut stops: BTreeMap<String, PunctualitySummary> = BTreeMap::new();
+
+    for group in groups {
+        match (group.by_route, group.by_stop) {
+            (false, false) => overall = group.summary(),
+            (true, false) => {
+                if let Some(route_id) = &group.route_id {
+                    routes.insert(route_id.clone(), group.summary());
+                }
+            }
+            (false, true) => {
+                if let Some(stop_id) = &group.stop_id {
+                    stops.insert(stop_id.clone(), group.summary());
+                }
+            }
+            (true, true) => {}
+        }
+    }
+
+    (overall, routes, stops)
+}
+
+#[actix_web::get("/on_time_performance")]
+pub async fn on_time_performance(
+    query: Query<OnTimePerformanceQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    let query = query.into_inner();
+
+    let end_date = query
+        .end_date
+        .unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let start_date = query
+        .start_date
+        .unwrap_or(end_date - chrono::Duration::days(DEFAULT_DAYS - 1));
+
+    if start_date > end_date {
+        return HttpResponse::BadRequest().body("start_date is after end_date");
+    }
+
+    if (end_date - start_date).num_days() >= MAX_DAYS {
+        return HttpResponse::BadRequest()
+            .body(format!("At most {} days can be requested", MAX_DAYS));
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    let groups = diesel::sql_query(PUNCTUALITY_QUERY)
+        .bind::<Text, _>(&query.chateau)
+        .bind::<Date, _>(start_date)
+        .bind::<Date, _>(end_date)
+        .bind::<Integer, _>(ON_TIME_EARLIEST_SECS)
+        .bind::<Integer, _>(ON_TIME_LATEST_SECS)
+        .bind::<Nullable<Text>, _>(query.route_id.as_deref())
+        .bind::<Nullable<Text>, _>(query.stop_id.as_deref())
+        .get_results::<PunctualityGroup>(&mut conn)
+        .await;
+
+    let groups = match groups {
+        Ok(groups) => groups,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read stop time history");
+        }
+    };
+
+    let (overall, routes, stops) = split_groups(&groups);
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=3600"))
+        .json(OnTimePerformanceResponse {
+            chateau: query.chateau,
+            start_date,
+            end_date,
+            overall,
+            routes,
+            stops,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(
+        route_id: Option<&str>,
+        stop_id: Option<&str>,
+        by_route: bool,
+        by_stop: bool,
+        counts: (i64, i64, i64),
+    ) -> PunctualityGroup {
+        PunctualityGroup {
+            by_route,
+            by_stop,
+            route_id: route_id.map(String::from),
+            stop_id: stop_id.map(String::from),
+            observations: counts.0,
+            early: counts.1,
+            late: counts.2,
+            average_delay_secs: Some(90.0),
+            median_delay_secs: Some(45.0),
+            p90_delay_secs: Some(400.0),
+        }
+    }
+
+    #[test]
+    fn grouping_sets_are_split_by_kind() {
+        let groups = vec![
+            group(None, None, false, false, (10, 1, 2)),
+            group(Some("r1"), None, true, false, (6, 0, 2)),
+            // stop times without a route are grouped under a null route id
+            group(None, None, true, false, (4, 1, 0)),
+            group(None, Some("s1"), false, true, (10, 1, 2)),
+        ];
+
+        let (overall, routes, stops) = split_groups(&groups);
+
+        assert_eq!(overall.observations, 10);
+        assert_eq!(overall.on_time, 7);
+        assert_eq!(overall.on_time_ratio, Some(0.7));
+        assert_eq!(overall.p90_delay_secs, Some(400.0));
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes["r1"].on_time, 4);
+
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops["s1"].late, 2);
+    }
+
+    #[test]
+    fn no_archived_stop_times_give_an_empty_summary() {
+        let (overall, routes, stops) = split_groups(&[]);
+
+        assert_eq!(overall, PunctualitySummary::default());
+        assert_eq!(overall.on_time_ratio, None);
+        assert!(routes.is_empty());
+        assert!(stops.is_empty());
+    }
+}
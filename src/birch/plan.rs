@@ -0,0 +1,246 @@
+// Copyright
+// Catenary Transit Initiatives
+// Journey planning endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use crate::timetable_cache::{cached_timetable, TimetableCacheActixData, TimetableCacheKey};
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::prairie::raptor::{
+    raptor, Journey, RaptorQuery, DEFAULT_MAX_TRANSFERS, MAX_MAX_TRANSFERS,
+};
+use catenary::prairie::realtime::{
+    apply_realtime_to_timetable, check_journey_against_realtime, JourneyRealtimeStatus,
+    RealtimeOverlay,
+};
+use catenary::prairie::transfer_patterns::{
+    journeys_from_transfer_patterns, load_transfer_patterns,
+};
+use catenary::EtcdConnectionIps;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+// how far after the departure time to load trips for
+const PLAN_HORIZON_SECS: i64 = 3600 * 8;
+
+#[derive(Deserialize, Clone, Debug)]
+struct PlanQuery {
+    from_chateau: String,
+    from_stop_id: String,
+    to_chateau: String,
+    to_stop_id: String,
+    departure_time: Option<u64>,
+    max_transfers: Option<usize>,
+    // route on realtime trip times from Aspen instead of the schedule, defaults to true
+    // journeys routed on the schedule are still checked against realtime data
+    use_realtime: Option<bool>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct PlanDebug {
+    algorithm: &'static str,
+    timetable_load_ms: u128,
+    routing_ms: u128,
+    stops_loaded: usize,
+    patterns_loaded: usize,
+    realtime_ms: u128,
+    realtime_trips_loaded: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct PlannedJourney {
+    #[serde(flatten)]
+    journey: Journey,
+    realtime: Option<JourneyRealtimeStatus>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct PlanResponse {
+    journeys: Vec<PlannedJourney>,
+    debug: PlanDebug,
+}
+
+#[actix_web::get("/plan")]
+pub async fn plan(
+    query: Query<PlanQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
+    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    timetable_cache: web::Data<TimetableCacheActixData>,
+) -> impl Responder {
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre.unwrap();
+
+    let departure_time_chrono = match query.departure_time {
+        Some(x) => match catenary::datetime_from_unix_seconds(x) {
+            Some(departure_time_chrono) => departure_time_chrono,
+            None => return HttpResponse::BadRequest().body("Invalid departure time"),
+        },
+        None => chrono::Utc::now(),
+    };
+
+    let chateaus = BTreeSet::from_iter([query.from_chateau.clone(), query.to_chateau.clone()])
+        .into_iter()
+        .collect::<Vec<String>>();
+
+    let timetable_timer = Instant::now();
+
+    let timetable_key = TimetableCacheKey::new(
+        &chateaus,
+        departure_time_chrono.timestamp(),
+        PLAN_HORIZON_SECS,
+    );
+
+    let timetable = cached_timetable(conn, &timetable_cache, &timetable_key).await;
+
+    let timetable = match timetable {
+        Ok(timetable) => timetable,
+        Err(err) => {
+            eprintln!("{:#?}", err);
+            return HttpResponse::InternalServerError().body("Could not load timetable");
+        }
+    };
+
+    let timetable_load_duration = timetable_timer.elapsed();
+
+    let realtime_timer = Instant::now();
+
+    let mut overlay = RealtimeOverlay::default();
+
+    for chateau in chateaus.iter() {
+        let trip_ids = timetable
+            .patterns
+            .iter()
+            .filter(|pattern| pattern.chateau == *chateau)
+            .flat_map(|pattern| pattern.trips.iter().map(|trip| trip.trip_id.clone()))
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
+
+        if trip_ids.is_empty() {
+            continue;
+        }
+
+        if let Some(response) = crate::departures_at_stop::fetch_trips_from_aspen(
+            chateau,
+            trip_ids,
+            etcd_connection_ips.as_ref(),
+            etcd_connection_options.as_ref(),
+        )
+        .await
+        {
+            overlay.add_chateau(chateau, response);
+        }
+    }
+
+    // the scheduled timetable is kept to compare planned legs against
+    let realtime_timetable = match query.use_realtime.unwrap_or(true) && !overlay.is_empty() {
+        false => None,
+        true => {
+            let mut realtime_timetable = timetable.as_ref().clone();
+            apply_realtime_to_timetable(&mut realtime_timetable, &overlay);
+            Some(realtime_timetable)
+        }
+    };
+
+    let routing_timetable = realtime_timetable.as_ref().unwrap_or(timetable.as_ref());
+
+    let realtime_duration = realtime_timer.elapsed();
+
+    let origins = timetable.stop_and_children(&query.from_chateau, &query.from_stop_id);
+    let targets = timetable.stop_and_children(&query.to_chateau, &query.to_stop_id);
+
+    if origins.is_empty() || targets.is_empty() {
+        return HttpResponse::NotFound().body("Origin or destination stop not found");
+    }
+
+    let routing_timer = Instant::now();
+
+    let max_transfers = query
+        .max_transfers
+        .unwrap_or(DEFAULT_MAX_TRANSFERS)
+        .min(MAX_MAX_TRANSFERS);
+
+    // precomputed transfer patterns only exist within a single chateau
+    let transfer_patterns = match query.from_chateau == query.to_chateau {
+        true => {
+            match load_transfer_patterns(conn, routing_timetable, &query.from_chateau, &origins)
+                .await
+            {
+                Ok(transfer_patterns) => transfer_patterns,
+                Err(err) => {
+                    eprintln!("{:#?}", err);
+                    vec![]
+                }
+            }
+        }
+        false => vec![],
+    };
+
+    let (algorithm, journeys) = match journeys_from_transfer_patterns(
+        routing_timetable,
+        &transfer_patterns,
+        &origins,
+        &targets,
+        departure_time_chrono.timestamp(),
+        max_transfers,
+    ) {
+        Some(journeys) => ("transfer_patterns", journeys),
+        // the patterns do not cover this query
+        None => {
+            let raptor_query = RaptorQuery {
+                origins: origins
+                    .iter()
+                    .map(|stop| (*stop, departure_time_chrono.timestamp()))
+                    .collect(),
+                targets: targets.iter().map(|stop| (*stop, 0)).collect(),
+                max_transfers,
+            };
+
+            let result = raptor(routing_timetable, &raptor_query);
+
+            (
+                "raptor",
+                result.journeys(routing_timetable, &raptor_query.targets),
+            )
+        }
+    };
+
+    let routing_duration = routing_timer.elapsed();
+
+    let journeys = journeys
+        .into_iter()
+        .map(|journey| PlannedJourney {
+            realtime: match overlay.is_empty() {
+                true => None,
+                false => Some(check_journey_against_realtime(
+                    &timetable, &overlay, &journey,
+                )),
+            },
+            journey,
+        })
+        .collect::<Vec<PlannedJourney>>();
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-cache"))
+        .json(PlanResponse {
+            journeys,
+            debug: PlanDebug {
+                algorithm,
+                timetable_load_ms: timetable_load_duration.as_millis(),
+                routing_ms: routing_duration.as_millis(),
+                stops_loaded: timetable.stops.len(),
+                patterns_loaded: timetable.patterns.len(),
+                realtime_ms: realtime_duration.as_millis(),
+                realtime_trips_loaded: overlay.len(),
+            },
+        })
+}
@@ -54,16 +54,25 @@ use serde_derive::Serialize;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::Row;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 use tilejson::TileJSON;
 mod api_key_management;
 mod aspenised_data_over_https;
 mod chicago_proxy;
+mod fare_lookup;
+mod feed_health;
 mod get_vehicle_trip_information;
+mod gtfs_errors;
 mod gtfs_rt_api;
+mod gtfs_rt_validation;
+mod isochrone;
 mod nearby_departures;
+mod on_time_performance;
+mod plan;
 mod route_info;
+mod station_graph;
+mod timetable_cache;
 
 #[derive(Clone, Debug)]
 struct ChateauCache {
@@ -321,6 +330,12 @@ pub async fn station_features_meta(req: HttpRequest) -> impl Responder {
         String::from("children_route_types"),
         String::from("smallint[]"),
     );
+    fields.insert(String::from("level_name"), String::from("text"));
+    fields.insert(
+        String::from("level_index"),
+        String::from("double precision"),
+    );
+    fields.insert(String::from("elevator"), String::from("boolean"));
 
     let fields = tilejson::VectorLayer::new(String::from("data"), fields);
 
@@ -353,6 +368,12 @@ pub async fn station_features_meta(req: HttpRequest) -> impl Responder {
         .body(serde_json::to_string(&tile_json).unwrap())
 }
 
+// stops at either end of an elevator pathway
+const ELEVATOR_EXISTS_SQL: &str =
+    "EXISTS (SELECT 1 FROM gtfs.pathways WHERE pathways.onestop_feed_id = stops.onestop_feed_id
+        AND pathways.attempt_id = stops.attempt_id AND pathways.pathway_mode = 5
+        AND (pathways.from_stop_id = stops.gtfs_id OR pathways.to_stop_id = stops.gtfs_id))";
+
 #[actix_web::get("/station_features/{z}/{x}/{y}")]
 pub async fn station_features(
     sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
@@ -393,17 +414,23 @@ SELECT
     route_types,
     children_ids,
     children_route_types,
+    (SELECT level_name FROM gtfs.levels WHERE levels.onestop_feed_id = stops.onestop_feed_id
+        AND levels.attempt_id = stops.attempt_id AND levels.level_id = stops.level_id) AS level_name,
+    (SELECT level_index FROM gtfs.levels WHERE levels.onestop_feed_id = stops.onestop_feed_id
+        AND levels.attempt_id = stops.attempt_id AND levels.level_id = stops.level_id) AS level_index,
+    {elevator_exists} AS elevator,
     ST_AsMVTGeom(ST_Transform(point, 3857), 
     ST_TileEnvelope({z}, {x}, {y}), 4096, 64, true) AS geom
 FROM
     gtfs.stops
 WHERE
     (point && ST_Transform(ST_TileEnvelope({z}, {x}, {y}), 4326)) AND allowed_spatial_query = true
-    AND (location_type=2 OR location_type=3 OR location_type=4)
+    AND (location_type=2 OR location_type=3 OR location_type=4 OR {elevator_exists})
 ) q",
         z = z,
         x = x,
-        y = y
+        y = y,
+        elevator_exists = ELEVATOR_EXISTS_SQL
     );
 
     // println!("Performing query \n {}", query_str);
@@ -1616,6 +1643,14 @@ async fn main() -> std::io::Result<()> {
             _ => None,
         };
 
+    // shared by every worker, so the tiles of one isochrone are searched once
+    let isochrone_cache: isochrone::IsochroneCacheActixData =
+        Arc::new(Mutex::new(isochrone::IsochroneCache::default()));
+
+    // shared by every worker, so journeys planned in the same hour load the timetable once
+    let timetable_cache: timetable_cache::TimetableCacheActixData =
+        Arc::new(Mutex::new(timetable_cache::TimetableCache::default()));
+
     // Create a new HTTP server.
     let builder = HttpServer::new(move || {
         App::new()
@@ -1639,6 +1674,8 @@ async fn main() -> std::io::Result<()> {
                 etcd_connection_options.clone(),
             )))
             .app_data(actix_web::web::Data::new(Arc::clone(&etcd_connection_ips)))
+            .app_data(actix_web::web::Data::new(Arc::clone(&isochrone_cache)))
+            .app_data(actix_web::web::Data::new(Arc::clone(&timetable_cache)))
             .route("/", web::get().to(index))
             .route("robots.txt", web::get().to(robots))
             .service(amtrakproxy)
@@ -1665,6 +1702,15 @@ async fn main() -> std::io::Result<()> {
             .service(chicago_proxy::ttarrivals_proxy)
             .service(nearby_departures::nearby_from_coords)
             .service(departures_at_stop::departures_at_stop)
+            .service(plan::plan)
+            .service(isochrone::isochrone)
+            .service(isochrone::isochrone_tile)
+            .service(on_time_performance::on_time_performance)
+            .service(gtfs_rt_validation::gtfs_rt_validation)
+            .service(feed_health::feed_health)
+            .service(gtfs_errors::gtfs_errors)
+            .service(fare_lookup::fare)
+            .service(station_graph::station_graph)
             .service(get_vehicle_trip_information::get_trip_init)
             .service(get_vehicle_trip_information::get_trip_rt_update)
             .service(get_vehicle_trip_information::get_vehicle_information)
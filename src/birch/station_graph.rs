@@ -0,0 +1,272 @@
+// Copyright
+// Catenary Transit Initiatives
+// Station graph endpoint written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Inside of a station, from its child stops, pathways and levels,
+// with the fastest step-free route from every entrance to every platform.
+
+use actix_web::web;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
+use actix_web::Responder;
+use catenary::models::{Level, Pathway, Stop};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::station_graph::{step_free_routes, StepFreeRoute};
+use diesel::BoolExpressionMethods;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+#[derive(Deserialize, Clone, Debug)]
+struct StationGraphQuery {
+    chateau: String,
+    /// The station, or any stop inside it
+    stop_id: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StationNode {
+    pub stop_id: String,
+    pub name: Option<String>,
+    /// 0 platform, 1 station, 2 entrance, 3 generic node, 4 boarding area
+    pub location_type: i16,
+    pub parent_station: Option<String>,
+    pub level_id: Option<String>,
+    pub platform_code: Option<String>,
+    pub wheelchair_boarding: i16,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StationGraphResponse {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub station: StationNode,
+    pub nodes: Vec<StationNode>,
+    pub pathways: Vec<Pathway>,
+    pub levels: Vec<Level>,
+    pub step_free_routes: Vec<StepFreeRoute>,
+    /// Platforms no entrance reaches without stairs or escalators
+    pub platforms_without_step_free_route: Vec<String>,
+}
+
+fn to_node(stop: &Stop) -> StationNode {
+    StationNode {
+        stop_id: stop.gtfs_id.clone(),
+        name: stop.name.clone(),
+        location_type: stop.location_type,
+        parent_station: stop.parent_station.clone(),
+        level_id: stop.level_id.clone(),
+        platform_code: stop.platform_code.clone(),
+        wheelchair_boarding: stop.wheelchair_boarding,
+        lat: stop.point.as_ref().map(|point| point.y),
+        lon: stop.point.as_ref().map(|point| point.x),
+    }
+}
+
+#[actix_web::get("/station_graph")]
+pub async fn station_graph(
+    query: Query<StationGraphQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    use catenary::schema::gtfs::levels::dsl as levels;
+    use catenary::schema::gtfs::pathways::dsl as pathways;
+    use catenary::schema::gtfs::stops::dsl as stops;
+
+    let query = query.into_inner();
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+
+    let mut conn = match conn_pre {
+        Ok(conn) => conn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not connect to postgres");
+        }
+    };
+
+    // the attempt shown on the map
+    let requested_stop = stops::stops
+        .filter(stops::chateau.eq(&query.chateau))
+        .filter(stops::gtfs_id.eq(&query.stop_id))
+        .filter(stops::allowed_spatial_query.eq(true))
+        .select(Stop::as_select())
+        .first::<Stop>(&mut conn)
+        .await;
+
+    let requested_stop = match requested_stop {
+        Ok(requested_stop) => requested_stop,
+        Err(diesel::result::Error::NotFound) => {
+            return HttpResponse::NotFound().body("Stop not found");
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read stops");
+        }
+    };
+
+    let feed_id = requested_stop.onestop_feed_id.clone();
+    let attempt_id = requested_stop.attempt_id.clone();
+
+    let station_id = match (requested_stop.location_type, &requested_stop.parent_station) {
+        (1, _) | (_, None) => requested_stop.gtfs_id.clone(),
+        (_, Some(parent_station)) => parent_station.clone(),
+    };
+
+    // stops of the station, then boarding areas, which belong to platforms
+    let mut station_stops: Vec<Stop> = vec![];
+    let mut parent_ids: Vec<String> = vec![station_id.clone()];
+    let mut seen_ids: BTreeSet<String> = BTreeSet::new();
+
+    while !parent_ids.is_empty() {
+        let found = stops::stops
+            .filter(stops::onestop_feed_id.eq(&feed_id))
+            .filter(stops::attempt_id.eq(&attempt_id))
+            .filter(
+                stops::gtfs_id
+                    .eq_any(&parent_ids)
+                    .or(stops::parent_station.eq_any(&parent_ids)),
+            )
+            .select(Stop::as_select())
+            .load::<Stop>(&mut conn)
+            .await;
+
+        let found = match found {
+            Ok(found) => found,
+            Err(err) => {
+                eprintln!("{}", err);
+                return HttpResponse::InternalServerError().body("Could not read stops");
+            }
+        };
+
+        parent_ids = vec![];
+
+        for stop in found {
+            if seen_ids.insert(stop.gtfs_id.clone()) {
+                parent_ids.push(stop.gtfs_id.clone());
+                station_stops.push(stop);
+            }
+        }
+    }
+
+    let Some(station) = station_stops
+        .iter()
+        .find(|stop| stop.gtfs_id == station_id)
+        .map(to_node)
+    else {
+        return HttpResponse::NotFound().body("Station not found");
+    };
+
+    let node_ids = station_stops
+        .iter()
+        .map(|stop| stop.gtfs_id.clone())
+        .collect::<Vec<String>>();
+
+    let station_pathways = pathways::pathways
+        .filter(pathways::onestop_feed_id.eq(&feed_id))
+        .filter(pathways::attempt_id.eq(&attempt_id))
+        .filter(
+            pathways::from_stop_id
+                .eq_any(&node_ids)
+                .or(pathways::to_stop_id.eq_any(&node_ids)),
+        )
+        .select(Pathway::as_select())
+        .load::<Pathway>(&mut conn)
+        .await;
+
+    let station_pathways = match station_pathways {
+        Ok(station_pathways) => station_pathways,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read pathways");
+        }
+    };
+
+    let level_ids = station_stops
+        .iter()
+        .filter_map(|stop| stop.level_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let station_levels = levels::levels
+        .filter(levels::onestop_feed_id.eq(&feed_id))
+        .filter(levels::attempt_id.eq(&attempt_id))
+        .filter(levels::level_id.eq_any(&level_ids))
+        .order(levels::level_index)
+        .select(Level::as_select())
+        .load::<Level>(&mut conn)
+        .await;
+
+    let station_levels = match station_levels {
+        Ok(station_levels) => station_levels,
+        Err(err) => {
+            eprintln!("{}", err);
+            return HttpResponse::InternalServerError().body("Could not read levels");
+        }
+    };
+
+    let entrance_ids = station_stops
+        .iter()
+        .filter(|stop| stop.location_type == 2)
+        .map(|stop| stop.gtfs_id.clone())
+        .collect::<Vec<String>>();
+
+    let platform_ids = station_stops
+        .iter()
+        .filter(|stop| stop.location_type == 0)
+        .map(|stop| stop.gtfs_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    // reaching a boarding area reaches its platform
+    let targets = station_stops
+        .iter()
+        .filter_map(|stop| match stop.location_type {
+            0 => Some((stop.gtfs_id.clone(), stop.gtfs_id.clone())),
+            4 => stop
+                .parent_station
+                .clone()
+                .filter(|platform_id| platform_ids.contains(platform_id))
+                .map(|platform_id| (stop.gtfs_id.clone(), platform_id)),
+            _ => None,
+        })
+        .collect::<HashMap<String, String>>();
+
+    let routes = step_free_routes(&entrance_ids, &targets, &station_pathways);
+
+    let platforms_without_step_free_route = match station_pathways.is_empty() {
+        // stations without pathways say nothing about step-free access
+        true => vec![],
+        false => platform_ids
+            .iter()
+            .filter(|platform_id| {
+                !routes
+                    .iter()
+                    .any(|route| route.platform_id == **platform_id)
+            })
+            .cloned()
+            .collect::<Vec<String>>(),
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=3600"))
+        .json(StationGraphResponse {
+            onestop_feed_id: feed_id,
+            attempt_id,
+            station,
+            nodes: station_stops
+                .iter()
+                .filter(|stop| stop.gtfs_id != station_id)
+                .map(to_node)
+                .collect(),
+            pathways: station_pathways,
+            levels: station_levels,
+            step_free_routes: routes,
+            platforms_without_step_free_route,
+        })
+}
@@ -0,0 +1,165 @@
+// Copyright
+// Catenary Transit Initiatives
+// Timetable cache written by Kyler Chin <kyler@catenarymaps.org>
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use ahash::AHashMap;
+use catenary::prairie::timetable::{load_timetable, Timetable};
+use diesel_async::AsyncPgConnection;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+// timetables start on the hour, so every request departing within the same hour shares one
+const WINDOW_ALIGN_SECS: i64 = 3600;
+
+// a new schedule is picked up once the cached timetable expires
+const CACHE_TTL: Duration = Duration::from_secs(600);
+const CACHE_MAX_ENTRIES: usize = 32;
+
+/// The chateaus and service window a timetable was loaded for
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct TimetableCacheKey {
+    chateaus: Vec<String>,
+    window_start: i64,
+    window_end: i64,
+}
+
+impl TimetableCacheKey {
+    /// Covers at least `departure_time` to `departure_time + horizon_secs`
+    pub fn new(chateaus: &[String], departure_time: i64, horizon_secs: i64) -> Self {
+        let mut chateaus = chateaus.to_vec();
+        chateaus.sort();
+        chateaus.dedup();
+
+        let window_start = departure_time - departure_time.rem_euclid(WINDOW_ALIGN_SECS);
+
+        TimetableCacheKey {
+            chateaus,
+            window_start,
+            window_end: window_start + WINDOW_ALIGN_SECS + horizon_secs,
+        }
+    }
+}
+
+type TimetableLoad = Arc<OnceCell<Arc<Timetable>>>;
+
+/// Timetables by key, loaded by whichever request asks first while the others wait on it
+#[derive(Default)]
+pub struct TimetableCache {
+    timetables: AHashMap<TimetableCacheKey, (Instant, TimetableLoad)>,
+}
+
+pub type TimetableCacheActixData = Arc<Mutex<TimetableCache>>;
+
+impl TimetableCache {
+    fn timetable(&mut self, key: &TimetableCacheKey, now: Instant) -> TimetableLoad {
+        self.timetables
+            .retain(|_, (created, _)| now.duration_since(*created) < CACHE_TTL);
+
+        if !self.timetables.contains_key(key) && self.timetables.len() >= CACHE_MAX_ENTRIES {
+            let oldest = self
+                .timetables
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(oldest, _)| oldest.clone());
+
+            if let Some(oldest) = oldest {
+                self.timetables.remove(&oldest);
+            }
+        }
+
+        Arc::clone(
+            &self
+                .timetables
+                .entry(key.clone())
+                .or_insert_with(|| (now, Arc::new(OnceCell::new())))
+                .1,
+        )
+    }
+}
+
+pub async fn cached_timetable(
+    conn: &mut AsyncPgConnection,
+    cache: &TimetableCacheActixData,
+    key: &TimetableCacheKey,
+) -> Result<Arc<Timetable>, Box<dyn Error + Send + Sync>> {
+    let load = cache.lock().unwrap().timetable(key, Instant::now());
+
+    // a failed load leaves the cell empty, so the next request tries again
+    let timetable = load
+        .get_or_try_init(|| async {
+            let window_start = chrono::DateTime::from_timestamp(key.window_start, 0)
+                .ok_or("Timetable window is out of range")?;
+            let window_end = chrono::DateTime::from_timestamp(key.window_end, 0)
+                .ok_or("Timetable window is out of range")?;
+
+            load_timetable(conn, &key.chateaus, window_start, window_end)
+                .await
+                .map(Arc::new)
+        })
+        .await?;
+
+    Ok(Arc::clone(timetable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chateaus(chateaus: &[&str]) -> Vec<String> {
+        chateaus.iter().map(|chateau| chateau.to_string()).collect()
+    }
+
+    #[test]
+    fn requests_within_the_hour_share_a_window_covering_their_horizon() {
+        let key = TimetableCacheKey::new(&chateaus(&["metro", "metrolink"]), 1_726_500_059, 600);
+
+        assert_eq!(
+            key,
+            TimetableCacheKey::new(&chateaus(&["metrolink", "metro"]), 1_726_498_800, 600)
+        );
+        assert_eq!(key.window_start, 1_726_498_800);
+        assert!(key.window_start <= 1_726_500_059);
+        assert!(key.window_end >= 1_726_500_059 + 600);
+
+        assert_ne!(
+            key,
+            TimetableCacheKey::new(&chateaus(&["metro", "metrolink"]), 1_726_502_400, 600)
+        );
+    }
+
+    #[test]
+    fn timetables_expire_and_the_oldest_is_dropped_when_full() {
+        let mut cache = TimetableCache::default();
+        let now = Instant::now();
+
+        let keys = (0..=CACHE_MAX_ENTRIES as i64)
+            .map(|i| TimetableCacheKey::new(&chateaus(&["metro"]), i * 3600, 600))
+            .collect::<Vec<TimetableCacheKey>>();
+
+        for (i, key) in keys.iter().enumerate() {
+            cache
+                .timetable(key, now + Duration::from_millis(i as u64))
+                .set(Arc::new(Timetable::default()))
+                .unwrap();
+        }
+
+        assert_eq!(cache.timetables.len(), CACHE_MAX_ENTRIES);
+        assert!(!cache.timetables.contains_key(&keys[0]));
+        assert!(cache
+            .timetable(&keys[CACHE_MAX_ENTRIES], now + Duration::from_secs(10))
+            .initialized());
+
+        // expired timetables are loaded again
+        assert!(!cache
+            .timetable(
+                &keys[CACHE_MAX_ENTRIES],
+                now + CACHE_TTL + Duration::from_secs(1)
+            )
+            .initialized());
+    }
+}
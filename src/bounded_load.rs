@@ -0,0 +1,170 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Assigns weighted items (chateaus, feeds) to workers with bounded loads, for the Aspen and Alpenrose leaders.
+// No worker is given more than LOAD_FACTOR times the average weight while another worker has room,
+// and items stay on their worker while it is under the bound, so changing weights do not shuffle them around.
+
+use std::collections::BTreeMap;
+
+/// A worker keeps its items until it holds more than this times the average load
+pub const LOAD_FACTOR: f64 = 1.25;
+
+/// Weight of the newest measurement in the moving average of a cost
+pub const SMOOTHING: f64 = 0.2;
+
+/// Moves a moving average towards the newest measurement
+pub fn smoothed(average: f64, measurement: f64) -> f64 {
+    average + SMOOTHING * (measurement - average)
+}
+
+/// Most weight a worker is given, and never less than the heaviest item, which has to go somewhere
+pub fn capacity(items: &[(String, f64)], worker_count: usize) -> f64 {
+    let total_weight = items.iter().map(|(_, weight)| weight).sum::<f64>();
+    let heaviest = items.iter().map(|(_, weight)| *weight).fold(0.0, f64::max);
+
+    (LOAD_FACTOR * total_weight / worker_count.max(1) as f64).max(heaviest)
+}
+
+/// item id -> worker id, for items given as (item id, weight).
+///
+/// Items are placed heaviest first, so the overflow is made of small items, and the same on every leader.
+/// 1. `pins` put items on their worker whatever the load, if the worker exists
+/// 2. items stay on the first worker returned by `kept` which has room
+/// 3. the rest go to the first worker returned by `preferred` which has room, or else the least loaded worker
+pub fn assign_bounded<'k, 'p>(
+    items: &[(String, f64)],
+    workers: &[String],
+    pins: &BTreeMap<String, String>,
+    kept: impl Fn(&str) -> Vec<&'k String>,
+    preferred: impl Fn(&str) -> Vec<&'p String>,
+) -> BTreeMap<String, String> {
+    let mut assignments: BTreeMap<String, String> = BTreeMap::new();
+
+    if workers.is_empty() {
+        return assignments;
+    }
+
+    let capacity = capacity(items, workers.len());
+
+    let mut items = items.iter().collect::<Vec<&(String, f64)>>();
+    items.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut loads: BTreeMap<&String, f64> =
+        workers.iter().map(|worker_id| (worker_id, 0.0)).collect();
+
+    for (item_id, weight) in items.iter() {
+        if let Some(pinned_worker) = pins.get(item_id) {
+            if let Some(load) = loads.get_mut(pinned_worker) {
+                *load += weight;
+                assignments.insert(item_id.clone(), pinned_worker.clone());
+            }
+        }
+    }
+
+    for (item_id, weight) in items.iter() {
+        if assignments.contains_key(item_id) {
+            continue;
+        }
+
+        let kept_worker = kept(item_id.as_str()).into_iter().find(|worker_id| {
+            loads
+                .get(*worker_id)
+                .is_some_and(|load| load + weight <= capacity)
+        });
+
+        if let Some(kept_worker) = kept_worker {
+            *loads.get_mut(kept_worker).unwrap() += weight;
+            assignments.insert(item_id.clone(), kept_worker.clone());
+        }
+    }
+
+    // new items, and items of removed or full workers
+    for (item_id, weight) in items.iter() {
+        if assignments.contains_key(item_id) {
+            continue;
+        }
+
+        let chosen = preferred(item_id.as_str())
+            .into_iter()
+            .find(|worker_id| {
+                loads
+                    .get(*worker_id)
+                    .is_some_and(|load| load + weight <= capacity)
+            })
+            .cloned()
+            .unwrap_or_else(|| {
+                loads
+                    .iter()
+                    .min_by(|a, b| a.1.total_cmp(b.1).then_with(|| a.0.cmp(b.0)))
+                    .map(|(worker_id, _)| (*worker_id).clone())
+                    .unwrap()
+            });
+
+        *loads.get_mut(&chosen).unwrap() += weight;
+        assignments.insert(item_id.clone(), chosen);
+    }
+
+    assignments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workers(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("w{}", i)).collect()
+    }
+
+    #[test]
+    fn kept_and_preferred_workers_are_used_only_while_they_have_room() {
+        let items = vec![
+            (String::from("a"), 4.0),
+            (String::from("b"), 4.0),
+            (String::from("c"), 4.0),
+            (String::from("d"), 4.0),
+        ];
+        let workers = workers(2);
+
+        // capacity is 1.25 * 16 / 2 = 10, so w0 holds two items at most
+        let assignments = assign_bounded(
+            &items,
+            &workers,
+            &BTreeMap::new(),
+            |_| vec![&workers[0]],
+            |_| vec![&workers[0]],
+        );
+
+        assert_eq!(assignments["a"], "w0");
+        assert_eq!(assignments["b"], "w0");
+        assert_eq!(assignments["c"], "w1");
+        assert_eq!(assignments["d"], "w1");
+    }
+
+    #[test]
+    fn pins_are_placed_whatever_the_load_and_without_workers_nothing_is() {
+        let items = vec![(String::from("a"), 10.0), (String::from("b"), 10.0)];
+        let workers = workers(2);
+        let pins = BTreeMap::from([
+            (String::from("a"), String::from("w1")),
+            (String::from("b"), String::from("w1")),
+        ]);
+
+        let assignments = assign_bounded(&items, &workers, &pins, |_| vec![], |_| vec![]);
+
+        assert_eq!(assignments["a"], "w1");
+        assert_eq!(assignments["b"], "w1");
+
+        assert!(assign_bounded(&items, &[], &pins, |_| vec![], |_| vec![]).is_empty());
+    }
+
+    #[test]
+    fn capacity_fits_the_heaviest_item() {
+        let items = vec![(String::from("a"), 300.0), (String::from("b"), 10.0)];
+
+        assert!((capacity(&items, 2) - 300.0).abs() < 1e-9);
+        assert!((capacity(&items, 1) - 1.25 * 310.0).abs() < 1e-9);
+        assert!((smoothed(100.0, 200.0) - 120.0).abs() < 1e-9);
+    }
+}
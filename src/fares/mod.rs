@@ -0,0 +1,927 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Prices a sequence of legs within one feed, using Fares v2 when the feed has leg rules,
+// and fare_attributes.txt with fare_rules.txt otherwise.
+// Leg rules limited to timeframes are not used, as timeframes.txt is not ingested.
+// Legacy contains_id rules only see the zones of the first and last stop of a leg.
+
+use crate::models::{
+    FareAttribute, FareLegRule, FareProduct, FareRule, FareTransferRule, Network, StopArea,
+};
+use ahash::{AHashMap, AHashSet};
+use serde::{Deserialize, Serialize};
+
+/// One ride, with stops already resolved by the caller
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct FareLeg {
+    pub route_id: String,
+    /// The boarding stop followed by its parent station
+    pub from_stop_ids: Vec<String>,
+    /// The alighting stop followed by its parent station
+    pub to_stop_ids: Vec<String>,
+    pub from_zone_id: Option<String>,
+    pub to_zone_id: Option<String>,
+    /// Unix seconds
+    pub departure_time: Option<u64>,
+    /// Unix seconds
+    pub arrival_time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FareSource {
+    FaresV2,
+    Legacy,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PricedLeg {
+    pub fare_product_id: Option<String>,
+    pub fare_product_name: Option<String>,
+    pub fare_media_id: Option<String>,
+    pub leg_group_id: Option<String>,
+    /// Price of the leg on its own
+    pub base_amount: Option<f64>,
+    /// Price charged for the leg after transfers from the previous legs
+    pub amount: Option<f64>,
+    /// Fare product or fare id of the transfer into this leg
+    pub transfer_from_previous: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FarePrice {
+    pub source: FareSource,
+    pub currency: Option<String>,
+    /// None when a leg could not be priced
+    pub total: Option<f64>,
+    pub legs: Vec<PricedLeg>,
+}
+
+/// Fare tables of one attempt of a feed
+#[derive(Default)]
+pub struct FeedFares {
+    pub fare_products: Vec<FareProduct>,
+    pub fare_leg_rules: Vec<FareLegRule>,
+    pub fare_transfer_rules: Vec<FareTransferRule>,
+    pub fare_attributes: Vec<FareAttribute>,
+    pub fare_rules: Vec<FareRule>,
+    stop_id_to_area_ids: AHashMap<String, Vec<String>>,
+    route_id_to_network_ids: AHashMap<String, Vec<String>>,
+}
+
+fn round_amount(amount: f64) -> f64 {
+    (amount * 1_000_000.0).round() / 1_000_000.0
+}
+
+/// An empty field in a rule matches everything
+fn field_matches(rule_field: &Option<String>, values: &AHashSet<&str>) -> bool {
+    match rule_field {
+        Some(rule_field) => values.contains(rule_field.as_str()),
+        None => true,
+    }
+}
+
+/// Without rule_priority, a rule naming a field wins over rules leaving it empty
+fn keep_most_specific<T, F>(candidates: &mut Vec<&T>, field: F)
+where
+    F: Fn(&T) -> &Option<String>,
+{
+    if candidates
+        .iter()
+        .any(|candidate| field(candidate).is_some())
+    {
+        candidates.retain(|candidate| field(candidate).is_some());
+    }
+}
+
+impl FeedFares {
+    pub fn new(
+        fare_products: Vec<FareProduct>,
+        fare_leg_rules: Vec<FareLegRule>,
+        fare_transfer_rules: Vec<FareTransferRule>,
+        fare_attributes: Vec<FareAttribute>,
+        fare_rules: Vec<FareRule>,
+        stop_areas: Vec<StopArea>,
+        networks: Vec<Network>,
+    ) -> FeedFares {
+        let mut stop_id_to_area_ids: AHashMap<String, Vec<String>> = AHashMap::new();
+
+        for stop_area in stop_areas {
+            stop_id_to_area_ids
+                .entry(stop_area.stop_id)
+                .or_default()
+                .push(stop_area.area_id);
+        }
+
+        let mut route_id_to_network_ids: AHashMap<String, Vec<String>> = AHashMap::new();
+
+        for network in networks {
+            for route_id in network.route_ids.into_iter().flatten() {
+                route_id_to_network_ids
+                    .entry(route_id)
+                    .or_default()
+                    .push(network.network_id.clone());
+            }
+        }
+
+        FeedFares {
+            fare_products,
+            fare_leg_rules,
+            fare_transfer_rules,
+            fare_attributes,
+            fare_rules,
+            stop_id_to_area_ids,
+            route_id_to_network_ids,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fare_leg_rules.is_empty() && self.fare_attributes.is_empty()
+    }
+
+    /// None when the feed has no fare data
+    pub fn price_legs(&self, legs: &[FareLeg], fare_media_id: Option<&str>) -> Option<FarePrice> {
+        match (
+            self.fare_leg_rules.is_empty(),
+            self.fare_attributes.is_empty(),
+        ) {
+            (false, _) => Some(self.price_legs_v2(legs, fare_media_id)),
+            (true, false) => Some(self.price_legs_legacy(legs)),
+            (true, true) => None,
+        }
+    }
+
+    fn area_ids(&self, stop_ids: &[String]) -> AHashSet<&str> {
+        stop_ids
+            .iter()
+            .filter_map(|stop_id| self.stop_id_to_area_ids.get(stop_id))
+            .flatten()
+            .map(|area_id| area_id.as_str())
+            .collect()
+    }
+
+    fn matching_leg_rules(&self, leg: &FareLeg) -> Vec<&FareLegRule> {
+        let network_ids: AHashSet<&str> = self
+            .route_id_to_network_ids
+            .get(&leg.route_id)
+            .map(|network_ids| network_ids.iter().map(|id| id.as_str()).collect())
+            .unwrap_or_default();
+        let from_area_ids = self.area_ids(&leg.from_stop_ids);
+        let to_area_ids = self.area_ids(&leg.to_stop_ids);
+
+        let mut candidates = self
+            .fare_leg_rules
+            .iter()
+            .filter(|rule| {
+                rule.from_timeframe_group_id.is_none() && rule.to_timeframe_group_id.is_none()
+            })
+            .filter(|rule| field_matches(&rule.network_id, &network_ids))
+            .filter(|rule| field_matches(&rule.from_area_id, &from_area_ids))
+            .filter(|rule| field_matches(&rule.to_area_id, &to_area_ids))
+            .collect::<Vec<&FareLegRule>>();
+
+        let uses_priority = self
+            .fare_leg_rules
+            .iter()
+            .any(|rule| rule.rule_priority.is_some());
+
+        match uses_priority {
+            true => {
+                let highest_priority = candidates
+                    .iter()
+                    .map(|rule| rule.rule_priority.unwrap_or(0))
+                    .max();
+
+                candidates.retain(|rule| Some(rule.rule_priority.unwrap_or(0)) == highest_priority);
+            }
+            false => {
+                keep_most_specific(&mut candidates, |rule: &FareLegRule| &rule.network_id);
+                keep_most_specific(&mut candidates, |rule: &FareLegRule| &rule.from_area_id);
+                keep_most_specific(&mut candidates, |rule: &FareLegRule| &rule.to_area_id);
+            }
+        }
+
+        candidates
+    }
+
+    /// Cheapest row of a product, preferring the requested fare media
+    fn cheapest_product(
+        &self,
+        fare_product_ids: &[&str],
+        fare_media_id: Option<&str>,
+    ) -> Option<&FareProduct> {
+        let rows = self
+            .fare_products
+            .iter()
+            .filter(|product| fare_product_ids.contains(&product.fare_product_id.as_str()))
+            .collect::<Vec<&FareProduct>>();
+
+        let on_media = rows
+            .iter()
+            .filter(|product| {
+                fare_media_id.is_some() && product.fare_media_id.as_deref() == fare_media_id
+            })
+            .copied()
+            .collect::<Vec<&FareProduct>>();
+
+        let rows = match on_media.is_empty() {
+            true => rows,
+            false => on_media,
+        };
+
+        rows.into_iter()
+            .min_by(|a, b| a.amount.total_cmp(&b.amount))
+    }
+
+    fn matching_transfer_rule(
+        &self,
+        from_leg_group_id: &Option<String>,
+        to_leg_group_id: &Option<String>,
+    ) -> Option<&FareTransferRule> {
+        let (Some(from_leg_group_id), Some(to_leg_group_id)) = (from_leg_group_id, to_leg_group_id)
+        else {
+            return None;
+        };
+
+        let from_group: AHashSet<&str> = [from_leg_group_id.as_str()].into_iter().collect();
+        let to_group: AHashSet<&str> = [to_leg_group_id.as_str()].into_iter().collect();
+
+        let mut candidates = self
+            .fare_transfer_rules
+            .iter()
+            .filter(|rule| field_matches(&rule.from_leg_group_id, &from_group))
+            .filter(|rule| field_matches(&rule.to_leg_group_id, &to_group))
+            .collect::<Vec<&FareTransferRule>>();
+
+        keep_most_specific(&mut candidates, |rule: &FareTransferRule| {
+            &rule.from_leg_group_id
+        });
+        keep_most_specific(&mut candidates, |rule: &FareTransferRule| {
+            &rule.to_leg_group_id
+        });
+
+        candidates.into_iter().next()
+    }
+
+    fn price_legs_v2(&self, legs: &[FareLeg], fare_media_id: Option<&str>) -> FarePrice {
+        let mut priced_legs: Vec<PricedLeg> = vec![];
+        let mut currency: Option<String> = None;
+        let mut mixed_currencies = false;
+
+        // first leg of the current run of transfers, and transfers made since
+        let mut chain_start: Option<&FareLeg> = None;
+        let mut chain_transfers: i32 = 0;
+
+        for (leg_index, leg) in legs.iter().enumerate() {
+            let rules = self.matching_leg_rules(leg);
+
+            let product = self.cheapest_product(
+                &rules
+                    .iter()
+                    .map(|rule| rule.fare_product_id.as_str())
+                    .collect::<Vec<&str>>(),
+                fare_media_id,
+            );
+
+            let leg_group_id = product.and_then(|product| {
+                rules
+                    .iter()
+                    .find(|rule| rule.fare_product_id == product.fare_product_id)
+                    .and_then(|rule| rule.leg_group_id.clone())
+            });
+
+            if let Some(product) = product {
+                match &currency {
+                    None => currency = Some(product.currency.clone()),
+                    Some(currency) => mixed_currencies |= *currency != product.currency,
+                }
+            }
+
+            let base_amount = product.map(|product| product.amount);
+            let mut amount = base_amount;
+            let mut transfer_from_previous = None;
+
+            let previous_leg = leg_index
+                .checked_sub(1)
+                .and_then(|previous_index| legs.get(previous_index));
+
+            if let (Some(previous), Some(previous_leg), Some(chain_start_leg)) =
+                (priced_legs.last(), previous_leg, chain_start)
+            {
+                let transfer_rule =
+                    self.matching_transfer_rule(&previous.leg_group_id, &leg_group_id);
+
+                let transfer_rule = transfer_rule.filter(|rule| {
+                    let within_count = match rule.transfer_count {
+                        Some(-1) | None => true,
+                        Some(transfer_count) => chain_transfers < transfer_count,
+                    };
+
+                    let within_duration = match rule.duration_limit {
+                        Some(duration_limit) => {
+                            let (start, end) = match rule.duration_limit_type.unwrap_or(0) {
+                                0 => (chain_start_leg.departure_time, leg.arrival_time),
+                                1 => (chain_start_leg.departure_time, leg.departure_time),
+                                2 => (previous_leg.arrival_time, leg.departure_time),
+                                _ => (previous_leg.arrival_time, leg.arrival_time),
+                            };
+
+                            match (start, end) {
+                                (Some(start), Some(end)) => {
+                                    end.saturating_sub(start) <= duration_limit as u64
+                                }
+                                _ => true,
+                            }
+                        }
+                        None => true,
+                    };
+
+                    within_count && within_duration
+                });
+
+                if let Some(transfer_rule) = transfer_rule {
+                    let transfer_amount = match &transfer_rule.fare_product_id {
+                        Some(fare_product_id) => self
+                            .cheapest_product(&[fare_product_id.as_str()], fare_media_id)
+                            .map(|product| product.amount),
+                        None => Some(0.0),
+                    };
+
+                    amount = match transfer_rule.fare_transfer_type {
+                        // A + AB
+                        0 => transfer_amount,
+                        // A + AB + B
+                        1 => base_amount
+                            .zip(transfer_amount)
+                            .map(|(base, transfer)| base + transfer),
+                        // AB, which replaces what was charged for the previous leg
+                        _ => transfer_amount
+                            .zip(previous.amount)
+                            .map(|(transfer, previous)| (transfer - previous).max(0.0)),
+                    };
+
+                    transfer_from_previous = Some(
+                        transfer_rule
+                            .fare_product_id
+                            .clone()
+                            .unwrap_or_else(|| String::from("free_transfer")),
+                    );
+                }
+            }
+
+            match transfer_from_previous.is_some() {
+                true => chain_transfers += 1,
+                false => {
+                    chain_start = Some(leg);
+                    chain_transfers = 0;
+                }
+            }
+
+            priced_legs.push(PricedLeg {
+                fare_product_id: product.map(|product| product.fare_product_id.clone()),
+                fare_product_name: product.and_then(|product| product.fare_product_name.clone()),
+                fare_media_id: product.and_then(|product| product.fare_media_id.clone()),
+                leg_group_id,
+                base_amount,
+                amount: amount.map(round_amount),
+                transfer_from_previous,
+            });
+        }
+
+        let total = match mixed_currencies {
+            true => None,
+            false => priced_legs
+                .iter()
+                .map(|leg| leg.amount)
+                .sum::<Option<f64>>()
+                .map(round_amount),
+        };
+
+        FarePrice {
+            source: FareSource::FaresV2,
+            currency,
+            total,
+            legs: priced_legs,
+        }
+    }
+
+    fn legacy_fare_matches(&self, fare_id: &str, leg: &FareLeg) -> bool {
+        let rules = self
+            .fare_rules
+            .iter()
+            .filter(|rule| rule.fare_id == fare_id)
+            .collect::<Vec<&FareRule>>();
+
+        if rules.is_empty() {
+            return true;
+        }
+
+        let leg_zones = [&leg.from_zone_id, &leg.to_zone_id]
+            .into_iter()
+            .flatten()
+            .map(|zone_id| zone_id.as_str())
+            .collect::<AHashSet<&str>>();
+
+        rules.iter().any(|rule| {
+            rule.route_id
+                .as_ref()
+                .map(|route_id| *route_id == leg.route_id)
+                .unwrap_or(true)
+                && rule
+                    .origin_id
+                    .as_ref()
+                    .map(|origin_id| Some(origin_id) == leg.from_zone_id.as_ref())
+                    .unwrap_or(true)
+                && rule
+                    .destination_id
+                    .as_ref()
+                    .map(|destination_id| Some(destination_id) == leg.to_zone_id.as_ref())
+                    .unwrap_or(true)
+                && field_matches(&rule.contains_id, &leg_zones)
+        })
+    }
+
+    fn price_legs_legacy(&self, legs: &[FareLeg]) -> FarePrice {
+        let mut priced_legs: Vec<PricedLeg> = vec![];
+        let mut currency: Option<String> = None;
+        let mut mixed_currencies = false;
+
+        // fare paid at the start of the current run of transfers
+        let mut chain: Option<(&FareAttribute, &FareLeg, i16)> = None;
+
+        for leg in legs {
+            let fare = self
+                .fare_attributes
+                .iter()
+                .filter(|fare| self.legacy_fare_matches(&fare.fare_id, leg))
+                .min_by(|a, b| a.price.total_cmp(&b.price));
+
+            if let Some(fare) = fare {
+                match &currency {
+                    None => currency = Some(fare.currency_type.clone()),
+                    Some(currency) => mixed_currencies |= *currency != fare.currency_type,
+                }
+            }
+
+            let transfer = match (chain, fare) {
+                (Some((chain_fare, chain_start_leg, transfers_used)), Some(fare))
+                    if chain_fare.fare_id == fare.fare_id =>
+                {
+                    let within_count = chain_fare
+                        .transfers
+                        .map(|transfers| transfers_used < transfers)
+                        .unwrap_or(true);
+
+                    let within_duration = match (
+                        chain_fare.transfer_duration,
+                        chain_start_leg.departure_time,
+                        leg.departure_time,
+                    ) {
+                        (Some(transfer_duration), Some(start), Some(departure)) => {
+                            departure.saturating_sub(start) <= transfer_duration as u64
+                        }
+                        _ => true,
+                    };
+
+                    within_count && within_duration
+                }
+                _ => false,
+            };
+
+            match (transfer, fare) {
+                (true, _) => {
+                    if let Some(chain) = chain.as_mut() {
+                        chain.2 += 1;
+                    }
+                }
+                (false, Some(fare)) => chain = Some((fare, leg, 0)),
+                (false, None) => chain = None,
+            }
+
+            priced_legs.push(PricedLeg {
+                fare_product_id: fare.map(|fare| fare.fare_id.clone()),
+                fare_product_name: None,
+                fare_media_id: None,
+                leg_group_id: None,
+                base_amount: fare.map(|fare| fare.price),
+                amount: match transfer {
+                    true => Some(0.0),
+                    false => fare.map(|fare| fare.price),
+                },
+                transfer_from_previous: match transfer {
+                    true => fare.map(|fare| fare.fare_id.clone()),
+                    false => None,
+                },
+            });
+        }
+
+        let total = match mixed_currencies {
+            true => None,
+            false => priced_legs
+                .iter()
+                .map(|leg| leg.amount)
+                .sum::<Option<f64>>()
+                .map(round_amount),
+        };
+
+        FarePrice {
+            source: FareSource::Legacy,
+            currency,
+            total,
+            legs: priced_legs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(fare_product_id: &str, amount: f64) -> FareProduct {
+        FareProduct {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            row_index: 0,
+            fare_product_id: fare_product_id.to_string(),
+            fare_product_name: None,
+            fare_media_id: None,
+            amount,
+            currency: String::from("USD"),
+            chateau: String::from("test"),
+        }
+    }
+
+    fn leg_rule(network_id: Option<&str>, fare_product_id: &str) -> FareLegRule {
+        FareLegRule {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            row_index: 0,
+            leg_group_id: Some(String::from("local")),
+            network_id: network_id.map(|network_id| network_id.to_string()),
+            from_area_id: None,
+            to_area_id: None,
+            from_timeframe_group_id: None,
+            to_timeframe_group_id: None,
+            fare_product_id: fare_product_id.to_string(),
+            rule_priority: None,
+            chateau: String::from("test"),
+        }
+    }
+
+    fn leg(route_id: &str, departure_time: u64) -> FareLeg {
+        FareLeg {
+            route_id: route_id.to_string(),
+            departure_time: Some(departure_time),
+            arrival_time: Some(departure_time + 600),
+            ..FareLeg::default()
+        }
+    }
+
+    fn transfer_rule(
+        fare_transfer_type: i16,
+        fare_product_id: Option<&str>,
+        duration_limit: Option<i32>,
+        duration_limit_type: Option<i16>,
+    ) -> FareTransferRule {
+        FareTransferRule {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            row_index: 0,
+            from_leg_group_id: Some(String::from("local")),
+            to_leg_group_id: Some(String::from("local")),
+            transfer_count: Some(-1),
+            duration_limit,
+            duration_limit_type,
+            fare_transfer_type,
+            fare_product_id: fare_product_id.map(|fare_product_id| fare_product_id.to_string()),
+            chateau: String::from("test"),
+        }
+    }
+
+    /// Two bus legs 20 minutes apart, with one transfer rule between them
+    fn price_bus_transfer(
+        transfer_rule: FareTransferRule,
+        transfer_product: FareProduct,
+    ) -> FarePrice {
+        let fares = FeedFares::new(
+            vec![product("bus", 2.0), transfer_product],
+            vec![leg_rule(None, "bus")],
+            vec![transfer_rule],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        fares
+            .price_legs(&[leg("1", 0), leg("2", 1200)], None)
+            .unwrap()
+    }
+
+    fn fare_attribute(
+        fare_id: &str,
+        price: f64,
+        transfers: Option<i16>,
+        transfer_duration: Option<i32>,
+    ) -> FareAttribute {
+        FareAttribute {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            fare_id: fare_id.to_string(),
+            price,
+            currency_type: String::from("USD"),
+            payment_method: 0,
+            transfers,
+            agency_id: None,
+            transfer_duration,
+            chateau: String::from("test"),
+        }
+    }
+
+    fn fare_rule(
+        fare_id: &str,
+        route_id: Option<&str>,
+        origin_id: Option<&str>,
+        destination_id: Option<&str>,
+        contains_id: Option<&str>,
+    ) -> FareRule {
+        FareRule {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            row_index: 0,
+            fare_id: fare_id.to_string(),
+            route_id: route_id.map(|route_id| route_id.to_string()),
+            origin_id: origin_id.map(|origin_id| origin_id.to_string()),
+            destination_id: destination_id.map(|destination_id| destination_id.to_string()),
+            contains_id: contains_id.map(|contains_id| contains_id.to_string()),
+            chateau: String::from("test"),
+        }
+    }
+
+    fn zone_leg(
+        route_id: &str,
+        departure_time: u64,
+        from_zone_id: &str,
+        to_zone_id: &str,
+    ) -> FareLeg {
+        FareLeg {
+            from_zone_id: Some(from_zone_id.to_string()),
+            to_zone_id: Some(to_zone_id.to_string()),
+            ..leg(route_id, departure_time)
+        }
+    }
+
+    #[test]
+    fn network_rule_wins_and_transfer_is_free() {
+        let fares = FeedFares::new(
+            vec![product("bus", 2.0), product("rail", 3.5)],
+            vec![leg_rule(None, "bus"), leg_rule(Some("rail"), "rail")],
+            vec![FareTransferRule {
+                onestop_feed_id: String::from("f-test"),
+                attempt_id: String::from("f-test-1"),
+                row_index: 0,
+                from_leg_group_id: Some(String::from("local")),
+                to_leg_group_id: Some(String::from("local")),
+                transfer_count: Some(1),
+                duration_limit: Some(5400),
+                duration_limit_type: Some(1),
+                fare_transfer_type: 0,
+                fare_product_id: None,
+                chateau: String::from("test"),
+            }],
+            vec![],
+            vec![],
+            vec![],
+            vec![Network {
+                onestop_feed_id: String::from("f-test"),
+                attempt_id: String::from("f-test-1"),
+                network_id: String::from("rail"),
+                network_name: None,
+                route_ids: vec![Some(String::from("red"))],
+                chateau: String::from("test"),
+            }],
+        );
+
+        let price = fares
+            .price_legs(&[leg("red", 0), leg("1", 1200), leg("2", 2400)], None)
+            .unwrap();
+
+        assert_eq!(price.source, FareSource::FaresV2);
+        assert_eq!(price.legs[0].fare_product_id.as_deref(), Some("rail"));
+        assert_eq!(price.legs[1].amount, Some(0.0));
+        // only one transfer is allowed
+        assert_eq!(price.legs[2].amount, Some(2.0));
+        assert_eq!(price.total, Some(5.5));
+    }
+
+    #[test]
+    fn transfer_type_0_charges_the_transfer_product_instead_of_the_leg() {
+        let price = price_bus_transfer(
+            transfer_rule(0, Some("transfer"), None, None),
+            product("transfer", 0.5),
+        );
+
+        assert_eq!(price.legs[1].base_amount, Some(2.0));
+        assert_eq!(price.legs[1].amount, Some(0.5));
+        assert_eq!(
+            price.legs[1].transfer_from_previous.as_deref(),
+            Some("transfer")
+        );
+        assert_eq!(price.total, Some(2.5));
+    }
+
+    #[test]
+    fn transfer_type_1_charges_the_transfer_product_on_top_of_the_leg() {
+        let price = price_bus_transfer(
+            transfer_rule(1, Some("transfer"), None, None),
+            product("transfer", 0.5),
+        );
+
+        assert_eq!(price.legs[1].amount, Some(2.5));
+        assert_eq!(price.total, Some(4.5));
+    }
+
+    #[test]
+    fn transfer_type_2_replaces_the_previous_leg_with_the_combined_product() {
+        let price = price_bus_transfer(
+            transfer_rule(2, Some("pass"), None, None),
+            product("pass", 3.0),
+        );
+
+        // the pass costs 3.00 and 2.00 was already paid on the first leg
+        assert_eq!(price.legs[1].amount, Some(1.0));
+        assert_eq!(price.total, Some(3.0));
+
+        // a combined product cheaper than the first leg is never a refund
+        let price = price_bus_transfer(
+            transfer_rule(2, Some("pass"), None, None),
+            product("pass", 1.5),
+        );
+
+        assert_eq!(price.legs[1].amount, Some(0.0));
+        assert_eq!(price.total, Some(2.0));
+    }
+
+    #[test]
+    fn duration_limit_is_measured_per_limit_type() {
+        // 600 seconds pass between the first arrival and the second departure,
+        // 1800 seconds between the first departure and the second arrival
+        let between_legs = price_bus_transfer(
+            transfer_rule(0, None, Some(900), Some(2)),
+            product("transfer", 0.5),
+        );
+
+        assert_eq!(between_legs.legs[1].amount, Some(0.0));
+        assert_eq!(
+            between_legs.legs[1].transfer_from_previous.as_deref(),
+            Some("free_transfer")
+        );
+
+        let whole_journey = price_bus_transfer(
+            transfer_rule(0, None, Some(900), Some(0)),
+            product("transfer", 0.5),
+        );
+
+        assert_eq!(whole_journey.legs[1].amount, Some(2.0));
+        assert_eq!(whole_journey.legs[1].transfer_from_previous, None);
+        assert_eq!(whole_journey.total, Some(4.0));
+    }
+
+    #[test]
+    fn legacy_fares_match_routes_and_zones_and_limit_transfers() {
+        let fares = FeedFares::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![
+                fare_attribute("local", 1.75, Some(1), Some(3600)),
+                fare_attribute("zone", 3.5, Some(0), None),
+            ],
+            vec![
+                fare_rule("local", Some("1"), None, None, None),
+                fare_rule("local", Some("2"), None, None, None),
+                fare_rule("zone", None, Some("A"), Some("B"), None),
+            ],
+            vec![],
+            vec![],
+        );
+
+        let price = fares
+            .price_legs(
+                &[
+                    leg("1", 0),
+                    leg("2", 1200),
+                    // the one transfer of local has been used
+                    leg("1", 2400),
+                    zone_leg("9", 3000, "A", "B"),
+                    // zone does not allow transfers
+                    zone_leg("9", 3600, "A", "B"),
+                ],
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(price.source, FareSource::Legacy);
+        assert_eq!(price.currency.as_deref(), Some("USD"));
+        assert_eq!(
+            price
+                .legs
+                .iter()
+                .map(|leg| leg.fare_product_id.as_deref())
+                .collect::<Vec<_>>(),
+            vec![
+                Some("local"),
+                Some("local"),
+                Some("local"),
+                Some("zone"),
+                Some("zone")
+            ]
+        );
+        assert_eq!(price.legs[1].amount, Some(0.0));
+        assert_eq!(
+            price.legs[1].transfer_from_previous.as_deref(),
+            Some("local")
+        );
+        assert_eq!(price.legs[2].amount, Some(1.75));
+        assert_eq!(price.legs[4].amount, Some(3.5));
+        assert_eq!(price.total, Some(10.5));
+    }
+
+    #[test]
+    fn legacy_transfer_expires_after_transfer_duration() {
+        let fares = FeedFares::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![fare_attribute("local", 1.75, None, Some(3600))],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        let price = fares
+            .price_legs(&[leg("1", 0), leg("2", 3600), leg("3", 3601)], None)
+            .unwrap();
+
+        assert_eq!(price.legs[1].amount, Some(0.0));
+        // measured from the departure of the leg the fare was paid on
+        assert_eq!(price.legs[2].amount, Some(1.75));
+        assert_eq!(price.total, Some(3.5));
+    }
+
+    #[test]
+    fn legacy_contains_id_and_unmatched_legs() {
+        let fares = FeedFares::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![fare_attribute("downtown", 1.25, Some(0), None)],
+            vec![fare_rule("downtown", None, None, None, Some("C"))],
+            vec![],
+            vec![],
+        );
+
+        let price = fares
+            .price_legs(&[zone_leg("1", 0, "A", "C")], None)
+            .unwrap();
+
+        assert_eq!(price.legs[0].fare_product_id.as_deref(), Some("downtown"));
+        assert_eq!(price.total, Some(1.25));
+
+        // a leg no fare covers leaves the journey without a total
+        let price = fares
+            .price_legs(
+                &[zone_leg("1", 0, "A", "C"), zone_leg("1", 1200, "A", "B")],
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(price.legs[1].fare_product_id, None);
+        assert_eq!(price.legs[1].amount, None);
+        assert_eq!(price.total, None);
+    }
+
+    #[test]
+    fn leg_rules_take_precedence_over_fare_attributes() {
+        let fares = FeedFares::new(
+            vec![product("bus", 2.0)],
+            vec![leg_rule(None, "bus")],
+            vec![],
+            vec![fare_attribute("local", 1.75, None, None)],
+            vec![],
+            vec![],
+            vec![],
+        );
+
+        let price = fares.price_legs(&[leg("1", 0)], None).unwrap();
+
+        assert_eq!(price.source, FareSource::FaresV2);
+        assert_eq!(price.total, Some(2.0));
+
+        assert!(FeedFares::default()
+            .price_legs(&[leg("1", 0)], None)
+            .is_none());
+    }
+}
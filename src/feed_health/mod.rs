@@ -0,0 +1,210 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Health of realtime feeds over their last few fetches, kept in gtfs.realtime_feed_health.
+// Alpenrose writes what it saw over HTTP, Aspen writes what it found inside the feed.
+// Each side only updates its own columns, so they can write at any time.
+
+use crate::models::RealtimeFeedHealthRow;
+use crate::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of fetches and ingestions kept for each feed
+pub const RECENT_RECORD_COUNT: usize = 20;
+
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One HTTP request made by Alpenrose
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FetchRecord {
+    pub time_ms: u64,
+    /// vehicles, trips or alerts
+    pub feed_type: String,
+    pub http_status: Option<u16>,
+    pub latency_ms: u64,
+    pub bytes: Option<u64>,
+    /// Set when no response was received, such as on timeouts
+    pub error: Option<String>,
+}
+
+impl FetchRecord {
+    pub fn is_success(&self) -> bool {
+        matches!(self.http_status, Some(200) | Some(304))
+    }
+}
+
+/// One feed decoded by Aspen
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IngestRecord {
+    pub time_ms: u64,
+    pub feed_type: String,
+    pub entity_count: usize,
+    pub header_timestamp: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FetchHistory {
+    pub last_success_ms: Option<u64>,
+    pub fetches: VecDeque<FetchRecord>,
+}
+
+impl FetchHistory {
+    pub fn push(&mut self, record: FetchRecord) {
+        if record.is_success() {
+            self.last_success_ms = self.last_success_ms.max(Some(record.time_ms));
+        }
+
+        push_recent(&mut self.fetches, record);
+    }
+}
+
+pub fn push_recent<T>(records: &mut VecDeque<T>, record: T) {
+    records.push_back(record);
+
+    while records.len() > RECENT_RECORD_COUNT {
+        records.pop_front();
+    }
+}
+
+pub async fn save_fetch_histories(
+    conn_pool: &CatenaryPostgresPool,
+    histories: Vec<(String, FetchHistory)>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    use crate::schema::gtfs::realtime_feed_health::dsl as realtime_feed_health;
+
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let now_ms = crate::duration_since_unix_epoch().as_millis() as i64;
+
+    for (realtime_feed_id, history) in histories {
+        let recent_fetches = serde_json::to_value(&history.fetches)?;
+        let last_success_ms = history.last_success_ms.map(|time| time as i64);
+
+        let insert = diesel::insert_into(realtime_feed_health::realtime_feed_health)
+            .values(RealtimeFeedHealthRow {
+                realtime_feed_id,
+                fetches_updated_ms: Some(now_ms),
+                last_success_ms,
+                recent_fetches: Some(recent_fetches.clone()),
+                ingests_updated_ms: None,
+                recent_ingests: None,
+            })
+            .on_conflict(realtime_feed_health::realtime_feed_id)
+            .do_update();
+
+        // a restarted worker has not seen a success yet, so keep the stored one
+        match last_success_ms {
+            Some(last_success_ms) => {
+                insert
+                    .set((
+                        realtime_feed_health::fetches_updated_ms.eq(Some(now_ms)),
+                        realtime_feed_health::last_success_ms.eq(Some(last_success_ms)),
+                        realtime_feed_health::recent_fetches.eq(Some(recent_fetches)),
+                    ))
+                    .execute(conn)
+                    .await?;
+            }
+            None => {
+                insert
+                    .set((
+                        realtime_feed_health::fetches_updated_ms.eq(Some(now_ms)),
+                        realtime_feed_health::recent_fetches.eq(Some(recent_fetches)),
+                    ))
+                    .execute(conn)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn save_ingest_histories(
+    conn_pool: &CatenaryPostgresPool,
+    histories: Vec<(String, VecDeque<IngestRecord>)>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    use crate::schema::gtfs::realtime_feed_health::dsl as realtime_feed_health;
+
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let now_ms = crate::duration_since_unix_epoch().as_millis() as i64;
+
+    for (realtime_feed_id, ingests) in histories {
+        let recent_ingests = serde_json::to_value(&ingests)?;
+
+        diesel::insert_into(realtime_feed_health::realtime_feed_health)
+            .values(RealtimeFeedHealthRow {
+                realtime_feed_id,
+                fetches_updated_ms: None,
+                last_success_ms: None,
+                recent_fetches: None,
+                ingests_updated_ms: Some(now_ms),
+                recent_ingests: Some(recent_ingests.clone()),
+            })
+            .on_conflict(realtime_feed_health::realtime_feed_id)
+            .do_update()
+            .set((
+                realtime_feed_health::ingests_updated_ms.eq(Some(now_ms)),
+                realtime_feed_health::recent_ingests.eq(Some(recent_ingests)),
+            ))
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(time_ms: u64, http_status: Option<u16>) -> FetchRecord {
+        FetchRecord {
+            time_ms,
+            feed_type: String::from("vehicles"),
+            http_status,
+            latency_ms: 100,
+            bytes: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn keeps_last_success_after_it_rolls_out() {
+        let mut history = FetchHistory::default();
+
+        history.push(fetch(1, Some(200)));
+
+        for time_ms in 2..(RECENT_RECORD_COUNT as u64 + 10) {
+            history.push(fetch(time_ms, Some(503)));
+        }
+
+        assert_eq!(history.fetches.len(), RECENT_RECORD_COUNT);
+        assert!(history.fetches.iter().all(|fetch| !fetch.is_success()));
+        assert_eq!(history.last_success_ms, Some(1));
+    }
+
+    #[test]
+    fn not_modified_is_a_success_and_late_records_do_not_move_it_back() {
+        let mut history = FetchHistory::default();
+
+        // a timeout never got a status
+        history.push(fetch(5, None));
+        assert_eq!(history.last_success_ms, None);
+
+        history.push(fetch(10, Some(304)));
+        assert_eq!(history.last_success_ms, Some(10));
+
+        // a slow request that started earlier finishes after it
+        history.push(fetch(8, Some(200)));
+        assert_eq!(history.last_success_ms, Some(10));
+        assert_eq!(history.fetches.len(), 3);
+    }
+}
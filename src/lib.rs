@@ -39,18 +39,26 @@ extern crate diesel_derive_newtype;
 extern crate serde;
 
 pub mod agency_secret;
+pub mod alpenrose;
 pub mod aspen;
+pub mod bounded_load;
 pub mod custom_pg_types;
 pub mod enum_to_int;
+pub mod fares;
+pub mod feed_health;
 pub mod gtfs_rt_handlers;
 pub mod gtfs_rt_rough_hash;
 pub mod id_cleanup;
 pub mod ip_to_location;
 pub mod maple_syrup;
 pub mod models;
+pub mod osm_extractor;
 pub mod postgis_to_diesel;
 pub mod postgres_tools;
+pub mod prairie;
 pub mod schema;
+pub mod station_graph;
+pub mod validate_gtfs;
 pub mod validate_gtfs_rt;
 use crate::aspen::lib::RealtimeFeedMetadataEtcd;
 use ahash::AHasher;
@@ -60,6 +68,7 @@ use fasthash::MetroHasher;
 use gtfs_realtime::{FeedEntity, FeedMessage};
 use gtfs_structures::RouteType;
 use schema::gtfs::trip_frequencies::start_time;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::hash::Hash;
 use std::hash::Hasher;
@@ -152,6 +161,18 @@ pub fn duration_since_unix_epoch() -> Duration {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
 }
 
+/// Latest unix time accepted from requests, the end of the year 9999
+const MAX_REQUEST_UNIX_SECONDS: u64 = 253_402_300_799;
+
+/// A unix time from a request, or None if it is too far away to search around
+pub fn datetime_from_unix_seconds(seconds: u64) -> Option<chrono::DateTime<chrono::Utc>> {
+    if seconds > MAX_REQUEST_UNIX_SECONDS {
+        return None;
+    }
+
+    chrono::DateTime::from_timestamp(seconds as i64, 0)
+}
+
 pub mod tailscale {
     //stolen from tailscale-rs
     //significantly adapted by Kyler Chin to use ipv6 addressing
@@ -183,13 +204,15 @@ pub mod tailscale {
 }
 
 pub mod aspen_dataset {
+    // These types go over tarpc and into Aspen snapshots as bincode, which has no field names or defaults.
+    // Adding a field changes the format, see "Changing the data format" in the Aspen README before deploying.
     use crate::RtCacheEntry;
     use crate::RtKey;
     use ahash::AHashMap;
     use compact_str::CompactString;
     use std::hash::Hash;
 
-    #[derive(Clone, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize)]
     pub struct AspenisedData {
         pub vehicle_positions: AHashMap<String, AspenisedVehiclePosition>,
         pub vehicle_routes_cache: AHashMap<String, AspenisedVehicleRouteCache>,
@@ -203,16 +226,38 @@ pub mod aspen_dataset {
         pub impacted_routes_alerts: AHashMap<String, Vec<String>>,
         pub impacted_stops_alerts: AHashMap<String, Vec<String>>,
         pub impacted_trips_alerts: AHashMap<String, Vec<String>>,
+        /// Recent alert changes, oldest first
+        pub alert_events: Vec<AspenisedAlertEvent>,
+        /// Sequence number of the latest alert event
+        pub alert_event_sequence: u64,
         pub last_updated_time_ms: u64,
     }
 
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum AlertEventKind {
+        New,
+        Changed,
+        Removed,
+    }
+
+    /// A service alert which appeared, changed or disappeared between two ingestions of a chateau
     #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct AspenisedAlertEvent {
+        pub sequence: u64,
+        pub kind: AlertEventKind,
+        pub alert_id: String,
+        /// For removed alerts, the last version of the alert
+        pub alert: AspenisedAlert,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenTimeRange {
         pub start: Option<u64>,
         pub end: Option<u64>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenEntitySelector {
         pub agency_id: Option<String>,
         pub route_id: Option<String>,
@@ -222,23 +267,23 @@ pub mod aspen_dataset {
         pub direction_id: Option<u32>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenTranslatedString {
         pub translation: Vec<AspenTranslation>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenTranslation {
         pub text: String,
         pub language: Option<String>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenTranslatedImage {
         pub localised_image: Vec<AspenLocalisedImage>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenLocalisedImage {
         pub url: String,
         pub media_type: String,
@@ -335,7 +380,7 @@ pub mod aspen_dataset {
         }
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenisedAlert {
         pub active_period: Vec<AspenTimeRange>,
         pub informed_entity: Vec<AspenEntitySelector>,
@@ -372,7 +417,7 @@ pub mod aspen_dataset {
         pub shape_id: Option<String>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenRawTripInfo {
         pub trip_id: Option<String>,
         pub route_id: Option<String>,
@@ -383,7 +428,7 @@ pub mod aspen_dataset {
         pub modified_trip: Option<ModifiedTripSelector>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct ModifiedTripSelector {
         pub modifications_id: Option<String>,
         pub affected_trip_id: Option<String>,
@@ -442,11 +487,39 @@ pub mod aspen_dataset {
         }
     }
 
+    /// Realtime data of a single stop, as refreshed on the trip information screen
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct StopTimeRefresh {
+        pub stop_id: Option<compact_str::CompactString>,
+        pub rt_arrival: Option<AspenStopTimeEvent>,
+        pub rt_departure: Option<AspenStopTimeEvent>,
+        pub schedule_relationship: Option<i32>,
+        pub gtfs_stop_sequence: Option<u16>,
+        pub rt_platform_string: Option<String>,
+        pub departure_occupancy_status: Option<i32>,
+    }
+
+    impl From<&AspenisedStopTimeUpdate> for StopTimeRefresh {
+        fn from(stop_time_update: &AspenisedStopTimeUpdate) -> Self {
+            StopTimeRefresh {
+                stop_id: stop_time_update.stop_id.clone(),
+                rt_arrival: stop_time_update.arrival.clone(),
+                rt_departure: stop_time_update.departure.clone(),
+                schedule_relationship: stop_time_update.schedule_relationship,
+                gtfs_stop_sequence: stop_time_update.stop_sequence.map(|x| x as u16),
+                rt_platform_string: stop_time_update.platform_string.clone(),
+                departure_occupancy_status: stop_time_update.departure_occupancy_status,
+            }
+        }
+    }
+
     #[derive(Clone, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
     pub struct AspenStopTimeEvent {
         pub delay: Option<i32>,
         pub time: Option<i64>,
         pub uncertainty: Option<i32>,
+        /// Estimated by Aspen from the delay earlier in the trip, instead of given by the feed
+        pub predicted: bool,
     }
 
     impl From<StopTimeEvent> for AspenStopTimeEvent {
@@ -455,11 +528,12 @@ pub mod aspen_dataset {
                 delay: stop_time_event.delay,
                 time: stop_time_event.time,
                 uncertainty: stop_time_event.uncertainty,
+                predicted: false,
             }
         }
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenisedVehiclePosition {
         pub trip: Option<AspenisedVehicleTripInfo>,
         pub vehicle: Option<AspenisedVehicleDescriptor>,
@@ -471,9 +545,11 @@ pub mod aspen_dataset {
         pub congestion_level: Option<i32>,
         pub occupancy_status: Option<i32>,
         pub occupancy_percentage: Option<u32>,
+        /// Position was interpolated from trip updates, as the feed has no GPS for this vehicle
+        pub position_estimated: bool,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct CatenaryRtVehiclePosition {
         pub latitude: f32,
         pub longitude: f32,
@@ -482,7 +558,7 @@ pub mod aspen_dataset {
         pub speed: Option<f32>,
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenisedVehicleDescriptor {
         pub id: Option<String>,
         pub label: Option<String>,
@@ -503,7 +579,7 @@ pub mod aspen_dataset {
         }
     }
 
-    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct AspenisedVehicleTripInfo {
         pub trip_id: Option<String>,
         pub trip_headsign: Option<String>,
@@ -669,6 +745,36 @@ pub struct EtcdConnectionIps {
     pub ip_addresses: Vec<String>,
 }
 
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CategoryOfRealtimeVehicleData {
+    Metro,
+    Bus,
+    Rail,
+    Other,
+}
+
+impl CategoryOfRealtimeVehicleData {
+    pub fn from_path_str(category: &str) -> Option<CategoryOfRealtimeVehicleData> {
+        match category {
+            "metro" => Some(CategoryOfRealtimeVehicleData::Metro),
+            "bus" => Some(CategoryOfRealtimeVehicleData::Bus),
+            "rail" => Some(CategoryOfRealtimeVehicleData::Rail),
+            "other" => Some(CategoryOfRealtimeVehicleData::Other),
+            _ => None,
+        }
+    }
+}
+
+pub fn category_to_allowed_route_ids(category: &CategoryOfRealtimeVehicleData) -> Vec<i16> {
+    match category {
+        CategoryOfRealtimeVehicleData::Metro => vec![0, 1, 12],
+        CategoryOfRealtimeVehicleData::Bus => vec![3, 11],
+        CategoryOfRealtimeVehicleData::Rail => vec![2],
+        CategoryOfRealtimeVehicleData::Other => vec![4, 5, 6, 7],
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SerializableStop {
     pub id: String,
@@ -763,6 +869,58 @@ impl CalendarUnified {
     }
 }
 
+pub fn make_calendar_structure_from_pg_single_chateau(
+    services_calendar_lookup_queries_to_perform: Vec<crate::models::Calendar>,
+    services_calendar_dates_lookup_queries_to_perform: Vec<crate::models::CalendarDate>,
+) -> BTreeMap<String, CalendarUnified> {
+    let mut calendar_structures: BTreeMap<String, CalendarUnified> = BTreeMap::new();
+
+    for calendar in services_calendar_lookup_queries_to_perform {
+        calendar_structures.insert(
+            calendar.service_id.clone(),
+            CalendarUnified {
+                id: calendar.service_id.clone(),
+                general_calendar: Some(GeneralCalendar {
+                    days: make_weekdays(&calendar),
+                    start_date: calendar.gtfs_start_date,
+                    end_date: calendar.gtfs_end_date,
+                }),
+                exceptions: None,
+            },
+        );
+    }
+
+    for calendar_date in services_calendar_dates_lookup_queries_to_perform {
+        let exception_number = match calendar_date.exception_type {
+            1 => gtfs_structures::Exception::Added,
+            2 => gtfs_structures::Exception::Deleted,
+            _ => panic!("WHAT IS THIS!!!!!!"),
+        };
+
+        match calendar_structures.entry(calendar_date.service_id.clone()) {
+            std::collections::btree_map::Entry::Occupied(mut oe) => {
+                let calendar_unified = oe.get_mut();
+
+                if let Some(entry) = &mut calendar_unified.exceptions {
+                    entry.insert(calendar_date.gtfs_date, exception_number);
+                } else {
+                    calendar_unified.exceptions = Some(BTreeMap::from_iter([(
+                        calendar_date.gtfs_date,
+                        exception_number,
+                    )]));
+                }
+            }
+            std::collections::btree_map::Entry::Vacant(ve) => {
+                ve.insert(CalendarUnified::empty_exception_from_calendar_date(
+                    &calendar_date,
+                ));
+            }
+        }
+    }
+
+    calendar_structures
+}
+
 pub struct TripToFindScheduleFor {
     pub trip_id: String,
     pub chateau: String,
@@ -897,4 +1055,15 @@ mod test_calendar {
             direction_id: "0".to_string(),
         };
     }
+
+    #[test]
+    fn request_times_beyond_chrono_are_rejected() {
+        assert_eq!(
+            datetime_from_unix_seconds(1_726_500_000).map(|x| x.timestamp()),
+            Some(1_726_500_000)
+        );
+        assert!(datetime_from_unix_seconds(MAX_REQUEST_UNIX_SECONDS).is_some());
+        assert!(datetime_from_unix_seconds(MAX_REQUEST_UNIX_SECONDS + 1).is_none());
+        assert!(datetime_from_unix_seconds(u64::MAX).is_none());
+    }
 }
@@ -2,6 +2,9 @@
 // Catenary Transit Initiatives
 // Attribution cannot be removed
 
+use crate::gtfs_ingestion_sequence::fares_into_postgres::delete_fares;
+use crate::gtfs_ingestion_sequence::flex_into_postgres::delete_flex;
+use crate::gtfs_ingestion_sequence::pathways_into_postgres::delete_pathways;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use diesel::query_dsl::methods::FilterDsl;
 use diesel::BoolExpressionMethods;
@@ -176,6 +179,10 @@ pub async fn delete_attempt_objects(
     .execute(conn)
     .await?;
 
+    delete_fares(feed_id, Some(attempt_id), Arc::clone(&pool)).await?;
+    delete_pathways(feed_id, Some(attempt_id), Arc::clone(&pool)).await?;
+    delete_flex(feed_id, Some(attempt_id), Arc::clone(&pool)).await?;
+
     //delete ingested static_download_attempts
     /*
 
@@ -299,6 +306,18 @@ pub async fn wipe_whole_feed(
     .execute(conn)
     .await?;
 
+    use catenary::schema::gtfs::gtfs_errors;
+
+    let _ = diesel::delete(
+        gtfs_errors::dsl::gtfs_errors.filter(gtfs_errors::dsl::onestop_feed_id.eq(&feed_id)),
+    )
+    .execute(conn)
+    .await?;
+
+    delete_fares(feed_id, None, Arc::clone(&pool)).await?;
+    delete_pathways(feed_id, None, Arc::clone(&pool)).await?;
+    delete_flex(feed_id, None, Arc::clone(&pool)).await?;
+
     //delete ingested static_download_attempts
     /*
 
@@ -0,0 +1,1082 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Diff ingestion patches the production attempt of a feed in place instead of writing a whole new attempt.
+// Itinerary and direction pattern ids are content hashes, so the new schedule is compared against the rows
+// already in postgres, and only inserted, changed and deleted rows are written, inside one transaction.
+// Smaller tables, fares, pathways and flex zones included, are replaced whole in that same transaction.
+// Stops and shapes are not compared, so a change to stops.txt or shapes.txt, or a schedule where too much
+// changed, falls back to a full ingest.
+
+use crate::gtfs_handlers::MAPLE_INGESTION_VERSION;
+use crate::gtfs_ingestion_sequence::calendar_into_postgres::calendar_to_pg;
+use crate::gtfs_ingestion_sequence::extra_stop_to_stop_shapes_into_postgres::insert_stop_to_stop_geometry;
+use crate::gtfs_ingestion_sequence::fares_into_postgres::{
+    delete_fare_rows, insert_fares, read_fares,
+};
+use crate::gtfs_ingestion_sequence::flex_into_postgres::{
+    delete_flex_rows, insert_flex, read_flex,
+};
+use crate::gtfs_ingestion_sequence::pathways_into_postgres::{
+    delete_pathway_rows, insert_pathways, read_pathways,
+};
+use crate::gtfs_process::{
+    agencies_to_pg, direction_pattern_to_pg, feed_info_to_pg, itinerary_to_pg, routes_to_pg,
+    stop_to_stop_linestring, trips_to_pg,
+};
+use ahash::AHashMap;
+use catenary::maple_syrup::ResponseFromReduce;
+use catenary::models::{
+    CompressedTrip, DirectionPatternMeta, DirectionPatternRow, ItineraryPatternMeta,
+    ItineraryPatternRow,
+};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::RunQueryDsl;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::hash::Hasher;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Files which must be unchanged for a feed to be diff ingested
+pub const DIFF_COMPARED_FILES: [&str; 2] = ["stops.txt", "shapes.txt"];
+
+/// Above this share of changed trips and itineraries, a full ingest is cheaper
+const MAX_CHANGED_SHARE: f64 = 0.3;
+
+pub fn diff_ingest_enabled() -> bool {
+    std::env::var("DIFF_INGEST").is_ok()
+}
+
+pub fn hash_gtfs_files(gtfs_path: &str) -> BTreeMap<String, u64> {
+    DIFF_COMPARED_FILES
+        .iter()
+        .filter_map(|file_name| {
+            let mut file = std::fs::File::open(format!("{}/{}", gtfs_path, file_name)).ok()?;
+            let mut hasher = seahash::SeaHasher::new();
+            let mut buffer = vec![0u8; 1 << 16];
+
+            loop {
+                let read = file.read(&mut buffer).ok()?;
+
+                if read == 0 {
+                    break;
+                }
+
+                hasher.write(&buffer[..read]);
+            }
+
+            Some((file_name.to_string(), hasher.finish()))
+        })
+        .collect()
+}
+
+pub struct DiffBase {
+    pub attempt_id: String,
+    pub file_hashes: BTreeMap<String, u64>,
+}
+
+/// The attempt to patch, when the feed has exactly one attempt in production from this version of Maple
+pub async fn find_diff_base(
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<Option<DiffBase>, Box<dyn Error + Send + Sync>> {
+    use catenary::schema::gtfs::ingested_static::dsl as ingested_static;
+
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let production_attempts = ingested_static::ingested_static
+        .filter(ingested_static::onestop_feed_id.eq(feed_id))
+        .filter(ingested_static::production.eq(true))
+        .filter(ingested_static::deleted.eq(false))
+        .filter(ingested_static::ingestion_successfully_finished.eq(true))
+        .select(catenary::models::IngestedStatic::as_select())
+        .load::<catenary::models::IngestedStatic>(conn)
+        .await?;
+
+    // several attempts in production split the dates of the feed between them, which a diff cannot keep
+    if production_attempts.len() != 1 {
+        return Ok(None);
+    }
+
+    let production_attempt = &production_attempts[0];
+
+    if production_attempt.ingestion_version != MAPLE_INGESTION_VERSION {
+        return Ok(None);
+    }
+
+    let file_hashes = production_attempt
+        .file_hashes
+        .clone()
+        .and_then(|file_hashes| serde_json::from_value::<BTreeMap<String, u64>>(file_hashes).ok());
+
+    Ok(file_hashes.map(|file_hashes| DiffBase {
+        attempt_id: production_attempt.attempt_id.clone(),
+        file_hashes,
+    }))
+}
+
+#[derive(Debug, Default)]
+pub struct DiffCounts {
+    pub trips_inserted: usize,
+    pub trips_deleted: usize,
+    pub itineraries_inserted: usize,
+    pub itineraries_deleted: usize,
+    pub itineraries_updated: usize,
+    pub direction_patterns_inserted: usize,
+    pub direction_patterns_deleted: usize,
+    pub direction_patterns_updated: usize,
+}
+
+pub enum DiffOutcome {
+    Applied(DiffCounts),
+    /// Reason for doing a full ingest instead
+    Fallback(String),
+}
+
+/// Trip ids are kept in reduction order, which changes between runs
+fn with_sorted_trip_ids(meta: &ItineraryPatternMeta) -> ItineraryPatternMeta {
+    let mut meta = meta.clone();
+    meta.trip_ids.sort();
+    meta
+}
+
+/// Rows the diff writes to the production attempt
+pub struct DiffPlan {
+    pub trips_to_delete: Vec<String>,
+    pub trips_to_insert: Vec<CompressedTrip>,
+    pub itineraries_to_delete: Vec<String>,
+    pub itineraries_to_insert: Vec<(ItineraryPatternMeta, Vec<ItineraryPatternRow>)>,
+    pub itinerary_metas_to_update: Vec<ItineraryPatternMeta>,
+    pub direction_patterns_to_delete: Vec<String>,
+    pub direction_patterns_to_insert: Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+    pub direction_patterns_to_update: Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+}
+
+/// Compares the rows in production with the rows of the new schedule, both keyed by id.
+/// Direction pattern rows of production must be sorted by stop sequence.
+pub fn plan_diff(
+    old_trips: &AHashMap<String, CompressedTrip>,
+    new_trips: &AHashMap<String, CompressedTrip>,
+    old_itinerary_metas: &AHashMap<String, ItineraryPatternMeta>,
+    new_itineraries: &AHashMap<String, (ItineraryPatternMeta, Vec<ItineraryPatternRow>)>,
+    old_direction_metas: &AHashMap<String, DirectionPatternMeta>,
+    old_direction_rows: &AHashMap<String, Vec<DirectionPatternRow>>,
+    new_direction_patterns: &AHashMap<String, (DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+) -> DiffPlan {
+    // changed trips are deleted and inserted again
+    let trips_to_delete = old_trips
+        .iter()
+        .filter(|(trip_id, old_trip)| new_trips.get(*trip_id) != Some(*old_trip))
+        .map(|(trip_id, _)| trip_id.clone())
+        .collect::<Vec<String>>();
+
+    let trips_to_insert = new_trips
+        .values()
+        .filter(|new_trip| old_trips.get(&new_trip.trip_id) != Some(*new_trip))
+        .cloned()
+        .collect::<Vec<CompressedTrip>>();
+
+    // the stops of an itinerary are part of its id, so only the trips using it can change
+    let itineraries_to_delete = old_itinerary_metas
+        .keys()
+        .filter(|itinerary_id| !new_itineraries.contains_key(*itinerary_id))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    let itineraries_to_insert = new_itineraries
+        .iter()
+        .filter(|(itinerary_id, _)| !old_itinerary_metas.contains_key(*itinerary_id))
+        .map(|(_, itinerary)| itinerary.clone())
+        .collect::<Vec<(ItineraryPatternMeta, Vec<ItineraryPatternRow>)>>();
+
+    let itinerary_metas_to_update = new_itineraries
+        .iter()
+        .filter_map(|(itinerary_id, (new_meta, _))| {
+            let old_meta = old_itinerary_metas.get(itinerary_id)?;
+
+            match with_sorted_trip_ids(old_meta) != with_sorted_trip_ids(new_meta) {
+                true => Some(new_meta.clone()),
+                false => None,
+            }
+        })
+        .collect::<Vec<ItineraryPatternMeta>>();
+
+    let direction_patterns_to_delete = old_direction_metas
+        .keys()
+        .filter(|direction_pattern_id| !new_direction_patterns.contains_key(*direction_pattern_id))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    let direction_patterns_to_insert = new_direction_patterns
+        .iter()
+        .filter(|(direction_pattern_id, _)| {
+            !old_direction_metas.contains_key(*direction_pattern_id)
+        })
+        .map(|(_, direction_pattern)| direction_pattern.clone())
+        .collect::<Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>>();
+
+    // the times of a direction pattern come from one of its itineraries, which may now be another one
+    let direction_patterns_to_update = new_direction_patterns
+        .iter()
+        .filter(|(direction_pattern_id, (new_meta, new_rows))| {
+            match old_direction_metas.get(*direction_pattern_id) {
+                Some(old_meta) => {
+                    old_meta != new_meta
+                        || old_direction_rows.get(*direction_pattern_id) != Some(new_rows)
+                }
+                None => false,
+            }
+        })
+        .map(|(_, direction_pattern)| direction_pattern.clone())
+        .collect::<Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>>();
+
+    DiffPlan {
+        trips_to_delete,
+        trips_to_insert,
+        itineraries_to_delete,
+        itineraries_to_insert,
+        itinerary_metas_to_update,
+        direction_patterns_to_delete,
+        direction_patterns_to_insert,
+        direction_patterns_to_update,
+    }
+}
+
+pub async fn diff_ingest(
+    gtfs: &gtfs_structures::Gtfs,
+    gtfs_path: &str,
+    reduction: &ResponseFromReduce,
+    file_hashes: &BTreeMap<String, u64>,
+    feed_id: &str,
+    chateau_id: &str,
+    diff_base: &DiffBase,
+    route_ids_to_shape_ids: &HashMap<String, HashSet<String>>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<DiffOutcome, Box<dyn Error + Send + Sync>> {
+    use catenary::schema::gtfs::direction_pattern::dsl as direction_pattern;
+    use catenary::schema::gtfs::direction_pattern_meta::dsl as direction_pattern_meta;
+    use catenary::schema::gtfs::itinerary_pattern_meta::dsl as itinerary_pattern_meta;
+    use catenary::schema::gtfs::trips_compressed::dsl as trips_compressed;
+
+    if *file_hashes != diff_base.file_hashes {
+        return Ok(DiffOutcome::Fallback(String::from(
+            "stops.txt or shapes.txt changed",
+        )));
+    }
+
+    let attempt_id = diff_base.attempt_id.as_str();
+
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    // rows in production
+
+    let old_trips = trips_compressed::trips_compressed
+        .filter(trips_compressed::onestop_feed_id.eq(feed_id))
+        .filter(trips_compressed::attempt_id.eq(attempt_id))
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await?
+        .into_iter()
+        .map(|trip| (trip.trip_id.clone(), trip))
+        .collect::<AHashMap<String, CompressedTrip>>();
+
+    let old_itinerary_metas = itinerary_pattern_meta::itinerary_pattern_meta
+        .filter(itinerary_pattern_meta::onestop_feed_id.eq(feed_id))
+        .filter(itinerary_pattern_meta::attempt_id.eq(attempt_id))
+        .select(ItineraryPatternMeta::as_select())
+        .load::<ItineraryPatternMeta>(conn)
+        .await?
+        .into_iter()
+        .map(|meta| (meta.itinerary_pattern_id.clone(), meta))
+        .collect::<AHashMap<String, ItineraryPatternMeta>>();
+
+    let old_direction_metas = direction_pattern_meta::direction_pattern_meta
+        .filter(direction_pattern_meta::onestop_feed_id.eq(feed_id))
+        .filter(direction_pattern_meta::attempt_id.eq(attempt_id))
+        .select(DirectionPatternMeta::as_select())
+        .load::<DirectionPatternMeta>(conn)
+        .await?
+        .into_iter()
+        .map(|meta| (meta.direction_pattern_id.clone(), meta))
+        .collect::<AHashMap<String, DirectionPatternMeta>>();
+
+    let mut old_direction_rows: AHashMap<String, Vec<DirectionPatternRow>> = AHashMap::new();
+
+    for row in direction_pattern::direction_pattern
+        .filter(direction_pattern::onestop_feed_id.eq(feed_id))
+        .filter(direction_pattern::attempt_id.eq(attempt_id))
+        .select(DirectionPatternRow::as_select())
+        .load::<DirectionPatternRow>(conn)
+        .await?
+    {
+        old_direction_rows
+            .entry(row.direction_pattern_id.clone())
+            .or_default()
+            .push(row);
+    }
+
+    for rows in old_direction_rows.values_mut() {
+        rows.sort_by_key(|row| row.stop_sequence);
+    }
+
+    // rows of the new schedule, under the production attempt
+
+    let new_trips = reduction
+        .itineraries_to_trips
+        .iter()
+        .flat_map(|(itinerary_id, compressed_trip_list)| {
+            trips_to_pg(
+                *itinerary_id,
+                compressed_trip_list,
+                reduction,
+                feed_id,
+                chateau_id,
+                attempt_id,
+            )
+        })
+        .map(|trip| (trip.trip_id.clone(), trip))
+        .collect::<AHashMap<String, CompressedTrip>>();
+
+    let new_itineraries = reduction
+        .itineraries
+        .iter()
+        .map(|(itinerary_id, itinerary)| {
+            (
+                itinerary_id.to_string(),
+                itinerary_to_pg(
+                    *itinerary_id,
+                    itinerary,
+                    reduction,
+                    feed_id,
+                    chateau_id,
+                    attempt_id,
+                ),
+            )
+        })
+        .collect::<AHashMap<String, (ItineraryPatternMeta, Vec<ItineraryPatternRow>)>>();
+
+    let new_direction_patterns = reduction
+        .direction_patterns
+        .iter()
+        .map(|(direction_pattern_id, direction_pattern)| {
+            (
+                direction_pattern_id.to_string(),
+                direction_pattern_to_pg(
+                    *direction_pattern_id,
+                    direction_pattern,
+                    reduction,
+                    feed_id,
+                    chateau_id,
+                    attempt_id,
+                ),
+            )
+        })
+        .collect::<AHashMap<String, (DirectionPatternMeta, Vec<DirectionPatternRow>)>>();
+
+    let DiffPlan {
+        trips_to_delete,
+        trips_to_insert,
+        itineraries_to_delete,
+        itineraries_to_insert,
+        itinerary_metas_to_update,
+        direction_patterns_to_delete,
+        direction_patterns_to_insert,
+        direction_patterns_to_update,
+    } = plan_diff(
+        &old_trips,
+        &new_trips,
+        &old_itinerary_metas,
+        &new_itineraries,
+        &old_direction_metas,
+        &old_direction_rows,
+        &new_direction_patterns,
+    );
+
+    let changed_count = trips_to_delete.len()
+        + trips_to_insert.len()
+        + itineraries_to_delete.len()
+        + itineraries_to_insert.len();
+
+    let total_count =
+        old_trips.len().max(new_trips.len()) + old_itinerary_metas.len().max(new_itineraries.len());
+
+    if total_count == 0 {
+        return Ok(DiffOutcome::Fallback(String::from("no trips to compare")));
+    }
+
+    if changed_count as f64 > total_count as f64 * MAX_CHANGED_SHARE {
+        return Ok(DiffOutcome::Fallback(format!(
+            "{} of {} trips and itineraries changed",
+            changed_count, total_count
+        )));
+    }
+
+    // fake shapes of removed direction patterns go with them
+    let removed_fake_shape_ids = direction_patterns_to_delete
+        .iter()
+        .filter(|direction_pattern_id| {
+            old_direction_metas
+                .get(*direction_pattern_id)
+                .map(|meta| meta.fake_shape)
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+
+    // small tables are replaced whole
+    let agencies_pg = agencies_to_pg(gtfs, feed_id, chateau_id, attempt_id);
+    let routes_pg = routes_to_pg(
+        gtfs,
+        feed_id,
+        chateau_id,
+        attempt_id,
+        route_ids_to_shape_ids,
+    );
+    let (calendar_pg, calendar_dates_pg) = calendar_to_pg(gtfs, feed_id, chateau_id, attempt_id);
+    let feed_info_pg = gtfs
+        .feed_info
+        .first()
+        .map(|feed_info| feed_info_to_pg(feed_info, feed_id, chateau_id, attempt_id));
+
+    // fare, pathway and flex files are not compared, so their tables are replaced whole as well
+    let fare_rows = read_fares(gtfs_path, feed_id, chateau_id, attempt_id);
+    let pathway_rows = read_pathways(gtfs_path, feed_id, chateau_id, attempt_id);
+    let flex_rows = read_flex(gtfs_path, feed_id, chateau_id, attempt_id);
+
+    let counts = DiffCounts {
+        trips_inserted: trips_to_insert.len(),
+        trips_deleted: trips_to_delete.len(),
+        itineraries_inserted: itineraries_to_insert.len(),
+        itineraries_deleted: itineraries_to_delete.len(),
+        itineraries_updated: itinerary_metas_to_update.len(),
+        direction_patterns_inserted: direction_patterns_to_insert.len(),
+        direction_patterns_deleted: direction_patterns_to_delete.len(),
+        direction_patterns_updated: direction_patterns_to_update.len(),
+    };
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        {
+            async move {
+                use catenary::schema::gtfs::agencies::dsl as agencies;
+                use catenary::schema::gtfs::calendar::dsl as calendar;
+                use catenary::schema::gtfs::calendar_dates::dsl as calendar_dates;
+                use catenary::schema::gtfs::feed_info::dsl as feed_info;
+                use catenary::schema::gtfs::itinerary_pattern::dsl as itinerary_pattern;
+                use catenary::schema::gtfs::routes::dsl as routes;
+                use catenary::schema::gtfs::shapes::dsl as shapes;
+
+                // trips
+
+                for trip_ids in trips_to_delete.chunks(1000) {
+                    diesel::delete(
+                        trips_compressed::trips_compressed
+                            .filter(trips_compressed::onestop_feed_id.eq(feed_id))
+                            .filter(trips_compressed::attempt_id.eq(attempt_id))
+                            .filter(trips_compressed::trip_id.eq_any(trip_ids)),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+
+                for trips_chunk in trips_to_insert.chunks(100) {
+                    diesel::insert_into(trips_compressed::trips_compressed)
+                        .values(trips_chunk)
+                        .execute(conn)
+                        .await?;
+                }
+
+                // itineraries
+
+                let itinerary_metas_to_delete = itineraries_to_delete
+                    .iter()
+                    .chain(
+                        itinerary_metas_to_update
+                            .iter()
+                            .map(|meta| &meta.itinerary_pattern_id),
+                    )
+                    .collect::<Vec<&String>>();
+
+                for itinerary_ids in itinerary_metas_to_delete.chunks(1000) {
+                    diesel::delete(
+                        itinerary_pattern_meta::itinerary_pattern_meta
+                            .filter(itinerary_pattern_meta::onestop_feed_id.eq(feed_id))
+                            .filter(itinerary_pattern_meta::attempt_id.eq(attempt_id))
+                            .filter(
+                                itinerary_pattern_meta::itinerary_pattern_id.eq_any(itinerary_ids),
+                            ),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+
+                for itinerary_ids in itineraries_to_delete.chunks(1000) {
+                    diesel::delete(
+                        itinerary_pattern::itinerary_pattern
+                            .filter(itinerary_pattern::onestop_feed_id.eq(feed_id))
+                            .filter(itinerary_pattern::attempt_id.eq(attempt_id))
+                            .filter(itinerary_pattern::itinerary_pattern_id.eq_any(itinerary_ids)),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+
+                for (itinerary_meta, itinerary_rows) in itineraries_to_insert.iter() {
+                    diesel::insert_into(itinerary_pattern_meta::itinerary_pattern_meta)
+                        .values(itinerary_meta)
+                        .execute(conn)
+                        .await?;
+
+                    for itinerary_chunk in itinerary_rows.chunks(100) {
+                        diesel::insert_into(itinerary_pattern::itinerary_pattern)
+                            .values(itinerary_chunk)
+                            .execute(conn)
+                            .await?;
+                    }
+                }
+
+                for itinerary_metas_chunk in itinerary_metas_to_update.chunks(100) {
+                    diesel::insert_into(itinerary_pattern_meta::itinerary_pattern_meta)
+                        .values(itinerary_metas_chunk)
+                        .execute(conn)
+                        .await?;
+                }
+
+                // direction patterns
+
+                let direction_patterns_to_replace = direction_patterns_to_delete
+                    .iter()
+                    .chain(
+                        direction_patterns_to_update
+                            .iter()
+                            .map(|(meta, _)| &meta.direction_pattern_id),
+                    )
+                    .collect::<Vec<&String>>();
+
+                for direction_pattern_ids in direction_patterns_to_replace.chunks(1000) {
+                    diesel::delete(
+                        direction_pattern_meta::direction_pattern_meta
+                            .filter(direction_pattern_meta::onestop_feed_id.eq(feed_id))
+                            .filter(direction_pattern_meta::attempt_id.eq(attempt_id))
+                            .filter(
+                                direction_pattern_meta::direction_pattern_id
+                                    .eq_any(direction_pattern_ids),
+                            ),
+                    )
+                    .execute(conn)
+                    .await?;
+
+                    diesel::delete(
+                        direction_pattern::direction_pattern
+                            .filter(direction_pattern::onestop_feed_id.eq(feed_id))
+                            .filter(direction_pattern::attempt_id.eq(attempt_id))
+                            .filter(
+                                direction_pattern::direction_pattern_id
+                                    .eq_any(direction_pattern_ids),
+                            ),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+
+                for (direction_pattern_meta_row, direction_pattern_rows) in
+                    direction_patterns_to_insert
+                        .iter()
+                        .chain(direction_patterns_to_update.iter())
+                {
+                    diesel::insert_into(direction_pattern_meta::direction_pattern_meta)
+                        .values(direction_pattern_meta_row)
+                        .execute(conn)
+                        .await?;
+
+                    for dir_chunk in direction_pattern_rows.chunks(100) {
+                        diesel::insert_into(direction_pattern::direction_pattern)
+                            .values(dir_chunk)
+                            .execute(conn)
+                            .await?;
+                    }
+                }
+
+                for shape_ids in removed_fake_shape_ids.chunks(1000) {
+                    diesel::delete(
+                        shapes::shapes
+                            .filter(shapes::onestop_feed_id.eq(feed_id))
+                            .filter(shapes::attempt_id.eq(attempt_id))
+                            .filter(shapes::stop_to_stop_generated.eq(true))
+                            .filter(shapes::shape_id.eq_any(shape_ids)),
+                    )
+                    .execute(conn)
+                    .await?;
+                }
+
+                // agencies, routes, calendars and feed info
+
+                diesel::delete(
+                    agencies::agencies
+                        .filter(agencies::static_onestop_id.eq(feed_id))
+                        .filter(agencies::attempt_id.eq(attempt_id)),
+                )
+                .execute(conn)
+                .await?;
+
+                diesel::insert_into(agencies::agencies)
+                    .values(&agencies_pg)
+                    .execute(conn)
+                    .await?;
+
+                diesel::delete(
+                    routes::routes
+                        .filter(routes::onestop_feed_id.eq(feed_id))
+                        .filter(routes::attempt_id.eq(attempt_id)),
+                )
+                .execute(conn)
+                .await?;
+
+                for routes_chunk in routes_pg.chunks(100) {
+                    diesel::insert_into(routes::routes)
+                        .values(routes_chunk)
+                        .execute(conn)
+                        .await?;
+                }
+
+                diesel::delete(
+                    calendar::calendar
+                        .filter(calendar::onestop_feed_id.eq(feed_id))
+                        .filter(calendar::attempt_id.eq(attempt_id)),
+                )
+                .execute(conn)
+                .await?;
+
+                for calendar_chunk in calendar_pg.chunks(100) {
+                    diesel::insert_into(calendar::calendar)
+                        .values(calendar_chunk)
+                        .execute(conn)
+                        .await?;
+                }
+
+                diesel::delete(
+                    calendar_dates::calendar_dates
+                        .filter(calendar_dates::onestop_feed_id.eq(feed_id))
+                        .filter(calendar_dates::attempt_id.eq(attempt_id)),
+                )
+                .execute(conn)
+                .await?;
+
+                for calendar_dates_chunk in calendar_dates_pg.chunks(100) {
+                    diesel::insert_into(calendar_dates::calendar_dates)
+                        .values(calendar_dates_chunk)
+                        .execute(conn)
+                        .await?;
+                }
+
+                diesel::delete(
+                    feed_info::feed_info
+                        .filter(feed_info::onestop_feed_id.eq(feed_id))
+                        .filter(feed_info::attempt_id.eq(attempt_id)),
+                )
+                .execute(conn)
+                .await?;
+
+                if let Some(feed_info_pg) = &feed_info_pg {
+                    diesel::insert_into(feed_info::feed_info)
+                        .values(feed_info_pg)
+                        .execute(conn)
+                        .await?;
+                }
+
+                // fares, pathways and flex
+
+                delete_fare_rows(conn, feed_id, Some(attempt_id)).await?;
+                insert_fares(conn, &fare_rows).await?;
+
+                delete_pathway_rows(conn, feed_id, Some(attempt_id)).await?;
+                insert_pathways(conn, &pathway_rows).await?;
+
+                delete_flex_rows(conn, feed_id, Some(attempt_id)).await?;
+                insert_flex(conn, &flex_rows).await?;
+
+                Ok(())
+            }
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    // geometry for new direction patterns without a shape, visible straight away as the attempt is in production
+    for (direction_pattern_meta_row, _) in direction_patterns_to_insert.iter() {
+        if !direction_pattern_meta_row.fake_shape {
+            continue;
+        }
+
+        let Some(direction_pattern) = direction_pattern_meta_row
+            .direction_pattern_id
+            .parse::<u64>()
+            .ok()
+            .and_then(|direction_pattern_id| {
+                reduction.direction_patterns.get(&direction_pattern_id)
+            })
+        else {
+            continue;
+        };
+
+        let linestring = stop_to_stop_linestring(gtfs, direction_pattern);
+        let route = gtfs.routes.get(direction_pattern.route_id.as_str());
+
+        if let (Some(linestring), Some(route)) = (linestring, route) {
+            let _ = insert_stop_to_stop_geometry(
+                feed_id,
+                attempt_id,
+                chateau_id,
+                route,
+                direction_pattern_meta_row
+                    .direction_pattern_id
+                    .parse::<u64>()?,
+                &linestring,
+                true,
+                Arc::clone(&arc_conn_pool),
+            )
+            .await;
+        }
+    }
+
+    Ok(DiffOutcome::Applied(counts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trip(trip_id: &str, itinerary_pattern_id: &str, start_time: u32) -> CompressedTrip {
+        CompressedTrip {
+            onestop_feed_id: String::from("f-test"),
+            trip_id: String::from(trip_id),
+            attempt_id: String::from("f-test-1"),
+            service_id: "weekday".into(),
+            trip_short_name: None,
+            direction_id: None,
+            block_id: None,
+            wheelchair_accessible: 0,
+            bikes_allowed: 0,
+            chateau: String::from("test"),
+            frequencies: None,
+            has_frequencies: false,
+            itinerary_pattern_id: String::from(itinerary_pattern_id),
+            route_id: String::from("route"),
+            start_time,
+        }
+    }
+
+    fn itinerary_meta(itinerary_pattern_id: &str, trip_ids: &[&str]) -> ItineraryPatternMeta {
+        ItineraryPatternMeta {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            trip_ids: trip_ids
+                .iter()
+                .map(|trip_id| Some(trip_id.to_string()))
+                .collect(),
+            itinerary_pattern_id: String::from(itinerary_pattern_id),
+            chateau: String::from("test"),
+            trip_headsign: None,
+            trip_headsign_translations: None,
+            shape_id: None,
+            timezone: String::from("Etc/UTC"),
+            route_id: "route".into(),
+            direction_pattern_id: None,
+        }
+    }
+
+    fn direction_pattern(
+        direction_pattern_id: &str,
+        arrival_times: &[i32],
+    ) -> (DirectionPatternMeta, Vec<DirectionPatternRow>) {
+        let meta = DirectionPatternMeta {
+            chateau: String::from("test"),
+            direction_pattern_id: String::from(direction_pattern_id),
+            headsign_or_destination: String::from("Downtown"),
+            gtfs_shape_id: None,
+            fake_shape: false,
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            route_id: Some("route".into()),
+            route_type: Some(3),
+            direction_id: None,
+        };
+
+        let rows = arrival_times
+            .iter()
+            .enumerate()
+            .map(|(stop_sequence, arrival_time)| DirectionPatternRow {
+                chateau: String::from("test"),
+                direction_pattern_id: String::from(direction_pattern_id),
+                stop_id: format!("stop-{}", stop_sequence).into(),
+                stop_sequence: stop_sequence as u32,
+                arrival_time_since_start: Some(*arrival_time),
+                departure_time_since_start: Some(*arrival_time),
+                interpolated_time_since_start: None,
+                onestop_feed_id: String::from("f-test"),
+                attempt_id: String::from("f-test-1"),
+            })
+            .collect();
+
+        (meta, rows)
+    }
+
+    fn trips_by_id(trips: Vec<CompressedTrip>) -> AHashMap<String, CompressedTrip> {
+        trips
+            .into_iter()
+            .map(|trip| (trip.trip_id.clone(), trip))
+            .collect()
+    }
+
+    fn itineraries_by_id(
+        metas: Vec<ItineraryPatternMeta>,
+    ) -> AHashMap<String, (ItineraryPatternMeta, Vec<ItineraryPatternRow>)> {
+        metas
+            .into_iter()
+            .map(|meta| (meta.itinerary_pattern_id.clone(), (meta, vec![])))
+            .collect()
+    }
+
+    fn direction_patterns_by_id(
+        direction_patterns: Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+    ) -> AHashMap<String, (DirectionPatternMeta, Vec<DirectionPatternRow>)> {
+        direction_patterns
+            .into_iter()
+            .map(|(meta, rows)| (meta.direction_pattern_id.clone(), (meta, rows)))
+            .collect()
+    }
+
+    fn plan_direction_patterns(
+        old: Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+        new: Vec<(DirectionPatternMeta, Vec<DirectionPatternRow>)>,
+    ) -> DiffPlan {
+        let old = direction_patterns_by_id(old);
+
+        let old_metas = old
+            .iter()
+            .map(|(id, (meta, _))| (id.clone(), meta.clone()))
+            .collect::<AHashMap<String, DirectionPatternMeta>>();
+        let old_rows = old
+            .iter()
+            .map(|(id, (_, rows))| (id.clone(), rows.clone()))
+            .collect::<AHashMap<String, Vec<DirectionPatternRow>>>();
+
+        plan_diff(
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &old_metas,
+            &old_rows,
+            &direction_patterns_by_id(new),
+        )
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn unchanged_schedule_writes_nothing() {
+        let trips = trips_by_id(vec![trip("a", "1", 3600), trip("b", "1", 7200)]);
+        let old_itinerary_metas = [itinerary_meta("1", &["a", "b"])]
+            .into_iter()
+            .map(|meta| (meta.itinerary_pattern_id.clone(), meta))
+            .collect::<AHashMap<String, ItineraryPatternMeta>>();
+        // trip ids come out of the reduction in another order
+        let new_itineraries = itineraries_by_id(vec![itinerary_meta("1", &["b", "a"])]);
+
+        let plan = plan_diff(
+            &trips,
+            &trips,
+            &old_itinerary_metas,
+            &new_itineraries,
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+        );
+
+        assert!(plan.trips_to_delete.is_empty());
+        assert!(plan.trips_to_insert.is_empty());
+        assert!(plan.itineraries_to_delete.is_empty());
+        assert!(plan.itineraries_to_insert.is_empty());
+        assert!(plan.itinerary_metas_to_update.is_empty());
+    }
+
+    #[test]
+    fn changed_trips_are_deleted_and_inserted_again() {
+        let old_trips = trips_by_id(vec![
+            trip("kept", "1", 3600),
+            trip("retimed", "1", 7200),
+            trip("removed", "1", 10800),
+        ]);
+        let new_trips = trips_by_id(vec![
+            trip("kept", "1", 3600),
+            trip("retimed", "1", 7500),
+            trip("added", "1", 14400),
+        ]);
+
+        let plan = plan_diff(
+            &old_trips,
+            &new_trips,
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+        );
+
+        assert_eq!(
+            sorted(plan.trips_to_delete),
+            vec![String::from("removed"), String::from("retimed")]
+        );
+
+        let mut inserted = plan
+            .trips_to_insert
+            .iter()
+            .map(|trip| (trip.trip_id.as_str(), trip.start_time))
+            .collect::<Vec<(&str, u32)>>();
+        inserted.sort();
+
+        assert_eq!(inserted, vec![("added", 14400), ("retimed", 7500)]);
+    }
+
+    #[test]
+    fn itineraries_are_matched_by_id() {
+        let old_itinerary_metas = [
+            itinerary_meta("removed", &["a"]),
+            itinerary_meta("same", &["b", "c"]),
+            itinerary_meta("more_trips", &["d"]),
+        ]
+        .into_iter()
+        .map(|meta| (meta.itinerary_pattern_id.clone(), meta))
+        .collect::<AHashMap<String, ItineraryPatternMeta>>();
+
+        let new_itineraries = itineraries_by_id(vec![
+            itinerary_meta("same", &["c", "b"]),
+            itinerary_meta("more_trips", &["d", "e"]),
+            itinerary_meta("added", &["f"]),
+        ]);
+
+        let plan = plan_diff(
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &old_itinerary_metas,
+            &new_itineraries,
+            &AHashMap::new(),
+            &AHashMap::new(),
+            &AHashMap::new(),
+        );
+
+        assert_eq!(plan.itineraries_to_delete, vec![String::from("removed")]);
+
+        assert_eq!(plan.itineraries_to_insert.len(), 1);
+        assert_eq!(
+            plan.itineraries_to_insert[0].0.itinerary_pattern_id,
+            "added"
+        );
+
+        // only the trip list of an existing itinerary changes, its rows are kept
+        assert_eq!(plan.itinerary_metas_to_update.len(), 1);
+        assert_eq!(
+            plan.itinerary_metas_to_update[0].itinerary_pattern_id,
+            "more_trips"
+        );
+    }
+
+    #[test]
+    fn direction_pattern_with_new_times_is_updated() {
+        let plan = plan_direction_patterns(
+            vec![
+                direction_pattern("same", &[0, 60, 120]),
+                direction_pattern("retimed", &[0, 60, 120]),
+                direction_pattern("removed", &[0, 300]),
+            ],
+            vec![
+                direction_pattern("same", &[0, 60, 120]),
+                direction_pattern("retimed", &[0, 90, 150]),
+                direction_pattern("added", &[0, 45]),
+            ],
+        );
+
+        assert_eq!(
+            plan.direction_patterns_to_delete,
+            vec![String::from("removed")]
+        );
+
+        assert_eq!(plan.direction_patterns_to_insert.len(), 1);
+        assert_eq!(
+            plan.direction_patterns_to_insert[0].0.direction_pattern_id,
+            "added"
+        );
+
+        assert_eq!(plan.direction_patterns_to_update.len(), 1);
+        assert_eq!(
+            plan.direction_patterns_to_update[0].0.direction_pattern_id,
+            "retimed"
+        );
+        assert_eq!(
+            plan.direction_patterns_to_update[0].1[1].arrival_time_since_start,
+            Some(90)
+        );
+    }
+
+    #[test]
+    fn direction_pattern_with_new_headsign_is_updated() {
+        let (mut renamed_meta, rows) = direction_pattern("renamed", &[0, 60]);
+        renamed_meta.headsign_or_destination = String::from("Airport");
+
+        let plan = plan_direction_patterns(
+            vec![direction_pattern("renamed", &[0, 60])],
+            vec![(renamed_meta, rows)],
+        );
+
+        assert!(plan.direction_patterns_to_delete.is_empty());
+        assert!(plan.direction_patterns_to_insert.is_empty());
+        assert_eq!(plan.direction_patterns_to_update.len(), 1);
+        assert_eq!(
+            plan.direction_patterns_to_update[0]
+                .0
+                .headsign_or_destination,
+            "Airport"
+        );
+    }
+
+    #[test]
+    fn trip_id_order_does_not_change_itineraries() {
+        let meta = ItineraryPatternMeta {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            trip_ids: vec![Some(String::from("b")), Some(String::from("a"))],
+            itinerary_pattern_id: String::from("1"),
+            chateau: String::from("test"),
+            trip_headsign: None,
+            trip_headsign_translations: None,
+            shape_id: None,
+            timezone: String::from("Etc/UTC"),
+            route_id: "route".into(),
+            direction_pattern_id: Some(String::from("2")),
+        };
+
+        let mut reordered = meta.clone();
+        reordered.trip_ids.reverse();
+
+        assert!(with_sorted_trip_ids(&meta) == with_sorted_trip_ids(&reordered));
+
+        let mut more_trips = meta.clone();
+        more_trips.trip_ids.push(Some(String::from("c")));
+
+        assert!(with_sorted_trip_ids(&meta) != with_sorted_trip_ids(&more_trips));
+    }
+}
@@ -11,6 +11,7 @@ pub async fn insert_stop_to_stop_geometry(
     route: &gtfs_structures::Route,
     direction_id: u64,
     linestring: &postgis_diesel::types::LineString<postgis_diesel::types::Point>,
+    allowed_spatial_query: bool,
     arc_conn_pool: Arc<CatenaryPostgresPool>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let conn_pool = arc_conn_pool.as_ref();
@@ -49,7 +50,7 @@ pub async fn insert_stop_to_stop_geometry(
         route_label: Some(route_label.clone()),
         route_label_translations: None,
         text_color: Some(text_color.clone()),
-        allowed_spatial_query: false,
+        allowed_spatial_query,
         stop_to_stop_generated: Some(true),
     };
 
@@ -0,0 +1,424 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// gtfs_structures does not read Fares v2, so every fare file is read here straight from the unzipped feed.
+
+use super::read_gtfs_file;
+use catenary::models::{
+    Area, FareAttribute, FareLegRule, FareMedia, FareProduct, FareRule, FareTransferRule, Network,
+    StopArea,
+};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct FareMediaCsv {
+    fare_media_id: String,
+    fare_media_name: Option<String>,
+    fare_media_type: i16,
+}
+
+#[derive(Deserialize)]
+struct FareProductCsv {
+    fare_product_id: String,
+    fare_product_name: Option<String>,
+    fare_media_id: Option<String>,
+    amount: f64,
+    currency: String,
+}
+
+#[derive(Deserialize)]
+struct FareLegRuleCsv {
+    leg_group_id: Option<String>,
+    network_id: Option<String>,
+    from_area_id: Option<String>,
+    to_area_id: Option<String>,
+    from_timeframe_group_id: Option<String>,
+    to_timeframe_group_id: Option<String>,
+    fare_product_id: String,
+    rule_priority: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct FareTransferRuleCsv {
+    from_leg_group_id: Option<String>,
+    to_leg_group_id: Option<String>,
+    transfer_count: Option<i32>,
+    duration_limit: Option<i32>,
+    duration_limit_type: Option<i16>,
+    fare_transfer_type: i16,
+    fare_product_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AreaCsv {
+    area_id: String,
+    area_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StopAreaCsv {
+    area_id: String,
+    stop_id: String,
+}
+
+#[derive(Deserialize)]
+struct NetworkCsv {
+    network_id: String,
+    network_name: Option<String>,
+}
+
+/// Rows of route_networks.txt, and the network_id column of routes.txt
+#[derive(Deserialize)]
+struct RouteNetworkCsv {
+    network_id: Option<String>,
+    route_id: String,
+}
+
+#[derive(Deserialize)]
+struct FareAttributeCsv {
+    fare_id: String,
+    price: f64,
+    currency_type: String,
+    payment_method: i16,
+    transfers: Option<i16>,
+    agency_id: Option<String>,
+    transfer_duration: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct FareRuleCsv {
+    fare_id: String,
+    route_id: Option<String>,
+    origin_id: Option<String>,
+    destination_id: Option<String>,
+    contains_id: Option<String>,
+}
+
+/// Fare rows of one attempt, read before any connection is taken
+pub struct FareRows {
+    pub fare_media: Vec<FareMedia>,
+    pub fare_products: Vec<FareProduct>,
+    pub fare_leg_rules: Vec<FareLegRule>,
+    pub fare_transfer_rules: Vec<FareTransferRule>,
+    pub areas: Vec<Area>,
+    pub stop_areas: Vec<StopArea>,
+    pub networks: Vec<Network>,
+    pub fare_attributes: Vec<FareAttribute>,
+    pub fare_rules: Vec<FareRule>,
+}
+
+pub fn read_fares(gtfs_path: &str, feed_id: &str, chateau_id: &str, attempt_id: &str) -> FareRows {
+    let fare_media_pg = read_gtfs_file::<FareMediaCsv>(gtfs_path, "fare_media.txt")
+        .into_iter()
+        .map(|row| FareMedia {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            fare_media_id: row.fare_media_id,
+            fare_media_name: row.fare_media_name,
+            fare_media_type: row.fare_media_type,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<FareMedia>>();
+
+    let fare_products_pg = read_gtfs_file::<FareProductCsv>(gtfs_path, "fare_products.txt")
+        .into_iter()
+        .enumerate()
+        .map(|(row_index, row)| FareProduct {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            row_index: row_index as i32,
+            fare_product_id: row.fare_product_id,
+            fare_product_name: row.fare_product_name,
+            fare_media_id: row.fare_media_id,
+            amount: row.amount,
+            currency: row.currency,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<FareProduct>>();
+
+    let fare_leg_rules_pg = read_gtfs_file::<FareLegRuleCsv>(gtfs_path, "fare_leg_rules.txt")
+        .into_iter()
+        .enumerate()
+        .map(|(row_index, row)| FareLegRule {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            row_index: row_index as i32,
+            leg_group_id: row.leg_group_id,
+            network_id: row.network_id,
+            from_area_id: row.from_area_id,
+            to_area_id: row.to_area_id,
+            from_timeframe_group_id: row.from_timeframe_group_id,
+            to_timeframe_group_id: row.to_timeframe_group_id,
+            fare_product_id: row.fare_product_id,
+            rule_priority: row.rule_priority,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<FareLegRule>>();
+
+    let fare_transfer_rules_pg =
+        read_gtfs_file::<FareTransferRuleCsv>(gtfs_path, "fare_transfer_rules.txt")
+            .into_iter()
+            .enumerate()
+            .map(|(row_index, row)| FareTransferRule {
+                onestop_feed_id: feed_id.to_string(),
+                attempt_id: attempt_id.to_string(),
+                row_index: row_index as i32,
+                from_leg_group_id: row.from_leg_group_id,
+                to_leg_group_id: row.to_leg_group_id,
+                transfer_count: row.transfer_count,
+                duration_limit: row.duration_limit,
+                duration_limit_type: row.duration_limit_type,
+                fare_transfer_type: row.fare_transfer_type,
+                fare_product_id: row.fare_product_id,
+                chateau: chateau_id.to_string(),
+            })
+            .collect::<Vec<FareTransferRule>>();
+
+    let areas_pg = read_gtfs_file::<AreaCsv>(gtfs_path, "areas.txt")
+        .into_iter()
+        .map(|row| Area {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            area_id: row.area_id,
+            area_name: row.area_name,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<Area>>();
+
+    let stop_areas_pg = read_gtfs_file::<StopAreaCsv>(gtfs_path, "stop_areas.txt")
+        .into_iter()
+        .map(|row| StopArea {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            area_id: row.area_id,
+            stop_id: row.stop_id,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<StopArea>>();
+
+    // network id -> (name, route ids)
+    let mut networks: BTreeMap<String, (Option<String>, Vec<Option<String>>)> = BTreeMap::new();
+
+    for row in read_gtfs_file::<NetworkCsv>(gtfs_path, "networks.txt") {
+        networks.entry(row.network_id).or_default().0 = row.network_name;
+    }
+
+    for file_name in ["route_networks.txt", "routes.txt"] {
+        for row in read_gtfs_file::<RouteNetworkCsv>(gtfs_path, file_name) {
+            if let Some(network_id) = row.network_id {
+                networks
+                    .entry(network_id)
+                    .or_default()
+                    .1
+                    .push(Some(row.route_id));
+            }
+        }
+    }
+
+    let networks_pg = networks
+        .into_iter()
+        .map(|(network_id, (network_name, route_ids))| Network {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            network_id,
+            network_name,
+            route_ids,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<Network>>();
+
+    let fare_attributes_pg = read_gtfs_file::<FareAttributeCsv>(gtfs_path, "fare_attributes.txt")
+        .into_iter()
+        .map(|row| FareAttribute {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            fare_id: row.fare_id,
+            price: row.price,
+            currency_type: row.currency_type,
+            payment_method: row.payment_method,
+            transfers: row.transfers,
+            agency_id: row.agency_id,
+            transfer_duration: row.transfer_duration,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<FareAttribute>>();
+
+    let fare_rules_pg = read_gtfs_file::<FareRuleCsv>(gtfs_path, "fare_rules.txt")
+        .into_iter()
+        .enumerate()
+        .map(|(row_index, row)| FareRule {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            row_index: row_index as i32,
+            fare_id: row.fare_id,
+            route_id: row.route_id,
+            origin_id: row.origin_id,
+            destination_id: row.destination_id,
+            contains_id: row.contains_id,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<FareRule>>();
+
+    FareRows {
+        fare_media: fare_media_pg,
+        fare_products: fare_products_pg,
+        fare_leg_rules: fare_leg_rules_pg,
+        fare_transfer_rules: fare_transfer_rules_pg,
+        areas: areas_pg,
+        stop_areas: stop_areas_pg,
+        networks: networks_pg,
+        fare_attributes: fare_attributes_pg,
+        fare_rules: fare_rules_pg,
+    }
+}
+
+/// Inserts on the given connection, so the diff ingest can run it inside its transaction
+pub async fn insert_fares(
+    conn: &mut AsyncPgConnection,
+    fare_rows: &FareRows,
+) -> Result<(), diesel::result::Error> {
+    for chunk in fare_rows.fare_media.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_media::dsl::fare_media)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.fare_products.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_products::dsl::fare_products)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.fare_leg_rules.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_leg_rules::dsl::fare_leg_rules)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.fare_transfer_rules.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_transfer_rules::dsl::fare_transfer_rules)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.areas.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::areas::dsl::areas)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.stop_areas.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::stop_areas::dsl::stop_areas)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.networks.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::networks::dsl::networks)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.fare_attributes.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_attributes::dsl::fare_attributes)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in fare_rows.fare_rules.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::fare_rules::dsl::fare_rules)
+            .values(chunk)
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn fares_into_postgres(
+    gtfs_path: &str,
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let fare_rows = read_fares(gtfs_path, feed_id, chateau_id, attempt_id);
+
+    insert_fares(conn, &fare_rows).await?;
+
+    Ok(())
+}
+
+/// Deletes the fare tables of one attempt, or of every attempt of the feed
+pub async fn delete_fare_rows(
+    conn: &mut AsyncPgConnection,
+    feed_id: &str,
+    attempt_id: Option<&str>,
+) -> Result<(), diesel::result::Error> {
+    macro_rules! delete_fare_table {
+        ($table:ident) => {{
+            use catenary::schema::gtfs::$table::dsl as columns;
+
+            let mut delete_query =
+                diesel::delete(columns::$table.filter(columns::onestop_feed_id.eq(feed_id)))
+                    .into_boxed();
+
+            if let Some(attempt_id) = attempt_id {
+                delete_query = delete_query.filter(columns::attempt_id.eq(attempt_id));
+            }
+
+            delete_query.execute(conn).await?;
+        }};
+    }
+
+    delete_fare_table!(fare_media);
+    delete_fare_table!(fare_products);
+    delete_fare_table!(fare_leg_rules);
+    delete_fare_table!(fare_transfer_rules);
+    delete_fare_table!(areas);
+    delete_fare_table!(stop_areas);
+    delete_fare_table!(networks);
+    delete_fare_table!(fare_attributes);
+    delete_fare_table!(fare_rules);
+
+    Ok(())
+}
+
+pub async fn delete_fares(
+    feed_id: &str,
+    attempt_id: Option<&str>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    delete_fare_rows(conn, feed_id, attempt_id).await?;
+
+    Ok(())
+}
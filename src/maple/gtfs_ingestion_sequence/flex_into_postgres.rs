@@ -0,0 +1,556 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// GTFS-Flex zones from locations.geojson and booking rules from booking_rules.txt.
+// gtfs_structures does not read windows or zones, so the stop times of flex trips are read here too.
+
+use super::read_gtfs_file;
+use ahash::{AHashMap, AHashSet};
+use catenary::maple_syrup::FlexStopTime;
+use catenary::models::{BookingRule, FlexLocation};
+use catenary::postgis_to_diesel::multi_polygon_geo_to_diesel;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::BufRead;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct FlexStopTimeCsv {
+    trip_id: String,
+    stop_id: Option<String>,
+    location_group_id: Option<String>,
+    location_id: Option<String>,
+    stop_sequence: u16,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    start_pickup_drop_off_window: Option<String>,
+    end_pickup_drop_off_window: Option<String>,
+    pickup_type: Option<i16>,
+    drop_off_type: Option<i16>,
+    stop_headsign: Option<String>,
+    pickup_booking_rule_id: Option<String>,
+    drop_off_booking_rule_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BookingRuleCsv {
+    booking_rule_id: String,
+    booking_type: i16,
+    prior_notice_duration_min: Option<i32>,
+    prior_notice_duration_max: Option<i32>,
+    prior_notice_last_day: Option<i32>,
+    prior_notice_last_time: Option<String>,
+    prior_notice_start_day: Option<i32>,
+    prior_notice_start_time: Option<String>,
+    prior_notice_service_id: Option<String>,
+    message: Option<String>,
+    pickup_message: Option<String>,
+    drop_off_message: Option<String>,
+    phone_number: Option<String>,
+    info_url: Option<String>,
+    booking_url: Option<String>,
+}
+
+fn parse_gtfs_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':');
+
+    let hours = parts.next()?.parse::<u32>().ok()?;
+    let minutes = parts.next()?.parse::<u32>().ok()?;
+    let seconds = parts.next()?.parse::<u32>().ok()?;
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Every stop time of the trips with at least one pickup and drop off window, by trip id
+pub fn read_flex_trips(gtfs_path: &str) -> AHashMap<String, Vec<FlexStopTime>> {
+    let mut flex_trips: AHashMap<String, Vec<FlexStopTime>> = AHashMap::new();
+
+    // stop_times.txt is large, so only feeds with windows are read twice
+    let Ok(file) = std::fs::File::open(format!("{}/stop_times.txt", gtfs_path)) else {
+        return flex_trips;
+    };
+
+    let mut header = String::new();
+
+    if std::io::BufReader::new(file)
+        .read_line(&mut header)
+        .is_err()
+        || !header.contains("start_pickup_drop_off_window")
+    {
+        return flex_trips;
+    }
+
+    for row in read_gtfs_file::<FlexStopTimeCsv>(gtfs_path, "stop_times.txt") {
+        let Some(stop_id) = row.stop_id.or(row.location_group_id).or(row.location_id) else {
+            continue;
+        };
+
+        flex_trips
+            .entry(row.trip_id)
+            .or_default()
+            .push(FlexStopTime {
+                stop_id: stop_id.into(),
+                stop_sequence: row.stop_sequence,
+                arrival_time: row.arrival_time.as_deref().and_then(parse_gtfs_time),
+                departure_time: row.departure_time.as_deref().and_then(parse_gtfs_time),
+                start_pickup_drop_off_window: row
+                    .start_pickup_drop_off_window
+                    .as_deref()
+                    .and_then(parse_gtfs_time),
+                end_pickup_drop_off_window: row
+                    .end_pickup_drop_off_window
+                    .as_deref()
+                    .and_then(parse_gtfs_time),
+                pickup_type: row.pickup_type.unwrap_or(0),
+                drop_off_type: row.drop_off_type.unwrap_or(0),
+                stop_headsign: row.stop_headsign,
+                pickup_booking_rule_id: row.pickup_booking_rule_id,
+                drop_off_booking_rule_id: row.drop_off_booking_rule_id,
+            });
+    }
+
+    flex_trips.retain(|_, flex_stop_times| {
+        flex_stop_times
+            .iter()
+            .any(|flex_stop_time| flex_stop_time.start_pickup_drop_off_window.is_some())
+    });
+
+    flex_trips
+}
+
+/// Reads the feed with gtfs_structures, leaving out the stop times of flex trips at zones and stop groups.
+/// gtfs_structures rejects the whole feed when a stop time refers to anything but a stop,
+/// and flex trips are reduced from `read_flex_trips` instead, so they only need to be in `gtfs.trips`.
+pub fn read_gtfs_with_flex(
+    gtfs_path: &str,
+    flex_trips: &AHashMap<String, Vec<FlexStopTime>>,
+) -> Result<gtfs_structures::Gtfs, gtfs_structures::Error> {
+    let mut raw_gtfs = gtfs_structures::RawGtfs::new(gtfs_path)?;
+
+    if !flex_trips.is_empty() {
+        if let (Ok(stops), Ok(stop_times)) = (&raw_gtfs.stops, &mut raw_gtfs.stop_times) {
+            let stop_ids = stops
+                .iter()
+                .map(|stop| stop.id.as_str())
+                .collect::<AHashSet<&str>>();
+
+            stop_times.retain(|stop_time| {
+                !flex_trips.contains_key(&stop_time.trip_id)
+                    || stop_ids.contains(stop_time.stop_id.as_str())
+            });
+        }
+    }
+
+    gtfs_structures::Gtfs::try_from(raw_gtfs)
+}
+
+fn read_flex_locations(
+    gtfs_path: &str,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Vec<FlexLocation> {
+    let Ok(geojson_text) = std::fs::read_to_string(format!("{}/locations.geojson", gtfs_path))
+    else {
+        return vec![];
+    };
+
+    let feature_collection = match geojson_text.parse::<geojson::GeoJson>() {
+        Ok(geojson::GeoJson::FeatureCollection(feature_collection)) => feature_collection,
+        _ => {
+            eprintln!(
+                "locations.geojson of {} is not a feature collection",
+                feed_id
+            );
+            return vec![];
+        }
+    };
+
+    feature_collection
+        .features
+        .into_iter()
+        .filter_map(|feature| {
+            let location_id = match &feature.id {
+                Some(geojson::feature::Id::String(id)) => id.clone(),
+                Some(geojson::feature::Id::Number(id)) => id.to_string(),
+                None => return None,
+            };
+
+            let property = |name: &str| {
+                feature
+                    .property(name)
+                    .and_then(|value| value.as_str())
+                    .map(|value| value.to_string())
+            };
+
+            let geometry = feature.geometry.clone()?;
+
+            // zones are polygons or multipolygons
+            let multipolygon = match geo::Geometry::<f64>::try_from(geometry.value) {
+                Ok(geo::Geometry::Polygon(polygon)) => geo::MultiPolygon::new(vec![polygon]),
+                Ok(geo::Geometry::MultiPolygon(multipolygon)) => multipolygon,
+                _ => return None,
+            };
+
+            Some(FlexLocation {
+                onestop_feed_id: feed_id.to_string(),
+                attempt_id: attempt_id.to_string(),
+                location_id,
+                stop_name: property("stop_name"),
+                stop_desc: property("stop_desc"),
+                geometry: multi_polygon_geo_to_diesel(multipolygon),
+                chateau: chateau_id.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Flex rows of one attempt, read before any connection is taken
+pub struct FlexRows {
+    pub flex_locations: Vec<FlexLocation>,
+    pub booking_rules: Vec<BookingRule>,
+}
+
+pub fn read_flex(gtfs_path: &str, feed_id: &str, chateau_id: &str, attempt_id: &str) -> FlexRows {
+    let flex_locations = read_flex_locations(gtfs_path, feed_id, chateau_id, attempt_id);
+
+    let booking_rules = read_gtfs_file::<BookingRuleCsv>(gtfs_path, "booking_rules.txt")
+        .into_iter()
+        .map(|row| BookingRule {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            booking_rule_id: row.booking_rule_id,
+            booking_type: row.booking_type,
+            prior_notice_duration_min: row.prior_notice_duration_min,
+            prior_notice_duration_max: row.prior_notice_duration_max,
+            prior_notice_last_day: row.prior_notice_last_day,
+            prior_notice_last_time: row.prior_notice_last_time,
+            prior_notice_start_day: row.prior_notice_start_day,
+            prior_notice_start_time: row.prior_notice_start_time,
+            prior_notice_service_id: row.prior_notice_service_id,
+            message: row.message,
+            pickup_message: row.pickup_message,
+            drop_off_message: row.drop_off_message,
+            phone_number: row.phone_number,
+            info_url: row.info_url,
+            booking_url: row.booking_url,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<BookingRule>>();
+
+    FlexRows {
+        flex_locations,
+        booking_rules,
+    }
+}
+
+/// Inserts on the given connection, so the diff ingest can run it inside its transaction
+pub async fn insert_flex(
+    conn: &mut AsyncPgConnection,
+    flex_rows: &FlexRows,
+) -> Result<(), diesel::result::Error> {
+    for chunk in flex_rows.flex_locations.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::flex_locations::dsl::flex_locations)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in flex_rows.booking_rules.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::booking_rules::dsl::booking_rules)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn flex_into_postgres(
+    gtfs_path: &str,
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let flex_rows = read_flex(gtfs_path, feed_id, chateau_id, attempt_id);
+
+    insert_flex(conn, &flex_rows).await?;
+
+    Ok(())
+}
+
+/// Deletes the flex zones and booking rules of one attempt, or of every attempt of the feed
+pub async fn delete_flex_rows(
+    conn: &mut AsyncPgConnection,
+    feed_id: &str,
+    attempt_id: Option<&str>,
+) -> Result<(), diesel::result::Error> {
+    use catenary::schema::gtfs::booking_rules::dsl as booking_rules;
+    use catenary::schema::gtfs::flex_locations::dsl as flex_locations;
+
+    let mut flex_locations_delete = diesel::delete(
+        flex_locations::flex_locations.filter(flex_locations::onestop_feed_id.eq(feed_id)),
+    )
+    .into_boxed();
+    let mut booking_rules_delete = diesel::delete(
+        booking_rules::booking_rules.filter(booking_rules::onestop_feed_id.eq(feed_id)),
+    )
+    .into_boxed();
+
+    if let Some(attempt_id) = attempt_id {
+        flex_locations_delete =
+            flex_locations_delete.filter(flex_locations::attempt_id.eq(attempt_id));
+        booking_rules_delete =
+            booking_rules_delete.filter(booking_rules::attempt_id.eq(attempt_id));
+    }
+
+    flex_locations_delete.execute(conn).await?;
+    booking_rules_delete.execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn delete_flex(
+    feed_id: &str,
+    attempt_id: Option<&str>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    delete_flex_rows(conn, feed_id, attempt_id).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_flex_feed() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("maple-flex-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = [
+            (
+                "agency.txt",
+                "agency_id,agency_name,agency_url,agency_timezone\n\
+                 dial,Dial A Ride,https://example.com,America/Los_Angeles\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\n\
+                 depot,Depot,34.05,-118.25\n\
+                 stop_a,Stop A,34.06,-118.24\n\
+                 stop_b,Stop B,34.07,-118.23\n",
+            ),
+            (
+                "routes.txt",
+                "route_id,agency_id,route_short_name,route_type\n\
+                 fixed,dial,1,3\n\
+                 zone,dial,Z,3\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id\n\
+                 fixed,weekdays,fixed_1\n\
+                 zone,weekdays,flex_1\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+                 weekdays,1,1,1,1,1,0,0,20240101,20241231\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,location_group_id,location_id,stop_sequence,start_pickup_drop_off_window,end_pickup_drop_off_window,pickup_type,drop_off_type,pickup_booking_rule_id,drop_off_booking_rule_id\n\
+                 fixed_1,08:00:00,08:00:00,stop_a,,,1,,,0,0,,\n\
+                 fixed_1,08:10:00,08:10:00,stop_b,,,2,,,0,0,,\n\
+                 flex_1,07:00:00,07:00:00,depot,,,1,,,0,1,,\n\
+                 flex_1,,,,,zone_a,2,07:00:00,17:00:00,2,2,call_ahead,call_ahead\n",
+            ),
+            (
+                "locations.geojson",
+                r#"{"type":"FeatureCollection","features":[{"type":"Feature","id":"zone_a","properties":{"stop_name":"Zone A"},"geometry":{"type":"Polygon","coordinates":[[[-118.3,34.0],[-118.2,34.0],[-118.2,34.1],[-118.3,34.1],[-118.3,34.0]]]}}]}"#,
+            ),
+            (
+                "booking_rules.txt",
+                "booking_rule_id,booking_type,prior_notice_duration_min,phone_number\n\
+                 call_ahead,1,60,555-0100\n",
+            ),
+        ];
+
+        for (file_name, contents) in files {
+            std::fs::write(dir.join(file_name), contents).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn zone_stop_times_are_reduced_from_the_flex_rows() {
+        let dir = write_flex_feed();
+        let gtfs_path = dir.to_str().unwrap();
+
+        let flex_trips = read_flex_trips(gtfs_path);
+
+        assert_eq!(flex_trips.len(), 1);
+        assert_eq!(
+            flex_trips["flex_1"]
+                .iter()
+                .map(|flex_stop_time| flex_stop_time.stop_id.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["depot", "zone_a"]
+        );
+
+        // the zone row alone would make gtfs_structures reject the feed
+        let gtfs = read_gtfs_with_flex(gtfs_path, &flex_trips).unwrap();
+
+        assert!(gtfs.trips.contains_key("flex_1"));
+        assert_eq!(gtfs.trips["fixed_1"].stop_times.len(), 2);
+
+        let reduction = catenary::maple_syrup::reduce_with_flex(&gtfs, &flex_trips);
+
+        let flex_itinerary = &reduction.itineraries[&reduction.trips_to_itineraries["flex_1"]];
+
+        assert_eq!(
+            flex_itinerary
+                .stop_sequences
+                .iter()
+                .map(|stop_diff| (
+                    stop_diff.stop_id.as_str(),
+                    stop_diff.start_pickup_drop_off_window_since_start,
+                    stop_diff.end_pickup_drop_off_window_since_start,
+                    stop_diff.pickup_booking_rule_id.as_deref(),
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("depot", None, None, None),
+                ("zone_a", Some(0), Some(10 * 3600), Some("call_ahead")),
+            ]
+        );
+        assert!(reduction.trips_to_itineraries.contains_key("fixed_1"));
+
+        let flex_rows = read_flex(gtfs_path, "f-dial", "dial", "f-dial-attempt");
+
+        assert_eq!(
+            flex_rows
+                .flex_locations
+                .iter()
+                .map(|location| (location.location_id.as_str(), location.stop_name.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("zone_a", Some("Zone A"))]
+        );
+        assert_eq!(flex_rows.booking_rules.len(), 1);
+        assert_eq!(flex_rows.booking_rules[0].booking_type, 1);
+        assert_eq!(
+            flex_rows.booking_rules[0].prior_notice_duration_min,
+            Some(60)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn feeds_without_windows_have_no_flex_trips() {
+        let dir = write_flex_feed();
+        let gtfs_path = dir.to_str().unwrap();
+
+        std::fs::write(
+            dir.join("stop_times.txt"),
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             fixed_1,08:00:00,08:00:00,stop_a,1\n\
+             fixed_1,08:10:00,08:10:00,stop_b,2\n",
+        )
+        .unwrap();
+
+        let flex_trips = read_flex_trips(gtfs_path);
+
+        assert!(flex_trips.is_empty());
+        assert!(read_gtfs_with_flex(gtfs_path, &flex_trips).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stop_groups_are_flex_stops_too() {
+        let dir = write_flex_feed();
+        let gtfs_path = dir.to_str().unwrap();
+
+        std::fs::write(
+            dir.join("stop_times.txt"),
+            "trip_id,arrival_time,departure_time,stop_id,location_group_id,location_id,stop_sequence,start_pickup_drop_off_window,end_pickup_drop_off_window,pickup_type,drop_off_type\n\
+             fixed_1,08:00:00,08:00:00,stop_a,,,1,,,0,0\n\
+             fixed_1,08:10:00,08:10:00,stop_b,,,2,,,0,0\n\
+             flex_1,,,,group_1,,1,09:00:00,12:00:00,2,2\n\
+             flex_1,,,,,zone_a,2,09:00:00,12:30:00,2,2\n",
+        )
+        .unwrap();
+
+        let flex_trips = read_flex_trips(gtfs_path);
+
+        assert_eq!(
+            flex_trips["flex_1"]
+                .iter()
+                .map(|flex_stop_time| flex_stop_time.stop_id.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["group_1", "zone_a"]
+        );
+
+        // neither row is at a stop, so the trip is left without stop times for gtfs_structures
+        let gtfs = read_gtfs_with_flex(gtfs_path, &flex_trips).unwrap();
+
+        assert!(gtfs.trips["flex_1"].stop_times.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locations_need_an_id_and_an_area() {
+        let dir = write_flex_feed();
+        let gtfs_path = dir.to_str().unwrap();
+
+        std::fs::write(
+            dir.join("locations.geojson"),
+            r#"{"type":"FeatureCollection","features":[
+                {"type":"Feature","id":"stop_point","properties":{},"geometry":{"type":"Point","coordinates":[-118.25,34.05]}},
+                {"type":"Feature","properties":{},"geometry":{"type":"Polygon","coordinates":[[[-118.3,34.0],[-118.2,34.0],[-118.2,34.1],[-118.3,34.0]]]}},
+                {"type":"Feature","id":7,"properties":{},"geometry":{"type":"MultiPolygon","coordinates":[[[[-118.3,34.0],[-118.2,34.0],[-118.2,34.1],[-118.3,34.0]]]]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let flex_rows = read_flex(gtfs_path, "f-dial", "dial", "f-dial-attempt");
+
+        assert_eq!(
+            flex_rows
+                .flex_locations
+                .iter()
+                .map(|location| (location.location_id.as_str(), location.stop_name.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("7", None)]
+        );
+
+        std::fs::write(dir.join("locations.geojson"), "not geojson").unwrap();
+
+        assert!(read_flex(gtfs_path, "f-dial", "dial", "f-dial-attempt")
+            .flex_locations
+            .is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,5 +4,25 @@
 
 pub mod calendar_into_postgres;
 pub mod extra_stop_to_stop_shapes_into_postgres;
+pub mod fares_into_postgres;
+pub mod flex_into_postgres;
+pub mod pathways_into_postgres;
 pub mod shapes_into_postgres;
 pub mod stops_into_postgres;
+
+use serde::de::DeserializeOwned;
+
+/// Rows of a file in the unzipped feed, skipping missing files and rows which cannot be read
+pub fn read_gtfs_file<T: DeserializeOwned>(gtfs_path: &str, file_name: &str) -> Vec<T> {
+    let Ok(file) = std::fs::File::open(format!("{}/{}", gtfs_path, file_name)) else {
+        return vec![];
+    };
+
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(file)
+        .deserialize::<T>()
+        .filter_map(|row| row.ok())
+        .collect()
+}
@@ -0,0 +1,169 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Station interiors from pathways.txt and levels.txt, read straight from the unzipped feed.
+
+use super::read_gtfs_file;
+use catenary::models::{Level, Pathway};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct PathwayCsv {
+    pathway_id: String,
+    from_stop_id: String,
+    to_stop_id: String,
+    pathway_mode: i16,
+    is_bidirectional: u8,
+    length: Option<f32>,
+    traversal_time: Option<i32>,
+    stair_count: Option<i32>,
+    max_slope: Option<f32>,
+    min_width: Option<f32>,
+    signposted_as: Option<String>,
+    reversed_signposted_as: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LevelCsv {
+    level_id: String,
+    level_index: f64,
+    level_name: Option<String>,
+}
+
+/// Station interior rows of one attempt, read before any connection is taken
+pub struct PathwayRows {
+    pub pathways: Vec<Pathway>,
+    pub levels: Vec<Level>,
+}
+
+pub fn read_pathways(
+    gtfs_path: &str,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> PathwayRows {
+    let pathways = read_gtfs_file::<PathwayCsv>(gtfs_path, "pathways.txt")
+        .into_iter()
+        .map(|row| Pathway {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            pathway_id: row.pathway_id,
+            from_stop_id: row.from_stop_id,
+            to_stop_id: row.to_stop_id,
+            pathway_mode: row.pathway_mode,
+            is_bidirectional: row.is_bidirectional == 1,
+            length: row.length,
+            traversal_time: row.traversal_time,
+            stair_count: row.stair_count,
+            max_slope: row.max_slope,
+            min_width: row.min_width,
+            signposted_as: row.signposted_as,
+            reversed_signposted_as: row.reversed_signposted_as,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<Pathway>>();
+
+    let levels = read_gtfs_file::<LevelCsv>(gtfs_path, "levels.txt")
+        .into_iter()
+        .map(|row| Level {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            level_id: row.level_id,
+            level_index: row.level_index,
+            level_name: row.level_name,
+            chateau: chateau_id.to_string(),
+        })
+        .collect::<Vec<Level>>();
+
+    PathwayRows { pathways, levels }
+}
+
+/// Inserts on the given connection, so the diff ingest can run it inside its transaction
+pub async fn insert_pathways(
+    conn: &mut AsyncPgConnection,
+    pathway_rows: &PathwayRows,
+) -> Result<(), diesel::result::Error> {
+    for chunk in pathway_rows.pathways.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::pathways::dsl::pathways)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    for chunk in pathway_rows.levels.chunks(100) {
+        diesel::insert_into(catenary::schema::gtfs::levels::dsl::levels)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn pathways_into_postgres(
+    gtfs_path: &str,
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let pathway_rows = read_pathways(gtfs_path, feed_id, chateau_id, attempt_id);
+
+    insert_pathways(conn, &pathway_rows).await?;
+
+    Ok(())
+}
+
+/// Deletes the pathways and levels of one attempt, or of every attempt of the feed
+pub async fn delete_pathway_rows(
+    conn: &mut AsyncPgConnection,
+    feed_id: &str,
+    attempt_id: Option<&str>,
+) -> Result<(), diesel::result::Error> {
+    use catenary::schema::gtfs::levels::dsl as levels;
+    use catenary::schema::gtfs::pathways::dsl as pathways;
+
+    let mut pathways_delete =
+        diesel::delete(pathways::pathways.filter(pathways::onestop_feed_id.eq(feed_id)))
+            .into_boxed();
+    let mut levels_delete =
+        diesel::delete(levels::levels.filter(levels::onestop_feed_id.eq(feed_id))).into_boxed();
+
+    if let Some(attempt_id) = attempt_id {
+        pathways_delete = pathways_delete.filter(pathways::attempt_id.eq(attempt_id));
+        levels_delete = levels_delete.filter(levels::attempt_id.eq(attempt_id));
+    }
+
+    pathways_delete.execute(conn).await?;
+    levels_delete.execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn delete_pathways(
+    feed_id: &str,
+    attempt_id: Option<&str>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    delete_pathway_rows(conn, feed_id, attempt_id).await?;
+
+    Ok(())
+}
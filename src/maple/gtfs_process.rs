@@ -6,23 +6,34 @@ use crate::gtfs_handlers::colour_correction::fix_background_colour_rgb_feed_rout
 use crate::gtfs_handlers::colour_correction::fix_foreground_colour_rgb_feed;
 // Initial version 3 of ingest written by Kyler Chin
 // Removal of the attribution is not allowed, as covered under the AGPL license
+use crate::diff_ingest::{diff_ingest, hash_gtfs_files, DiffBase, DiffOutcome};
 use crate::gtfs_handlers::shape_colour_calculator::shape_to_colour;
 use crate::gtfs_handlers::shape_colour_calculator::ShapeToColourResponse;
 use crate::gtfs_handlers::stops_associated_items::*;
 use crate::gtfs_ingestion_sequence::calendar_into_postgres::calendar_into_postgres;
 use crate::gtfs_ingestion_sequence::extra_stop_to_stop_shapes_into_postgres::insert_stop_to_stop_geometry;
+use crate::gtfs_ingestion_sequence::fares_into_postgres::fares_into_postgres;
+use crate::gtfs_ingestion_sequence::flex_into_postgres::{
+    flex_into_postgres, read_flex_trips, read_gtfs_with_flex,
+};
+use crate::gtfs_ingestion_sequence::pathways_into_postgres::pathways_into_postgres;
 use crate::gtfs_ingestion_sequence::shapes_into_postgres::shapes_into_postgres;
 use crate::gtfs_ingestion_sequence::stops_into_postgres::stops_into_postgres;
+use crate::schedule_validation::save_schedule_notices;
 use crate::DownloadedFeedsInformation;
 use catenary::enum_to_int::*;
 use catenary::gtfs_schedule_protobuf::frequencies_to_protobuf;
 use catenary::maple_syrup;
+use catenary::maple_syrup::{
+    DirectionPattern, ItineraryCover, ResponseFromReduce, TripUnderItinerary,
+};
 use catenary::models::{
     DirectionPatternMeta, DirectionPatternRow, ItineraryPatternMeta, ItineraryPatternRow,
     Route as RoutePgModel,
 };
 use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::route_id_transform;
+use catenary::validate_gtfs::{find_duplicate_ids, validate_schedule};
 use chrono::NaiveDate;
 use diesel::ExpressionMethods;
 use diesel_async::RunQueryDsl;
@@ -31,7 +42,7 @@ use gtfs_structures::FeedInfo;
 use gtfs_translations::translation_csv_text_to_translations;
 use gtfs_translations::TranslationResult;
 use prost::Message;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::sync::Arc;
 use std::time::Instant;
@@ -43,6 +54,10 @@ pub struct GtfsSummary {
     pub languages_avaliable: HashSet<String>,
     pub default_lang: Option<String>,
     pub general_timezone: String,
+    /// Hashes of the files which must be unchanged for a diff ingestion
+    pub file_hashes: BTreeMap<String, u64>,
+    /// Production attempt which was patched in place, when the feed was diff ingested
+    pub diff_applied_to: Option<String>,
 }
 
 // take a feed id and throw it into postgres
@@ -53,6 +68,7 @@ pub async fn gtfs_process_feed(
     chateau_id: &str,
     attempt_id: &str,
     this_download_data: &DownloadedFeedsInformation,
+    diff_base: Option<&DiffBase>,
 ) -> Result<GtfsSummary, Box<dyn Error + Send + Sync>> {
     println!("Begin feed {} processing", feed_id);
     let start = Instant::now();
@@ -63,13 +79,18 @@ pub async fn gtfs_process_feed(
     //read the GTFS zip file
     let path = format!("{}/{}", gtfs_unzipped_path, feed_id);
 
-    let gtfs = gtfs_structures::Gtfs::new(path.as_str())?;
+    let flex_trips = read_flex_trips(&path);
+
+    let gtfs = read_gtfs_with_flex(&path, &flex_trips)?;
 
     println!(
         "Finished reading GTFS for {}, took {:?}",
         feed_id, gtfs.read_duration
     );
 
+    let mut schedule_notices = validate_schedule(&gtfs, chrono::Utc::now().date_naive());
+    schedule_notices.extend(find_duplicate_ids(&path));
+
     // Read Translations.txt, don't fail if it doesn't exist
     let translation_path = format!("{}/{}/translations.txt", gtfs_unzipped_path, feed_id);
     let translation_data = std::fs::read_to_string(translation_path);
@@ -90,6 +111,8 @@ pub async fn gtfs_process_feed(
             0 => String::from("Etc/UTC"),
             _ => gtfs.agencies[0].timezone.clone(),
         },
+        file_hashes: hash_gtfs_files(&path),
+        diff_applied_to: None,
     };
 
     let feed_info: Option<FeedInfo> = match !gtfs.feed_info.is_empty() {
@@ -133,41 +156,95 @@ pub async fn gtfs_process_feed(
         route_ids_to_shape_ids,
     } = shape_to_colour(feed_id, &gtfs);
 
-    //insert agencies
-    let mut agency_id_already_done: HashSet<Option<&String>> = HashSet::new();
-
-    for agency in &gtfs.agencies {
-        use catenary::schema::gtfs::agencies::dsl::agencies;
-
-        if !agency_id_already_done.contains(&agency.id.as_ref()) {
-            let agency_row = catenary::models::Agency {
-                static_onestop_id: feed_id.to_string(),
-                agency_id: agency.id.clone().unwrap_or_else(|| "".to_string()),
-                attempt_id: attempt_id.to_string(),
-                agency_name: agency.name.clone(),
-                agency_name_translations: None,
-                agency_url_translations: None,
-                agency_url: agency.url.clone(),
-                agency_fare_url: agency.fare_url.clone(),
-                agency_fare_url_translations: None,
-                chateau: chateau_id.to_string(),
-                agency_lang: agency.lang.clone(),
-                agency_phone: agency.phone.clone(),
-                agency_timezone: agency.timezone.clone(),
-            };
+    let start_reduction_timer = Instant::now();
+    let reduction = maple_syrup::reduce_with_flex(&gtfs, &flex_trips);
+    println!(
+        "Reduced schedule for {} in {:?}",
+        feed_id,
+        start_reduction_timer.elapsed()
+    );
+    println!(
+        "{} itineraries, {} trips, {:.2} ratio",
+        reduction.itineraries.len(),
+        reduction.trips_to_itineraries.len(),
+        reduction.trips_to_itineraries.len() as f64 / reduction.itineraries.len() as f64
+    );
 
-            diesel::insert_into(agencies)
-                .values(agency_row)
-                .execute(conn)
-                .await?;
+    if let Some(diff_base) = diff_base {
+        match diff_ingest(
+            &gtfs,
+            &path,
+            &reduction,
+            &gtfs_summary.file_hashes,
+            feed_id,
+            chateau_id,
+            diff_base,
+            &route_ids_to_shape_ids,
+            Arc::clone(&arc_conn_pool),
+        )
+        .await?
+        {
+            DiffOutcome::Applied(counts) => {
+                println!(
+                    "Diff ingested {} into {}: {:?}, took {:.3}s",
+                    feed_id,
+                    diff_base.attempt_id,
+                    counts,
+                    start.elapsed().as_secs_f32()
+                );
+
+                gtfs_summary.diff_applied_to = Some(diff_base.attempt_id.clone());
+
+                // the diff updated the base attempt in place, so the notices describe that attempt
+                if let Err(err) = save_schedule_notices(
+                    feed_id,
+                    &diff_base.attempt_id,
+                    chateau_id,
+                    this_download_data.hash,
+                    &schedule_notices,
+                    Arc::clone(&arc_conn_pool),
+                )
+                .await
+                {
+                    eprintln!(
+                        "Could not save schedule validation of {}: {:?}",
+                        feed_id, err
+                    );
+                }
 
-            agency_id_already_done.insert(agency.id.as_ref());
-        } else {
-            eprintln!("Warning! Duplicate agency id found: \n{:?}", agency);
+                return Ok(gtfs_summary);
+            }
+            DiffOutcome::Fallback(reason) => {
+                println!("Full ingest of {}: {}", feed_id, reason);
+            }
         }
     }
 
-    drop(agency_id_already_done);
+    if let Err(err) = save_schedule_notices(
+        feed_id,
+        attempt_id,
+        chateau_id,
+        this_download_data.hash,
+        &schedule_notices,
+        Arc::clone(&arc_conn_pool),
+    )
+    .await
+    {
+        eprintln!(
+            "Could not save schedule validation of {}: {:?}",
+            feed_id, err
+        );
+    }
+
+    //insert agencies
+    for agency_row in agencies_to_pg(&gtfs, feed_id, chateau_id, attempt_id) {
+        use catenary::schema::gtfs::agencies::dsl::agencies;
+
+        diesel::insert_into(agencies)
+            .values(agency_row)
+            .execute(conn)
+            .await?;
+    }
 
     //shove raw geometry into postgresql
     if chateau_id != "amtrak" {
@@ -207,98 +284,64 @@ pub async fn gtfs_process_feed(
     )
     .await?;
 
-    // insert trip and itineraries
+    fares_into_postgres(
+        &path,
+        feed_id,
+        Arc::clone(&arc_conn_pool),
+        chateau_id,
+        attempt_id,
+    )
+    .await?;
 
-    let start_reduction_timer = Instant::now();
-    let reduction = maple_syrup::reduce(&gtfs);
-    println!(
-        "Reduced schedule for {} in {:?}",
+    pathways_into_postgres(
+        &path,
         feed_id,
-        start_reduction_timer.elapsed()
-    );
-    println!(
-        "{} itineraries, {} trips, {:.2} ratio",
-        reduction.itineraries.len(),
-        reduction.trips_to_itineraries.len(),
-        reduction.trips_to_itineraries.len() as f64 / reduction.itineraries.len() as f64
-    );
-    for (direction_pattern_id, direction_pattern) in &reduction.direction_patterns {
-        let gtfs_shape_id = match &direction_pattern.gtfs_shape_id {
-            Some(gtfs_shape_id) => gtfs_shape_id.clone(),
-            None => direction_pattern_id.to_string(),
-        };
-
-        let first_itin_id = reduction
-            .direction_pattern_id_to_itineraries
-            .get(direction_pattern_id)
-            .unwrap()
-            .iter()
-            .next()
-            .expect("Expected Itin for direction id");
+        Arc::clone(&arc_conn_pool),
+        chateau_id,
+        attempt_id,
+    )
+    .await?;
 
-        let itin_pattern = reduction
-            .itineraries
-            .get(first_itin_id)
-            .expect("Did not find itin pattern, crashing....");
+    flex_into_postgres(
+        &path,
+        feed_id,
+        Arc::clone(&arc_conn_pool),
+        chateau_id,
+        attempt_id,
+    )
+    .await?;
+
+    // insert trip and itineraries
 
+    for (direction_pattern_id, direction_pattern) in &reduction.direction_patterns {
         if direction_pattern.gtfs_shape_id.is_none() {
-            //: postgis_diesel::types::LineString<postgis_diesel::types::Point>
-
-            let stop_points = direction_pattern
-                .stop_sequence
-                .iter()
-                .filter_map(|stop_id| gtfs.stops.get(stop_id.as_str()))
-                .filter_map(|stop| match (stop.latitude, stop.longitude) {
-                    (Some(latitude), Some(longitude)) => Some(postgis_diesel::types::Point {
-                        y: latitude,
-                        x: longitude,
-                        srid: Some(4326),
-                    }),
-                    _ => None,
-                })
-                .collect::<Vec<postgis_diesel::types::Point>>();
-
-            if stop_points.len() > 2 {
-                let linestring: postgis_diesel::types::LineString<postgis_diesel::types::Point> =
-                    postgis_diesel::types::LineString {
-                        points: stop_points,
-                        srid: Some(4326),
-                    };
-
-                //insert into shapes and shapes_not_bus
-
-                let route = gtfs.routes.get(direction_pattern.route_id.as_str());
-
-                if let Some(route) = route {
-                    let _ = insert_stop_to_stop_geometry(
-                        feed_id,
-                        attempt_id,
-                        chateau_id,
-                        route,
-                        *direction_pattern_id,
-                        &linestring,
-                        Arc::clone(&arc_conn_pool),
-                    )
-                    .await;
-                }
+            let linestring = stop_to_stop_linestring(&gtfs, direction_pattern);
+            let route = gtfs.routes.get(direction_pattern.route_id.as_str());
+
+            //insert into shapes and shapes_not_bus
+            if let (Some(linestring), Some(route)) = (linestring, route) {
+                let _ = insert_stop_to_stop_geometry(
+                    feed_id,
+                    attempt_id,
+                    chateau_id,
+                    route,
+                    *direction_pattern_id,
+                    &linestring,
+                    false,
+                    Arc::clone(&arc_conn_pool),
+                )
+                .await;
             }
         }
 
-        let direction_pattern_meta = DirectionPatternMeta {
-            chateau: chateau_id.to_string(),
-            direction_pattern_id: direction_pattern_id.to_string(),
-            headsign_or_destination: direction_pattern
-                .headsign_or_destination
-                .clone()
-                .unwrap_or_else(|| "".to_string()),
-            gtfs_shape_id: Some(gtfs_shape_id.clone()),
-            fake_shape: direction_pattern.gtfs_shape_id.is_none(),
-            onestop_feed_id: feed_id.to_string(),
-            attempt_id: attempt_id.to_string(),
-            route_id: Some(itin_pattern.route_id.clone()),
-            route_type: Some(itin_pattern.route_type),
-            direction_id: itin_pattern.direction_id,
-        };
+        let (direction_pattern_meta, direction_pattern_rows) = direction_pattern_to_pg(
+            *direction_pattern_id,
+            direction_pattern,
+            &reduction,
+            feed_id,
+            chateau_id,
+            attempt_id,
+        );
 
         //insert stop list into DirectionPatternRow
 
@@ -309,23 +352,6 @@ pub async fn gtfs_process_feed(
         .execute(conn)
         .await?;
 
-        let direction_pattern_rows: Vec<DirectionPatternRow> = itin_pattern
-            .stop_sequences
-            .iter()
-            .enumerate()
-            .map(|(stop_idx, stop_time)| DirectionPatternRow {
-                attempt_id: attempt_id.to_string(),
-                chateau: chateau_id.to_string(),
-                direction_pattern_id: direction_pattern_id.to_string(),
-                stop_id: stop_time.stop_id.clone(),
-                stop_sequence: stop_idx as u32,
-                onestop_feed_id: feed_id.to_string(),
-                arrival_time_since_start: stop_time.arrival_time_since_start,
-                departure_time_since_start: stop_time.departure_time_since_start,
-                interpolated_time_since_start: stop_time.interpolated_time_since_start,
-            })
-            .collect();
-
         for dir_chunk in direction_pattern_rows.chunks(100) {
             diesel::insert_into(catenary::schema::gtfs::direction_pattern::dsl::direction_pattern)
                 .values(dir_chunk)
@@ -335,29 +361,14 @@ pub async fn gtfs_process_feed(
     }
 
     for (itinerary_id, itinerary) in &reduction.itineraries {
-        let itinerary_pg_meta = ItineraryPatternMeta {
-            onestop_feed_id: feed_id.to_string(),
-            chateau: chateau_id.to_string(),
-            attempt_id: attempt_id.to_string(),
-            timezone: itinerary.timezone.clone(),
-            trip_headsign: itinerary
-                .trip_headsign
-                .clone()
-                .map(|x| x.replace(" - Funded in part by/SB County Measure A", "")),
-            trip_headsign_translations: None,
-            itinerary_pattern_id: itinerary_id.to_string(),
-            trip_ids: reduction
-                .itineraries_to_trips
-                .get(itinerary_id)
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|trip_under_itin| Some(trip_under_itin.trip_id.to_string()))
-                .collect::<Vec<Option<String>>>(),
-            shape_id: itinerary.shape_id.clone(),
-            route_id: itinerary.route_id.clone(),
-            direction_pattern_id: Some(itinerary.direction_pattern_id.to_string()),
-        };
+        let (itinerary_pg_meta, itinerary_pg) = itinerary_to_pg(
+            *itinerary_id,
+            itinerary,
+            &reduction,
+            feed_id,
+            chateau_id,
+            attempt_id,
+        );
 
         diesel::insert_into(
             catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta,
@@ -366,24 +377,6 @@ pub async fn gtfs_process_feed(
         .execute(conn)
         .await?;
 
-        let itinerary_pg = itinerary
-            .stop_sequences
-            .iter()
-            .enumerate()
-            .map(|(stop_index, stop_sequence)| ItineraryPatternRow {
-                onestop_feed_id: feed_id.to_string(),
-                chateau: chateau_id.to_string(),
-                attempt_id: attempt_id.to_string(),
-                itinerary_pattern_id: itinerary_id.to_string(),
-                stop_sequence: stop_index as i32,
-                stop_id: stop_sequence.stop_id.clone(),
-                gtfs_stop_sequence: stop_sequence.gtfs_stop_sequence as u32,
-                arrival_time_since_start: stop_sequence.arrival_time_since_start,
-                departure_time_since_start: stop_sequence.departure_time_since_start,
-                interpolated_time_since_start: stop_sequence.interpolated_time_since_start,
-            })
-            .collect::<Vec<_>>();
-
         for itinerary_chunk in itinerary_pg.chunks(100) {
             diesel::insert_into(catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern)
                 .values(itinerary_chunk)
@@ -393,37 +386,14 @@ pub async fn gtfs_process_feed(
     }
 
     for (itinerary_id, compressed_trip_list) in &reduction.itineraries_to_trips {
-        let trip_pg = compressed_trip_list
-            .iter()
-            .map(|compressed_trip_raw| catenary::models::CompressedTrip {
-                onestop_feed_id: feed_id.to_string(),
-                chateau: chateau_id.to_string(),
-                attempt_id: attempt_id.to_string(),
-                itinerary_pattern_id: itinerary_id.to_string(),
-                trip_id: compressed_trip_raw.trip_id.to_string(),
-                service_id: compressed_trip_raw.service_id.clone(),
-                direction_id: reduction
-                    .itineraries
-                    .get(itinerary_id)
-                    .unwrap()
-                    .direction_id,
-                start_time: compressed_trip_raw.start_time,
-                trip_short_name: compressed_trip_raw.trip_short_name.clone(),
-                block_id: compressed_trip_raw.block_id.clone(),
-                wheelchair_accessible: compressed_trip_raw.wheelchair_accessible,
-                bikes_allowed: compressed_trip_raw.bikes_allowed,
-                has_frequencies: !compressed_trip_raw.frequencies.is_empty(),
-                route_id: route_id_transform(feed_id, compressed_trip_raw.route_id.clone()),
-                frequencies: match !compressed_trip_raw.frequencies.is_empty() {
-                    true => {
-                        let prost_message =
-                            frequencies_to_protobuf(&compressed_trip_raw.frequencies);
-                        Some(prost_message.encode_to_vec())
-                    }
-                    false => None,
-                },
-            })
-            .collect::<Vec<_>>();
+        let trip_pg = trips_to_pg(
+            *itinerary_id,
+            compressed_trip_list,
+            &reduction,
+            feed_id,
+            chateau_id,
+            attempt_id,
+        );
 
         diesel::insert_into(catenary::schema::gtfs::trips_compressed::dsl::trips_compressed)
             .values(trip_pg)
@@ -433,52 +403,13 @@ pub async fn gtfs_process_feed(
 
     //insert routes
 
-    let routes_pg: Vec<RoutePgModel> = gtfs
-        .routes
-        .iter()
-        .map(|(route_id, route)| {
-            let colour = fix_background_colour_rgb_feed_route(feed_id, route.color, route);
-            let text_colour =
-                fix_foreground_colour_rgb_feed(feed_id, route.color, route.text_color);
-
-            let colour_pg = format!("#{:02x}{:02x}{:02x}", colour.r, colour.g, colour.b);
-            let text_colour_pg = format!(
-                "#{:02x}{:02x}{:02x}",
-                text_colour.r, text_colour.g, text_colour.b
-            );
-
-            let route_pg = RoutePgModel {
-                onestop_feed_id: feed_id.to_string(),
-                route_id: route_id_transform(feed_id, route_id.to_string()),
-                attempt_id: attempt_id.to_string(),
-                agency_id: route.agency_id.clone(),
-                short_name: route.short_name.clone(),
-                long_name: route.long_name.clone(),
-                chateau: chateau_id.to_string(),
-                color: Some(colour_pg),
-                text_color: Some(text_colour_pg),
-                short_name_translations: None,
-                long_name_translations: None,
-                gtfs_desc: route.desc.clone(),
-                gtfs_desc_translations: None,
-                route_type: route_type_to_int(&route.route_type),
-                url: route.url.clone(),
-                url_translations: None,
-                shapes_list: route_ids_to_shape_ids
-                    .get(&route_id.clone())
-                    .map(|shapes_list| {
-                        shapes_list
-                            .iter()
-                            .map(|x| Some(x.clone()))
-                            .collect::<Vec<Option<String>>>()
-                    }),
-                gtfs_order: route.order,
-                continuous_drop_off: continuous_pickup_drop_off_to_i16(&route.continuous_drop_off),
-                continuous_pickup: continuous_pickup_drop_off_to_i16(&route.continuous_pickup),
-            };
-            route_pg
-        })
-        .collect();
+    let routes_pg = routes_to_pg(
+        &gtfs,
+        feed_id,
+        chateau_id,
+        attempt_id,
+        &route_ids_to_shape_ids,
+    );
 
     for routes_chunk in routes_pg.chunks(100) {
         diesel::insert_into(catenary::schema::gtfs::routes::dsl::routes)
@@ -494,23 +425,8 @@ pub async fn gtfs_process_feed(
     if let Some(feed_info) = &feed_info {
         use catenary::schema::gtfs::feed_info::dsl::feed_info as feed_table;
 
-        let feed_info_pg = catenary::models::FeedInfo {
-            onestop_feed_id: feed_id.to_string(),
-            feed_publisher_name: feed_info.name.clone(),
-            feed_publisher_url: feed_info.url.clone(),
-            feed_lang: feed_info.lang.clone(),
-            feed_start_date: feed_info.start_date,
-            feed_end_date: feed_info.end_date,
-            feed_version: feed_info.version.clone(),
-            feed_contact_email: feed_info.contact_email.clone(),
-            feed_contact_url: feed_info.contact_url.clone(),
-            attempt_id: attempt_id.to_string(),
-            default_lang: feed_info.default_lang.clone(),
-            chateau: chateau_id.to_string(),
-        };
-
         diesel::insert_into(feed_table)
-            .values(feed_info_pg)
+            .values(feed_info_to_pg(feed_info, feed_id, chateau_id, attempt_id))
             .execute(conn)
             .await?;
     }
@@ -571,6 +487,310 @@ pub async fn gtfs_process_feed(
     Ok(gtfs_summary)
 }
 
+// Rows of the schedule tables, shared by full and diff ingestion
+
+pub fn agencies_to_pg(
+    gtfs: &gtfs_structures::Gtfs,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Vec<catenary::models::Agency> {
+    let mut agency_id_already_done: HashSet<Option<&String>> = HashSet::new();
+    let mut agencies_pg: Vec<catenary::models::Agency> = Vec::new();
+
+    for agency in &gtfs.agencies {
+        if !agency_id_already_done.contains(&agency.id.as_ref()) {
+            agencies_pg.push(catenary::models::Agency {
+                static_onestop_id: feed_id.to_string(),
+                agency_id: agency.id.clone().unwrap_or_else(|| "".to_string()),
+                attempt_id: attempt_id.to_string(),
+                agency_name: agency.name.clone(),
+                agency_name_translations: None,
+                agency_url_translations: None,
+                agency_url: agency.url.clone(),
+                agency_fare_url: agency.fare_url.clone(),
+                agency_fare_url_translations: None,
+                chateau: chateau_id.to_string(),
+                agency_lang: agency.lang.clone(),
+                agency_phone: agency.phone.clone(),
+                agency_timezone: agency.timezone.clone(),
+            });
+
+            agency_id_already_done.insert(agency.id.as_ref());
+        } else {
+            eprintln!("Warning! Duplicate agency id found: \n{:?}", agency);
+        }
+    }
+
+    agencies_pg
+}
+
+/// Geometry through the stops of a direction pattern, for direction patterns without a shape
+pub fn stop_to_stop_linestring(
+    gtfs: &gtfs_structures::Gtfs,
+    direction_pattern: &DirectionPattern,
+) -> Option<postgis_diesel::types::LineString<postgis_diesel::types::Point>> {
+    let stop_points = direction_pattern
+        .stop_sequence
+        .iter()
+        .filter_map(|stop_id| gtfs.stops.get(stop_id.as_str()))
+        .filter_map(|stop| match (stop.latitude, stop.longitude) {
+            (Some(latitude), Some(longitude)) => Some(postgis_diesel::types::Point {
+                y: latitude,
+                x: longitude,
+                srid: Some(4326),
+            }),
+            _ => None,
+        })
+        .collect::<Vec<postgis_diesel::types::Point>>();
+
+    match stop_points.len() > 2 {
+        true => Some(postgis_diesel::types::LineString {
+            points: stop_points,
+            srid: Some(4326),
+        }),
+        false => None,
+    }
+}
+
+pub fn direction_pattern_to_pg(
+    direction_pattern_id: u64,
+    direction_pattern: &DirectionPattern,
+    reduction: &ResponseFromReduce,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> (DirectionPatternMeta, Vec<DirectionPatternRow>) {
+    let gtfs_shape_id = match &direction_pattern.gtfs_shape_id {
+        Some(gtfs_shape_id) => gtfs_shape_id.clone(),
+        None => direction_pattern_id.to_string(),
+    };
+
+    let first_itin_id = reduction
+        .direction_pattern_id_to_itineraries
+        .get(&direction_pattern_id)
+        .unwrap()
+        .iter()
+        .next()
+        .expect("Expected Itin for direction id");
+
+    let itin_pattern = reduction
+        .itineraries
+        .get(first_itin_id)
+        .expect("Did not find itin pattern, crashing....");
+
+    let direction_pattern_meta = DirectionPatternMeta {
+        chateau: chateau_id.to_string(),
+        direction_pattern_id: direction_pattern_id.to_string(),
+        headsign_or_destination: direction_pattern
+            .headsign_or_destination
+            .clone()
+            .unwrap_or_else(|| "".to_string()),
+        gtfs_shape_id: Some(gtfs_shape_id.clone()),
+        fake_shape: direction_pattern.gtfs_shape_id.is_none(),
+        onestop_feed_id: feed_id.to_string(),
+        attempt_id: attempt_id.to_string(),
+        route_id: Some(itin_pattern.route_id.clone()),
+        route_type: Some(itin_pattern.route_type),
+        direction_id: itin_pattern.direction_id,
+    };
+
+    let direction_pattern_rows: Vec<DirectionPatternRow> = itin_pattern
+        .stop_sequences
+        .iter()
+        .enumerate()
+        .map(|(stop_idx, stop_time)| DirectionPatternRow {
+            attempt_id: attempt_id.to_string(),
+            chateau: chateau_id.to_string(),
+            direction_pattern_id: direction_pattern_id.to_string(),
+            stop_id: stop_time.stop_id.clone(),
+            stop_sequence: stop_idx as u32,
+            onestop_feed_id: feed_id.to_string(),
+            arrival_time_since_start: stop_time.arrival_time_since_start,
+            departure_time_since_start: stop_time.departure_time_since_start,
+            interpolated_time_since_start: stop_time.interpolated_time_since_start,
+        })
+        .collect();
+
+    (direction_pattern_meta, direction_pattern_rows)
+}
+
+pub fn itinerary_to_pg(
+    itinerary_id: u64,
+    itinerary: &ItineraryCover,
+    reduction: &ResponseFromReduce,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> (ItineraryPatternMeta, Vec<ItineraryPatternRow>) {
+    let itinerary_pg_meta = ItineraryPatternMeta {
+        onestop_feed_id: feed_id.to_string(),
+        chateau: chateau_id.to_string(),
+        attempt_id: attempt_id.to_string(),
+        timezone: itinerary.timezone.clone(),
+        trip_headsign: itinerary
+            .trip_headsign
+            .clone()
+            .map(|x| x.replace(" - Funded in part by/SB County Measure A", "")),
+        trip_headsign_translations: None,
+        itinerary_pattern_id: itinerary_id.to_string(),
+        trip_ids: reduction
+            .itineraries_to_trips
+            .get(&itinerary_id)
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|trip_under_itin| Some(trip_under_itin.trip_id.to_string()))
+            .collect::<Vec<Option<String>>>(),
+        shape_id: itinerary.shape_id.clone(),
+        route_id: itinerary.route_id.clone(),
+        direction_pattern_id: Some(itinerary.direction_pattern_id.to_string()),
+    };
+
+    let itinerary_pg = itinerary
+        .stop_sequences
+        .iter()
+        .enumerate()
+        .map(|(stop_index, stop_sequence)| ItineraryPatternRow {
+            onestop_feed_id: feed_id.to_string(),
+            chateau: chateau_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            itinerary_pattern_id: itinerary_id.to_string(),
+            stop_sequence: stop_index as i32,
+            stop_id: stop_sequence.stop_id.clone(),
+            gtfs_stop_sequence: stop_sequence.gtfs_stop_sequence as u32,
+            arrival_time_since_start: stop_sequence.arrival_time_since_start,
+            departure_time_since_start: stop_sequence.departure_time_since_start,
+            interpolated_time_since_start: stop_sequence.interpolated_time_since_start,
+            timepoint: Some(stop_sequence.timepoint),
+            start_pickup_drop_off_window_since_start: stop_sequence
+                .start_pickup_drop_off_window_since_start,
+            end_pickup_drop_off_window_since_start: stop_sequence
+                .end_pickup_drop_off_window_since_start,
+            pickup_booking_rule_id: stop_sequence.pickup_booking_rule_id.clone(),
+            drop_off_booking_rule_id: stop_sequence.drop_off_booking_rule_id.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    (itinerary_pg_meta, itinerary_pg)
+}
+
+pub fn trips_to_pg(
+    itinerary_id: u64,
+    compressed_trip_list: &[TripUnderItinerary],
+    reduction: &ResponseFromReduce,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> Vec<catenary::models::CompressedTrip> {
+    compressed_trip_list
+        .iter()
+        .map(|compressed_trip_raw| catenary::models::CompressedTrip {
+            onestop_feed_id: feed_id.to_string(),
+            chateau: chateau_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            itinerary_pattern_id: itinerary_id.to_string(),
+            trip_id: compressed_trip_raw.trip_id.to_string(),
+            service_id: compressed_trip_raw.service_id.clone(),
+            direction_id: reduction
+                .itineraries
+                .get(&itinerary_id)
+                .unwrap()
+                .direction_id,
+            start_time: compressed_trip_raw.start_time,
+            trip_short_name: compressed_trip_raw.trip_short_name.clone(),
+            block_id: compressed_trip_raw.block_id.clone(),
+            wheelchair_accessible: compressed_trip_raw.wheelchair_accessible,
+            bikes_allowed: compressed_trip_raw.bikes_allowed,
+            has_frequencies: !compressed_trip_raw.frequencies.is_empty(),
+            route_id: route_id_transform(feed_id, compressed_trip_raw.route_id.clone()),
+            frequencies: match !compressed_trip_raw.frequencies.is_empty() {
+                true => {
+                    let prost_message = frequencies_to_protobuf(&compressed_trip_raw.frequencies);
+                    Some(prost_message.encode_to_vec())
+                }
+                false => None,
+            },
+        })
+        .collect::<Vec<_>>()
+}
+
+pub fn routes_to_pg(
+    gtfs: &gtfs_structures::Gtfs,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+    route_ids_to_shape_ids: &HashMap<String, HashSet<String>>,
+) -> Vec<RoutePgModel> {
+    gtfs.routes
+        .iter()
+        .map(|(route_id, route)| {
+            let colour = fix_background_colour_rgb_feed_route(feed_id, route.color, route);
+            let text_colour =
+                fix_foreground_colour_rgb_feed(feed_id, route.color, route.text_color);
+
+            let colour_pg = format!("#{:02x}{:02x}{:02x}", colour.r, colour.g, colour.b);
+            let text_colour_pg = format!(
+                "#{:02x}{:02x}{:02x}",
+                text_colour.r, text_colour.g, text_colour.b
+            );
+
+            let route_pg = RoutePgModel {
+                onestop_feed_id: feed_id.to_string(),
+                route_id: route_id_transform(feed_id, route_id.to_string()),
+                attempt_id: attempt_id.to_string(),
+                agency_id: route.agency_id.clone(),
+                short_name: route.short_name.clone(),
+                long_name: route.long_name.clone(),
+                chateau: chateau_id.to_string(),
+                color: Some(colour_pg),
+                text_color: Some(text_colour_pg),
+                short_name_translations: None,
+                long_name_translations: None,
+                gtfs_desc: route.desc.clone(),
+                gtfs_desc_translations: None,
+                route_type: route_type_to_int(&route.route_type),
+                url: route.url.clone(),
+                url_translations: None,
+                shapes_list: route_ids_to_shape_ids
+                    .get(&route_id.clone())
+                    .map(|shapes_list| {
+                        shapes_list
+                            .iter()
+                            .map(|x| Some(x.clone()))
+                            .collect::<Vec<Option<String>>>()
+                    }),
+                gtfs_order: route.order,
+                continuous_drop_off: continuous_pickup_drop_off_to_i16(&route.continuous_drop_off),
+                continuous_pickup: continuous_pickup_drop_off_to_i16(&route.continuous_pickup),
+            };
+            route_pg
+        })
+        .collect()
+}
+
+pub fn feed_info_to_pg(
+    feed_info: &FeedInfo,
+    feed_id: &str,
+    chateau_id: &str,
+    attempt_id: &str,
+) -> catenary::models::FeedInfo {
+    catenary::models::FeedInfo {
+        onestop_feed_id: feed_id.to_string(),
+        feed_publisher_name: feed_info.name.clone(),
+        feed_publisher_url: feed_info.url.clone(),
+        feed_lang: feed_info.lang.clone(),
+        feed_start_date: feed_info.start_date,
+        feed_end_date: feed_info.end_date,
+        feed_version: feed_info.version.clone(),
+        feed_contact_email: feed_info.contact_email.clone(),
+        feed_contact_url: feed_info.contact_url.clone(),
+        attempt_id: attempt_id.to_string(),
+        default_lang: feed_info.default_lang.clone(),
+        chateau: chateau_id.to_string(),
+    }
+}
+
 pub fn pickup_dropoff_to_i16(x: &gtfs_structures::PickupDropOffType) -> i16 {
     match x {
         gtfs_structures::PickupDropOffType::Regular => 0,
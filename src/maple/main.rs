@@ -34,6 +34,8 @@
 use ahash::AHashMap;
 use catenary::postgres_tools::make_async_pool;
 use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::prairie::transfer_patterns::precompute_transfer_patterns_for_chateau;
+use catenary::validate_gtfs::ScheduleNotice;
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use futures::StreamExt;
@@ -49,10 +51,12 @@ use crate::cleanup::wipe_whole_feed;
 mod assign_production_tables;
 mod chateau_postprocess;
 mod cleanup;
+mod diff_ingest;
 mod gtfs_handlers;
 mod gtfs_ingestion_sequence;
 mod gtfs_process;
 mod refresh_metadata_tables;
+mod schedule_validation;
 mod transitland_download;
 mod update_schedules_with_new_chateau_id;
 
@@ -249,7 +253,7 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
             // process into GTFS representation
             // run function to insert GTFS raw data
             // (todo) use k/d tree presentation to calculate line optimisation and transfer patterns (not clear how this works, needs further research)
-            // (todo) hand off to routing algorithm preprocessing engine Prarie (needs further research and development)
+            // routing algorithm preprocessing engine Prairie runs for every chateau ingested below
 
             // 2. update metadata
             futures::stream::iter(eligible_feeds.iter().map(|eligible_feed| {
@@ -424,7 +428,7 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
 
             let total_feeds_to_process = feeds_to_process.len() as u16;
 
-            futures::stream::iter(
+            let ingested_chateaus = futures::stream::iter(
                 feeds_to_process
                 .into_iter()
                 .map(|(feed_id, attempt_id, chateau_id)| {
@@ -442,6 +446,18 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                                 let this_download_data = download_feed_info_hashmap.get(&feed_id).unwrap();
                                 
                                     let start_time = chrono::Utc::now().timestamp_millis();
+
+                                    // patch the production attempt in place when only the schedule changed
+                                    let diff_base = match diff_ingest::diff_ingest_enabled() {
+                                        true => match diff_ingest::find_diff_base(&feed_id, Arc::clone(&arc_conn_pool)).await {
+                                            Ok(diff_base) => diff_base,
+                                            Err(err) => {
+                                                eprintln!("Could not look up the production attempt of {} for a diff ingest, doing a full ingest: {:#?}", feed_id, err);
+                                                None
+                                            }
+                                        },
+                                        false => None,
+                                    };
                                 
                                     // call function to process GTFS feed, accepting feed_id, diesel pool args, chateau_id, attempt_id
                                     let gtfs_process_result = gtfs_process_feed(
@@ -451,6 +467,7 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                                         &chateau_id,
                                         &attempt_id,
                                         this_download_data,
+                                        diff_base.as_ref(),
                                     )
                                     .await;
 
@@ -465,6 +482,8 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                                     );
 
                                     std::mem::drop(ingest_progress);
+
+                                    let ingest_succeeded = gtfs_process_result.is_ok();
         
                                     if gtfs_process_result.is_ok() {
                                         // at the end, UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as ingested
@@ -478,42 +497,82 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                                             .execute(conn)
                                             .await;
 
+                                        let gtfs_summary = gtfs_process_result.unwrap();
+
                                         use catenary::schema::gtfs::ingested_static::dsl::ingested_static;
 
-                                        let ingested_static_pq = catenary::models::IngestedStatic {
-                                            onestop_feed_id: feed_id.clone(),
-                                            attempt_id: attempt_id.clone(),
-                                            languages_avaliable: vec![],
-                                            file_hash: format!("{}",download_feed_info_hashmap.get(&feed_id).unwrap().hash.unwrap()),
-                                            ingest_start_unix_time_ms: start_time,
-                                            ingest_end_unix_time_ms: chrono::Utc::now().timestamp_millis(),
-                                            ingest_duration_ms: (chrono::Utc::now().timestamp_millis() - start_time) as i32,
-                                            ingesting_in_progress: false,
-                                            ingestion_errored: false,
-                                            ingestion_successfully_finished: true,
-                                            deleted: false,
-                                            default_lang: gtfs_process_result.unwrap().default_lang,
-                                            production: false,
-                                            feed_expiration_date: None,
-                                            feed_start_date: None,
-                                            ingestion_version: MAPLE_INGESTION_VERSION,
-                                        };
-
-                                        let _ = diesel::insert_into(ingested_static)
-                                            .values(&ingested_static_pq)
-                                            .on_conflict_do_nothing()
-                                            .execute(conn)
-                                            .await;
+                                        let file_hashes = serde_json::to_value(&gtfs_summary.file_hashes).ok();
+
+                                        match &gtfs_summary.diff_applied_to {
+                                            Some(diff_attempt_id) => {
+                                                // the patched attempt stays in production, now with the hashes of this download
+                                                use catenary::schema::gtfs::ingested_static::dsl as ingested_static_columns;
+
+                                                let _ = diesel::update(ingested_static
+                                                    .filter(ingested_static_columns::onestop_feed_id.eq(&feed_id))
+                                                    .filter(ingested_static_columns::attempt_id.eq(diff_attempt_id))
+                                                )
+                                                .set((
+                                                    ingested_static_columns::file_hash.eq(format!("{}",this_download_data.hash.unwrap())),
+                                                    ingested_static_columns::file_hashes.eq(file_hashes),
+                                                    ingested_static_columns::ingest_start_unix_time_ms.eq(start_time),
+                                                    ingested_static_columns::ingest_end_unix_time_ms.eq(chrono::Utc::now().timestamp_millis()),
+                                                    ingested_static_columns::ingest_duration_ms.eq((chrono::Utc::now().timestamp_millis() - start_time) as i32),
+                                                    ingested_static_columns::default_lang.eq(gtfs_summary.default_lang.clone()),
+                                                ))
+                                                .execute(conn)
+                                                .await;
+                                            }
+                                            None => {
+                                                let ingested_static_pq = catenary::models::IngestedStatic {
+                                                    onestop_feed_id: feed_id.clone(),
+                                                    attempt_id: attempt_id.clone(),
+                                                    languages_avaliable: vec![],
+                                                    file_hash: format!("{}",download_feed_info_hashmap.get(&feed_id).unwrap().hash.unwrap()),
+                                                    ingest_start_unix_time_ms: start_time,
+                                                    ingest_end_unix_time_ms: chrono::Utc::now().timestamp_millis(),
+                                                    ingest_duration_ms: (chrono::Utc::now().timestamp_millis() - start_time) as i32,
+                                                    ingesting_in_progress: false,
+                                                    ingestion_errored: false,
+                                                    ingestion_successfully_finished: true,
+                                                    deleted: false,
+                                                    default_lang: gtfs_summary.default_lang.clone(),
+                                                    production: false,
+                                                    feed_expiration_date: None,
+                                                    feed_start_date: None,
+                                                    ingestion_version: MAPLE_INGESTION_VERSION,
+                                                    file_hashes,
+                                                };
+
+                                                let _ = diesel::insert_into(ingested_static)
+                                                    .values(&ingested_static_pq)
+                                                    .on_conflict_do_nothing()
+                                                    .execute(conn)
+                                                    .await;
+
+                                                let _ = assign_production_tables::assign_production_tables(
+                                                    &feed_id,
+                                                    &attempt_id,
+                                                    Arc::clone(&arc_conn_pool),
+                                                ).await;
+                                            }
+                                        }
 
-                                        let _ = assign_production_tables::assign_production_tables(
+                                    } else {
+                                        let gtfs_process_error = gtfs_process_result.unwrap_err();
+
+                                        //print output
+                                        eprintln!("GTFS process failed for feed {},\n {:?}", feed_id, gtfs_process_error);
+
+                                        //keep the reason next to the validation results of this attempt
+                                        let _ = schedule_validation::save_schedule_notices(
                                             &feed_id,
                                             &attempt_id,
+                                            &chateau_id,
+                                            this_download_data.hash,
+                                            &[ScheduleNotice::ingest_failed(format!("{}", gtfs_process_error))],
                                             Arc::clone(&arc_conn_pool),
                                         ).await;
-
-                                    } else {
-                                        //print output
-                                        eprintln!("GTFS process failed for feed {},\n {:?}", feed_id, gtfs_process_result.unwrap_err());
     
                                         //UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as failure
                                         use catenary::schema::gtfs::static_download_attempts::dsl::static_download_attempts;
@@ -536,15 +595,20 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                                     let _ = diesel::delete(in_progress_static_ingests.filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::onestop_feed_id.eq(&feed_id))
                                     .filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::attempt_id.eq(&attempt_id)))
                                 .execute(conn).await;
-                                
+
+                                // the chateau needs new transfer patterns once its schedule changed
+                                ingest_succeeded.then_some(chateau_id)
                             }
                         
                     }
                 
             ))
             .buffer_unordered(get_threads_gtfs())
-            .collect::<Vec<()>>()
-            .await;
+            .collect::<Vec<Option<String>>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<HashSet<String>>();
 
             // delete static feeds that no longer exist
 
@@ -577,6 +641,18 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                 Arc::clone(&arc_conn_pool),
             )
             .await?;
+
+            // 6. Precompute the transfer patterns of the chateaus which were ingested
+            // the Prairie job still runs on a schedule to roll the pattern windows forward
+
+            for chateau_id in ingested_chateaus {
+                if let Err(err) =
+                    precompute_transfer_patterns_for_chateau(&chateau_id, Arc::clone(&arc_conn_pool))
+                        .await
+                {
+                    eprintln!("Prairie failed for chateau {}: {:?}", chateau_id, err);
+                }
+            }
         }
     } else {
         eprintln!("Not enough data in transitland!");
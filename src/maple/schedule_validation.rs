@@ -0,0 +1,88 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Stores the schedule validation results of each ingestion attempt in gtfs.gtfs_errors,
+// one row per broken rule, so agencies can be told what is wrong with their feed.
+
+use catenary::models::GtfsError;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::validate_gtfs::ScheduleNotice;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::RunQueryDsl;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Results of older attempts are kept this long
+const RETENTION_MS: i64 = 90 * 86400 * 1000;
+
+pub async fn save_schedule_notices(
+    feed_id: &str,
+    attempt_id: &str,
+    chateau_id: &str,
+    file_hash: Option<u64>,
+    notices: &[ScheduleNotice],
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use catenary::schema::gtfs::gtfs_errors::dsl as gtfs_errors;
+
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    let rows = notices
+        .iter()
+        .map(|notice| GtfsError {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            rule: notice.rule.code().to_string(),
+            severity: notice.severity.code().to_string(),
+            error: notice.rule.description().to_string(),
+            count: notice.count as i32,
+            sample_ids: serde_json::to_value(&notice.sample_ids).unwrap(),
+            file_hash: file_hash.map(|hash| format!("{}", hash)),
+            chateau: chateau_id.to_string(),
+            checked_unix_time_ms: now_ms,
+        })
+        .collect::<Vec<GtfsError>>();
+
+    // a diff ingest validates its base attempt again, so the earlier notices of the attempt are replaced
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::delete(
+                gtfs_errors::gtfs_errors
+                    .filter(gtfs_errors::onestop_feed_id.eq(feed_id))
+                    .filter(gtfs_errors::attempt_id.eq(attempt_id)),
+            )
+            .execute(conn)
+            .await?;
+
+            for rows_chunk in rows.chunks(100) {
+                diesel::insert_into(gtfs_errors::gtfs_errors)
+                    .values(rows_chunk)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    diesel::delete(
+        gtfs_errors::gtfs_errors
+            .filter(gtfs_errors::onestop_feed_id.eq(feed_id))
+            .filter(gtfs_errors::checked_unix_time_ms.lt(now_ms - RETENTION_MS)),
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
@@ -1,8 +1,8 @@
 // GTFS stop time compression algorithm
 // Compatible with transfer patterns
 
-use crate::ahash_fast_hash;
 use crate::enum_to_int::*;
+use crate::fast_hash;
 use ahash::AHashMap;
 use ahash::AHashSet;
 use compact_str::CompactString;
@@ -44,6 +44,29 @@ pub struct StopDifference {
     //true is exact, false is approximate
     pub timepoint: bool,
     pub gtfs_stop_sequence: u16,
+    // GTFS-Flex, the stop is a zone or a group of stops served at any time in the window
+    pub start_pickup_drop_off_window_since_start: Option<i32>,
+    pub end_pickup_drop_off_window_since_start: Option<i32>,
+    pub pickup_booking_rule_id: Option<String>,
+    pub drop_off_booking_rule_id: Option<String>,
+}
+
+/// A stop time of a GTFS-Flex trip. gtfs_structures does not read zones or windows,
+/// so these are read from stop_times.txt by Maple.
+#[derive(Clone, Debug)]
+pub struct FlexStopTime {
+    /// stop_id, location_group_id or location_id
+    pub stop_id: CompactString,
+    pub stop_sequence: u16,
+    pub arrival_time: Option<u32>,
+    pub departure_time: Option<u32>,
+    pub start_pickup_drop_off_window: Option<u32>,
+    pub end_pickup_drop_off_window: Option<u32>,
+    pub pickup_type: i16,
+    pub drop_off_type: i16,
+    pub stop_headsign: Option<String>,
+    pub pickup_booking_rule_id: Option<String>,
+    pub drop_off_booking_rule_id: Option<String>,
 }
 
 pub struct DirectionPattern {
@@ -77,61 +100,51 @@ pub struct ResponseFromReduce {
 }
 
 pub fn reduce(gtfs: &gtfs_structures::Gtfs) -> ResponseFromReduce {
+    reduce_with_flex(gtfs, &AHashMap::new())
+}
+
+/// flex_trips holds every stop time of the trips with a pickup and drop off window,
+/// which are reduced from those rows instead of the stop times of gtfs_structures
+pub fn reduce_with_flex(
+    gtfs: &gtfs_structures::Gtfs,
+    flex_trips: &AHashMap<String, Vec<FlexStopTime>>,
+) -> ResponseFromReduce {
     let mut itineraries: AHashMap<u64, ItineraryCover> = AHashMap::new();
     let mut trips_to_itineraries: AHashMap<CompactString, u64> = AHashMap::new();
     let mut itineraries_to_trips: AHashMap<u64, Vec<TripUnderItinerary>> = AHashMap::new();
     let mut direction_pattern_id_to_itineraries: AHashMap<u64, AHashSet<u64>> = AHashMap::new();
 
     for (trip_id, trip) in &gtfs.trips {
-        let mut stop_diffs: Vec<StopDifference> = Vec::new();
-
-        if trip.stop_times.len() < 2 {
-            println!("Trip {} doesn't contain enough times", trip);
-            continue;
-        }
-
-        //according to the gtfs spec
-        //Arrival times are "Required for the first and last stop in a trip (defined by stop_times.stop_sequence)"
-        if trip.stop_times[0].arrival_time.is_none()
-            || trip.stop_times[trip.stop_times.len() - 1]
-                .departure_time
-                .is_none()
-        {
-            println!("Invalid trip {} with no start or end time", trip_id);
-            continue;
-        }
+        let (start_time, mut stop_diffs) = match flex_trips.get(trip_id.as_str()) {
+            Some(flex_stop_times) => match flex_stop_differences(flex_stop_times) {
+                Some(flex_reduced) => flex_reduced,
+                None => {
+                    println!("Invalid flex trip {} with no start time or window", trip_id);
+                    continue;
+                }
+            },
+            None => {
+                if trip.stop_times.len() < 2 {
+                    println!("Trip {} doesn't contain enough times", trip);
+                    continue;
+                }
 
-        let start_time: u32 = trip.stop_times[0].arrival_time.unwrap();
+                //according to the gtfs spec
+                //Arrival times are "Required for the first and last stop in a trip (defined by stop_times.stop_sequence)"
+                if trip.stop_times[0].arrival_time.is_none()
+                    || trip.stop_times[trip.stop_times.len() - 1]
+                        .departure_time
+                        .is_none()
+                {
+                    println!("Invalid trip {} with no start or end time", trip_id);
+                    continue;
+                }
 
-        //this trip "starts at 09:00" local time or something
-        for stop_time in trip.stop_times.iter() {
-            let arrival_time_since_start: Option<i32> = stop_time
-                .arrival_time
-                .map(|arrival_time| arrival_time as i32 - start_time as i32);
+                let start_time: u32 = trip.stop_times[0].arrival_time.unwrap();
 
-            let departure_time_since_start: Option<i32> = stop_time
-                .departure_time
-                .map(|departure_time| departure_time as i32 - start_time as i32);
-
-            let stop_diff = StopDifference {
-                stop_id: (&stop_time.stop.id).into(),
-                arrival_time_since_start,
-                departure_time_since_start,
-                continuous_pickup: continuous_pickup_drop_off_to_i16(&stop_time.continuous_pickup),
-                continuous_drop_off: continuous_pickup_drop_off_to_i16(
-                    &stop_time.continuous_drop_off,
-                ),
-                interpolation_by_catenary: false,
-                interpolated_time_since_start: None,
-                stop_headsign: stop_time.stop_headsign.clone(),
-                drop_off_type: pickup_dropoff_to_i16(&stop_time.drop_off_type),
-                pickup_type: pickup_dropoff_to_i16(&stop_time.pickup_type),
-                timepoint: timepoint_to_bool(&stop_time.timepoint),
-                gtfs_stop_sequence: stop_time.stop_sequence,
-            };
-
-            stop_diffs.push(stop_diff);
-        }
+                (start_time, stop_differences(&trip.stop_times, start_time))
+            }
+        };
 
         //interpolate times for stops that don't have times
         let stop_indicies_requiring_interpolation: Vec<usize> = stop_diffs
@@ -140,6 +153,7 @@ pub fn reduce(gtfs: &gtfs_structures::Gtfs) -> ResponseFromReduce {
             .filter(|(_, stop_diff)| {
                 stop_diff.arrival_time_since_start.is_none()
                     && stop_diff.departure_time_since_start.is_none()
+                    && stop_diff.start_pickup_drop_off_window_since_start.is_none()
             })
             .map(|(index, _)| index)
             .collect();
@@ -243,10 +257,13 @@ pub fn reduce(gtfs: &gtfs_structures::Gtfs) -> ResponseFromReduce {
         let timezone = match stated_timezone {
             Some(timezone) => timezone,
             None => {
-                let first_stop = &gtfs.stops[&trip.stop_times[0].stop.id];
+                // flex zones are not stops, so use the first stop which is one
+                let first_stop = stop_diffs
+                    .iter()
+                    .find_map(|stop_diff| gtfs.stops.get(stop_diff.stop_id.as_str()));
 
-                match (first_stop.longitude, first_stop.latitude) {
-                    (Some(long), Some(lat)) => String::from(FINDER.get_tz_name(long, lat)),
+                match first_stop.map(|stop| (stop.longitude, stop.latitude)) {
+                    Some((Some(long), Some(lat))) => String::from(FINDER.get_tz_name(long, lat)),
                     _ => {
                         println!("Couldn't find timezone for trip {}", trip_id);
                         String::from("Etc/UTC")
@@ -313,7 +330,8 @@ pub fn reduce(gtfs: &gtfs_structures::Gtfs) -> ResponseFromReduce {
         };
 
         //itinerary id generated
-        let hash_of_itinerary = ahash_fast_hash(&itinerary_cover);
+        // ids are deterministic so ingestions of the same feed can be compared
+        let hash_of_itinerary = fast_hash(&itinerary_cover);
 
         itineraries.insert(hash_of_itinerary, itinerary_cover);
         trips_to_itineraries.insert(trip_id.into(), hash_of_itinerary);
@@ -388,6 +406,87 @@ pub fn reduce(gtfs: &gtfs_structures::Gtfs) -> ResponseFromReduce {
     }
 }
 
+fn stop_differences(
+    stop_times: &[gtfs_structures::StopTime],
+    start_time: u32,
+) -> Vec<StopDifference> {
+    //this trip "starts at 09:00" local time or something
+    stop_times
+        .iter()
+        .map(|stop_time| StopDifference {
+            stop_id: (&stop_time.stop.id).into(),
+            arrival_time_since_start: stop_time
+                .arrival_time
+                .map(|arrival_time| arrival_time as i32 - start_time as i32),
+            departure_time_since_start: stop_time
+                .departure_time
+                .map(|departure_time| departure_time as i32 - start_time as i32),
+            continuous_pickup: continuous_pickup_drop_off_to_i16(&stop_time.continuous_pickup),
+            continuous_drop_off: continuous_pickup_drop_off_to_i16(&stop_time.continuous_drop_off),
+            interpolation_by_catenary: false,
+            interpolated_time_since_start: None,
+            stop_headsign: stop_time.stop_headsign.clone(),
+            drop_off_type: pickup_dropoff_to_i16(&stop_time.drop_off_type),
+            pickup_type: pickup_dropoff_to_i16(&stop_time.pickup_type),
+            timepoint: timepoint_to_bool(&stop_time.timepoint),
+            gtfs_stop_sequence: stop_time.stop_sequence,
+            start_pickup_drop_off_window_since_start: None,
+            end_pickup_drop_off_window_since_start: None,
+            pickup_booking_rule_id: None,
+            drop_off_booking_rule_id: None,
+        })
+        .collect()
+}
+
+/// Start time and stop differences of a flex trip, which starts at its first time or first window
+pub fn flex_stop_differences(
+    flex_stop_times: &[FlexStopTime],
+) -> Option<(u32, Vec<StopDifference>)> {
+    let mut flex_stop_times = flex_stop_times.iter().collect::<Vec<&FlexStopTime>>();
+    flex_stop_times.sort_by_key(|flex_stop_time| flex_stop_time.stop_sequence);
+
+    if flex_stop_times.len() < 2 {
+        return None;
+    }
+
+    let first = flex_stop_times[0];
+    let start_time = first
+        .arrival_time
+        .or(first.departure_time)
+        .or(first.start_pickup_drop_off_window)?;
+
+    let since_start = |time: Option<u32>| time.map(|time| time as i32 - start_time as i32);
+
+    let stop_diffs = flex_stop_times
+        .into_iter()
+        .map(|flex_stop_time| StopDifference {
+            stop_id: flex_stop_time.stop_id.clone(),
+            arrival_time_since_start: since_start(flex_stop_time.arrival_time),
+            departure_time_since_start: since_start(flex_stop_time.departure_time),
+            interpolation_by_catenary: false,
+            interpolated_time_since_start: None,
+            // continuous stopping is forbidden on trips with windows
+            continuous_pickup: 1,
+            continuous_drop_off: 1,
+            stop_headsign: flex_stop_time.stop_headsign.clone(),
+            drop_off_type: flex_stop_time.drop_off_type,
+            pickup_type: flex_stop_time.pickup_type,
+            timepoint: flex_stop_time.start_pickup_drop_off_window.is_none(),
+            gtfs_stop_sequence: flex_stop_time.stop_sequence,
+            start_pickup_drop_off_window_since_start: since_start(
+                flex_stop_time.start_pickup_drop_off_window,
+            ),
+            end_pickup_drop_off_window_since_start: since_start(
+                flex_stop_time.end_pickup_drop_off_window,
+            ),
+            pickup_booking_rule_id: flex_stop_time.pickup_booking_rule_id.clone(),
+            drop_off_booking_rule_id: flex_stop_time.drop_off_booking_rule_id.clone(),
+        })
+        .collect::<Vec<StopDifference>>();
+
+    Some((start_time, stop_diffs))
+}
+
 fn calculate_direction_pattern_id(route_id: &str, stop_sequence: Vec<CompactString>) -> u64 {
     let mut hash_of_direction_pattern_temp: Vec<CompactString> = Vec::new();
 
@@ -397,8 +496,95 @@ fn calculate_direction_pattern_id(route_id: &str, stop_sequence: Vec<CompactStri
         hash_of_direction_pattern_temp.push(stop_id);
     }
 
-    ahash_fast_hash(&hash_of_direction_pattern_temp)
+    fast_hash(&hash_of_direction_pattern_temp)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn flex_stop_time(
+        stop_id: &str,
+        stop_sequence: u16,
+        window: Option<(u32, u32)>,
+    ) -> FlexStopTime {
+        FlexStopTime {
+            stop_id: stop_id.into(),
+            stop_sequence,
+            arrival_time: None,
+            departure_time: None,
+            start_pickup_drop_off_window: window.map(|(start, _)| start),
+            end_pickup_drop_off_window: window.map(|(_, end)| end),
+            pickup_type: 2,
+            drop_off_type: 2,
+            stop_headsign: None,
+            pickup_booking_rule_id: Some(String::from("call_ahead")),
+            drop_off_booking_rule_id: None,
+        }
+    }
+
+    #[test]
+    fn flex_windows_are_relative_to_the_first_window() {
+        let mut depot = flex_stop_time("depot", 1, None);
+        depot.departure_time = Some(7 * 3600);
+
+        let flex_stop_times = vec![
+            flex_stop_time("zone", 3, Some((8 * 3600, 18 * 3600))),
+            depot,
+            flex_stop_time("zone", 2, Some((7 * 3600, 17 * 3600))),
+        ];
+
+        let (start_time, stop_diffs) = flex_stop_differences(&flex_stop_times).unwrap();
+
+        assert_eq!(start_time, 7 * 3600);
+        assert_eq!(
+            stop_diffs
+                .iter()
+                .map(|stop_diff| (
+                    stop_diff.gtfs_stop_sequence,
+                    stop_diff.start_pickup_drop_off_window_since_start,
+                    stop_diff.end_pickup_drop_off_window_since_start,
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, None, None),
+                (2, Some(0), Some(10 * 3600)),
+                (3, Some(3600), Some(11 * 3600)),
+            ]
+        );
+        assert!(stop_diffs[0].timepoint);
+        assert!(!stop_diffs[1].timepoint);
+    }
+
+    #[test]
+    fn flex_trips_need_two_stops_and_a_first_time() {
+        assert!(
+            flex_stop_differences(&[flex_stop_time("zone", 1, Some((7 * 3600, 17 * 3600)))])
+                .is_none()
+        );
+
+        // the first stop has neither a time nor a window
+        assert!(flex_stop_differences(&[
+            flex_stop_time("depot", 1, None),
+            flex_stop_time("zone", 2, Some((7 * 3600, 17 * 3600))),
+        ])
+        .is_none());
+
+        // a window opening before the first fixed time is negative
+        let mut depot = flex_stop_time("depot", 1, None);
+        depot.arrival_time = Some(8 * 3600);
+
+        let (start_time, stop_diffs) = flex_stop_differences(&[
+            depot,
+            flex_stop_time("zone", 2, Some((7 * 3600, 17 * 3600))),
+        ])
+        .unwrap();
+
+        assert_eq!(start_time, 8 * 3600);
+        assert_eq!(stop_diffs[0].arrival_time_since_start, Some(0));
+        assert_eq!(
+            stop_diffs[1].start_pickup_drop_off_window_since_start,
+            Some(-3600)
+        );
+    }
+}
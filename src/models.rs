@@ -25,6 +25,13 @@ pub struct ItineraryPatternRow {
     pub stop_id: CompactString,
     pub chateau: String,
     pub gtfs_stop_sequence: u32,
+    //true is exact, false is approximate
+    pub timepoint: Option<bool>,
+    // GTFS-Flex, when the stop is a zone served within a window instead of at a time
+    pub start_pickup_drop_off_window_since_start: Option<i32>,
+    pub end_pickup_drop_off_window_since_start: Option<i32>,
+    pub pickup_booking_rule_id: Option<String>,
+    pub drop_off_booking_rule_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, QueryableByName)]
@@ -59,7 +66,9 @@ pub struct ItineraryPatternRowNearbyLookup {
     pub route_id: String,
 }
 
-#[derive(Queryable, Selectable, Insertable, Clone, Serialize, Deserialize, QueryableByName)]
+#[derive(
+    Queryable, Selectable, Insertable, Clone, Serialize, Deserialize, QueryableByName, PartialEq,
+)]
 #[diesel(table_name = crate::schema::gtfs::itinerary_pattern_meta)]
 pub struct ItineraryPatternMeta {
     pub onestop_feed_id: String,
@@ -75,7 +84,7 @@ pub struct ItineraryPatternMeta {
     pub direction_pattern_id: Option<String>,
 }
 
-#[derive(Queryable, Selectable, Insertable, Clone, Serialize, Deserialize, Debug)]
+#[derive(Queryable, Selectable, Insertable, Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[diesel(table_name = crate::schema::gtfs::trips_compressed)]
 pub struct CompressedTrip {
     pub onestop_feed_id: String,
@@ -212,6 +221,8 @@ pub struct IngestedStatic {
     pub ingestion_version: i32,
     pub default_lang: Option<String>,
     pub languages_avaliable: Vec<Option<String>>,
+    /// file name -> hash, for the files compared by diff ingestion
+    pub file_hashes: Option<Value>,
 }
 
 #[derive(Queryable, Selectable, Insertable, Debug, Clone)]
@@ -360,7 +371,15 @@ pub struct AdminCredentials {
 }
 
 #[derive(
-    Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize, QueryableByName,
+    Queryable,
+    Selectable,
+    Insertable,
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    QueryableByName,
+    PartialEq,
 )]
 #[diesel(table_name = crate::schema::gtfs::direction_pattern)]
 #[derive(Associations)]
@@ -378,7 +397,15 @@ pub struct DirectionPatternRow {
 }
 
 #[derive(
-    Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize, QueryableByName,
+    Queryable,
+    Selectable,
+    Insertable,
+    Debug,
+    Clone,
+    Serialize,
+    Deserialize,
+    QueryableByName,
+    PartialEq,
 )]
 #[diesel(table_name = crate::schema::gtfs::direction_pattern_meta)]
 pub struct DirectionPatternMeta {
@@ -409,3 +436,289 @@ pub struct IpToGeoAddr {
     pub longitude: f64,
     pub timezone: Option<String>,
 }
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::transfer_patterns)]
+pub struct TransferPatternNode {
+    pub chateau: String,
+    pub source_stop_id: String,
+    pub node_id: i32,
+    pub parent_node_id: Option<i32>,
+    pub stop_id: String,
+    pub arrive_by_walking: bool,
+    pub is_target: bool,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::transfer_patterns_meta)]
+pub struct TransferPatternsMeta {
+    pub chateau: String,
+    pub computed_unix_time_ms: i64,
+    pub window_start: i64,
+    pub window_end: i64,
+    pub source_stops_count: i32,
+    pub nodes_count: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::footpaths)]
+pub struct FootpathRow {
+    pub chateau: String,
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub distance_metres: f32,
+    pub duration_seconds: i32,
+    pub computed_unix_time_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::aspen_snapshots)]
+pub struct AspenSnapshotRow {
+    pub chateau: String,
+    pub created_unix_time_ms: i64,
+    pub snapshot: Vec<u8>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::stop_time_history)]
+pub struct StopTimeHistoryRow {
+    pub service_date: chrono::NaiveDate,
+    pub chateau: String,
+    pub trip_id: String,
+    pub route_id: Option<String>,
+    pub stop_id: String,
+    pub stop_sequence: i32,
+    pub arrival_time: Option<i64>,
+    pub arrival_delay: Option<i32>,
+    pub departure_time: Option<i64>,
+    pub departure_delay: Option<i32>,
+    pub recorded_unix_time_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::gtfs_errors)]
+pub struct GtfsError {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub rule: String,
+    pub severity: String,
+    pub error: String,
+    pub count: i32,
+    pub sample_ids: Value,
+    pub file_hash: Option<String>,
+    pub chateau: String,
+    pub checked_unix_time_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::gtfs_rt_validation_runs)]
+pub struct GtfsRtValidationRun {
+    pub realtime_feed_id: String,
+    pub feed_type: String,
+    pub checked_unix_time_ms: i64,
+    pub chateau: String,
+    pub header_timestamp: Option<i64>,
+    pub entity_count: i32,
+    pub error_count: i32,
+    pub warning_count: i32,
+    pub notices: Value,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::realtime_feed_health)]
+pub struct RealtimeFeedHealthRow {
+    pub realtime_feed_id: String,
+    pub fetches_updated_ms: Option<i64>,
+    pub last_success_ms: Option<i64>,
+    pub recent_fetches: Option<Value>,
+    pub ingests_updated_ms: Option<i64>,
+    pub recent_ingests: Option<Value>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_media)]
+pub struct FareMedia {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub fare_media_id: String,
+    pub fare_media_name: Option<String>,
+    pub fare_media_type: i16,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_products)]
+pub struct FareProduct {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    /// Row in fare_products.txt, as a product has one row per fare media
+    pub row_index: i32,
+    pub fare_product_id: String,
+    pub fare_product_name: Option<String>,
+    pub fare_media_id: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_leg_rules)]
+pub struct FareLegRule {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub row_index: i32,
+    pub leg_group_id: Option<String>,
+    pub network_id: Option<String>,
+    pub from_area_id: Option<String>,
+    pub to_area_id: Option<String>,
+    pub from_timeframe_group_id: Option<String>,
+    pub to_timeframe_group_id: Option<String>,
+    pub fare_product_id: String,
+    pub rule_priority: Option<i32>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_transfer_rules)]
+pub struct FareTransferRule {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub row_index: i32,
+    pub from_leg_group_id: Option<String>,
+    pub to_leg_group_id: Option<String>,
+    pub transfer_count: Option<i32>,
+    pub duration_limit: Option<i32>,
+    pub duration_limit_type: Option<i16>,
+    pub fare_transfer_type: i16,
+    pub fare_product_id: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::areas)]
+pub struct Area {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub area_id: String,
+    pub area_name: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::stop_areas)]
+pub struct StopArea {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub area_id: String,
+    pub stop_id: String,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::networks)]
+pub struct Network {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub network_id: String,
+    pub network_name: Option<String>,
+    pub route_ids: Vec<Option<String>>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_attributes)]
+pub struct FareAttribute {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub fare_id: String,
+    pub price: f64,
+    pub currency_type: String,
+    pub payment_method: i16,
+    /// None allows unlimited transfers
+    pub transfers: Option<i16>,
+    pub agency_id: Option<String>,
+    pub transfer_duration: Option<i32>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::fare_rules)]
+pub struct FareRule {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub row_index: i32,
+    pub fare_id: String,
+    pub route_id: Option<String>,
+    pub origin_id: Option<String>,
+    pub destination_id: Option<String>,
+    pub contains_id: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::pathways)]
+pub struct Pathway {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub pathway_id: String,
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    /// 1 walkway, 2 stairs, 3 moving sidewalk, 4 escalator, 5 elevator, 6 fare gate, 7 exit gate
+    pub pathway_mode: i16,
+    pub is_bidirectional: bool,
+    pub length: Option<f32>,
+    pub traversal_time: Option<i32>,
+    pub stair_count: Option<i32>,
+    pub max_slope: Option<f32>,
+    pub min_width: Option<f32>,
+    pub signposted_as: Option<String>,
+    pub reversed_signposted_as: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::levels)]
+pub struct Level {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub level_id: String,
+    pub level_index: f64,
+    pub level_name: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::flex_locations)]
+pub struct FlexLocation {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub location_id: String,
+    pub stop_name: Option<String>,
+    pub stop_desc: Option<String>,
+    pub geometry: postgis_diesel::types::MultiPolygon<postgis_diesel::types::Point>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::booking_rules)]
+pub struct BookingRule {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub booking_rule_id: String,
+    /// 0 real time, 1 same day with advance notice, 2 prior days
+    pub booking_type: i16,
+    pub prior_notice_duration_min: Option<i32>,
+    pub prior_notice_duration_max: Option<i32>,
+    pub prior_notice_last_day: Option<i32>,
+    pub prior_notice_last_time: Option<String>,
+    pub prior_notice_start_day: Option<i32>,
+    pub prior_notice_start_time: Option<String>,
+    pub prior_notice_service_id: Option<String>,
+    pub message: Option<String>,
+    pub pickup_message: Option<String>,
+    pub drop_off_message: Option<String>,
+    pub phone_number: Option<String>,
+    pub info_url: Option<String>,
+    pub booking_url: Option<String>,
+    pub chateau: String,
+}
@@ -0,0 +1,416 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Stop to stop walking transfers, computed on the pedestrian graph from the OSM extractor.
+// Stops are snapped to the closest walkable node, and the straight line from the stop to that node is added to the walk.
+
+use crate::models::{FootpathRow, Stop};
+use crate::osm_extractor::pedestrian_graph::PedestrianGraph;
+use crate::postgres_tools::CatenaryPostgresPool;
+use ahash::AHashMap;
+use diesel::dsl::sql;
+use diesel::query_dsl::methods::FilterDsl;
+use diesel::query_dsl::methods::SelectDsl;
+use diesel::sql_types::Bool;
+use diesel::BoolExpressionMethods;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use geo::HaversineDistance;
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
+use std::error::Error;
+use std::sync::Arc;
+
+pub const DEFAULT_FOOTPATH_RADIUS_METRES: f64 = 400.0;
+
+/// Average walking speed, about 4.7 km/h
+pub const WALKING_SPEED_METRES_PER_SEC: f64 = 1.3;
+
+/// Stops further than this from any walkable way are not connected
+pub const MAX_SNAP_DISTANCE_METRES: f64 = 150.0;
+
+/// Walks may be this many times longer than the straight line radius, to get around blocks
+const MAX_DETOUR_FACTOR: f64 = 1.5;
+
+const METRES_PER_DEGREE_LATITUDE: f64 = 111_320.0;
+
+#[derive(Clone, Debug)]
+pub struct FootpathStop {
+    pub stop_id: String,
+    pub point: geo::Point<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComputedFootpath {
+    pub from_stop: usize,
+    pub to_stop: usize,
+    pub distance_metres: f64,
+    pub duration_seconds: u32,
+}
+
+pub fn walking_duration_secs(distance_metres: f64) -> u32 {
+    (distance_metres / WALKING_SPEED_METRES_PER_SEC).ceil() as u32
+}
+
+/// Every walk between two stops which is at most `radius_metres` apart in a straight line
+pub fn compute_footpaths(
+    graph: &PedestrianGraph,
+    stops: &[FootpathStop],
+    radius_metres: f64,
+) -> Vec<ComputedFootpath> {
+    let stop_tree: RTree<GeomWithData<[f64; 2], usize>> = RTree::bulk_load(
+        stops
+            .iter()
+            .enumerate()
+            .map(|(idx, stop)| GeomWithData::new([stop.point.x(), stop.point.y()], idx))
+            .collect(),
+    );
+
+    let snapped = stops
+        .iter()
+        .map(|stop| graph.nearest_node(&stop.point, MAX_SNAP_DISTANCE_METRES))
+        .collect::<Vec<Option<(u32, f64)>>>();
+
+    let mut footpaths: Vec<ComputedFootpath> = vec![];
+
+    for (from_idx, from_stop) in stops.iter().enumerate() {
+        let (from_node, from_snap_distance) = match snapped[from_idx] {
+            Some(snapped) => snapped,
+            None => continue,
+        };
+
+        // a degree of longitude shrinks away from the equator, so search with the longer of the two
+        let radius_degrees = radius_metres
+            / (METRES_PER_DEGREE_LATITUDE * from_stop.point.y().to_radians().cos().max(0.01));
+
+        let candidates = stop_tree
+            .locate_within_distance(
+                [from_stop.point.x(), from_stop.point.y()],
+                radius_degrees * radius_degrees,
+            )
+            .map(|candidate| candidate.data)
+            .filter(|to_idx| *to_idx != from_idx)
+            .filter(|to_idx| {
+                from_stop.point.haversine_distance(&stops[*to_idx].point) <= radius_metres
+            })
+            .collect::<Vec<usize>>();
+
+        if candidates.is_empty() {
+            continue;
+        }
+
+        let distances = graph.walking_distances_from(from_node, radius_metres * MAX_DETOUR_FACTOR);
+
+        for to_idx in candidates {
+            let (to_node, to_snap_distance) = match snapped[to_idx] {
+                Some(snapped) => snapped,
+                None => continue,
+            };
+
+            let network_distance = match distances.get(&to_node) {
+                Some(distance) => *distance,
+                None => continue,
+            };
+
+            let distance_metres = from_snap_distance + network_distance + to_snap_distance;
+
+            footpaths.push(ComputedFootpath {
+                from_stop: from_idx,
+                to_stop: to_idx,
+                distance_metres,
+                duration_seconds: walking_duration_secs(distance_metres),
+            });
+        }
+    }
+
+    footpaths
+}
+
+async fn load_production_attempt_ids(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    Ok(crate::schema::gtfs::ingested_static::dsl::ingested_static
+        .filter(crate::schema::gtfs::ingested_static::dsl::production.eq(true))
+        .filter(crate::schema::gtfs::ingested_static::dsl::deleted.eq(false))
+        .select(crate::schema::gtfs::ingested_static::dsl::attempt_id)
+        .load::<String>(conn)
+        .await?)
+}
+
+/// Production stops of every chateau inside of the bounding box (min lon, min lat, max lon, max lat)
+pub async fn load_stops_in_bbox(
+    conn: &mut AsyncPgConnection,
+    bbox: (f64, f64, f64, f64),
+) -> Result<AHashMap<String, Vec<FootpathStop>>, Box<dyn Error + Send + Sync>> {
+    let production_attempt_ids = load_production_attempt_ids(conn).await?;
+
+    let where_query_for_stops = format!(
+        "gtfs.stops.point && ST_MakeEnvelope({}, {}, {}, {}, 4326)",
+        bbox.0, bbox.1, bbox.2, bbox.3
+    );
+
+    let stops = crate::schema::gtfs::stops::dsl::stops
+        .filter(sql::<Bool>(&where_query_for_stops))
+        .filter(crate::schema::gtfs::stops::dsl::attempt_id.eq_any(&production_attempt_ids))
+        .select(Stop::as_select())
+        .load::<Stop>(conn)
+        .await?;
+
+    let mut stops_per_chateau: AHashMap<String, Vec<FootpathStop>> = AHashMap::new();
+
+    for stop in stops {
+        if let Some(point) = &stop.point {
+            stops_per_chateau
+                .entry(stop.chateau.clone())
+                .or_default()
+                .push(FootpathStop {
+                    stop_id: stop.gtfs_id.clone(),
+                    point: geo::Point::new(point.x, point.y),
+                });
+        }
+    }
+
+    Ok(stops_per_chateau)
+}
+
+/// Ids of every production stop of the chateau, also outside of the bounding box
+pub async fn load_production_stop_ids(
+    conn: &mut AsyncPgConnection,
+    chateau: &str,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let production_attempt_ids = load_production_attempt_ids(conn).await?;
+
+    Ok(crate::schema::gtfs::stops::dsl::stops
+        .filter(crate::schema::gtfs::stops::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::stops::dsl::attempt_id.eq_any(&production_attempt_ids))
+        .select(crate::schema::gtfs::stops::dsl::gtfs_id)
+        .load::<String>(conn)
+        .await?)
+}
+
+/// Replaces the footpaths starting at the given stops, and removes the footpaths of the chateau
+/// from or to stops which are not in `production_stop_ids` anymore
+pub async fn save_footpaths_for_chateau(
+    conn: &mut AsyncPgConnection,
+    chateau: &str,
+    stops: &[FootpathStop],
+    footpaths: &[ComputedFootpath],
+    production_stop_ids: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let computed_unix_time_ms = chrono::Utc::now().timestamp_millis();
+
+    let from_stop_ids = stops
+        .iter()
+        .map(|stop| stop.stop_id.clone())
+        .collect::<Vec<String>>();
+
+    let rows = footpaths
+        .iter()
+        .map(|footpath| FootpathRow {
+            chateau: chateau.to_string(),
+            from_stop_id: stops[footpath.from_stop].stop_id.clone(),
+            to_stop_id: stops[footpath.to_stop].stop_id.clone(),
+            distance_metres: footpath.distance_metres as f32,
+            duration_seconds: footpath.duration_seconds as i32,
+            computed_unix_time_ms,
+        })
+        .collect::<Vec<FootpathRow>>();
+
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            // the graph may cover only part of the chateau, so footpaths elsewhere are only removed once a stop is gone
+            diesel::delete(
+                crate::schema::gtfs::footpaths::dsl::footpaths
+                    .filter(crate::schema::gtfs::footpaths::dsl::chateau.eq(chateau))
+                    .filter(
+                        crate::schema::gtfs::footpaths::dsl::from_stop_id
+                            .ne_all(production_stop_ids)
+                            .or(crate::schema::gtfs::footpaths::dsl::to_stop_id
+                                .ne_all(production_stop_ids)),
+                    ),
+            )
+            .execute(conn)
+            .await?;
+
+            for from_stop_ids_chunk in from_stop_ids.chunks(1000) {
+                diesel::delete(
+                    crate::schema::gtfs::footpaths::dsl::footpaths
+                        .filter(crate::schema::gtfs::footpaths::dsl::chateau.eq(chateau))
+                        .filter(
+                            crate::schema::gtfs::footpaths::dsl::from_stop_id
+                                .eq_any(from_stop_ids_chunk),
+                        ),
+                )
+                .execute(conn)
+                .await?;
+            }
+
+            for rows_chunk in rows.chunks(1000) {
+                diesel::insert_into(crate::schema::gtfs::footpaths::dsl::footpaths)
+                    .values(rows_chunk)
+                    .execute(conn)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Computes and saves footpaths for every chateau with stops inside of the graph
+pub async fn compute_footpaths_for_graph(
+    graph: &PedestrianGraph,
+    radius_metres: f64,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let bbox = match graph.bounding_box() {
+        Some(bbox) => bbox,
+        None => return Ok(()),
+    };
+
+    let stops_per_chateau = load_stops_in_bbox(conn, bbox).await?;
+
+    for (chateau, stops) in stops_per_chateau {
+        let footpaths = compute_footpaths(graph, &stops, radius_metres);
+
+        println!(
+            "Chateau {}: {} footpaths between {} stops",
+            chateau,
+            footpaths.len(),
+            stops.len()
+        );
+
+        let production_stop_ids = load_production_stop_ids(conn, &chateau).await?;
+
+        save_footpaths_for_chateau(conn, &chateau, &stops, &footpaths, &production_stop_ids)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osm_extractor::ExportOsm;
+
+    // a footway along 34N with nodes about 92 metres apart, and a separate footway north of it
+    fn graph() -> PedestrianGraph {
+        let node = |id: i64, lon: f64, lat: f64| osmpbfreader::Node {
+            id: osmpbfreader::NodeId(id),
+            tags: osmpbfreader::Tags::new(),
+            decimicro_lat: (lat * 1e7).round() as i32,
+            decimicro_lon: (lon * 1e7).round() as i32,
+        };
+
+        let footway = |id: i64, nodes: &[i64]| {
+            let mut tags = osmpbfreader::Tags::new();
+            tags.insert("highway".into(), "footway".into());
+
+            osmpbfreader::Way {
+                id: osmpbfreader::WayId(id),
+                tags,
+                nodes: nodes.iter().map(|id| osmpbfreader::NodeId(*id)).collect(),
+            }
+        };
+
+        PedestrianGraph::from_export(&ExportOsm {
+            nodes: vec![
+                node(1, -118.0, 34.0),
+                node(2, -117.999, 34.0),
+                node(3, -117.998, 34.0),
+                node(4, -117.999, 34.002),
+                node(5, -117.998, 34.002),
+            ],
+            ways: vec![footway(1, &[1, 2, 3]), footway(2, &[4, 5])],
+        })
+    }
+
+    fn stop(stop_id: &str, lon: f64, lat: f64) -> FootpathStop {
+        FootpathStop {
+            stop_id: stop_id.to_string(),
+            point: geo::Point::new(lon, lat),
+        }
+    }
+
+    #[test]
+    fn walks_follow_the_graph_and_include_the_snap_to_it() {
+        let graph = graph();
+
+        let stops = vec![
+            stop("west", -118.0, 34.0001),
+            stop("east", -117.998, 34.0001),
+            // close in a straight line, but on a footway which does not join the others
+            stop("north", -117.999, 34.0021),
+            // too far from any footway to snap to it
+            stop("field", -118.0015, 34.001),
+        ];
+
+        let mut footpaths = compute_footpaths(&graph, &stops, DEFAULT_FOOTPATH_RADIUS_METRES);
+        footpaths.sort_by_key(|footpath| (footpath.from_stop, footpath.to_stop));
+
+        assert_eq!(
+            footpaths
+                .iter()
+                .map(|footpath| (footpath.from_stop, footpath.to_stop))
+                .collect::<Vec<(usize, usize)>>(),
+            vec![(0, 1), (1, 0)]
+        );
+
+        let snap = stops[0]
+            .point
+            .haversine_distance(&geo::Point::new(-118.0, 34.0));
+        let along_the_footway =
+            geo::Point::new(-118.0, 34.0).haversine_distance(&geo::Point::new(-117.998, 34.0));
+
+        let footpath = &footpaths[0];
+
+        assert!((footpath.distance_metres - (along_the_footway + 2.0 * snap)).abs() < 0.1);
+        assert_eq!(
+            footpath.duration_seconds,
+            walking_duration_secs(footpath.distance_metres)
+        );
+    }
+
+    #[test]
+    fn stops_beyond_the_radius_are_not_connected() {
+        let graph = graph();
+
+        let stops = vec![
+            stop("west", -118.0, 34.0001),
+            stop("middle", -117.999, 34.0001),
+            stop("east", -117.998, 34.0001),
+        ];
+
+        // neighbours are about 92 metres apart, and the ends about 184 metres
+        let footpaths = compute_footpaths(&graph, &stops, 120.0);
+
+        assert_eq!(footpaths.len(), 4);
+        assert!(footpaths
+            .iter()
+            .all(|footpath| footpath.from_stop.abs_diff(footpath.to_stop) == 1));
+    }
+
+    #[test]
+    fn walking_durations_round_up() {
+        assert_eq!(walking_duration_secs(0.0), 0);
+        assert_eq!(walking_duration_secs(1.3), 1);
+        assert_eq!(walking_duration_secs(1.31), 2);
+        assert_eq!(walking_duration_secs(2.6), 2);
+    }
+}
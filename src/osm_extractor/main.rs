@@ -8,14 +8,14 @@ use std::process::exit;
 
 use geofabrik_handler::poly_parser;
 
+use catenary::osm_extractor::footpaths::{
+    compute_footpaths_for_graph, DEFAULT_FOOTPATH_RADIUS_METRES,
+};
+use catenary::osm_extractor::pedestrian_graph::PedestrianGraph;
+use catenary::osm_extractor::ExportOsm;
+use catenary::postgres_tools::make_async_pool;
 use osmpbfreader::OsmObj;
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Clone)]
-struct ExportOsm {
-    nodes: Vec<Node>,
-    ways: Vec<Way>,
-}
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -29,6 +29,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .get::<String>("temp_dir")
         .expect("Missing parameter temp_dir");
 
+    // stop to stop walking transfers are written to Postgres when this is set
+    let compute_footpaths = arguments.get::<bool>("compute_footpaths").unwrap_or(false);
+    let footpath_radius_metres = arguments
+        .get::<f64>("footpath_radius_metres")
+        .unwrap_or(DEFAULT_FOOTPATH_RADIUS_METRES);
+
+    let arc_conn_pool = match compute_footpaths {
+        true => Some(Arc::new(make_async_pool().await?)),
+        false => None,
+    };
+
     //create dirs if they don't exist
 
     use std::collections::HashSet;
@@ -351,6 +362,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         ped_bike_file.write_all(&bincoded_ped_bike)?;
 
         println!("Wrote to {}", ped_bike_file_path);
+
+        if let Some(arc_conn_pool) = &arc_conn_pool {
+            let pedestrian_graph = PedestrianGraph::from_export(&export_ped_bike);
+
+            println!(
+                "Pedestrian graph has {} nodes, computing footpaths within {} m",
+                pedestrian_graph.node_count(),
+                footpath_radius_metres
+            );
+
+            compute_footpaths_for_graph(
+                &pedestrian_graph,
+                footpath_radius_metres,
+                Arc::clone(arc_conn_pool),
+            )
+            .await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,17 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use osmpbfreader::Node;
+use osmpbfreader::Way;
+use serde::{Deserialize, Serialize};
+
+pub mod footpaths;
+pub mod pedestrian_graph;
+
+/// Pedestrian and bike network written by the OSM extractor, one file per Geofabrik region
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExportOsm {
+    pub nodes: Vec<Node>,
+    pub ways: Vec<Way>,
+}
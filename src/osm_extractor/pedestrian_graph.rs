@@ -0,0 +1,346 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use crate::osm_extractor::ExportOsm;
+use ahash::AHashMap;
+use geo::HaversineDistance;
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Undirected walking graph, with edge lengths stored in millimetres
+pub struct PedestrianGraph {
+    pub osm_node_ids: Vec<i64>,
+    pub points: Vec<geo::Point<f64>>,
+    pub edges: Vec<Vec<(u32, u32)>>,
+    tree: RTree<GeomWithData<[f64; 2], u32>>,
+}
+
+fn is_walkable(way: &osmpbfreader::Way) -> bool {
+    let tag = |key: &str| way.tags.get(key).map(|value| value.as_str());
+
+    if tag("highway").is_none() {
+        return false;
+    }
+
+    if matches!(tag("highway"), Some("motorway") | Some("motorway_link")) {
+        return false;
+    }
+
+    if matches!(tag("foot"), Some("no")) {
+        return false;
+    }
+
+    if matches!(
+        tag("access"),
+        Some("no") | Some("private") | Some("military")
+    ) && !matches!(tag("foot"), Some("yes") | Some("designated"))
+    {
+        return false;
+    }
+
+    true
+}
+
+impl PedestrianGraph {
+    pub fn from_export(export: &ExportOsm) -> PedestrianGraph {
+        let node_coords: AHashMap<i64, geo::Point<f64>> = export
+            .nodes
+            .iter()
+            .map(|node| (node.id.0, geo::Point::new(node.lon(), node.lat())))
+            .collect();
+
+        let mut osm_node_ids: Vec<i64> = vec![];
+        let mut points: Vec<geo::Point<f64>> = vec![];
+        let mut edges: Vec<Vec<(u32, u32)>> = vec![];
+        let mut index_of_osm_node: AHashMap<i64, u32> = AHashMap::new();
+
+        for way in export.ways.iter().filter(|way| is_walkable(way)) {
+            let mut previous: Option<u32> = None;
+
+            for node_id in way.nodes.iter() {
+                let point = match node_coords.get(&node_id.0) {
+                    Some(point) => *point,
+                    // the node was outside of the extract, so the way is broken here
+                    None => {
+                        previous = None;
+                        continue;
+                    }
+                };
+
+                let idx = *index_of_osm_node.entry(node_id.0).or_insert_with(|| {
+                    osm_node_ids.push(node_id.0);
+                    points.push(point);
+                    edges.push(vec![]);
+                    (points.len() - 1) as u32
+                });
+
+                if let Some(previous) = previous {
+                    if previous != idx {
+                        let length_mm = (points[previous as usize].haversine_distance(&point)
+                            * 1000.0)
+                            .round() as u32;
+
+                        edges[previous as usize].push((idx, length_mm));
+                        edges[idx as usize].push((previous, length_mm));
+                    }
+                }
+
+                previous = Some(idx);
+            }
+        }
+
+        let tree = RTree::bulk_load(
+            points
+                .iter()
+                .enumerate()
+                .map(|(idx, point)| GeomWithData::new([point.x(), point.y()], idx as u32))
+                .collect(),
+        );
+
+        PedestrianGraph {
+            osm_node_ids,
+            points,
+            edges,
+            tree,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.points.len()
+    }
+
+    /// (min lon, min lat, max lon, max lat) of every node in the graph
+    pub fn bounding_box(&self) -> Option<(f64, f64, f64, f64)> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let mut bbox = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+
+        for point in self.points.iter() {
+            bbox.0 = bbox.0.min(point.x());
+            bbox.1 = bbox.1.min(point.y());
+            bbox.2 = bbox.2.max(point.x());
+            bbox.3 = bbox.3.max(point.y());
+        }
+
+        Some(bbox)
+    }
+
+    /// Closest graph node and the distance to it in metres, if it is within `max_distance_metres`
+    pub fn nearest_node(
+        &self,
+        point: &geo::Point<f64>,
+        max_distance_metres: f64,
+    ) -> Option<(u32, f64)> {
+        let nearest = self.tree.nearest_neighbor(&[point.x(), point.y()])?;
+
+        let distance = self.points[nearest.data as usize].haversine_distance(point);
+
+        match distance <= max_distance_metres {
+            true => Some((nearest.data, distance)),
+            false => None,
+        }
+    }
+
+    /// Dijkstra from `source`, stopping once paths are longer than `max_distance_metres`.
+    /// Returns the walking distance in metres to every node reached.
+    pub fn walking_distances_from(
+        &self,
+        source: u32,
+        max_distance_metres: f64,
+    ) -> AHashMap<u32, f64> {
+        let max_distance_mm = (max_distance_metres * 1000.0) as u64;
+
+        let mut distances: AHashMap<u32, u64> = AHashMap::new();
+        let mut heap: BinaryHeap<Reverse<(u64, u32)>> = BinaryHeap::new();
+
+        distances.insert(source, 0);
+        heap.push(Reverse((0, source)));
+
+        while let Some(Reverse((distance, node))) = heap.pop() {
+            if distances.get(&node).is_some_and(|best| *best < distance) {
+                continue;
+            }
+
+            for (neighbour, length_mm) in self.edges[node as usize].iter() {
+                let next_distance = distance + *length_mm as u64;
+
+                if next_distance > max_distance_mm {
+                    continue;
+                }
+
+                let improved = match distances.get(neighbour) {
+                    Some(best) => next_distance < *best,
+                    None => true,
+                };
+
+                if improved {
+                    distances.insert(*neighbour, next_distance);
+                    heap.push(Reverse((next_distance, *neighbour)));
+                }
+            }
+        }
+
+        distances
+            .into_iter()
+            .map(|(node, distance_mm)| (node, distance_mm as f64 / 1000.0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, lon: f64, lat: f64) -> osmpbfreader::Node {
+        osmpbfreader::Node {
+            id: osmpbfreader::NodeId(id),
+            tags: osmpbfreader::Tags::new(),
+            decimicro_lat: (lat * 1e7).round() as i32,
+            decimicro_lon: (lon * 1e7).round() as i32,
+        }
+    }
+
+    fn way(id: i64, tags: &[(&str, &str)], nodes: &[i64]) -> osmpbfreader::Way {
+        let mut way_tags = osmpbfreader::Tags::new();
+
+        for (key, value) in tags {
+            way_tags.insert((*key).into(), (*value).into());
+        }
+
+        osmpbfreader::Way {
+            id: osmpbfreader::WayId(id),
+            tags: way_tags,
+            nodes: nodes.iter().map(|id| osmpbfreader::NodeId(*id)).collect(),
+        }
+    }
+
+    fn distances_by_osm_node(graph: &PedestrianGraph, from: i64, max: f64) -> AHashMap<i64, f64> {
+        let source = graph
+            .osm_node_ids
+            .iter()
+            .position(|id| *id == from)
+            .unwrap() as u32;
+
+        graph
+            .walking_distances_from(source, max)
+            .into_iter()
+            .map(|(node, distance)| (graph.osm_node_ids[node as usize], distance))
+            .collect()
+    }
+
+    fn row_of_nodes() -> Vec<osmpbfreader::Node> {
+        (1..=7)
+            .map(|id| node(id, -118.0 + 0.001 * (id - 1) as f64, 34.0))
+            .collect()
+    }
+
+    #[test]
+    fn only_walkable_ways_are_joined() {
+        let nodes = row_of_nodes();
+
+        let export = ExportOsm {
+            ways: vec![
+                way(1, &[("highway", "footway")], &[1, 2, 3]),
+                way(2, &[("highway", "motorway")], &[3, 4]),
+                way(
+                    3,
+                    &[("highway", "residential"), ("access", "private")],
+                    &[4, 5],
+                ),
+                way(4, &[("highway", "path"), ("foot", "no")], &[2, 5]),
+                way(
+                    5,
+                    &[
+                        ("highway", "service"),
+                        ("access", "private"),
+                        ("foot", "yes"),
+                    ],
+                    &[3, 6],
+                ),
+                way(6, &[("building", "yes")], &[1, 7]),
+            ],
+            nodes: nodes.clone(),
+        };
+
+        let graph = PedestrianGraph::from_export(&export);
+
+        assert_eq!(graph.node_count(), 4);
+
+        let distances = distances_by_osm_node(&graph, 1, 10_000.0);
+
+        let point =
+            |id: i64| geo::Point::new(nodes[id as usize - 1].lon(), nodes[id as usize - 1].lat());
+        let to_3 = point(1).haversine_distance(&point(2)) + point(2).haversine_distance(&point(3));
+
+        assert_eq!(distances.len(), 4);
+        assert!((distances[&3] - to_3).abs() < 0.01);
+        assert!((distances[&6] - (to_3 + point(3).haversine_distance(&point(6)))).abs() < 0.01);
+    }
+
+    #[test]
+    fn ways_are_broken_at_nodes_outside_of_the_extract() {
+        let export = ExportOsm {
+            nodes: row_of_nodes(),
+            ways: vec![way(1, &[("highway", "footway")], &[1, 99, 2, 3])],
+        };
+
+        let graph = PedestrianGraph::from_export(&export);
+
+        assert_eq!(graph.node_count(), 3);
+
+        let from_1 = distances_by_osm_node(&graph, 1, 10_000.0);
+        let from_2 = distances_by_osm_node(&graph, 2, 10_000.0);
+
+        assert_eq!(from_1.keys().copied().collect::<Vec<i64>>(), vec![1]);
+        assert!(from_2.contains_key(&3));
+    }
+
+    #[test]
+    fn searches_stop_at_the_distance_limit() {
+        let export = ExportOsm {
+            nodes: row_of_nodes(),
+            ways: vec![way(1, &[("highway", "footway")], &[1, 2, 3])],
+        };
+
+        let graph = PedestrianGraph::from_export(&export);
+
+        // about 92 metres between neighbouring nodes
+        let distances = distances_by_osm_node(&graph, 1, 150.0);
+
+        assert!(distances.contains_key(&2));
+        assert!(!distances.contains_key(&3));
+    }
+
+    #[test]
+    fn stops_only_snap_to_nearby_nodes() {
+        let export = ExportOsm {
+            nodes: row_of_nodes(),
+            ways: vec![way(1, &[("highway", "footway")], &[1, 2, 3])],
+        };
+
+        let graph = PedestrianGraph::from_export(&export);
+
+        let (node, distance) = graph
+            .nearest_node(&geo::Point::new(-117.9991, 34.0001), 50.0)
+            .unwrap();
+
+        assert_eq!(graph.osm_node_ids[node as usize], 2);
+        assert!(distance > 10.0 && distance < 20.0);
+
+        assert!(graph
+            .nearest_node(&geo::Point::new(-117.999, 34.01), 50.0)
+            .is_none());
+
+        let (min_lon, min_lat, max_lon, max_lat) = graph.bounding_box().unwrap();
+
+        assert!((min_lon + 118.0).abs() < 1e-6 && (max_lon + 117.998).abs() < 1e-6);
+        assert!((min_lat - 34.0).abs() < 1e-6 && (max_lat - 34.0).abs() < 1e-6);
+    }
+}
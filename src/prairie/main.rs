@@ -0,0 +1,46 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Prairie preprocessing job. Maple recomputes the transfer patterns of every chateau it ingests,
+// this job is run on a schedule (such as daily from cron) to roll the pattern windows forward
+// and to pick up chateaus Maple failed to finish.
+// Usage: prairie [chateau_id ...]
+// With no arguments, the transfer patterns of every chateau which is missing them, whose patterns
+// are about to run out, or which has a newer schedule are recomputed
+
+use catenary::postgres_tools::make_async_pool;
+use catenary::prairie::transfer_patterns::{
+    precompute_transfer_patterns_for_chateau, stale_chateaus,
+};
+use std::error::Error;
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let arc_conn_pool = Arc::new(make_async_pool().await?);
+
+    let mut chateaus: Vec<String> = std::env::args().skip(1).collect();
+
+    if chateaus.is_empty() {
+        let conn_pool = arc_conn_pool.as_ref();
+        let conn_pre = conn_pool.get().await;
+        let conn = &mut conn_pre?;
+
+        chateaus = stale_chateaus(conn).await?;
+
+        println!("{} chateaus need new transfer patterns", chateaus.len());
+    }
+
+    for chateau in chateaus {
+        if let Err(err) =
+            precompute_transfer_patterns_for_chateau(&chateau, Arc::clone(&arc_conn_pool)).await
+        {
+            eprintln!("Prairie failed for chateau {}: {:?}", chateau, err);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,10 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+pub mod raptor;
+pub mod realtime;
+pub mod timetable;
+pub mod transfer_patterns;
@@ -0,0 +1,704 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Round-Based Public Transit Routing, Delling, Pajor and Werneck (2012)
+// Round k computes the earliest arrival at every stop using at most k trips.
+
+use crate::prairie::timetable::{Timetable, MIN_TRANSFER_SECS};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_MAX_TRANSFERS: usize = 4;
+
+/// Most transfers a request may ask for, more would only make the search slower
+pub const MAX_MAX_TRANSFERS: usize = 8;
+
+const UNREACHED: i64 = i64::MAX;
+
+#[derive(Clone, Copy, Debug)]
+pub enum RaptorLabel {
+    Origin,
+    Ride {
+        pattern: usize,
+        trip: usize,
+        board_position: usize,
+        alight_position: usize,
+    },
+    Walk {
+        from_stop: usize,
+        duration_secs: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct RaptorQuery {
+    // stop index and the unix time the rider is ready to leave from it
+    pub origins: Vec<(usize, i64)>,
+    // stop index and the seconds still needed after arriving, such as walking to the destination
+    pub targets: Vec<(usize, i64)>,
+    pub max_transfers: usize,
+}
+
+pub struct RaptorResult {
+    // rounds[k][stop] is the earliest arrival using k trips and how it was reached
+    pub rounds: Vec<Vec<Option<(i64, RaptorLabel)>>>,
+    pub best_arrival: Vec<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransitLeg {
+    pub chateau: String,
+    pub trip_id: String,
+    pub route_id: String,
+    pub itinerary_pattern_id: String,
+    pub trip_headsign: Option<String>,
+    pub trip_short_name: Option<String>,
+    pub service_date: chrono::NaiveDate,
+    pub from_stop_id: String,
+    pub from_gtfs_stop_sequence: u32,
+    pub to_stop_id: String,
+    pub to_gtfs_stop_sequence: u32,
+    pub departure_time: i64,
+    pub arrival_time: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WalkLeg {
+    pub from_chateau: String,
+    pub from_stop_id: String,
+    pub to_chateau: String,
+    pub to_stop_id: String,
+    pub departure_time: i64,
+    pub arrival_time: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JourneyLeg {
+    Transit(TransitLeg),
+    Walk(WalkLeg),
+}
+
+impl JourneyLeg {
+    pub fn departure_time(&self) -> i64 {
+        match self {
+            JourneyLeg::Transit(leg) => leg.departure_time,
+            JourneyLeg::Walk(leg) => leg.departure_time,
+        }
+    }
+
+    pub fn arrival_time(&self) -> i64 {
+        match self {
+            JourneyLeg::Transit(leg) => leg.arrival_time,
+            JourneyLeg::Walk(leg) => leg.arrival_time,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Journey {
+    pub departure_time: i64,
+    pub arrival_time: i64,
+    pub transfers: usize,
+    pub legs: Vec<JourneyLeg>,
+}
+
+pub fn raptor(timetable: &Timetable, query: &RaptorQuery) -> RaptorResult {
+    let stop_count = timetable.stops.len();
+    let max_rounds = query.max_transfers.saturating_add(1);
+
+    let mut rounds: Vec<Vec<Option<(i64, RaptorLabel)>>> = vec![vec![None; stop_count]];
+    let mut best_arrival: Vec<i64> = vec![UNREACHED; stop_count];
+    let mut marked: Vec<bool> = vec![false; stop_count];
+
+    for (stop, time) in query.origins.iter() {
+        if *time < best_arrival[*stop] {
+            best_arrival[*stop] = *time;
+            rounds[0][*stop] = Some((*time, RaptorLabel::Origin));
+            marked[*stop] = true;
+        }
+    }
+
+    relax_footpaths(timetable, &mut rounds[0], &mut best_arrival, &mut marked);
+
+    for k in 1..=max_rounds {
+        // the best target arrival so far, used to prune anything that can no longer help
+        let target_bound = query
+            .targets
+            .iter()
+            .filter(|(stop, _)| best_arrival[*stop] != UNREACHED)
+            .map(|(stop, egress)| best_arrival[*stop] + egress)
+            .min()
+            .unwrap_or(UNREACHED);
+
+        // pattern -> earliest position of a stop improved in the last round
+        let mut queue: Vec<(usize, usize)> = vec![];
+        let mut pattern_first_position: ahash::AHashMap<usize, usize> = ahash::AHashMap::new();
+
+        for (stop, is_marked) in marked.iter_mut().enumerate() {
+            if !*is_marked {
+                continue;
+            }
+
+            *is_marked = false;
+
+            for (pattern, position) in timetable.patterns_at_stop[stop].iter() {
+                pattern_first_position
+                    .entry(*pattern)
+                    .and_modify(|existing| *existing = (*existing).min(*position))
+                    .or_insert(*position);
+            }
+        }
+
+        queue.extend(pattern_first_position);
+
+        if queue.is_empty() {
+            break;
+        }
+
+        let mut this_round: Vec<Option<(i64, RaptorLabel)>> = vec![None; stop_count];
+        let previous_round = &rounds[k - 1];
+
+        for (pattern_idx, start_position) in queue {
+            let pattern = &timetable.patterns[pattern_idx];
+
+            // trip index and boarding position
+            let mut current_trip: Option<(usize, usize)> = None;
+
+            for position in start_position..pattern.stops.len() {
+                let stop = pattern.stops[position];
+
+                if let Some((trip, board_position)) = current_trip {
+                    let arrival = pattern.arrival_time(trip, position);
+
+                    if arrival < best_arrival[stop] && arrival < target_bound {
+                        best_arrival[stop] = arrival;
+                        this_round[stop] = Some((
+                            arrival,
+                            RaptorLabel::Ride {
+                                pattern: pattern_idx,
+                                trip,
+                                board_position,
+                                alight_position: position,
+                            },
+                        ));
+                        marked[stop] = true;
+                    }
+                }
+
+                if let Some((previous_arrival, previous_label)) = previous_round[stop] {
+                    // staying at the stop after riding means changing vehicles
+                    let previous_arrival = match previous_label {
+                        RaptorLabel::Ride { .. } => previous_arrival + MIN_TRANSFER_SECS,
+                        _ => previous_arrival,
+                    };
+
+                    let can_catch_earlier = match current_trip {
+                        Some((trip, _)) => {
+                            previous_arrival <= pattern.departure_time(trip, position)
+                        }
+                        None => true,
+                    };
+
+                    if can_catch_earlier {
+                        if let Some(new_trip) = pattern.earliest_trip(position, previous_arrival) {
+                            let is_earlier = match current_trip {
+                                Some((trip, _)) => new_trip < trip,
+                                None => true,
+                            };
+
+                            if is_earlier {
+                                current_trip = Some((new_trip, position));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        relax_footpaths(timetable, &mut this_round, &mut best_arrival, &mut marked);
+
+        rounds.push(this_round);
+    }
+
+    RaptorResult {
+        rounds,
+        best_arrival,
+    }
+}
+
+/// The fastest journey to a stop over every departure in a window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileArrival {
+    pub departure_time: i64,
+    pub arrival_time: i64,
+    // number of vehicles boarded
+    pub trips: usize,
+}
+
+impl ProfileArrival {
+    pub fn travel_time_secs(&self) -> i64 {
+        self.arrival_time - self.departure_time
+    }
+}
+
+/// One to all profile search, giving the shortest travel time to every stop when leaving
+/// at any time between `window_start` and `window_end`.
+/// Origins are a stop and the seconds needed to reach it. Leaving later than just in time
+/// for a trip at an origin only shortens the journey, so only those departures are searched.
+pub fn profile_search(
+    timetable: &Timetable,
+    origins: &[(usize, i64)],
+    window_start: i64,
+    window_end: i64,
+    max_transfers: usize,
+) -> Vec<Option<ProfileArrival>> {
+    let mut departure_times = vec![window_start];
+
+    for (stop, access_secs) in origins.iter() {
+        for (pattern_idx, position) in timetable.patterns_at_stop[*stop].iter() {
+            let pattern = &timetable.patterns[*pattern_idx];
+
+            // nothing can be boarded at the last stop
+            if *position + 1 == pattern.stops.len() {
+                continue;
+            }
+
+            departure_times.extend(
+                (0..pattern.trips.len())
+                    .map(|trip| pattern.departure_time(trip, *position) - access_secs)
+                    .filter(|departure_time| {
+                        *departure_time >= window_start && *departure_time <= window_end
+                    }),
+            );
+        }
+    }
+
+    departure_times.sort_unstable();
+    departure_times.dedup();
+
+    let mut fastest: Vec<Option<ProfileArrival>> = vec![None; timetable.stops.len()];
+
+    for departure_time in departure_times {
+        let query = RaptorQuery {
+            origins: origins
+                .iter()
+                .map(|(stop, access_secs)| (*stop, departure_time + access_secs))
+                .collect(),
+            targets: vec![],
+            max_transfers,
+        };
+
+        let result = raptor(timetable, &query);
+
+        for (stop, arrival_time) in result.best_arrival.iter().enumerate() {
+            if *arrival_time == UNREACHED {
+                continue;
+            }
+
+            let is_faster = match fastest[stop] {
+                Some(existing) => *arrival_time - departure_time < existing.travel_time_secs(),
+                None => true,
+            };
+
+            if is_faster {
+                let trips = result
+                    .rounds
+                    .iter()
+                    .position(
+                        |round| matches!(round[stop], Some((time, _)) if time == *arrival_time),
+                    )
+                    .unwrap_or(0);
+
+                fastest[stop] = Some(ProfileArrival {
+                    departure_time,
+                    arrival_time: *arrival_time,
+                    trips,
+                });
+            }
+        }
+    }
+
+    fastest
+}
+
+// footpaths are only taken from stops reached by riding, never chained after another walk
+fn relax_footpaths(
+    timetable: &Timetable,
+    round: &mut [Option<(i64, RaptorLabel)>],
+    best_arrival: &mut [i64],
+    marked: &mut [bool],
+) {
+    let walk_sources = marked
+        .iter()
+        .enumerate()
+        .filter(|(_, is_marked)| **is_marked)
+        .filter_map(|(stop, _)| round[stop].map(|(time, label)| (stop, time, label)))
+        .filter(|(_, _, label)| !matches!(label, RaptorLabel::Walk { .. }))
+        .map(|(stop, time, _)| (stop, time))
+        .collect::<Vec<(usize, i64)>>();
+
+    for (from_stop, time) in walk_sources {
+        for footpath in timetable.footpaths[from_stop].iter() {
+            let arrival = time + footpath.duration_secs as i64;
+
+            if arrival < best_arrival[footpath.to_stop] {
+                best_arrival[footpath.to_stop] = arrival;
+                round[footpath.to_stop] = Some((
+                    arrival,
+                    RaptorLabel::Walk {
+                        from_stop,
+                        duration_secs: footpath.duration_secs,
+                    },
+                ));
+                marked[footpath.to_stop] = true;
+            }
+        }
+    }
+}
+
+impl RaptorResult {
+    /// Builds the journey arriving at `stop` in round `round`, walking back through the labels
+    pub fn journey_to(&self, timetable: &Timetable, stop: usize, round: usize) -> Option<Journey> {
+        let mut legs: Vec<JourneyLeg> = vec![];
+
+        let mut current_stop = stop;
+        let mut current_round = round;
+
+        loop {
+            let (time, label) = self.rounds[current_round][current_stop]?;
+
+            match label {
+                RaptorLabel::Origin => break,
+                RaptorLabel::Walk {
+                    from_stop,
+                    duration_secs,
+                } => {
+                    let from = &timetable.stops[from_stop];
+                    let to = &timetable.stops[current_stop];
+
+                    legs.push(JourneyLeg::Walk(WalkLeg {
+                        from_chateau: from.chateau.clone(),
+                        from_stop_id: from.stop_id.clone(),
+                        to_chateau: to.chateau.clone(),
+                        to_stop_id: to.stop_id.clone(),
+                        departure_time: time - duration_secs as i64,
+                        arrival_time: time,
+                    }));
+
+                    current_stop = from_stop;
+                }
+                RaptorLabel::Ride {
+                    pattern,
+                    trip,
+                    board_position,
+                    alight_position,
+                } => {
+                    let pattern_data = &timetable.patterns[pattern];
+                    let trip_data = &pattern_data.trips[trip];
+                    let board_stop = pattern_data.stops[board_position];
+
+                    legs.push(JourneyLeg::Transit(TransitLeg {
+                        chateau: pattern_data.chateau.clone(),
+                        trip_id: trip_data.trip_id.clone(),
+                        route_id: trip_data.route_id.clone(),
+                        itinerary_pattern_id: pattern_data.itinerary_pattern_id.clone(),
+                        trip_headsign: pattern_data.trip_headsign.clone(),
+                        trip_short_name: trip_data.trip_short_name.as_ref().map(|x| x.to_string()),
+                        service_date: trip_data.service_date,
+                        from_stop_id: timetable.stops[board_stop].stop_id.clone(),
+                        from_gtfs_stop_sequence: pattern_data.gtfs_stop_sequences[board_position],
+                        to_stop_id: timetable.stops[current_stop].stop_id.clone(),
+                        to_gtfs_stop_sequence: pattern_data.gtfs_stop_sequences[alight_position],
+                        departure_time: pattern_data.departure_time(trip, board_position),
+                        arrival_time: time,
+                    }));
+
+                    current_stop = board_stop;
+                    current_round = current_round.checked_sub(1)?;
+                }
+            }
+        }
+
+        legs.reverse();
+
+        let departure_time = legs.first()?.departure_time();
+        let arrival_time = legs.last()?.arrival_time();
+
+        let transfers = legs
+            .iter()
+            .filter(|leg| matches!(leg, JourneyLeg::Transit(_)))
+            .count()
+            .saturating_sub(1);
+
+        Some(Journey {
+            departure_time,
+            arrival_time,
+            transfers,
+            legs,
+        })
+    }
+
+    /// Pareto optimal journeys over arrival time and number of trips
+    pub fn journeys(&self, timetable: &Timetable, targets: &[(usize, i64)]) -> Vec<Journey> {
+        let mut journeys: Vec<Journey> = vec![];
+        let mut best_so_far = UNREACHED;
+
+        for round in 0..self.rounds.len() {
+            let best_target = targets
+                .iter()
+                .filter_map(|(stop, egress)| {
+                    self.rounds[round][*stop].map(|(time, _)| (*stop, time + egress))
+                })
+                .min_by_key(|(_, time)| *time);
+
+            if let Some((stop, time)) = best_target {
+                if time < best_so_far {
+                    if let Some(journey) = self.journey_to(timetable, stop, round) {
+                        best_so_far = time;
+                        journeys.push(journey);
+                    }
+                }
+            }
+        }
+
+        journeys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prairie::timetable::{TimetablePattern, TimetableStop, TimetableTrip};
+
+    fn stop(stop_id: &str) -> TimetableStop {
+        TimetableStop {
+            chateau: String::from("test"),
+            stop_id: stop_id.to_string(),
+            name: None,
+            code: None,
+            platform_code: None,
+            parent_station: None,
+            lat: None,
+            lon: None,
+        }
+    }
+
+    fn pattern(
+        id: &str,
+        stops: Vec<usize>,
+        offsets: Vec<i64>,
+        starts: Vec<i64>,
+    ) -> TimetablePattern {
+        TimetablePattern {
+            chateau: String::from("test"),
+            itinerary_pattern_id: id.to_string(),
+            direction_pattern_id: None,
+            route_id: id.to_string(),
+            trip_headsign: None,
+            gtfs_stop_sequences: (0..stops.len() as u32).collect(),
+            stops,
+            arrival_offsets: offsets.clone(),
+            departure_offsets: offsets,
+            trips: starts
+                .into_iter()
+                .enumerate()
+                .map(|(i, start_time)| TimetableTrip {
+                    trip_id: format!("{}-{}", id, i),
+                    route_id: id.to_string(),
+                    service_date: chrono::NaiveDate::from_ymd_opt(2024, 9, 1).unwrap(),
+                    trip_short_name: None,
+                    start_time,
+                    frequency_start_secs: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn transfer_beats_slow_direct_trip() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+        let c = timetable.add_stop(stop("C"));
+        let b_platform_2 = timetable.add_stop(stop("B2"));
+
+        // slow local A -> B -> C
+        timetable.add_pattern(pattern(
+            "local",
+            vec![a, b, c],
+            vec![0, 600, 3600],
+            vec![1000],
+        ));
+        // express A -> B
+        timetable.add_pattern(pattern("express", vec![a, b], vec![0, 300], vec![1000]));
+        // connection from the other platform, B2 -> C
+        timetable.add_pattern(pattern(
+            "connection",
+            vec![b_platform_2, c],
+            vec![0, 600],
+            vec![1400, 2000],
+        ));
+        timetable.add_footpath(b, b_platform_2, 60);
+
+        let query = RaptorQuery {
+            origins: vec![(a, 900)],
+            targets: vec![(c, 0)],
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+        };
+
+        let result = raptor(&timetable, &query);
+        let journeys = result.journeys(&timetable, &query.targets);
+
+        assert_eq!(journeys.len(), 2);
+
+        // direct trip on the local
+        assert_eq!(journeys[0].arrival_time, 4600);
+        assert_eq!(journeys[0].transfers, 0);
+
+        // express, walk to the other platform, then the connection
+        assert_eq!(journeys[1].arrival_time, 2000);
+        assert_eq!(journeys[1].transfers, 1);
+        assert_eq!(journeys[1].legs.len(), 3);
+    }
+
+    #[test]
+    fn connection_at_the_same_stop_needs_the_transfer_time() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+        let c = timetable.add_stop(stop("C"));
+
+        timetable.add_pattern(pattern("inbound", vec![a, b], vec![0, 300], vec![0]));
+        // the first outbound trip leaves 30 seconds after the inbound one arrives
+        timetable.add_pattern(pattern(
+            "outbound",
+            vec![b, c],
+            vec![0, 300],
+            vec![330, 900],
+        ));
+
+        let query = RaptorQuery {
+            origins: vec![(a, 0)],
+            targets: vec![(c, 0)],
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+        };
+
+        let result = raptor(&timetable, &query);
+        let journeys = result.journeys(&timetable, &query.targets);
+
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(journeys[0].arrival_time, 1200);
+        assert_eq!(journeys[0].legs[1].departure_time(), 900);
+    }
+
+    #[test]
+    fn profile_keeps_the_fastest_departure_in_the_window() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+
+        timetable.add_pattern(pattern("slow", vec![a, b], vec![0, 1800], vec![1000]));
+        timetable.add_pattern(pattern("fast", vec![a, b], vec![0, 600], vec![2000]));
+
+        // two minutes of walking to A
+        let profile = profile_search(&timetable, &[(a, 120)], 0, 3000, DEFAULT_MAX_TRANSFERS);
+
+        // leaving at the start of the window waits for the fast trip, leaving just in time for it is quickest
+        assert_eq!(
+            profile[b],
+            Some(ProfileArrival {
+                departure_time: 1880,
+                arrival_time: 2600,
+                trips: 1,
+            })
+        );
+        assert_eq!(
+            profile[a],
+            Some(ProfileArrival {
+                departure_time: 0,
+                arrival_time: 120,
+                trips: 0,
+            })
+        );
+
+        // the fast trip can no longer be caught just in time from inside a shorter window
+        let profile = profile_search(&timetable, &[(a, 120)], 0, 1000, DEFAULT_MAX_TRANSFERS);
+
+        assert_eq!(profile[b].unwrap().departure_time, 880);
+        assert_eq!(profile[b].unwrap().travel_time_secs(), 1720);
+    }
+
+    #[test]
+    fn profile_compares_departures_from_every_origin() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let c = timetable.add_stop(stop("C"));
+        let d = timetable.add_stop(stop("D"));
+
+        timetable.add_pattern(pattern("from_a", vec![a, c], vec![0, 900], vec![1000]));
+        timetable.add_pattern(pattern("from_d", vec![d, c], vec![0, 1500], vec![1000]));
+
+        // A is a ten minute walk away, D is next to the origin
+        let profile = profile_search(
+            &timetable,
+            &[(a, 600), (d, 60)],
+            0,
+            3600,
+            DEFAULT_MAX_TRANSFERS,
+        );
+
+        // the longer walk to the quicker trip beats the trip from the nearest stop
+        assert_eq!(
+            profile[c],
+            Some(ProfileArrival {
+                departure_time: 400,
+                arrival_time: 1900,
+                trips: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn journeys_are_limited_by_transfers_and_missed_trips() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+        let c = timetable.add_stop(stop("C"));
+
+        timetable.add_pattern(pattern("first", vec![a, b], vec![0, 300], vec![1000]));
+        timetable.add_pattern(pattern("second", vec![b, c], vec![0, 300], vec![1400]));
+
+        let search = |ready_at: i64, max_transfers: usize| {
+            let query = RaptorQuery {
+                origins: vec![(a, ready_at)],
+                targets: vec![(c, 0)],
+                max_transfers,
+            };
+
+            raptor(&timetable, &query).journeys(&timetable, &query.targets)
+        };
+
+        let journeys = search(900, 1);
+
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(journeys[0].arrival_time, 1700);
+        assert_eq!(journeys[0].transfers, 1);
+
+        // C needs both trips
+        assert!(search(900, 0).is_empty());
+
+        // the only trip from A has already left
+        assert!(search(1001, 1).is_empty());
+
+        // the rounds stop once nothing improves, however many transfers are allowed
+        assert_eq!(search(900, usize::MAX).len(), 1);
+    }
+}
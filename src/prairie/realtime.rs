@@ -0,0 +1,694 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Overlays Aspen trip updates onto the scheduled timetable.
+// Delayed trips no longer share the offsets of their itinerary, so each one is moved
+// into its own single trip pattern. Cancelled trips and skipped stops are removed.
+
+use crate::aspen::lib::TripsSelectionResponse;
+use crate::aspen_dataset::AspenisedTripUpdate;
+use crate::prairie::raptor::{Journey, JourneyLeg};
+use crate::prairie::timetable::{Timetable, TimetablePattern, TimetableStop, MIN_TRANSFER_SECS};
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+
+// GTFS-rt TripDescriptor.ScheduleRelationship
+const TRIP_CANCELED: i32 = 3;
+const TRIP_DELETED: i32 = 7;
+
+// GTFS-rt StopTimeUpdate.ScheduleRelationship
+const STOP_SKIPPED: i32 = 1;
+const STOP_NO_DATA: i32 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct RealtimeOverlay {
+    // (chateau, trip_id) -> trip updates
+    trip_updates: AHashMap<(String, String), Vec<AspenisedTripUpdate>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RealtimeStopEvent {
+    pub arrival: i64,
+    pub departure: i64,
+    pub skipped: bool,
+}
+
+impl RealtimeOverlay {
+    pub fn add_chateau(&mut self, chateau: &str, response: TripsSelectionResponse) {
+        for (trip_id, trip_update_ids) in response.trip_id_to_trip_update_ids {
+            let updates = trip_update_ids
+                .iter()
+                .filter_map(|id| response.trip_updates.get(id))
+                .cloned()
+                .collect::<Vec<AspenisedTripUpdate>>();
+
+            if !updates.is_empty() {
+                self.trip_updates
+                    .insert((chateau.to_string(), trip_id), updates);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.trip_updates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trip_updates.is_empty()
+    }
+
+    /// The update of the trip on the service date, and for frequency based trips, of the run
+    /// starting `frequency_start_secs` after the start of the service date
+    pub fn trip_update(
+        &self,
+        chateau: &str,
+        trip_id: &str,
+        service_date: &chrono::NaiveDate,
+        frequency_start_secs: Option<u32>,
+    ) -> Option<&AspenisedTripUpdate> {
+        let updates = self
+            .trip_updates
+            .get(&(chateau.to_string(), trip_id.to_string()))?;
+
+        let service_date_str = service_date.format("%Y%m%d").to_string();
+
+        updates
+            .iter()
+            .filter(
+                |trip_update| match (&trip_update.trip.start_time, frequency_start_secs) {
+                    (Some(update_start_time), Some(frequency_start_secs)) => {
+                        parse_gtfs_time(update_start_time) == Some(frequency_start_secs)
+                    }
+                    _ => true,
+                },
+            )
+            .find(|trip_update| match &trip_update.trip.start_date {
+                Some(start_date) => *start_date == service_date_str,
+                None => true,
+            })
+    }
+}
+
+fn parse_gtfs_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':');
+
+    let hours = parts.next()?.parse::<u32>().ok()?;
+    let minutes = parts.next()?.parse::<u32>().ok()?;
+    let seconds = parts.next()?.parse::<u32>().ok()?;
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+pub fn is_trip_cancelled(trip_update: &AspenisedTripUpdate) -> bool {
+    matches!(
+        trip_update.trip.schedule_relationship,
+        Some(TRIP_CANCELED) | Some(TRIP_DELETED)
+    )
+}
+
+/// Realtime arrival and departure at every stop of the trip.
+/// Stops without their own update carry the delay of the last updated stop before them.
+pub fn realtime_stop_events(
+    stops: &[TimetableStop],
+    pattern: &TimetablePattern,
+    trip_idx: usize,
+    trip_update: &AspenisedTripUpdate,
+) -> Vec<RealtimeStopEvent> {
+    let mut current_delay: Option<i64> = trip_update.delay.map(|x| x as i64);
+
+    let mut events: Vec<RealtimeStopEvent> = Vec::with_capacity(pattern.stops.len());
+
+    // updates are in stop order, so each one is only matched after the ones before it
+    let mut next_update: usize = 0;
+
+    for position in 0..pattern.stops.len() {
+        let scheduled_arrival = pattern.arrival_time(trip_idx, position);
+        let scheduled_departure = pattern.departure_time(trip_idx, position);
+
+        let gtfs_stop_sequence = pattern.gtfs_stop_sequences[position];
+        let stop_id = stops[pattern.stops[position]].stop_id.as_str();
+
+        let matched_update = trip_update
+            .stop_time_update
+            .iter()
+            .enumerate()
+            .skip(next_update)
+            .find(|(_, stu)| match stu.stop_sequence {
+                Some(stop_sequence) => stop_sequence == gtfs_stop_sequence,
+                // feeds leaving out the stop sequence are matched by stop id, a loop visits the stop again later
+                None => stu.stop_id.as_deref() == Some(stop_id),
+            });
+
+        if let Some((update_idx, _)) = matched_update {
+            next_update = update_idx + 1;
+        }
+
+        let stop_time_update = matched_update.map(|(_, stu)| stu);
+
+        let mut skipped = false;
+
+        let (arrival, departure) = match stop_time_update {
+            Some(stu) if stu.schedule_relationship == Some(STOP_NO_DATA) => {
+                current_delay = None;
+
+                (scheduled_arrival, scheduled_departure)
+            }
+            Some(stu) => {
+                skipped = stu.schedule_relationship == Some(STOP_SKIPPED);
+
+                let arrival = match &stu.arrival {
+                    Some(event) => match (event.time, event.delay) {
+                        (Some(time), _) => time,
+                        (None, Some(delay)) => scheduled_arrival + delay as i64,
+                        (None, None) => scheduled_arrival + current_delay.unwrap_or(0),
+                    },
+                    None => scheduled_arrival + current_delay.unwrap_or(0),
+                };
+
+                if stu.arrival.is_some() {
+                    current_delay = Some(arrival - scheduled_arrival);
+                }
+
+                let departure = match &stu.departure {
+                    Some(event) => match (event.time, event.delay) {
+                        (Some(time), _) => time,
+                        (None, Some(delay)) => scheduled_departure + delay as i64,
+                        (None, None) => scheduled_departure + current_delay.unwrap_or(0),
+                    },
+                    None => scheduled_departure + current_delay.unwrap_or(0),
+                };
+
+                if stu.departure.is_some() {
+                    current_delay = Some(departure - scheduled_departure);
+                }
+
+                (arrival, departure.max(arrival))
+            }
+            None => (
+                scheduled_arrival + current_delay.unwrap_or(0),
+                scheduled_departure + current_delay.unwrap_or(0),
+            ),
+        };
+
+        events.push(RealtimeStopEvent {
+            arrival,
+            departure,
+            skipped,
+        });
+    }
+
+    // a vehicle cannot leave a stop before it left the previous one
+    for position in 1..events.len() {
+        let previous_departure = events[position - 1].departure;
+
+        if events[position].arrival < previous_departure {
+            events[position].arrival = previous_departure;
+        }
+
+        if events[position].departure < events[position].arrival {
+            events[position].departure = events[position].arrival;
+        }
+    }
+
+    events
+}
+
+/// Removes cancelled trips and moves every delayed trip into its own pattern
+pub fn apply_realtime_to_timetable(timetable: &mut Timetable, overlay: &RealtimeOverlay) {
+    let mut new_patterns: Vec<TimetablePattern> = vec![];
+
+    let stops = &timetable.stops;
+
+    for pattern in timetable.patterns.iter_mut() {
+        let mut kept_trips = Vec::with_capacity(pattern.trips.len());
+
+        for trip_idx in 0..pattern.trips.len() {
+            let trip = &pattern.trips[trip_idx];
+
+            let trip_update = match overlay.trip_update(
+                &pattern.chateau,
+                &trip.trip_id,
+                &trip.service_date,
+                trip.frequency_start_secs,
+            ) {
+                Some(trip_update) => trip_update,
+                None => {
+                    kept_trips.push(trip.clone());
+                    continue;
+                }
+            };
+
+            if is_trip_cancelled(trip_update) {
+                continue;
+            }
+
+            let events = realtime_stop_events(stops, pattern, trip_idx, trip_update);
+
+            let unchanged = events.iter().enumerate().all(|(position, event)| {
+                !event.skipped
+                    && event.arrival == pattern.arrival_time(trip_idx, position)
+                    && event.departure == pattern.departure_time(trip_idx, position)
+            });
+
+            if unchanged {
+                kept_trips.push(trip.clone());
+                continue;
+            }
+
+            let positions = (0..events.len())
+                .filter(|position| !events[*position].skipped)
+                .collect::<Vec<usize>>();
+
+            if positions.len() < 2 {
+                continue;
+            }
+
+            new_patterns.push(TimetablePattern {
+                chateau: pattern.chateau.clone(),
+                itinerary_pattern_id: pattern.itinerary_pattern_id.clone(),
+                direction_pattern_id: pattern.direction_pattern_id.clone(),
+                route_id: pattern.route_id.clone(),
+                trip_headsign: pattern.trip_headsign.clone(),
+                stops: positions.iter().map(|p| pattern.stops[*p]).collect(),
+                gtfs_stop_sequences: positions
+                    .iter()
+                    .map(|p| pattern.gtfs_stop_sequences[*p])
+                    .collect(),
+                arrival_offsets: positions
+                    .iter()
+                    .map(|p| events[*p].arrival - trip.start_time)
+                    .collect(),
+                departure_offsets: positions
+                    .iter()
+                    .map(|p| events[*p].departure - trip.start_time)
+                    .collect(),
+                trips: vec![trip.clone()],
+            });
+        }
+
+        pattern.trips = kept_trips;
+    }
+
+    timetable
+        .patterns
+        .retain(|pattern| !pattern.trips.is_empty());
+    timetable.patterns.extend(new_patterns);
+
+    timetable.rebuild_pattern_index();
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LegRealtimeStatus {
+    pub has_realtime: bool,
+    pub cancelled: bool,
+    pub departure_time_realtime: Option<i64>,
+    pub arrival_time_realtime: Option<i64>,
+    pub boarding_stop_skipped: bool,
+    pub alighting_stop_skipped: bool,
+    // the rider can no longer make this leg after the realtime arrival of the previous one
+    pub transfer_infeasible: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JourneyRealtimeStatus {
+    pub feasible: bool,
+    pub arrival_time_realtime: i64,
+    pub legs: Vec<LegRealtimeStatus>,
+}
+
+/// Compares a planned journey against realtime data, flagging every leg which can no longer be taken
+pub fn check_journey_against_realtime(
+    timetable: &Timetable,
+    overlay: &RealtimeOverlay,
+    journey: &Journey,
+) -> JourneyRealtimeStatus {
+    let mut legs: Vec<LegRealtimeStatus> = Vec::with_capacity(journey.legs.len());
+
+    // when the rider is ready to continue, following realtime data
+    let mut ready_time: Option<i64> = None;
+    let mut previous_leg_is_transit = false;
+    let mut feasible = true;
+
+    for leg in journey.legs.iter() {
+        let mut status = LegRealtimeStatus::default();
+
+        match leg {
+            JourneyLeg::Walk(walk) => {
+                let start = ready_time.unwrap_or(walk.departure_time);
+                let arrival = start + (walk.arrival_time - walk.departure_time);
+
+                status.departure_time_realtime = Some(start);
+                status.arrival_time_realtime = Some(arrival);
+
+                ready_time = Some(arrival);
+                previous_leg_is_transit = false;
+            }
+            JourneyLeg::Transit(transit) => {
+                let mut departure = transit.departure_time;
+                let mut arrival = transit.arrival_time;
+
+                let scheduled_trip = find_scheduled_trip(timetable, transit);
+
+                if let Some(trip_update) = overlay.trip_update(
+                    &transit.chateau,
+                    &transit.trip_id,
+                    &transit.service_date,
+                    scheduled_trip.and_then(|(pattern, trip_idx)| {
+                        pattern.trips[trip_idx].frequency_start_secs
+                    }),
+                ) {
+                    status.has_realtime = true;
+                    status.cancelled = is_trip_cancelled(trip_update);
+
+                    if let Some((pattern, trip_idx)) = scheduled_trip {
+                        let events =
+                            realtime_stop_events(&timetable.stops, pattern, trip_idx, trip_update);
+
+                        let from_position = pattern
+                            .gtfs_stop_sequences
+                            .iter()
+                            .position(|x| *x == transit.from_gtfs_stop_sequence);
+                        let to_position = pattern
+                            .gtfs_stop_sequences
+                            .iter()
+                            .position(|x| *x == transit.to_gtfs_stop_sequence);
+
+                        if let Some(from_position) = from_position {
+                            departure = events[from_position].departure;
+                            status.boarding_stop_skipped = events[from_position].skipped;
+                        }
+
+                        if let Some(to_position) = to_position {
+                            arrival = events[to_position].arrival;
+                            status.alighting_stop_skipped = events[to_position].skipped;
+                        }
+                    }
+
+                    status.departure_time_realtime = Some(departure);
+                    status.arrival_time_realtime = Some(arrival);
+                }
+
+                // the delayed arrival of the previous vehicle, plus the time to change at the same stop
+                if let Some(ready_time) = ready_time {
+                    let transfer_time = match previous_leg_is_transit {
+                        true => MIN_TRANSFER_SECS,
+                        false => 0,
+                    };
+
+                    if departure < ready_time + transfer_time {
+                        status.transfer_infeasible = true;
+                    }
+                }
+
+                if status.cancelled
+                    || status.boarding_stop_skipped
+                    || status.alighting_stop_skipped
+                    || status.transfer_infeasible
+                {
+                    feasible = false;
+                }
+
+                ready_time = Some(arrival);
+                previous_leg_is_transit = true;
+            }
+        }
+
+        legs.push(status);
+    }
+
+    JourneyRealtimeStatus {
+        feasible,
+        arrival_time_realtime: ready_time.unwrap_or(journey.arrival_time),
+        legs,
+    }
+}
+
+// the scheduled trip the leg was planned on, matched by trip id, service date and departure time
+fn find_scheduled_trip<'a>(
+    timetable: &'a Timetable,
+    transit: &crate::prairie::raptor::TransitLeg,
+) -> Option<(&'a TimetablePattern, usize)> {
+    timetable
+        .patterns
+        .iter()
+        .filter(|pattern| {
+            pattern.chateau == transit.chateau
+                && pattern.itinerary_pattern_id == transit.itinerary_pattern_id
+        })
+        .flat_map(|pattern| {
+            pattern
+                .trips
+                .iter()
+                .enumerate()
+                .filter(|(_, trip)| {
+                    trip.trip_id == transit.trip_id && trip.service_date == transit.service_date
+                })
+                .map(move |(trip_idx, trip)| (pattern, trip_idx, trip.start_time))
+        })
+        .min_by_key(|(pattern, trip_idx, _)| {
+            match pattern
+                .gtfs_stop_sequences
+                .iter()
+                .position(|x| *x == transit.from_gtfs_stop_sequence)
+            {
+                Some(position) => {
+                    (pattern.departure_time(*trip_idx, position) - transit.departure_time).abs()
+                }
+                None => i64::MAX,
+            }
+        })
+        .map(|(pattern, trip_idx, _)| (pattern, trip_idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aspen_dataset::{AspenRawTripInfo, AspenStopTimeEvent, AspenisedStopTimeUpdate};
+    use crate::prairie::raptor::{raptor, RaptorQuery};
+    use crate::prairie::timetable::{TimetableStop, TimetableTrip};
+
+    fn stop(stop_id: &str) -> TimetableStop {
+        TimetableStop {
+            chateau: String::from("test"),
+            stop_id: stop_id.to_string(),
+            name: None,
+            code: None,
+            platform_code: None,
+            parent_station: None,
+            lat: None,
+            lon: None,
+        }
+    }
+
+    fn line(id: &str, stops: Vec<usize>, offsets: Vec<i64>, start_time: i64) -> TimetablePattern {
+        TimetablePattern {
+            chateau: String::from("test"),
+            itinerary_pattern_id: id.to_string(),
+            direction_pattern_id: None,
+            route_id: id.to_string(),
+            trip_headsign: None,
+            gtfs_stop_sequences: (0..stops.len() as u32).collect(),
+            stops,
+            arrival_offsets: offsets.clone(),
+            departure_offsets: offsets,
+            trips: vec![TimetableTrip {
+                trip_id: id.to_string(),
+                route_id: id.to_string(),
+                service_date: chrono::NaiveDate::from_ymd_opt(2024, 9, 1).unwrap(),
+                trip_short_name: None,
+                start_time,
+                frequency_start_secs: None,
+            }],
+        }
+    }
+
+    fn delayed(trip_id: &str, delay: i32) -> AspenisedTripUpdate {
+        AspenisedTripUpdate {
+            trip: AspenRawTripInfo {
+                trip_id: Some(trip_id.to_string()),
+                route_id: None,
+                direction_id: None,
+                start_time: None,
+                start_date: None,
+                schedule_relationship: None,
+                modified_trip: None,
+            },
+            vehicle: None,
+            timestamp: None,
+            delay: Some(delay),
+            stop_time_update: vec![],
+            trip_properties: None,
+            trip_headsign: None,
+        }
+    }
+
+    /// Red A -> B arrives at 300, blue B -> C leaves at 600
+    fn scheduled_connection() -> (Timetable, Journey) {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+        let c = timetable.add_stop(stop("C"));
+
+        timetable.add_pattern(line("red", vec![a, b], vec![0, 300], 0));
+        timetable.add_pattern(line("blue", vec![b, c], vec![0, 300], 600));
+
+        let query = RaptorQuery {
+            origins: vec![(a, 0)],
+            targets: vec![(c, 0)],
+            max_transfers: 1,
+        };
+
+        let journey = raptor(&timetable, &query)
+            .journeys(&timetable, &query.targets)
+            .pop()
+            .unwrap();
+
+        assert_eq!(journey.legs.len(), 2);
+
+        (timetable, journey)
+    }
+
+    fn overlay_with(trip_update: AspenisedTripUpdate) -> RealtimeOverlay {
+        let mut overlay = RealtimeOverlay::default();
+
+        overlay.trip_updates.insert(
+            (
+                String::from("test"),
+                trip_update.trip.trip_id.clone().unwrap(),
+            ),
+            vec![trip_update],
+        );
+
+        overlay
+    }
+
+    #[test]
+    fn delay_of_the_inbound_trip_breaks_the_connection() {
+        let (timetable, journey) = scheduled_connection();
+
+        // red now arrives at 580, leaving 20 seconds to change onto blue
+        let overlay = overlay_with(delayed("red", 280));
+
+        let status = check_journey_against_realtime(&timetable, &overlay, &journey);
+
+        assert!(!status.feasible);
+        assert!(!status.legs[0].transfer_infeasible);
+        assert_eq!(status.legs[0].arrival_time_realtime, Some(580));
+        assert!(status.legs[1].transfer_infeasible);
+    }
+
+    #[test]
+    fn connection_holds_while_the_transfer_time_is_kept() {
+        let (timetable, journey) = scheduled_connection();
+
+        // red arrives at 540, exactly the minimum transfer time before blue
+        let overlay = overlay_with(delayed("red", 240));
+
+        let status = check_journey_against_realtime(&timetable, &overlay, &journey);
+
+        assert!(status.feasible);
+        assert!(!status.legs[1].transfer_infeasible);
+        assert_eq!(status.arrival_time_realtime, 900);
+    }
+
+    #[test]
+    fn delayed_outbound_trip_keeps_the_connection() {
+        let (timetable, journey) = scheduled_connection();
+
+        let mut overlay = overlay_with(delayed("red", 280));
+        overlay
+            .trip_updates
+            .extend(overlay_with(delayed("blue", 120)).trip_updates);
+
+        let status = check_journey_against_realtime(&timetable, &overlay, &journey);
+
+        assert!(status.feasible);
+        assert_eq!(status.legs[1].departure_time_realtime, Some(720));
+        assert_eq!(status.arrival_time_realtime, 1020);
+    }
+
+    fn stop_time_update(
+        stop_id: &str,
+        arrival: Option<AspenStopTimeEvent>,
+        departure: Option<AspenStopTimeEvent>,
+    ) -> AspenisedStopTimeUpdate {
+        AspenisedStopTimeUpdate {
+            stop_sequence: None,
+            stop_id: Some(stop_id.into()),
+            arrival,
+            departure,
+            departure_occupancy_status: None,
+            schedule_relationship: None,
+            stop_time_properties: None,
+            platform_string: None,
+        }
+    }
+
+    fn event(delay: Option<i32>, time: Option<i64>) -> Option<AspenStopTimeEvent> {
+        Some(AspenStopTimeEvent {
+            delay,
+            time,
+            uncertainty: None,
+            predicted: false,
+        })
+    }
+
+    #[test]
+    fn updates_without_stop_sequences_match_each_visit_of_a_loop_by_stop_id() {
+        let mut timetable = Timetable::default();
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+
+        timetable.add_pattern(line("loop", vec![a, b, a], vec![0, 300, 600], 0));
+
+        let mut trip_update = delayed("loop", 0);
+        trip_update.delay = None;
+        trip_update.stop_time_update = vec![
+            stop_time_update("A", None, event(Some(60), None)),
+            stop_time_update("A", event(None, Some(900)), None),
+        ];
+
+        let events =
+            realtime_stop_events(&timetable.stops, &timetable.patterns[0], 0, &trip_update);
+
+        assert_eq!(events[0].departure, 60);
+        // B has no update and carries the delay from the first visit of A
+        assert_eq!(events[1].arrival, 360);
+        // the second update is for the second visit of A
+        assert_eq!(events[2].arrival, 900);
+    }
+
+    #[test]
+    fn runs_of_a_frequency_trip_are_told_apart_by_start_time() {
+        let run = |start_time: &str, delay: i32| {
+            let mut trip_update = delayed("red", delay);
+            trip_update.trip.start_time = Some(start_time.to_string());
+            trip_update
+        };
+
+        let mut overlay = RealtimeOverlay::default();
+        overlay.trip_updates.insert(
+            (String::from("test"), String::from("red")),
+            vec![run("07:00:00", 60), run("7:10:00", 120)],
+        );
+
+        let service_date = chrono::NaiveDate::from_ymd_opt(2024, 9, 1).unwrap();
+
+        let delay_of = |frequency_start_secs: Option<u32>| {
+            overlay
+                .trip_update("test", "red", &service_date, frequency_start_secs)
+                .and_then(|trip_update| trip_update.delay)
+        };
+
+        assert_eq!(delay_of(Some(7 * 3600)), Some(60));
+        assert_eq!(delay_of(Some(7 * 3600 + 600)), Some(120));
+        assert_eq!(delay_of(Some(7 * 3600 + 1200)), None);
+        // trips with their own schedule take the update of the service date
+        assert_eq!(delay_of(None), Some(60));
+    }
+}
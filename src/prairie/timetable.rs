@@ -0,0 +1,655 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// The timetable is built directly from the compressed itinerary tables written by Maple.
+// Every trip in an itinerary shares the same offsets from the start of the trip,
+// so an itinerary is exactly a RAPTOR route: trips never overtake each other,
+// and the trip departing at stop i at time t is found by binary searching on the trip start times.
+
+use crate::gtfs_schedule_protobuf::protobuf_to_frequencies;
+use crate::models::{
+    Calendar, CalendarDate, CompressedTrip, FootpathRow, ItineraryPatternMeta, ItineraryPatternRow,
+    Stop,
+};
+use crate::CalendarUnified;
+use ahash::AHashMap;
+use compact_str::CompactString;
+use diesel::query_dsl::methods::FilterDsl;
+use diesel::query_dsl::methods::SelectDsl;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+
+/// Time penalty to change between platforms of the same parent station, in seconds
+pub const SAME_STATION_TRANSFER_SECS: u32 = 120;
+
+/// Time needed to get off one vehicle and onto another at the same stop, in seconds
+pub const MIN_TRANSFER_SECS: i64 = 60;
+
+/// How far before the window to look for trips which already started but are still running
+pub const TRIP_LOOKBACK_SECS: i64 = 3600 * 6;
+
+#[derive(Clone, Debug)]
+pub struct TimetableStop {
+    pub chateau: String,
+    pub stop_id: String,
+    pub name: Option<String>,
+    pub code: Option<String>,
+    pub platform_code: Option<String>,
+    pub parent_station: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimetableTrip {
+    pub trip_id: String,
+    pub route_id: String,
+    pub service_date: chrono::NaiveDate,
+    pub trip_short_name: Option<CompactString>,
+    //unix time in seconds of the start of the trip
+    pub start_time: i64,
+    // runs of a frequency based trip share the trip id, and are told apart by their start time,
+    // in seconds since the start of the service date
+    pub frequency_start_secs: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimetablePattern {
+    pub chateau: String,
+    pub itinerary_pattern_id: String,
+    pub direction_pattern_id: Option<String>,
+    pub route_id: String,
+    pub trip_headsign: Option<String>,
+    pub stops: Vec<usize>,
+    pub gtfs_stop_sequences: Vec<u32>,
+    pub arrival_offsets: Vec<i64>,
+    pub departure_offsets: Vec<i64>,
+    //sorted by start time
+    pub trips: Vec<TimetableTrip>,
+}
+
+impl TimetablePattern {
+    pub fn arrival_time(&self, trip_idx: usize, position: usize) -> i64 {
+        self.trips[trip_idx].start_time + self.arrival_offsets[position]
+    }
+
+    pub fn departure_time(&self, trip_idx: usize, position: usize) -> i64 {
+        self.trips[trip_idx].start_time + self.departure_offsets[position]
+    }
+
+    /// Returns the first trip which departs from the stop at `position` at or after `time`
+    pub fn earliest_trip(&self, position: usize, time: i64) -> Option<usize> {
+        let target_start = time - self.departure_offsets[position];
+
+        let idx = self
+            .trips
+            .partition_point(|trip| trip.start_time < target_start);
+
+        match idx < self.trips.len() {
+            true => Some(idx),
+            false => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Footpath {
+    pub to_stop: usize,
+    pub duration_secs: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timetable {
+    pub stops: Vec<TimetableStop>,
+    pub stop_lookup: AHashMap<(String, String), usize>,
+    pub patterns: Vec<TimetablePattern>,
+    // stop index -> list of (pattern index, position of the stop in the pattern)
+    pub patterns_at_stop: Vec<Vec<(usize, usize)>>,
+    pub footpaths: Vec<Vec<Footpath>>,
+    pub window_start: i64,
+    pub window_end: i64,
+}
+
+impl Timetable {
+    pub fn stop_index(&self, chateau: &str, stop_id: &str) -> Option<usize> {
+        self.stop_lookup
+            .get(&(chateau.to_string(), stop_id.to_string()))
+            .copied()
+    }
+
+    /// The stop itself, plus every platform under it if it is a station
+    pub fn stop_and_children(&self, chateau: &str, stop_id: &str) -> Vec<usize> {
+        self.stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| {
+                stop.chateau == chateau
+                    && (stop.stop_id == stop_id || stop.parent_station.as_deref() == Some(stop_id))
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    pub fn add_stop(&mut self, stop: TimetableStop) -> usize {
+        let key = (stop.chateau.clone(), stop.stop_id.clone());
+
+        if let Some(idx) = self.stop_lookup.get(&key) {
+            return *idx;
+        }
+
+        let idx = self.stops.len();
+        self.stops.push(stop);
+        self.stop_lookup.insert(key, idx);
+        self.patterns_at_stop.push(vec![]);
+        self.footpaths.push(vec![]);
+
+        idx
+    }
+
+    pub fn add_pattern(&mut self, mut pattern: TimetablePattern) -> usize {
+        pattern.trips.sort_by_key(|trip| trip.start_time);
+
+        let idx = self.patterns.len();
+
+        for (position, stop_idx) in pattern.stops.iter().enumerate() {
+            self.patterns_at_stop[*stop_idx].push((idx, position));
+        }
+
+        self.patterns.push(pattern);
+
+        idx
+    }
+
+    /// Recomputes patterns_at_stop after patterns were removed or edited in place
+    pub fn rebuild_pattern_index(&mut self) {
+        for patterns in self.patterns_at_stop.iter_mut() {
+            patterns.clear();
+        }
+
+        for pattern in self.patterns.iter_mut() {
+            pattern.trips.sort_by_key(|trip| trip.start_time);
+        }
+
+        for (idx, pattern) in self.patterns.iter().enumerate() {
+            for (position, stop_idx) in pattern.stops.iter().enumerate() {
+                self.patterns_at_stop[*stop_idx].push((idx, position));
+            }
+        }
+    }
+
+    /// Adds a one way footpath, keeping the fastest one if the pair already exists
+    pub fn add_footpath(&mut self, from_stop: usize, to_stop: usize, duration_secs: u32) {
+        if from_stop == to_stop {
+            return;
+        }
+
+        match self.footpaths[from_stop]
+            .iter_mut()
+            .find(|footpath| footpath.to_stop == to_stop)
+        {
+            Some(existing) => {
+                existing.duration_secs = existing.duration_secs.min(duration_secs);
+            }
+            None => self.footpaths[from_stop].push(Footpath {
+                to_stop,
+                duration_secs,
+            }),
+        }
+    }
+
+    /// Connects platforms sharing a parent station, and parent stations to their platforms
+    pub fn add_same_station_transfers(&mut self) {
+        let mut stations: AHashMap<(String, String), Vec<usize>> = AHashMap::new();
+
+        for (idx, stop) in self.stops.iter().enumerate() {
+            if let Some(parent_station) = &stop.parent_station {
+                stations
+                    .entry((stop.chateau.clone(), parent_station.clone()))
+                    .or_default()
+                    .push(idx);
+            }
+        }
+
+        for ((chateau, parent_station), children) in stations {
+            let mut members = children;
+
+            if let Some(parent_idx) = self.stop_index(&chateau, &parent_station) {
+                members.push(parent_idx);
+            }
+
+            for a in members.iter() {
+                for b in members.iter() {
+                    self.add_footpath(*a, *b, SAME_STATION_TRANSFER_SECS);
+                }
+            }
+        }
+    }
+}
+
+/// The schedule data of a single chateau, exactly as it was queried from Postgres
+pub struct ChateauScheduleRows {
+    pub chateau: String,
+    pub stops: Vec<Stop>,
+    pub itinerary_rows: Vec<ItineraryPatternRow>,
+    pub itinerary_meta: Vec<ItineraryPatternMeta>,
+    pub trips: Vec<CompressedTrip>,
+    pub calendar: Vec<Calendar>,
+    pub calendar_dates: Vec<CalendarDate>,
+    pub footpaths: Vec<FootpathRow>,
+}
+
+pub async fn load_chateau_schedule_rows(
+    conn: &mut AsyncPgConnection,
+    chateau: &str,
+) -> Result<ChateauScheduleRows, Box<dyn Error + Send + Sync>> {
+    // only use the attempts which are currently in production
+    let feed_ids: Vec<String> = crate::schema::gtfs::static_feeds::dsl::static_feeds
+        .filter(crate::schema::gtfs::static_feeds::dsl::chateau.eq(chateau))
+        .select(crate::schema::gtfs::static_feeds::dsl::onestop_feed_id)
+        .load::<String>(conn)
+        .await?;
+
+    let attempt_ids: Vec<String> = crate::schema::gtfs::ingested_static::dsl::ingested_static
+        .filter(crate::schema::gtfs::ingested_static::dsl::onestop_feed_id.eq_any(&feed_ids))
+        .filter(crate::schema::gtfs::ingested_static::dsl::production.eq(true))
+        .filter(crate::schema::gtfs::ingested_static::dsl::deleted.eq(false))
+        .select(crate::schema::gtfs::ingested_static::dsl::attempt_id)
+        .load::<String>(conn)
+        .await?;
+
+    let stops = crate::schema::gtfs::stops::dsl::stops
+        .filter(crate::schema::gtfs::stops::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::stops::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(Stop::as_select())
+        .load::<Stop>(conn)
+        .await?;
+
+    let itinerary_rows = crate::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
+        .filter(crate::schema::gtfs::itinerary_pattern::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::itinerary_pattern::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(ItineraryPatternRow::as_select())
+        .load::<ItineraryPatternRow>(conn)
+        .await?;
+
+    let itinerary_meta = crate::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta
+        .filter(crate::schema::gtfs::itinerary_pattern_meta::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::itinerary_pattern_meta::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(ItineraryPatternMeta::as_select())
+        .load::<ItineraryPatternMeta>(conn)
+        .await?;
+
+    let trips = crate::schema::gtfs::trips_compressed::dsl::trips_compressed
+        .filter(crate::schema::gtfs::trips_compressed::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::trips_compressed::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await?;
+
+    let calendar = crate::schema::gtfs::calendar::dsl::calendar
+        .filter(crate::schema::gtfs::calendar::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::calendar::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(Calendar::as_select())
+        .load::<Calendar>(conn)
+        .await?;
+
+    let calendar_dates = crate::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(crate::schema::gtfs::calendar_dates::dsl::chateau.eq(chateau))
+        .filter(crate::schema::gtfs::calendar_dates::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(CalendarDate::as_select())
+        .load::<CalendarDate>(conn)
+        .await?;
+
+    // walking transfers computed by the OSM extractor
+    let footpaths = crate::schema::gtfs::footpaths::dsl::footpaths
+        .filter(crate::schema::gtfs::footpaths::dsl::chateau.eq(chateau))
+        .select(FootpathRow::as_select())
+        .load::<FootpathRow>(conn)
+        .await?;
+
+    Ok(ChateauScheduleRows {
+        chateau: chateau.to_string(),
+        stops,
+        itinerary_rows,
+        itinerary_meta,
+        trips,
+        calendar,
+        calendar_dates,
+        footpaths,
+    })
+}
+
+/// Loads every chateau and builds a timetable containing the trips running between `window_start` and `window_end`
+pub async fn load_timetable(
+    conn: &mut AsyncPgConnection,
+    chateaus: &[String],
+    window_start: chrono::DateTime<chrono::Utc>,
+    window_end: chrono::DateTime<chrono::Utc>,
+) -> Result<Timetable, Box<dyn Error + Send + Sync>> {
+    let mut timetable = Timetable {
+        window_start: window_start.timestamp(),
+        window_end: window_end.timestamp(),
+        ..Default::default()
+    };
+
+    for chateau in chateaus {
+        let rows = load_chateau_schedule_rows(conn, chateau).await?;
+
+        add_chateau_to_timetable(&mut timetable, rows);
+    }
+
+    timetable.add_same_station_transfers();
+
+    Ok(timetable)
+}
+
+pub fn add_chateau_to_timetable(timetable: &mut Timetable, rows: ChateauScheduleRows) {
+    let chateau = rows.chateau;
+
+    for stop in rows.stops {
+        timetable.add_stop(TimetableStop {
+            chateau: chateau.clone(),
+            stop_id: stop.gtfs_id,
+            name: stop.name,
+            code: stop.code,
+            platform_code: stop.platform_code,
+            parent_station: stop.parent_station,
+            lat: stop.point.as_ref().map(|point| point.y),
+            lon: stop.point.as_ref().map(|point| point.x),
+        });
+    }
+
+    let calendar_structure: BTreeMap<String, CalendarUnified> =
+        crate::make_calendar_structure_from_pg_single_chateau(rows.calendar, rows.calendar_dates);
+
+    let mut itineraries: AHashMap<String, Vec<ItineraryPatternRow>> = AHashMap::new();
+
+    for row in rows.itinerary_rows {
+        itineraries
+            .entry(row.itinerary_pattern_id.clone())
+            .or_default()
+            .push(row);
+    }
+
+    let mut trips_per_itinerary: AHashMap<String, Vec<CompressedTrip>> = AHashMap::new();
+
+    for trip in rows.trips {
+        trips_per_itinerary
+            .entry(trip.itinerary_pattern_id.clone())
+            .or_default()
+            .push(trip);
+    }
+
+    let window_input = chrono::DateTime::from_timestamp(timetable.window_start, 0).unwrap();
+    let back_duration = chrono::TimeDelta::new(TRIP_LOOKBACK_SECS, 0).unwrap();
+    let forward_duration =
+        chrono::TimeDelta::new(timetable.window_end - timetable.window_start, 0).unwrap();
+
+    for meta in rows.itinerary_meta {
+        let mut itinerary_rows = match itineraries.remove(&meta.itinerary_pattern_id) {
+            Some(itinerary_rows) => itinerary_rows,
+            None => continue,
+        };
+
+        let trips = match trips_per_itinerary.remove(&meta.itinerary_pattern_id) {
+            Some(trips) => trips,
+            None => continue,
+        };
+
+        let timezone = match chrono_tz::Tz::from_str(meta.timezone.as_str()) {
+            Ok(timezone) => timezone,
+            Err(_) => continue,
+        };
+
+        itinerary_rows.sort_by_key(|row| row.stop_sequence);
+
+        let mut stops = Vec::with_capacity(itinerary_rows.len());
+        let mut gtfs_stop_sequences = Vec::with_capacity(itinerary_rows.len());
+        let mut arrival_offsets = Vec::with_capacity(itinerary_rows.len());
+        let mut departure_offsets = Vec::with_capacity(itinerary_rows.len());
+
+        let mut last_known_offset: i64 = 0;
+        let mut missing_stop = false;
+
+        for row in itinerary_rows.iter() {
+            let stop_idx = match timetable.stop_index(&chateau, row.stop_id.as_str()) {
+                Some(stop_idx) => stop_idx,
+                None => {
+                    missing_stop = true;
+                    break;
+                }
+            };
+
+            let arrival = row
+                .arrival_time_since_start
+                .or(row.interpolated_time_since_start)
+                .or(row.departure_time_since_start)
+                .map(|x| x as i64)
+                .unwrap_or(last_known_offset);
+
+            let departure = row
+                .departure_time_since_start
+                .or(row.interpolated_time_since_start)
+                .or(row.arrival_time_since_start)
+                .map(|x| x as i64)
+                .unwrap_or(arrival);
+
+            last_known_offset = departure;
+
+            stops.push(stop_idx);
+            gtfs_stop_sequences.push(row.gtfs_stop_sequence);
+            arrival_offsets.push(arrival);
+            departure_offsets.push(departure);
+        }
+
+        if missing_stop || stops.len() < 2 {
+            continue;
+        }
+
+        let mut timetable_trips: Vec<TimetableTrip> = vec![];
+
+        for trip in trips {
+            let service = match calendar_structure.get(trip.service_id.as_str()) {
+                Some(service) => service,
+                None => continue,
+            };
+
+            let frequency: Option<crate::gtfs_schedule_protobuf::GtfsFrequenciesProto> = trip
+                .frequencies
+                .as_ref()
+                .and_then(|data| prost::Message::decode(data.as_ref()).ok());
+
+            let frequencies = frequency.map(|x| protobuf_to_frequencies(&x));
+
+            let t_to_find_schedule_for = crate::TripToFindScheduleFor {
+                trip_id: trip.trip_id.clone(),
+                chateau: chateau.clone(),
+                timezone,
+                time_since_start_of_service_date: chrono::TimeDelta::new(trip.start_time as i64, 0)
+                    .unwrap(),
+                frequency: frequencies.clone(),
+                itinerary_id: meta.itinerary_pattern_id.clone(),
+                direction_id: meta.direction_pattern_id.clone().unwrap_or_default(),
+            };
+
+            let dates = crate::find_service_ranges(
+                service,
+                &t_to_find_schedule_for,
+                window_input,
+                back_duration,
+                forward_duration,
+            );
+
+            for (service_date, reference_start) in dates {
+                let reference_start = reference_start.timestamp();
+
+                match &frequencies {
+                    Some(frequencies) => {
+                        for frequency in frequencies {
+                            if frequency.headway_secs == 0 {
+                                continue;
+                            }
+
+                            let mut start = frequency.start_time;
+
+                            while start < frequency.end_time {
+                                timetable_trips.push(TimetableTrip {
+                                    trip_id: trip.trip_id.clone(),
+                                    route_id: trip.route_id.clone(),
+                                    service_date,
+                                    trip_short_name: trip.trip_short_name.clone(),
+                                    start_time: reference_start + start as i64,
+                                    frequency_start_secs: Some(start),
+                                });
+
+                                start += frequency.headway_secs;
+                            }
+                        }
+                    }
+                    None => timetable_trips.push(TimetableTrip {
+                        trip_id: trip.trip_id.clone(),
+                        route_id: trip.route_id.clone(),
+                        service_date,
+                        trip_short_name: trip.trip_short_name.clone(),
+                        start_time: reference_start + trip.start_time as i64,
+                        frequency_start_secs: None,
+                    }),
+                }
+            }
+        }
+
+        // drop trips which have already finished or have not yet started by the end of the window
+        let trip_length = *arrival_offsets.last().unwrap();
+
+        timetable_trips.retain(|trip| {
+            trip.start_time + trip_length >= timetable.window_start
+                && trip.start_time <= timetable.window_end
+        });
+
+        if timetable_trips.is_empty() {
+            continue;
+        }
+
+        timetable.add_pattern(TimetablePattern {
+            chateau: chateau.clone(),
+            itinerary_pattern_id: meta.itinerary_pattern_id.clone(),
+            direction_pattern_id: meta.direction_pattern_id.clone(),
+            route_id: meta.route_id.to_string(),
+            trip_headsign: meta.trip_headsign.clone(),
+            stops,
+            gtfs_stop_sequences,
+            arrival_offsets,
+            departure_offsets,
+            trips: timetable_trips,
+        });
+    }
+
+    for footpath in rows.footpaths {
+        if let (Some(from_stop), Some(to_stop)) = (
+            timetable.stop_index(&chateau, &footpath.from_stop_id),
+            timetable.stop_index(&chateau, &footpath.to_stop_id),
+        ) {
+            timetable.add_footpath(from_stop, to_stop, footpath.duration_seconds.max(0) as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(gtfs_id: &str) -> Stop {
+        Stop {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            gtfs_id: gtfs_id.to_string(),
+            name: None,
+            name_translations: None,
+            displayname: None,
+            code: None,
+            gtfs_desc: None,
+            gtfs_desc_translations: None,
+            location_type: 0,
+            parent_station: None,
+            zone_id: None,
+            url: None,
+            point: None,
+            timezone: None,
+            wheelchair_boarding: 0,
+            primary_route_type: None,
+            level_id: None,
+            platform_code: None,
+            platform_code_translations: None,
+            routes: vec![],
+            route_types: vec![],
+            children_ids: vec![],
+            children_route_types: vec![],
+            station_feature: false,
+            hidden: false,
+            chateau: String::from("test"),
+            location_alias: None,
+            tts_name_translations: None,
+            tts_name: None,
+            allowed_spatial_query: true,
+        }
+    }
+
+    fn footpath(from_stop_id: &str, to_stop_id: &str, duration_seconds: i32) -> FootpathRow {
+        FootpathRow {
+            chateau: String::from("test"),
+            from_stop_id: from_stop_id.to_string(),
+            to_stop_id: to_stop_id.to_string(),
+            distance_metres: duration_seconds as f32 * 1.3,
+            duration_seconds,
+            computed_unix_time_ms: 0,
+        }
+    }
+
+    #[test]
+    fn footpaths_from_the_osm_extractor_join_stops() {
+        let mut timetable = Timetable::default();
+
+        add_chateau_to_timetable(
+            &mut timetable,
+            ChateauScheduleRows {
+                chateau: String::from("test"),
+                stops: vec![stop("a"), stop("b"), stop("c")],
+                itinerary_rows: vec![],
+                itinerary_meta: vec![],
+                trips: vec![],
+                calendar: vec![],
+                calendar_dates: vec![],
+                footpaths: vec![
+                    footpath("a", "b", 120),
+                    footpath("a", "b", 90),
+                    footpath("b", "a", 95),
+                    footpath("a", "gone", 30),
+                    footpath("c", "c", 10),
+                ],
+            },
+        );
+
+        let a = timetable.stop_index("test", "a").unwrap();
+        let b = timetable.stop_index("test", "b").unwrap();
+        let c = timetable.stop_index("test", "c").unwrap();
+
+        let walks_from = |stop: usize| {
+            timetable.footpaths[stop]
+                .iter()
+                .map(|footpath| (footpath.to_stop, footpath.duration_secs))
+                .collect::<Vec<(usize, u32)>>()
+        };
+
+        // the faster of two walks between the same stops is kept, and each direction stands alone
+        assert_eq!(walks_from(a), vec![(b, 90)]);
+        assert_eq!(walks_from(b), vec![(a, 95)]);
+        assert!(walks_from(c).is_empty());
+    }
+}
@@ -0,0 +1,1075 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Transfer Patterns, Bast et al. (2010)
+// For every source stop, a profile search over the week finds every optimal journey.
+// Only the sequence of stops where the rider boards, alights or walks is kept,
+// merged into a tree rooted at the source. A query evaluates the tree at the
+// requested time, using a direct-connection lookup on the itineraries for each edge.
+// The precompute runs after Maple ingests a chateau and in the scheduled Prairie job.
+// Source stops are searched in parallel and stored in batches, so a run which stops early
+// leaves the previous trees of the remaining stops in place.
+// Stops without a tree, and queries the trees cannot answer, are planned with RAPTOR instead.
+
+use crate::models::{TransferPatternNode, TransferPatternsMeta};
+use crate::postgres_tools::CatenaryPostgresPool;
+use crate::prairie::raptor::{
+    raptor, Journey, JourneyLeg, RaptorQuery, TransitLeg, WalkLeg, DEFAULT_MAX_TRANSFERS,
+};
+use crate::prairie::timetable::{load_timetable, Timetable, MIN_TRANSFER_SECS};
+use ahash::AHashMap;
+use diesel::query_dsl::methods::FilterDsl;
+use diesel::query_dsl::methods::SelectDsl;
+use diesel::ExpressionMethods;
+use diesel::SelectableHelper;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use rayon::prelude::*;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Length of the profile search used to compute the patterns, a week so services
+/// running only on some days are included
+pub const PRECOMPUTE_WINDOW_SECS: i64 = 3600 * 24 * 7;
+
+/// Queries allowing more transfers than this are answered by RAPTOR
+pub const PRECOMPUTE_MAX_TRANSFERS: usize = DEFAULT_MAX_TRANSFERS;
+
+/// Source stops searched and written to postgres together
+pub const PRECOMPUTE_BATCH_SIZE: usize = 500;
+
+#[derive(Clone, Debug)]
+pub struct TransferPatternDagNode {
+    pub parent: Option<usize>,
+    pub stop: usize,
+    pub arrive_by_walking: bool,
+    pub is_target: bool,
+}
+
+/// Tree of transfer patterns for one source stop. Node 0 is always the source,
+/// and every parent is stored before its children.
+#[derive(Clone, Debug)]
+pub struct TransferPatternDag {
+    pub source_stop: usize,
+    pub nodes: Vec<TransferPatternDagNode>,
+    children: AHashMap<(usize, usize, bool), usize>,
+}
+
+impl TransferPatternDag {
+    pub fn new(source_stop: usize) -> Self {
+        TransferPatternDag {
+            source_stop,
+            nodes: vec![TransferPatternDagNode {
+                parent: None,
+                stop: source_stop,
+                arrive_by_walking: false,
+                is_target: false,
+            }],
+            children: AHashMap::new(),
+        }
+    }
+
+    fn child(&mut self, parent: usize, stop: usize, arrive_by_walking: bool) -> usize {
+        match self.children.get(&(parent, stop, arrive_by_walking)) {
+            Some(existing) => *existing,
+            None => {
+                let idx = self.nodes.len();
+
+                self.nodes.push(TransferPatternDagNode {
+                    parent: Some(parent),
+                    stop,
+                    arrive_by_walking,
+                    is_target: false,
+                });
+                self.children.insert((parent, stop, arrive_by_walking), idx);
+
+                idx
+            }
+        }
+    }
+
+    /// Inserts the pattern of stops used by the journey
+    pub fn insert_journey(&mut self, timetable: &Timetable, journey: &Journey) {
+        let mut current = 0;
+
+        for leg in journey.legs.iter() {
+            let (chateau, stop_id, arrive_by_walking) = match leg {
+                JourneyLeg::Transit(leg) => (&leg.chateau, &leg.to_stop_id, false),
+                JourneyLeg::Walk(leg) => (&leg.to_chateau, &leg.to_stop_id, true),
+            };
+
+            let stop = match timetable.stop_index(chateau, stop_id) {
+                Some(stop) => stop,
+                None => return,
+            };
+
+            current = self.child(current, stop, arrive_by_walking);
+        }
+
+        if current != 0 {
+            self.nodes[current].is_target = true;
+        }
+    }
+
+    pub fn from_rows(
+        timetable: &Timetable,
+        source_stop: usize,
+        mut rows: Vec<TransferPatternNode>,
+    ) -> Option<Self> {
+        rows.sort_by_key(|row| row.node_id);
+
+        let mut dag = TransferPatternDag::new(source_stop);
+        dag.nodes.clear();
+
+        for row in rows {
+            let stop = timetable.stop_index(&row.chateau, &row.stop_id)?;
+            let parent = row.parent_node_id.map(|x| x as usize);
+
+            if let Some(parent) = parent {
+                dag.children
+                    .insert((parent, stop, row.arrive_by_walking), dag.nodes.len());
+            }
+
+            dag.nodes.push(TransferPatternDagNode {
+                parent,
+                stop,
+                arrive_by_walking: row.arrive_by_walking,
+                is_target: row.is_target,
+            });
+        }
+
+        match dag.nodes.first() {
+            Some(root) if root.parent.is_none() => Some(dag),
+            _ => None,
+        }
+    }
+
+    pub fn to_rows(&self, timetable: &Timetable) -> Vec<TransferPatternNode> {
+        let source = &timetable.stops[self.source_stop];
+
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| TransferPatternNode {
+                chateau: source.chateau.clone(),
+                source_stop_id: source.stop_id.clone(),
+                node_id: idx as i32,
+                parent_node_id: node.parent.map(|x| x as i32),
+                stop_id: timetable.stops[node.stop].stop_id.clone(),
+                arrive_by_walking: node.arrive_by_walking,
+                is_target: node.is_target,
+            })
+            .collect()
+    }
+
+    /// Evaluates the tree at `departure_time`, returning the Pareto optimal journeys to any of the targets
+    pub fn evaluate(
+        &self,
+        timetable: &Timetable,
+        departure_time: i64,
+        targets: &[usize],
+    ) -> Vec<Journey> {
+        // arrival time at each node and the leg used to get there
+        let mut reached: Vec<Option<(i64, Option<JourneyLeg>)>> = vec![None; self.nodes.len()];
+
+        reached[0] = Some((departure_time, None));
+
+        for idx in 1..self.nodes.len() {
+            let node = &self.nodes[idx];
+            let parent = node.parent.unwrap();
+
+            let (parent_time, parent_leg) = match &reached[parent] {
+                Some((time, leg)) => (*time, leg),
+                None => continue,
+            };
+
+            let parent_stop = self.nodes[parent].stop;
+
+            let leg = match node.arrive_by_walking {
+                true => walk_leg(timetable, parent_stop, node.stop, parent_time),
+                false => {
+                    // staying at the stop after riding means changing vehicles
+                    let ready_time = match parent_leg {
+                        Some(JourneyLeg::Transit(_)) => parent_time + MIN_TRANSFER_SECS,
+                        _ => parent_time,
+                    };
+
+                    direct_connection(timetable, parent_stop, node.stop, ready_time)
+                }
+            };
+
+            if let Some(leg) = leg {
+                reached[idx] = Some((leg.arrival_time(), Some(leg)));
+            }
+        }
+
+        let mut candidates: Vec<Journey> = vec![];
+
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if !node.is_target || !targets.contains(&node.stop) || reached[idx].is_none() {
+                continue;
+            }
+
+            let mut legs: Vec<JourneyLeg> = vec![];
+            let mut current = idx;
+
+            while let Some((_, Some(leg))) = &reached[current] {
+                legs.push(leg.clone());
+                current = self.nodes[current].parent.unwrap();
+            }
+
+            legs.reverse();
+
+            let transfers = legs
+                .iter()
+                .filter(|leg| matches!(leg, JourneyLeg::Transit(_)))
+                .count()
+                .saturating_sub(1);
+
+            if let (Some(first), Some(last)) = (legs.first(), legs.last()) {
+                candidates.push(Journey {
+                    departure_time: first.departure_time(),
+                    arrival_time: last.arrival_time(),
+                    transfers,
+                    legs,
+                });
+            }
+        }
+
+        // keep only journeys which are not dominated on both arrival time and transfers
+        candidates.sort_by_key(|journey| (journey.transfers, journey.arrival_time));
+
+        let mut journeys: Vec<Journey> = vec![];
+
+        for journey in candidates {
+            let dominated = journeys
+                .last()
+                .map(|best: &Journey| best.arrival_time <= journey.arrival_time)
+                .unwrap_or(false);
+
+            if !dominated {
+                journeys.push(journey);
+            }
+        }
+
+        journeys
+    }
+}
+
+fn walk_leg(timetable: &Timetable, from: usize, to: usize, time: i64) -> Option<JourneyLeg> {
+    let footpath = timetable.footpaths[from]
+        .iter()
+        .find(|footpath| footpath.to_stop == to)?;
+
+    let from_stop = &timetable.stops[from];
+    let to_stop = &timetable.stops[to];
+
+    Some(JourneyLeg::Walk(WalkLeg {
+        from_chateau: from_stop.chateau.clone(),
+        from_stop_id: from_stop.stop_id.clone(),
+        to_chateau: to_stop.chateau.clone(),
+        to_stop_id: to_stop.stop_id.clone(),
+        departure_time: time,
+        arrival_time: time + footpath.duration_secs as i64,
+    }))
+}
+
+/// Earliest arrival at `to` riding a single trip boarded at `from` at or after `time`
+pub fn direct_connection(
+    timetable: &Timetable,
+    from: usize,
+    to: usize,
+    time: i64,
+) -> Option<JourneyLeg> {
+    let mut best: Option<(i64, usize, usize, usize, usize)> = None;
+
+    for (pattern_idx, from_position) in timetable.patterns_at_stop[from].iter() {
+        let pattern = &timetable.patterns[*pattern_idx];
+
+        let to_position = match pattern.stops[from_position + 1..]
+            .iter()
+            .position(|stop| *stop == to)
+        {
+            Some(offset) => from_position + 1 + offset,
+            None => continue,
+        };
+
+        if let Some(trip) = pattern.earliest_trip(*from_position, time) {
+            let arrival = pattern.arrival_time(trip, to_position);
+
+            let is_better = match best {
+                Some((best_arrival, _, _, _, _)) => arrival < best_arrival,
+                None => true,
+            };
+
+            if is_better {
+                best = Some((arrival, *pattern_idx, trip, *from_position, to_position));
+            }
+        }
+    }
+
+    let (arrival, pattern_idx, trip, from_position, to_position) = best?;
+
+    let pattern = &timetable.patterns[pattern_idx];
+    let trip_data = &pattern.trips[trip];
+
+    Some(JourneyLeg::Transit(TransitLeg {
+        chateau: pattern.chateau.clone(),
+        trip_id: trip_data.trip_id.clone(),
+        route_id: trip_data.route_id.clone(),
+        itinerary_pattern_id: pattern.itinerary_pattern_id.clone(),
+        trip_headsign: pattern.trip_headsign.clone(),
+        trip_short_name: trip_data.trip_short_name.as_ref().map(|x| x.to_string()),
+        service_date: trip_data.service_date,
+        from_stop_id: timetable.stops[from].stop_id.clone(),
+        from_gtfs_stop_sequence: pattern.gtfs_stop_sequences[from_position],
+        to_stop_id: timetable.stops[to].stop_id.clone(),
+        to_gtfs_stop_sequence: pattern.gtfs_stop_sequences[to_position],
+        departure_time: pattern.departure_time(trip, from_position),
+        arrival_time: arrival,
+    }))
+}
+
+/// Every distinct time a trip departs from the stop inside the timetable window
+pub fn departure_times_at_stop(timetable: &Timetable, stop: usize) -> Vec<i64> {
+    let mut times = timetable.patterns_at_stop[stop]
+        .iter()
+        .flat_map(|(pattern_idx, position)| {
+            let pattern = &timetable.patterns[*pattern_idx];
+
+            (0..pattern.trips.len()).map(move |trip| pattern.departure_time(trip, *position))
+        })
+        .filter(|time| *time >= timetable.window_start && *time <= timetable.window_end)
+        .collect::<Vec<i64>>();
+
+    times.sort_unstable();
+    times.dedup();
+
+    times
+}
+
+/// Profile search from the source, one RAPTOR run per departure from the stop.
+/// Departures are searched from the latest to the earliest, and a journey is only walked back
+/// and inserted when it arrives earlier than every later departure using as many trips or fewer,
+/// so each optimal journey is extracted once instead of once per departure and target.
+pub fn compute_dag_for_source(
+    timetable: &Timetable,
+    source_stop: usize,
+    max_transfers: usize,
+) -> TransferPatternDag {
+    let mut dag = TransferPatternDag::new(source_stop);
+
+    // best_later[k][stop] is the earliest arrival of a later departure using at most k trips
+    let mut best_later: Vec<Vec<i64>> = vec![];
+
+    for departure_time in departure_times_at_stop(timetable, source_stop)
+        .into_iter()
+        .rev()
+    {
+        let query = RaptorQuery {
+            origins: vec![(source_stop, departure_time)],
+            targets: vec![],
+            max_transfers,
+        };
+
+        let result = raptor(timetable, &query);
+
+        while best_later.len() < result.rounds.len() {
+            let fewer_trips = best_later
+                .last()
+                .cloned()
+                .unwrap_or_else(|| vec![i64::MAX; timetable.stops.len()]);
+
+            best_later.push(fewer_trips);
+        }
+
+        for (round, arrivals) in result.rounds.iter().enumerate() {
+            for (stop, arrival) in arrivals.iter().enumerate() {
+                let arrival_time = match arrival {
+                    Some((time, _)) if stop != source_stop => *time,
+                    _ => continue,
+                };
+
+                if arrival_time >= best_later[round][stop] {
+                    continue;
+                }
+
+                if let Some(journey) = result.journey_to(timetable, stop, round) {
+                    dag.insert_journey(timetable, &journey);
+                }
+
+                for more_trips in best_later[round..].iter_mut() {
+                    more_trips[stop] = more_trips[stop].min(arrival_time);
+                }
+            }
+        }
+    }
+
+    dag
+}
+
+/// Stops with at least one itinerary, the only ones worth a tree
+pub fn served_stops(timetable: &Timetable) -> Vec<usize> {
+    (0..timetable.stops.len())
+        .filter(|stop| !timetable.patterns_at_stop[*stop].is_empty())
+        .collect()
+}
+
+/// Trees for the source stops, searched in parallel. Stops from which nothing is reachable get no tree.
+pub fn compute_dags(
+    timetable: &Timetable,
+    source_stops: &[usize],
+    max_transfers: usize,
+) -> Vec<TransferPatternDag> {
+    source_stops
+        .par_iter()
+        .filter(|source_stop| !timetable.patterns_at_stop[**source_stop].is_empty())
+        .map(|source_stop| compute_dag_for_source(timetable, *source_stop, max_transfers))
+        .filter(|dag| dag.nodes.len() > 1)
+        .collect()
+}
+
+/// Journeys from the stored trees, or None when RAPTOR has to answer instead:
+/// a served origin has no tree, more transfers are allowed than the trees were computed with,
+/// or no tree reaches the destination within the allowed transfers
+pub fn journeys_from_transfer_patterns(
+    timetable: &Timetable,
+    dags: &[TransferPatternDag],
+    origins: &[usize],
+    targets: &[usize],
+    departure_time: i64,
+    max_transfers: usize,
+) -> Option<Vec<Journey>> {
+    if dags.is_empty() || max_transfers > PRECOMPUTE_MAX_TRANSFERS {
+        return None;
+    }
+
+    let every_origin_has_a_tree = origins
+        .iter()
+        .filter(|origin| !timetable.patterns_at_stop[**origin].is_empty())
+        .all(|origin| dags.iter().any(|dag| dag.source_stop == *origin));
+
+    if !every_origin_has_a_tree {
+        return None;
+    }
+
+    let mut journeys = dags
+        .iter()
+        .flat_map(|dag| dag.evaluate(timetable, departure_time, targets))
+        .filter(|journey| journey.transfers <= max_transfers)
+        .collect::<Vec<Journey>>();
+
+    if journeys.is_empty() {
+        return None;
+    }
+
+    journeys.sort_by_key(|journey| (journey.transfers, journey.arrival_time));
+
+    Some(journeys)
+}
+
+/// Recomputes and replaces every transfer pattern of the chateau.
+/// Each batch of source stops replaces its own rows, and the metadata is only written once
+/// every batch is stored, so an interrupted run is picked up again by `stale_chateaus`.
+pub async fn precompute_transfer_patterns_for_chateau(
+    chateau: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let start = Instant::now();
+
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let window_start = chrono::Utc::now();
+    let window_end = window_start + chrono::TimeDelta::new(PRECOMPUTE_WINDOW_SECS, 0).unwrap();
+
+    let timetable =
+        Arc::new(load_timetable(conn, &[chateau.to_string()], window_start, window_end).await?);
+
+    println!(
+        "Prairie loaded {} stops and {} itineraries for {} in {:?}",
+        timetable.stops.len(),
+        timetable.patterns.len(),
+        chateau,
+        start.elapsed()
+    );
+
+    let source_stops = served_stops(&timetable);
+
+    let mut source_stops_count: i32 = 0;
+    let mut nodes_count: i64 = 0;
+
+    for (batch_idx, batch) in source_stops.chunks(PRECOMPUTE_BATCH_SIZE).enumerate() {
+        let batch = batch.to_vec();
+
+        let batch_stop_ids = batch
+            .iter()
+            .map(|stop| timetable.stops[*stop].stop_id.clone())
+            .collect::<Vec<String>>();
+
+        // the profile searches are CPU bound
+        let timetable_for_batch = Arc::clone(&timetable);
+        let (rows, batch_dags_count) = tokio::task::spawn_blocking(move || {
+            let dags = compute_dags(&timetable_for_batch, &batch, PRECOMPUTE_MAX_TRANSFERS);
+
+            let rows = dags
+                .iter()
+                .flat_map(|dag| dag.to_rows(&timetable_for_batch))
+                .collect::<Vec<TransferPatternNode>>();
+
+            (rows, dags.len() as i32)
+        })
+        .await?;
+
+        source_stops_count += batch_dags_count;
+        nodes_count += rows.len() as i64;
+
+        // stops of the batch without a tree lose their old rows too
+        conn.transaction::<(), diesel::result::Error, _>(|conn| {
+            async move {
+                diesel::delete(
+                    crate::schema::gtfs::transfer_patterns::dsl::transfer_patterns
+                        .filter(crate::schema::gtfs::transfer_patterns::dsl::chateau.eq(chateau))
+                        .filter(
+                            crate::schema::gtfs::transfer_patterns::dsl::source_stop_id
+                                .eq_any(&batch_stop_ids),
+                        ),
+                )
+                .execute(conn)
+                .await?;
+
+                for rows_chunk in rows.chunks(1000) {
+                    diesel::insert_into(
+                        crate::schema::gtfs::transfer_patterns::dsl::transfer_patterns,
+                    )
+                    .values(rows_chunk)
+                    .execute(conn)
+                    .await?;
+                }
+
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+        println!(
+            "Prairie stored {}/{} source stops of {} in {:?}",
+            (batch_idx * PRECOMPUTE_BATCH_SIZE + PRECOMPUTE_BATCH_SIZE).min(source_stops.len()),
+            source_stops.len(),
+            chateau,
+            start.elapsed()
+        );
+    }
+
+    println!(
+        "Prairie computed {} transfer pattern nodes for {} in {:?}",
+        nodes_count,
+        chateau,
+        start.elapsed()
+    );
+
+    let source_stop_ids = source_stops
+        .iter()
+        .map(|stop| timetable.stops[*stop].stop_id.clone())
+        .collect::<Vec<String>>();
+
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            // trees of stops which are no longer served
+            diesel::delete(
+                crate::schema::gtfs::transfer_patterns::dsl::transfer_patterns
+                    .filter(crate::schema::gtfs::transfer_patterns::dsl::chateau.eq(chateau))
+                    .filter(
+                        crate::schema::gtfs::transfer_patterns::dsl::source_stop_id
+                            .ne_all(&source_stop_ids),
+                    ),
+            )
+            .execute(conn)
+            .await?;
+
+            let meta = TransferPatternsMeta {
+                chateau: chateau.to_string(),
+                computed_unix_time_ms: chrono::Utc::now().timestamp_millis(),
+                window_start: window_start.timestamp(),
+                window_end: window_end.timestamp(),
+                source_stops_count,
+                nodes_count,
+            };
+
+            diesel::insert_into(
+                crate::schema::gtfs::transfer_patterns_meta::dsl::transfer_patterns_meta,
+            )
+            .values(&meta)
+            .on_conflict(crate::schema::gtfs::transfer_patterns_meta::dsl::chateau)
+            .do_update()
+            .set((
+                crate::schema::gtfs::transfer_patterns_meta::dsl::computed_unix_time_ms
+                    .eq(meta.computed_unix_time_ms),
+                crate::schema::gtfs::transfer_patterns_meta::dsl::window_start
+                    .eq(meta.window_start),
+                crate::schema::gtfs::transfer_patterns_meta::dsl::window_end.eq(meta.window_end),
+                crate::schema::gtfs::transfer_patterns_meta::dsl::source_stops_count
+                    .eq(meta.source_stops_count),
+                crate::schema::gtfs::transfer_patterns_meta::dsl::nodes_count.eq(meta.nodes_count),
+            ))
+            .execute(conn)
+            .await?;
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    println!(
+        "Prairie finished transfer patterns for {} in {:?}",
+        chateau,
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Patterns cover a fixed window from when they were computed, and go out of date with every new schedule
+pub fn is_stale(
+    meta: Option<&TransferPatternsMeta>,
+    last_ingest_unix_time_ms: Option<i64>,
+    now_unix_time: i64,
+) -> bool {
+    match meta {
+        None => true,
+        Some(meta) => {
+            meta.window_end - now_unix_time < PRECOMPUTE_WINDOW_SECS / 2
+                || last_ingest_unix_time_ms
+                    .map(|last_ingest| last_ingest > meta.computed_unix_time_ms)
+                    .unwrap_or(false)
+        }
+    }
+}
+
+/// Chateaus whose patterns are missing, about to run out, or older than their schedules
+pub async fn stale_chateaus(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let chateaus = crate::schema::gtfs::chateaus::dsl::chateaus
+        .select(crate::models::Chateau::as_select())
+        .load::<crate::models::Chateau>(conn)
+        .await?;
+
+    let last_ingest_per_feed = crate::schema::gtfs::ingested_static::dsl::ingested_static
+        .filter(crate::schema::gtfs::ingested_static::dsl::production.eq(true))
+        .select((
+            crate::schema::gtfs::ingested_static::dsl::onestop_feed_id,
+            crate::schema::gtfs::ingested_static::dsl::ingest_end_unix_time_ms,
+        ))
+        .load::<(String, i64)>(conn)
+        .await?
+        .into_iter()
+        .fold(
+            AHashMap::new(),
+            |mut last_ingest_per_feed: AHashMap<String, i64>, (feed_id, ingest_end)| {
+                let last_ingest = last_ingest_per_feed.entry(feed_id).or_insert(ingest_end);
+                *last_ingest = (*last_ingest).max(ingest_end);
+                last_ingest_per_feed
+            },
+        );
+
+    let metas = crate::schema::gtfs::transfer_patterns_meta::dsl::transfer_patterns_meta
+        .select(TransferPatternsMeta::as_select())
+        .load::<TransferPatternsMeta>(conn)
+        .await?
+        .into_iter()
+        .map(|meta| (meta.chateau.clone(), meta))
+        .collect::<AHashMap<String, TransferPatternsMeta>>();
+
+    let now_unix_time = chrono::Utc::now().timestamp();
+
+    Ok(chateaus
+        .into_iter()
+        .filter(|chateau| {
+            let last_ingest = chateau
+                .static_feeds
+                .iter()
+                .flatten()
+                .filter_map(|feed_id| last_ingest_per_feed.get(feed_id))
+                .max()
+                .copied();
+
+            is_stale(metas.get(&chateau.chateau), last_ingest, now_unix_time)
+        })
+        .map(|chateau| chateau.chateau)
+        .collect())
+}
+
+/// Loads the stored trees for the source stops, skipping any stop without patterns
+pub async fn load_transfer_patterns(
+    conn: &mut AsyncPgConnection,
+    timetable: &Timetable,
+    chateau: &str,
+    source_stops: &[usize],
+) -> Result<Vec<TransferPatternDag>, Box<dyn Error + Send + Sync>> {
+    let source_stop_ids = source_stops
+        .iter()
+        .map(|stop| timetable.stops[*stop].stop_id.clone())
+        .collect::<Vec<String>>();
+
+    let rows = crate::schema::gtfs::transfer_patterns::dsl::transfer_patterns
+        .filter(crate::schema::gtfs::transfer_patterns::dsl::chateau.eq(chateau))
+        .filter(
+            crate::schema::gtfs::transfer_patterns::dsl::source_stop_id.eq_any(&source_stop_ids),
+        )
+        .select(TransferPatternNode::as_select())
+        .load::<TransferPatternNode>(conn)
+        .await?;
+
+    let mut rows_per_source: AHashMap<String, Vec<TransferPatternNode>> = AHashMap::new();
+
+    for row in rows {
+        rows_per_source
+            .entry(row.source_stop_id.clone())
+            .or_default()
+            .push(row);
+    }
+
+    Ok(source_stops
+        .iter()
+        .filter_map(|stop| {
+            let rows = rows_per_source.remove(&timetable.stops[*stop].stop_id)?;
+
+            TransferPatternDag::from_rows(timetable, *stop, rows)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prairie::timetable::{TimetablePattern, TimetableStop, TimetableTrip};
+
+    fn stop(stop_id: &str) -> TimetableStop {
+        TimetableStop {
+            chateau: String::from("test"),
+            stop_id: stop_id.to_string(),
+            name: None,
+            code: None,
+            platform_code: None,
+            parent_station: None,
+            lat: None,
+            lon: None,
+        }
+    }
+
+    fn line(id: &str, stops: Vec<usize>, offsets: Vec<i64>, starts: Vec<i64>) -> TimetablePattern {
+        TimetablePattern {
+            chateau: String::from("test"),
+            itinerary_pattern_id: id.to_string(),
+            direction_pattern_id: None,
+            route_id: id.to_string(),
+            trip_headsign: None,
+            gtfs_stop_sequences: (0..stops.len() as u32).collect(),
+            stops,
+            arrival_offsets: offsets.clone(),
+            departure_offsets: offsets,
+            trips: starts
+                .into_iter()
+                .enumerate()
+                .map(|(i, start_time)| TimetableTrip {
+                    trip_id: format!("{}-{}", id, i),
+                    route_id: id.to_string(),
+                    service_date: chrono::NaiveDate::from_ymd_opt(2024, 9, 1).unwrap(),
+                    trip_short_name: None,
+                    start_time,
+                    frequency_start_secs: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// A -> B by the red line every 10 minutes, B -> C by the blue line every 10 minutes,
+    /// and a slow direct green line A -> C once an hour
+    fn two_lines_and_a_slow_direct() -> (Timetable, usize, usize, usize) {
+        let mut timetable = Timetable {
+            window_start: 0,
+            window_end: 7200,
+            ..Timetable::default()
+        };
+
+        let a = timetable.add_stop(stop("A"));
+        let b = timetable.add_stop(stop("B"));
+        let c = timetable.add_stop(stop("C"));
+
+        timetable.add_pattern(line(
+            "red",
+            vec![a, b],
+            vec![0, 300],
+            (0..12).map(|i| i * 600).collect(),
+        ));
+        timetable.add_pattern(line(
+            "blue",
+            vec![b, c],
+            vec![0, 300],
+            (0..12).map(|i| i * 600 + 60).collect(),
+        ));
+        timetable.add_pattern(line("green", vec![a, c], vec![0, 2400], vec![0, 3600]));
+
+        (timetable, a, b, c)
+    }
+
+    #[test]
+    fn tree_matches_raptor_at_another_time_of_day() {
+        let (timetable, a, _, c) = two_lines_and_a_slow_direct();
+
+        let dags = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        );
+
+        let journeys = journeys_from_transfer_patterns(&timetable, &dags, &[a], &[c], 1200, 4)
+            .expect("A has a tree reaching C");
+
+        let raptor_query = RaptorQuery {
+            origins: vec![(a, 1200)],
+            targets: vec![(c, 0)],
+            max_transfers: 4,
+        };
+        let raptor_journeys =
+            raptor(&timetable, &raptor_query).journeys(&timetable, &raptor_query.targets);
+
+        let summary = |journeys: &[Journey]| {
+            journeys
+                .iter()
+                .map(|journey| (journey.transfers, journey.arrival_time))
+                .collect::<Vec<(usize, i64)>>()
+        };
+
+        // the direct green trip at 3600, or red at 1200 then blue at 1860
+        assert_eq!(summary(&journeys), vec![(0, 6000), (1, 2160)]);
+        assert_eq!(summary(&journeys), summary(&raptor_journeys));
+    }
+
+    #[test]
+    fn max_transfers_filters_the_tree() {
+        let (timetable, a, _, c) = two_lines_and_a_slow_direct();
+
+        let dags = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        );
+
+        let journeys =
+            journeys_from_transfer_patterns(&timetable, &dags, &[a], &[c], 0, 0).unwrap();
+
+        assert_eq!(journeys.len(), 1);
+        assert_eq!(journeys[0].transfers, 0);
+        assert_eq!(journeys[0].arrival_time, 2400);
+    }
+
+    #[test]
+    fn falls_back_when_only_transfers_reach_the_destination_within_the_limit() {
+        let (mut timetable, a, b, _) = two_lines_and_a_slow_direct();
+
+        // D is only served from B
+        let d = timetable.add_stop(stop("D"));
+        timetable.add_pattern(line("yellow", vec![b, d], vec![0, 120], vec![400]));
+
+        let dags = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        );
+
+        assert!(journeys_from_transfer_patterns(&timetable, &dags, &[a], &[d], 0, 1).is_some());
+        assert!(journeys_from_transfer_patterns(&timetable, &dags, &[a], &[d], 0, 0).is_none());
+    }
+
+    #[test]
+    fn falls_back_when_more_transfers_are_allowed_than_precomputed() {
+        let (timetable, a, _, c) = two_lines_and_a_slow_direct();
+
+        let dags = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        );
+
+        assert!(journeys_from_transfer_patterns(
+            &timetable,
+            &dags,
+            &[a],
+            &[c],
+            0,
+            PRECOMPUTE_MAX_TRANSFERS + 1
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn falls_back_when_the_destination_is_not_in_the_tree() {
+        let (mut timetable, a, _, _) = two_lines_and_a_slow_direct();
+
+        let island = timetable.add_stop(stop("Island"));
+
+        let dags = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        );
+
+        assert!(
+            journeys_from_transfer_patterns(&timetable, &dags, &[a], &[island], 0, 4).is_none()
+        );
+    }
+
+    #[test]
+    fn falls_back_when_a_served_origin_has_no_tree() {
+        let (timetable, a, b, c) = two_lines_and_a_slow_direct();
+
+        // only the tree of B was stored, as if the job stopped before reaching A
+        let dags = vec![compute_dag_for_source(
+            &timetable,
+            b,
+            PRECOMPUTE_MAX_TRANSFERS,
+        )];
+
+        assert!(journeys_from_transfer_patterns(&timetable, &dags, &[b], &[c], 0, 4).is_some());
+        assert!(journeys_from_transfer_patterns(&timetable, &dags, &[a, b], &[c], 0, 4).is_none());
+    }
+
+    #[test]
+    fn every_served_stop_with_somewhere_to_go_gets_a_tree() {
+        let (mut timetable, a, b, _) = two_lines_and_a_slow_direct();
+
+        // nothing is served at the island, so it is not searched
+        let island = timetable.add_stop(stop("Island"));
+
+        assert!(!served_stops(&timetable).contains(&island));
+
+        let mut sources = compute_dags(
+            &timetable,
+            &served_stops(&timetable),
+            PRECOMPUTE_MAX_TRANSFERS,
+        )
+        .iter()
+        .map(|dag| dag.source_stop)
+        .collect::<Vec<usize>>();
+        sources.sort_unstable();
+
+        // C is the last stop of every line, so it has no tree
+        assert_eq!(sources, vec![a, b]);
+    }
+
+    #[test]
+    fn latest_first_search_keeps_every_pattern_of_the_full_search() {
+        let (timetable, a, _, _) = two_lines_and_a_slow_direct();
+
+        // every journey of every departure, without skipping those a later departure already found
+        let mut full = TransferPatternDag::new(a);
+
+        for departure_time in departure_times_at_stop(&timetable, a) {
+            let result = raptor(
+                &timetable,
+                &RaptorQuery {
+                    origins: vec![(a, departure_time)],
+                    targets: vec![],
+                    max_transfers: PRECOMPUTE_MAX_TRANSFERS,
+                },
+            );
+
+            for target in 0..timetable.stops.len() {
+                if target != a {
+                    for journey in result.journeys(&timetable, &[(target, 0)]) {
+                        full.insert_journey(&timetable, &journey);
+                    }
+                }
+            }
+        }
+
+        let target_paths = |dag: &TransferPatternDag| {
+            dag.nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| node.is_target)
+                .map(|(idx, _)| {
+                    let mut path = vec![];
+                    let mut current = Some(idx);
+
+                    while let Some(node) = current {
+                        path.push((dag.nodes[node].stop, dag.nodes[node].arrive_by_walking));
+                        current = dag.nodes[node].parent;
+                    }
+
+                    path
+                })
+                .collect::<std::collections::BTreeSet<Vec<(usize, bool)>>>()
+        };
+
+        let pruned = compute_dag_for_source(&timetable, a, PRECOMPUTE_MAX_TRANSFERS);
+
+        assert_eq!(target_paths(&pruned), target_paths(&full));
+    }
+
+    #[test]
+    fn rows_round_trip_keeps_the_tree() {
+        let (timetable, a, _, c) = two_lines_and_a_slow_direct();
+
+        let dag = compute_dag_for_source(&timetable, a, PRECOMPUTE_MAX_TRANSFERS);
+        let mut rows = dag.to_rows(&timetable);
+        // rows come back from postgres in any order
+        rows.reverse();
+
+        let restored = TransferPatternDag::from_rows(&timetable, a, rows).unwrap();
+
+        let shape = |dag: &TransferPatternDag| {
+            dag.nodes
+                .iter()
+                .map(|node| {
+                    (
+                        node.parent,
+                        node.stop,
+                        node.arrive_by_walking,
+                        node.is_target,
+                    )
+                })
+                .collect::<Vec<(Option<usize>, usize, bool, bool)>>()
+        };
+
+        assert_eq!(shape(&restored), shape(&dag));
+        assert_eq!(
+            restored.evaluate(&timetable, 0, &[c]).len(),
+            dag.evaluate(&timetable, 0, &[c]).len()
+        );
+    }
+
+    #[test]
+    fn patterns_are_stale_when_missing_expiring_or_older_than_the_schedule() {
+        let meta = TransferPatternsMeta {
+            chateau: String::from("test"),
+            computed_unix_time_ms: 1_000_000,
+            window_start: 1_000,
+            window_end: 1_000 + PRECOMPUTE_WINDOW_SECS,
+            source_stops_count: 10,
+            nodes_count: 100,
+        };
+
+        assert!(is_stale(None, None, 1_000));
+        assert!(!is_stale(Some(&meta), Some(900_000), 1_000));
+        // a schedule ingested after the patterns were computed
+        assert!(is_stale(Some(&meta), Some(1_000_001), 1_000));
+        // less than half of the window left
+        assert!(is_stale(
+            Some(&meta),
+            None,
+            1_000 + PRECOMPUTE_WINDOW_SECS / 2 + 1
+        ));
+    }
+}
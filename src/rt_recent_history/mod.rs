@@ -15,4 +15,4 @@ pub struct RtCacheEntry {
     pub events: Vec<AspenStopTimeEvent>,
 }
 
-//Each Rt cache entry can be inserted directly into Postgres as a cache?
+//Final stop times are archived into gtfs.stop_time_history by Aspen, see delay_archive.rs
@@ -36,6 +36,59 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.areas (onestop_feed_id, attempt_id, area_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            area_id -> Text,
+            area_name -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.aspen_snapshots (chateau) {
+            chateau -> Text,
+            created_unix_time_ms -> Int8,
+            snapshot -> Bytea,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.booking_rules (onestop_feed_id, attempt_id, booking_rule_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            booking_rule_id -> Text,
+            booking_type -> Int2,
+            prior_notice_duration_min -> Nullable<Int4>,
+            prior_notice_duration_max -> Nullable<Int4>,
+            prior_notice_last_day -> Nullable<Int4>,
+            prior_notice_last_time -> Nullable<Text>,
+            prior_notice_start_day -> Nullable<Int4>,
+            prior_notice_start_time -> Nullable<Text>,
+            prior_notice_service_id -> Nullable<Text>,
+            message -> Nullable<Text>,
+            pickup_message -> Nullable<Text>,
+            drop_off_message -> Nullable<Text>,
+            phone_number -> Nullable<Text>,
+            info_url -> Nullable<Text>,
+            booking_url -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -146,6 +199,117 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_attributes (onestop_feed_id, attempt_id, fare_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            fare_id -> Text,
+            price -> Float8,
+            currency_type -> Text,
+            payment_method -> Int2,
+            transfers -> Nullable<Int2>,
+            agency_id -> Nullable<Text>,
+            transfer_duration -> Nullable<Int4>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_leg_rules (onestop_feed_id, attempt_id, row_index) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            row_index -> Int4,
+            leg_group_id -> Nullable<Text>,
+            network_id -> Nullable<Text>,
+            from_area_id -> Nullable<Text>,
+            to_area_id -> Nullable<Text>,
+            from_timeframe_group_id -> Nullable<Text>,
+            to_timeframe_group_id -> Nullable<Text>,
+            fare_product_id -> Text,
+            rule_priority -> Nullable<Int4>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_media (onestop_feed_id, attempt_id, fare_media_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            fare_media_id -> Text,
+            fare_media_name -> Nullable<Text>,
+            fare_media_type -> Int2,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_products (onestop_feed_id, attempt_id, row_index) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            row_index -> Int4,
+            fare_product_id -> Text,
+            fare_product_name -> Nullable<Text>,
+            fare_media_id -> Nullable<Text>,
+            amount -> Float8,
+            currency -> Text,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_rules (onestop_feed_id, attempt_id, row_index) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            row_index -> Int4,
+            fare_id -> Text,
+            route_id -> Nullable<Text>,
+            origin_id -> Nullable<Text>,
+            destination_id -> Nullable<Text>,
+            contains_id -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.fare_transfer_rules (onestop_feed_id, attempt_id, row_index) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            row_index -> Int4,
+            from_leg_group_id -> Nullable<Text>,
+            to_leg_group_id -> Nullable<Text>,
+            transfer_count -> Nullable<Int4>,
+            duration_limit -> Nullable<Int4>,
+            duration_limit_type -> Nullable<Int2>,
+            fare_transfer_type -> Int2,
+            fare_product_id -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -172,12 +336,66 @@ pub mod gtfs {
         use diesel::sql_types::*;
         use crate::custom_pg_types::*;
 
-        gtfs.gtfs_errors (onestop_feed_id, attempt_id) {
+        gtfs.flex_locations (onestop_feed_id, attempt_id, location_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            location_id -> Text,
+            stop_name -> Nullable<Text>,
+            stop_desc -> Nullable<Text>,
+            geometry -> Geometry,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.footpaths (chateau, from_stop_id, to_stop_id) {
+            chateau -> Text,
+            from_stop_id -> Text,
+            to_stop_id -> Text,
+            distance_metres -> Float4,
+            duration_seconds -> Int4,
+            computed_unix_time_ms -> Int8,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.gtfs_errors (onestop_feed_id, attempt_id, rule) {
             onestop_feed_id -> Text,
             error -> Text,
             attempt_id -> Text,
             file_hash -> Nullable<Text>,
             chateau -> Text,
+            rule -> Text,
+            severity -> Text,
+            count -> Int4,
+            sample_ids -> Jsonb,
+            checked_unix_time_ms -> Int8,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.gtfs_rt_validation_runs (realtime_feed_id, feed_type, checked_unix_time_ms) {
+            realtime_feed_id -> Text,
+            feed_type -> Text,
+            checked_unix_time_ms -> Int8,
+            chateau -> Text,
+            header_timestamp -> Nullable<Int8>,
+            entity_count -> Int4,
+            error_count -> Int4,
+            warning_count -> Int4,
+            notices -> Jsonb,
         }
     }
 
@@ -216,6 +434,7 @@ pub mod gtfs {
             default_lang -> Nullable<Text>,
             languages_avaliable -> Array<Nullable<Text>>,
             ingestion_version -> Int4,
+            file_hashes -> Nullable<Jsonb>,
         }
     }
 
@@ -255,6 +474,11 @@ pub mod gtfs {
             chateau -> Text,
             gtfs_stop_sequence -> Oid,
             interpolated_time_since_start -> Nullable<Int4>,
+            timepoint -> Nullable<Bool>,
+            start_pickup_drop_off_window_since_start -> Nullable<Int4>,
+            end_pickup_drop_off_window_since_start -> Nullable<Int4>,
+            pickup_booking_rule_id -> Nullable<Text>,
+            drop_off_booking_rule_id -> Nullable<Text>,
         }
     }
 
@@ -278,6 +502,75 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.levels (onestop_feed_id, attempt_id, level_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            level_id -> Text,
+            level_index -> Float8,
+            level_name -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.networks (onestop_feed_id, attempt_id, network_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            network_id -> Text,
+            network_name -> Nullable<Text>,
+            route_ids -> Array<Nullable<Text>>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.pathways (onestop_feed_id, attempt_id, pathway_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            pathway_id -> Text,
+            from_stop_id -> Text,
+            to_stop_id -> Text,
+            pathway_mode -> Int2,
+            is_bidirectional -> Bool,
+            length -> Nullable<Float4>,
+            traversal_time -> Nullable<Int4>,
+            stair_count -> Nullable<Int4>,
+            max_slope -> Nullable<Float4>,
+            min_width -> Nullable<Float4>,
+            signposted_as -> Nullable<Text>,
+            reversed_signposted_as -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.realtime_feed_health (realtime_feed_id) {
+            realtime_feed_id -> Text,
+            fetches_updated_ms -> Nullable<Int8>,
+            last_success_ms -> Nullable<Int8>,
+            recent_fetches -> Nullable<Jsonb>,
+            ingests_updated_ms -> Nullable<Int8>,
+            recent_ingests -> Nullable<Jsonb>,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -400,6 +693,40 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.stop_areas (onestop_feed_id, attempt_id, area_id, stop_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            area_id -> Text,
+            stop_id -> Text,
+            chateau -> Text,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.stop_time_history (service_date, chateau, trip_id, stop_id, stop_sequence) {
+            service_date -> Date,
+            chateau -> Text,
+            trip_id -> Text,
+            route_id -> Nullable<Text>,
+            stop_id -> Text,
+            stop_sequence -> Int4,
+            arrival_time -> Nullable<Int8>,
+            arrival_delay -> Nullable<Int4>,
+            departure_time -> Nullable<Int8>,
+            departure_delay -> Nullable<Int4>,
+            recorded_unix_time_ms -> Int8,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -454,6 +781,37 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.transfer_patterns (chateau, source_stop_id, node_id) {
+            chateau -> Text,
+            source_stop_id -> Text,
+            node_id -> Int4,
+            parent_node_id -> Nullable<Int4>,
+            stop_id -> Text,
+            arrive_by_walking -> Bool,
+            is_target -> Bool,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.transfer_patterns_meta (chateau) {
+            chateau -> Text,
+            computed_unix_time_ms -> Int8,
+            window_start -> Int8,
+            window_end -> Int8,
+            source_stops_count -> Int4,
+            nodes_count -> Int8,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -498,6 +856,9 @@ pub mod gtfs {
     diesel::allow_tables_to_appear_in_same_query!(
         admin_credentials,
         agencies,
+        areas,
+        aspen_snapshots,
+        booking_rules,
         calendar,
         calendar_dates,
         chateau_metadata_last_updated_time,
@@ -505,13 +866,26 @@ pub mod gtfs {
         direction_pattern,
         direction_pattern_meta,
         f_test,
+        fare_attributes,
+        fare_leg_rules,
+        fare_media,
+        fare_products,
+        fare_rules,
+        fare_transfer_rules,
         feed_info,
+        flex_locations,
+        footpaths,
         gtfs_errors,
+        gtfs_rt_validation_runs,
         in_progress_static_ingests,
         ingested_static,
         ip_addr_to_geo,
         itinerary_pattern,
         itinerary_pattern_meta,
+        levels,
+        networks,
+        pathways,
+        realtime_feed_health,
         realtime_feeds,
         realtime_passwords,
         routes,
@@ -519,8 +893,12 @@ pub mod gtfs {
         static_download_attempts,
         static_feeds,
         static_passwords,
+        stop_areas,
+        stop_time_history,
         stops,
         stopsforroute,
+        transfer_patterns,
+        transfer_patterns_meta,
         trip_frequencies,
         trips_compressed,
     );
@@ -0,0 +1,77 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Forwards alert events from Aspen to clients watching routes or stops.
+// Alerts which do not name any route, stop or trip apply to the whole agency, so every subscriber of the chateau gets them.
+
+use crate::aspen_client::cached_or_new_aspen_client;
+use ahash::AHashSet;
+use catenary::aspen::lib::AlertEventsResponse;
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::aspen_dataset::AspenisedAlert;
+use catenary::EtcdConnectionIps;
+use std::sync::Arc;
+use tarpc::context;
+
+pub struct AlertSubscription {
+    pub route_ids: AHashSet<String>,
+    pub stop_ids: AHashSet<String>,
+}
+
+impl AlertSubscription {
+    pub fn wants(&self, alert: &AspenisedAlert) -> bool {
+        alert.informed_entity.iter().any(|entity| {
+            let route_id = entity
+                .route_id
+                .as_ref()
+                .or(entity.trip.as_ref().and_then(|trip| trip.route_id.as_ref()));
+
+            if route_id.is_some_and(|route_id| self.route_ids.contains(route_id)) {
+                return true;
+            }
+
+            if entity
+                .stop_id
+                .as_ref()
+                .is_some_and(|stop_id| self.stop_ids.contains(stop_id))
+            {
+                return true;
+            }
+
+            route_id.is_none() && entity.stop_id.is_none() && entity.trip.is_none()
+        })
+    }
+}
+
+pub async fn fetch_alert_events(
+    chateau_id: String,
+    after_sequence: Option<u64>,
+    cached_client: Option<AspenRpcClient>,
+    etcd_connection_ips: Arc<EtcdConnectionIps>,
+    etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+) -> (Option<AspenRpcClient>, Option<AlertEventsResponse>) {
+    let aspen_client = match cached_or_new_aspen_client(
+        &chateau_id,
+        cached_client,
+        &etcd_connection_ips,
+        &etcd_connection_options,
+    )
+    .await
+    {
+        Some(aspen_client) => aspen_client,
+        None => return (None, None),
+    };
+
+    match aspen_client
+        .get_alert_events_since(context::current(), chateau_id.clone(), after_sequence)
+        .await
+    {
+        Ok(Some(response)) => (Some(aspen_client), Some(response)),
+        Ok(None) => (None, None),
+        Err(err) => {
+            eprintln!("Error fetching alert events for {}: {}", chateau_id, err);
+            (None, None)
+        }
+    }
+}
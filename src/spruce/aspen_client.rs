@@ -0,0 +1,58 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::aspen::lib::ChateauMetadataEtcd;
+use catenary::EtcdConnectionIps;
+use std::sync::Arc;
+
+/// Connects to the Aspen worker currently assigned to the chateau
+pub async fn aspen_client_for_chateau(
+    chateau_id: &str,
+    etcd_connection_ips: &Arc<EtcdConnectionIps>,
+    etcd_connection_options: &Arc<Option<etcd_client::ConnectOptions>>,
+) -> Result<Option<AspenRpcClient>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut etcd = etcd_client::Client::connect(
+        etcd_connection_ips.ip_addresses.as_slice(),
+        etcd_connection_options.as_ref().to_owned(),
+    )
+    .await?;
+
+    let etcd_data = etcd
+        .get(
+            format!("/aspen_assigned_chateaus/{}", chateau_id).as_str(),
+            None,
+        )
+        .await?;
+
+    let chateau_metadata = match etcd_data.kvs().first() {
+        Some(kv) => bincode::deserialize::<ChateauMetadataEtcd>(kv.value())?,
+        None => return Ok(None),
+    };
+
+    let aspen_client =
+        catenary::aspen::lib::spawn_aspen_client_from_ip(&chateau_metadata.socket).await?;
+
+    Ok(Some(aspen_client))
+}
+
+/// Reuses the client from the last poll, or looks up the assigned worker again
+pub async fn cached_or_new_aspen_client(
+    chateau_id: &str,
+    cached_client: Option<AspenRpcClient>,
+    etcd_connection_ips: &Arc<EtcdConnectionIps>,
+    etcd_connection_options: &Arc<Option<etcd_client::ConnectOptions>>,
+) -> Option<AspenRpcClient> {
+    if let Some(aspen_client) = cached_client {
+        return Some(aspen_client);
+    }
+
+    match aspen_client_for_chateau(chateau_id, etcd_connection_ips, etcd_connection_options).await {
+        Ok(aspen_client) => aspen_client,
+        Err(err) => {
+            eprintln!("Could not connect to Aspen for {}: {}", chateau_id, err);
+            None
+        }
+    }
+}
@@ -0,0 +1,801 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// The hub keeps every websocket session's subscriptions and polls Aspen for each chateau that someone is watching.
+// Each poll is compared against the previous one, so sessions are only sent vehicles that moved or changed,
+// plus vehicles that entered their bounding box, and the ids of vehicles that left it.
+// Followed trips are polled the same way, and only sent when their realtime data changed.
+// Alert events are read from Aspen's per chateau log, continuing from the last sequence number seen.
+
+use crate::alerts::{fetch_alert_events, AlertSubscription};
+use crate::aspen_client::cached_or_new_aspen_client;
+use crate::protocol::{BoundingBox, ClientMessage, ServerMessage};
+use crate::trips::{
+    fetch_trips, find_trip_update, find_vehicle, trip_has_finished, trip_message, FetchedTrips,
+    TripKey, TripSubscription, TRIP_WITHOUT_DATA_TIMEOUT,
+};
+use actix::prelude::*;
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen::lib::AlertEventsResponse;
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::aspen::lib::GetVehicleLocationsResponse;
+use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::aspen_dataset::AspenisedVehicleRouteCache;
+use catenary::category_to_allowed_route_ids;
+use catenary::CategoryOfRealtimeVehicleData;
+use catenary::EtcdConnectionIps;
+use std::sync::Arc;
+use std::time::Duration;
+use tarpc::context;
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// JSON text frame to send to a websocket session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Push(pub String);
+
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Connect {
+    pub recipient: Recipient<Push>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub session_id: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FromClient {
+    pub session_id: usize,
+    pub message: ClientMessage,
+}
+
+struct VehicleSubscription {
+    bbox: Option<BoundingBox>,
+    sent_vehicle_ids: AHashSet<String>,
+    sent_hash_of_routes: Option<u64>,
+}
+
+struct Session {
+    recipient: Recipient<Push>,
+    vehicle_subscriptions: AHashMap<(String, CategoryOfRealtimeVehicleData), VehicleSubscription>,
+    trip_subscriptions: AHashMap<TripKey, TripSubscription>,
+    alert_subscriptions: AHashMap<String, AlertSubscription>,
+}
+
+#[derive(Default)]
+struct ChateauVehicles {
+    vehicle_positions: AHashMap<String, AspenisedVehiclePosition>,
+    route_cache: AHashMap<String, AspenisedVehicleRouteCache>,
+    hash_of_routes: Option<u64>,
+    last_updated_time_ms: u64,
+    /// Vehicles which are new or different in the latest poll
+    changed_vehicle_ids: AHashSet<String>,
+}
+
+pub struct SubscriptionHub {
+    sessions: AHashMap<usize, Session>,
+    next_session_id: usize,
+    chateaus: AHashMap<String, ChateauVehicles>,
+    polls_in_flight: AHashSet<String>,
+    trip_polls_in_flight: AHashSet<String>,
+    alert_polls_in_flight: AHashSet<String>,
+    /// Sequence number of the last alert event forwarded, per chateau
+    alert_cursors: AHashMap<String, u64>,
+    aspen_clients: AHashMap<String, AspenRpcClient>,
+    etcd_connection_ips: Arc<EtcdConnectionIps>,
+    etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+}
+
+/// Vehicles to send to a subscriber and ids it should remove, given the ids it already has.
+/// `sent_vehicle_ids` is replaced with the vehicles now visible to the subscriber.
+pub fn diff_vehicles(
+    vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+    changed_vehicle_ids: &AHashSet<String>,
+    route_types: &[i16],
+    bbox: Option<&BoundingBox>,
+    sent_vehicle_ids: &mut AHashSet<String>,
+) -> (AHashMap<String, AspenisedVehiclePosition>, Vec<String>) {
+    let visible = vehicle_positions
+        .iter()
+        .filter(|(_, vehicle)| route_types.contains(&vehicle.route_type))
+        .filter(|(_, vehicle)| match (&vehicle.position, bbox) {
+            (Some(position), Some(bbox)) => bbox.contains(position.longitude, position.latitude),
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+        .collect::<Vec<(&String, &AspenisedVehiclePosition)>>();
+
+    let updated = visible
+        .iter()
+        .filter(|(vehicle_id, _)| {
+            !sent_vehicle_ids.contains(*vehicle_id) || changed_vehicle_ids.contains(*vehicle_id)
+        })
+        .map(|(vehicle_id, vehicle)| ((*vehicle_id).clone(), (*vehicle).clone()))
+        .collect::<AHashMap<String, AspenisedVehiclePosition>>();
+
+    let visible_ids = visible
+        .iter()
+        .map(|(vehicle_id, _)| (*vehicle_id).clone())
+        .collect::<AHashSet<String>>();
+
+    let removed = sent_vehicle_ids
+        .iter()
+        .filter(|vehicle_id| !visible_ids.contains(*vehicle_id))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    *sent_vehicle_ids = visible_ids;
+
+    (updated, removed)
+}
+
+async fn fetch_vehicle_locations(
+    chateau_id: String,
+    cached_client: Option<AspenRpcClient>,
+    existing_fasthash_of_routes: Option<u64>,
+    etcd_connection_ips: Arc<EtcdConnectionIps>,
+    etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+) -> (Option<AspenRpcClient>, Option<GetVehicleLocationsResponse>) {
+    let aspen_client = match cached_or_new_aspen_client(
+        &chateau_id,
+        cached_client,
+        &etcd_connection_ips,
+        &etcd_connection_options,
+    )
+    .await
+    {
+        Some(aspen_client) => aspen_client,
+        None => return (None, None),
+    };
+
+    match aspen_client
+        .get_vehicle_locations(
+            context::current(),
+            chateau_id.clone(),
+            existing_fasthash_of_routes,
+        )
+        .await
+    {
+        Ok(Some(response)) => (Some(aspen_client), Some(response)),
+        // the chateau may have been moved to another worker, so look it up again next time
+        Ok(None) => (None, None),
+        Err(err) => {
+            eprintln!("Error fetching vehicles for {}: {}", chateau_id, err);
+            (None, None)
+        }
+    }
+}
+
+impl SubscriptionHub {
+    pub fn new(
+        etcd_connection_ips: Arc<EtcdConnectionIps>,
+        etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+    ) -> SubscriptionHub {
+        SubscriptionHub {
+            sessions: AHashMap::new(),
+            next_session_id: 0,
+            chateaus: AHashMap::new(),
+            polls_in_flight: AHashSet::new(),
+            trip_polls_in_flight: AHashSet::new(),
+            alert_polls_in_flight: AHashSet::new(),
+            alert_cursors: AHashMap::new(),
+            aspen_clients: AHashMap::new(),
+            etcd_connection_ips,
+            etcd_connection_options,
+        }
+    }
+
+    fn push(&self, session_id: usize, message: &ServerMessage) {
+        if let Some(session) = self.sessions.get(&session_id) {
+            match serde_json::to_string(message) {
+                Ok(text) => session.recipient.do_send(Push(text)),
+                Err(err) => eprintln!("Could not serialise message: {}", err),
+            }
+        }
+    }
+
+    fn send_vehicles(
+        &mut self,
+        session_id: usize,
+        chateau_id: &str,
+        category: CategoryOfRealtimeVehicleData,
+        only_new_to_subscriber: bool,
+    ) {
+        let chateau_vehicles = match self.chateaus.get(chateau_id) {
+            Some(chateau_vehicles) => chateau_vehicles,
+            None => return,
+        };
+
+        let subscription = match self.sessions.get_mut(&session_id).and_then(|session| {
+            session
+                .vehicle_subscriptions
+                .get_mut(&(chateau_id.to_string(), category))
+        }) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+
+        let route_types = category_to_allowed_route_ids(&category);
+
+        let no_changes = AHashSet::new();
+
+        let (updated, removed) = diff_vehicles(
+            &chateau_vehicles.vehicle_positions,
+            match only_new_to_subscriber {
+                true => &no_changes,
+                false => &chateau_vehicles.changed_vehicle_ids,
+            },
+            &route_types,
+            subscription.bbox.as_ref(),
+            &mut subscription.sent_vehicle_ids,
+        );
+
+        let route_cache = match subscription.sent_hash_of_routes == chateau_vehicles.hash_of_routes
+        {
+            true => None,
+            false => Some(
+                chateau_vehicles
+                    .route_cache
+                    .iter()
+                    .filter(|(_, route)| route_types.contains(&route.route_type))
+                    .map(|(route_id, route)| (route_id.clone(), route.clone()))
+                    .collect::<AHashMap<String, AspenisedVehicleRouteCache>>(),
+            ),
+        };
+
+        subscription.sent_hash_of_routes = chateau_vehicles.hash_of_routes;
+
+        if updated.is_empty() && removed.is_empty() && route_cache.is_none() {
+            return;
+        }
+
+        let message = ServerMessage::Vehicles {
+            chateau: chateau_id.to_string(),
+            category,
+            updated,
+            removed,
+            route_cache,
+            last_updated_time_ms: chateau_vehicles.last_updated_time_ms,
+        };
+
+        self.push(session_id, &message);
+    }
+
+    fn vehicles_fetched(&mut self, chateau_id: String, response: GetVehicleLocationsResponse) {
+        let chateau_vehicles = self.chateaus.entry(chateau_id.clone()).or_default();
+
+        if chateau_vehicles.last_updated_time_ms == response.last_updated_time_ms
+            && response.vehicle_route_cache.is_none()
+        {
+            return;
+        }
+
+        chateau_vehicles.changed_vehicle_ids = response
+            .vehicle_positions
+            .iter()
+            .filter(|(vehicle_id, vehicle)| {
+                chateau_vehicles.vehicle_positions.get(*vehicle_id) != Some(*vehicle)
+            })
+            .map(|(vehicle_id, _)| vehicle_id.clone())
+            .collect();
+
+        chateau_vehicles.vehicle_positions = response.vehicle_positions;
+        chateau_vehicles.last_updated_time_ms = response.last_updated_time_ms;
+        chateau_vehicles.hash_of_routes = Some(response.hash_of_routes);
+
+        if let Some(route_cache) = response.vehicle_route_cache {
+            chateau_vehicles.route_cache = route_cache;
+        }
+
+        let subscribers = self
+            .sessions
+            .iter()
+            .flat_map(|(session_id, session)| {
+                session
+                    .vehicle_subscriptions
+                    .keys()
+                    .filter(|(subscribed_chateau, _)| *subscribed_chateau == chateau_id)
+                    .map(move |(_, category)| (*session_id, *category))
+            })
+            .collect::<Vec<(usize, CategoryOfRealtimeVehicleData)>>();
+
+        for (session_id, category) in subscribers {
+            self.send_vehicles(session_id, &chateau_id, category, false);
+        }
+    }
+
+    fn trips_fetched(&mut self, chateau_id: String, fetched: FetchedTrips) {
+        let now = catenary::duration_since_unix_epoch().as_secs() as i64;
+
+        let mut ended_trips: Vec<(usize, TripKey)> = vec![];
+
+        for (session_id, session) in self.sessions.iter_mut() {
+            for (trip_key, subscription) in session.trip_subscriptions.iter_mut() {
+                if trip_key.0 != chateau_id {
+                    continue;
+                }
+
+                let trip_update =
+                    match find_trip_update(&fetched, &trip_key.1, trip_key.2.as_deref()) {
+                        Some(trip_update) => trip_update,
+                        None => {
+                            if subscription.seen_data
+                                || subscription.subscribed_at.elapsed() > TRIP_WITHOUT_DATA_TIMEOUT
+                            {
+                                ended_trips.push((*session_id, trip_key.clone()));
+                            }
+                            continue;
+                        }
+                    };
+
+                subscription.seen_data = true;
+
+                if trip_has_finished(trip_update, now) {
+                    ended_trips.push((*session_id, trip_key.clone()));
+                    continue;
+                }
+
+                let vehicle = find_vehicle(&fetched, &trip_key.1, trip_key.2.as_deref());
+
+                let text =
+                    match serde_json::to_string(&trip_message(trip_key, trip_update, vehicle)) {
+                        Ok(text) => text,
+                        Err(err) => {
+                            eprintln!("Could not serialise message: {}", err);
+                            continue;
+                        }
+                    };
+
+                if subscription.last_sent.as_ref() != Some(&text) {
+                    subscription.last_sent = Some(text.clone());
+                    session.recipient.do_send(Push(text));
+                }
+            }
+        }
+
+        for (session_id, trip_key) in ended_trips {
+            self.push(
+                session_id,
+                &ServerMessage::TripEnded {
+                    chateau: trip_key.0.clone(),
+                    trip_id: trip_key.1.clone(),
+                    start_date: trip_key.2.clone(),
+                },
+            );
+
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                session.trip_subscriptions.remove(&trip_key);
+            }
+        }
+    }
+
+    fn vehicle_chateaus(&self) -> AHashSet<String> {
+        self.sessions
+            .values()
+            .flat_map(|session| {
+                session
+                    .vehicle_subscriptions
+                    .keys()
+                    .map(|(chateau_id, _)| chateau_id.clone())
+            })
+            .collect()
+    }
+
+    /// Trip ids followed in each chateau
+    fn trip_chateaus(&self) -> AHashMap<String, AHashSet<String>> {
+        let mut trip_chateaus: AHashMap<String, AHashSet<String>> = AHashMap::new();
+
+        for session in self.sessions.values() {
+            for (chateau_id, trip_id, _) in session.trip_subscriptions.keys() {
+                trip_chateaus
+                    .entry(chateau_id.clone())
+                    .or_default()
+                    .insert(trip_id.clone());
+            }
+        }
+
+        trip_chateaus
+    }
+
+    fn alert_chateaus(&self) -> AHashSet<String> {
+        self.sessions
+            .values()
+            .flat_map(|session| session.alert_subscriptions.keys().cloned())
+            .collect()
+    }
+
+    fn alert_events_fetched(&mut self, chateau_id: String, response: AlertEventsResponse) {
+        self.alert_cursors
+            .insert(chateau_id.clone(), response.latest_sequence);
+
+        for event in response.events {
+            let message = ServerMessage::Alert {
+                chateau: chateau_id.clone(),
+                kind: event.kind,
+                alert_id: event.alert_id.clone(),
+                alert: event.alert.clone(),
+            };
+
+            let text = match serde_json::to_string(&message) {
+                Ok(text) => text,
+                Err(err) => {
+                    eprintln!("Could not serialise message: {}", err);
+                    continue;
+                }
+            };
+
+            for session in self.sessions.values() {
+                if session
+                    .alert_subscriptions
+                    .get(&chateau_id)
+                    .is_some_and(|subscription| subscription.wants(&event.alert))
+                {
+                    session.recipient.do_send(Push(text.clone()));
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        let vehicle_chateaus = self.vehicle_chateaus();
+        let trip_chateaus = self.trip_chateaus();
+        let alert_chateaus = self.alert_chateaus();
+
+        // forget chateaus nobody is watching anymore
+        self.chateaus
+            .retain(|chateau_id, _| vehicle_chateaus.contains(chateau_id));
+        self.alert_cursors
+            .retain(|chateau_id, _| alert_chateaus.contains(chateau_id));
+        self.aspen_clients.retain(|chateau_id, _| {
+            vehicle_chateaus.contains(chateau_id)
+                || trip_chateaus.contains_key(chateau_id)
+                || alert_chateaus.contains(chateau_id)
+        });
+
+        self.poll_vehicles(ctx, vehicle_chateaus);
+        self.poll_trips(ctx, trip_chateaus);
+        self.poll_alerts(ctx, alert_chateaus);
+    }
+
+    fn poll_alerts(&mut self, ctx: &mut Context<Self>, alert_chateaus: AHashSet<String>) {
+        for chateau_id in alert_chateaus {
+            if self.alert_polls_in_flight.contains(&chateau_id) {
+                continue;
+            }
+
+            self.alert_polls_in_flight.insert(chateau_id.clone());
+
+            let fetch = fetch_alert_events(
+                chateau_id.clone(),
+                self.alert_cursors.get(&chateau_id).copied(),
+                self.aspen_clients.get(&chateau_id).cloned(),
+                Arc::clone(&self.etcd_connection_ips),
+                Arc::clone(&self.etcd_connection_options),
+            );
+
+            ctx.spawn(
+                fetch
+                    .into_actor(self)
+                    .map(move |(aspen_client, response), hub, _ctx| {
+                        hub.alert_polls_in_flight.remove(&chateau_id);
+                        hub.keep_aspen_client(&chateau_id, aspen_client);
+
+                        if let Some(response) = response {
+                            hub.alert_events_fetched(chateau_id, response);
+                        }
+                    }),
+            );
+        }
+    }
+
+    fn keep_aspen_client(&mut self, chateau_id: &str, aspen_client: Option<AspenRpcClient>) {
+        match aspen_client {
+            Some(aspen_client) => {
+                self.aspen_clients
+                    .insert(chateau_id.to_string(), aspen_client);
+            }
+            None => {
+                self.aspen_clients.remove(chateau_id);
+            }
+        }
+    }
+
+    fn poll_vehicles(&mut self, ctx: &mut Context<Self>, vehicle_chateaus: AHashSet<String>) {
+        for chateau_id in vehicle_chateaus {
+            if self.polls_in_flight.contains(&chateau_id) {
+                continue;
+            }
+
+            self.polls_in_flight.insert(chateau_id.clone());
+
+            let fetch = fetch_vehicle_locations(
+                chateau_id.clone(),
+                self.aspen_clients.get(&chateau_id).cloned(),
+                self.chateaus
+                    .get(&chateau_id)
+                    .and_then(|chateau_vehicles| chateau_vehicles.hash_of_routes),
+                Arc::clone(&self.etcd_connection_ips),
+                Arc::clone(&self.etcd_connection_options),
+            );
+
+            ctx.spawn(
+                fetch
+                    .into_actor(self)
+                    .map(move |(aspen_client, response), hub, _ctx| {
+                        hub.polls_in_flight.remove(&chateau_id);
+                        hub.keep_aspen_client(&chateau_id, aspen_client);
+
+                        if let Some(response) = response {
+                            hub.vehicles_fetched(chateau_id, response);
+                        }
+                    }),
+            );
+        }
+    }
+
+    fn poll_trips(
+        &mut self,
+        ctx: &mut Context<Self>,
+        trip_chateaus: AHashMap<String, AHashSet<String>>,
+    ) {
+        for (chateau_id, trip_ids) in trip_chateaus {
+            if self.trip_polls_in_flight.contains(&chateau_id) {
+                continue;
+            }
+
+            self.trip_polls_in_flight.insert(chateau_id.clone());
+
+            let fetch = fetch_trips(
+                chateau_id.clone(),
+                trip_ids.into_iter().collect(),
+                self.aspen_clients.get(&chateau_id).cloned(),
+                Arc::clone(&self.etcd_connection_ips),
+                Arc::clone(&self.etcd_connection_options),
+            );
+
+            ctx.spawn(
+                fetch
+                    .into_actor(self)
+                    .map(move |(aspen_client, fetched), hub, _ctx| {
+                        hub.trip_polls_in_flight.remove(&chateau_id);
+                        hub.keep_aspen_client(&chateau_id, aspen_client);
+
+                        if let Some(fetched) = fetched {
+                            hub.trips_fetched(chateau_id, fetched);
+                        }
+                    }),
+            );
+        }
+    }
+}
+
+impl Actor for SubscriptionHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(POLL_INTERVAL, |hub, ctx| hub.poll(ctx));
+    }
+}
+
+impl Handler<Connect> for SubscriptionHub {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+
+        self.sessions.insert(
+            session_id,
+            Session {
+                recipient: msg.recipient,
+                vehicle_subscriptions: AHashMap::new(),
+                trip_subscriptions: AHashMap::new(),
+                alert_subscriptions: AHashMap::new(),
+            },
+        );
+
+        session_id
+    }
+}
+
+impl Handler<Disconnect> for SubscriptionHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        self.sessions.remove(&msg.session_id);
+    }
+}
+
+impl Handler<FromClient> for SubscriptionHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: FromClient, _: &mut Context<Self>) {
+        let session = match self.sessions.get_mut(&msg.session_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        match msg.message {
+            ClientMessage::SubscribeVehicles {
+                chateau,
+                category,
+                bbox,
+            } => {
+                session
+                    .vehicle_subscriptions
+                    .entry((chateau.clone(), category))
+                    .and_modify(|subscription| subscription.bbox = bbox)
+                    .or_insert(VehicleSubscription {
+                        bbox,
+                        sent_vehicle_ids: AHashSet::new(),
+                        sent_hash_of_routes: None,
+                    });
+
+                // vehicles already known are sent right away, the rest arrive with the next poll
+                self.send_vehicles(msg.session_id, &chateau, category, true);
+            }
+            ClientMessage::UnsubscribeVehicles { chateau, category } => {
+                session.vehicle_subscriptions.remove(&(chateau, category));
+            }
+            // the first stop times arrive with the next poll
+            ClientMessage::SubscribeTrip {
+                chateau,
+                trip_id,
+                start_date,
+            } => {
+                session
+                    .trip_subscriptions
+                    .entry((chateau, trip_id, start_date))
+                    .or_insert_with(TripSubscription::new);
+            }
+            ClientMessage::UnsubscribeTrip {
+                chateau,
+                trip_id,
+                start_date,
+            } => {
+                session
+                    .trip_subscriptions
+                    .remove(&(chateau, trip_id, start_date));
+            }
+            // sending this again replaces the routes and stops watched in the chateau
+            ClientMessage::SubscribeAlerts {
+                chateau,
+                route_ids,
+                stop_ids,
+            } => {
+                session.alert_subscriptions.insert(
+                    chateau,
+                    AlertSubscription {
+                        route_ids: route_ids.into_iter().collect(),
+                        stop_ids: stop_ids.into_iter().collect(),
+                    },
+                );
+            }
+            ClientMessage::UnsubscribeAlerts { chateau } => {
+                session.alert_subscriptions.remove(&chateau);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::aspen_dataset::CatenaryRtVehiclePosition;
+
+    fn vehicle(route_type: i16, longitude: f32, latitude: f32) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            trip: None,
+            vehicle: None,
+            position: Some(CatenaryRtVehiclePosition {
+                latitude,
+                longitude,
+                bearing: None,
+                odometer: None,
+                speed: None,
+            }),
+            timestamp: None,
+            route_type,
+            current_stop_sequence: None,
+            current_status: None,
+            congestion_level: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            position_estimated: false,
+        }
+    }
+
+    #[test]
+    fn only_changes_inside_the_bounding_box_are_sent() {
+        let bbox = BoundingBox {
+            min_lon: -118.5,
+            min_lat: 33.9,
+            max_lon: -118.0,
+            max_lat: 34.2,
+        };
+
+        let mut vehicle_positions = AHashMap::new();
+        vehicle_positions.insert(String::from("a"), vehicle(3, -118.2, 34.0));
+        vehicle_positions.insert(String::from("b"), vehicle(3, -118.3, 34.1));
+        vehicle_positions.insert(String::from("far"), vehicle(3, -117.0, 34.0));
+        vehicle_positions.insert(String::from("train"), vehicle(2, -118.2, 34.0));
+
+        let mut sent = AHashSet::new();
+
+        let (updated, removed) = diff_vehicles(
+            &vehicle_positions,
+            &AHashSet::new(),
+            &[3, 11],
+            Some(&bbox),
+            &mut sent,
+        );
+
+        assert_eq!(updated.len(), 2);
+        assert!(removed.is_empty());
+
+        // a moves, b leaves the box
+        vehicle_positions.insert(String::from("a"), vehicle(3, -118.21, 34.0));
+        vehicle_positions.insert(String::from("b"), vehicle(3, -116.0, 34.1));
+
+        let changed = [String::from("a"), String::from("b")]
+            .into_iter()
+            .collect::<AHashSet<String>>();
+
+        let (updated, removed) = diff_vehicles(
+            &vehicle_positions,
+            &changed,
+            &[3, 11],
+            Some(&bbox),
+            &mut sent,
+        );
+
+        assert_eq!(updated.keys().collect::<Vec<&String>>(), vec!["a"]);
+        assert_eq!(removed, vec![String::from("b")]);
+    }
+
+    #[test]
+    fn vehicles_losing_their_position_or_leaving_the_feed_are_removed() {
+        let mut vehicle_positions = AHashMap::new();
+        vehicle_positions.insert(String::from("a"), vehicle(3, -118.2, 34.0));
+        vehicle_positions.insert(String::from("lost"), vehicle(3, -118.3, 34.1));
+        vehicle_positions.insert(String::from("gone"), vehicle(3, -118.4, 34.1));
+
+        let mut sent = AHashSet::new();
+
+        let (updated, removed) =
+            diff_vehicles(&vehicle_positions, &AHashSet::new(), &[3], None, &mut sent);
+
+        assert_eq!(updated.len(), 3);
+        assert!(removed.is_empty());
+
+        vehicle_positions.insert(
+            String::from("lost"),
+            AspenisedVehiclePosition {
+                position: None,
+                ..vehicle(3, -118.3, 34.1)
+            },
+        );
+        vehicle_positions.remove("gone");
+
+        // nothing changed for a, so it is not sent again
+        let (updated, mut removed) =
+            diff_vehicles(&vehicle_positions, &AHashSet::new(), &[3], None, &mut sent);
+
+        removed.sort();
+
+        assert!(updated.is_empty());
+        assert_eq!(removed, vec![String::from("gone"), String::from("lost")]);
+        assert_eq!(
+            sent,
+            [String::from("a")]
+                .into_iter()
+                .collect::<AHashSet<String>>()
+        );
+    }
+}
@@ -21,16 +21,60 @@
     clippy::iter_cloned_collect
 )]
 
-use actix::ActorContext;
-use actix::{Actor, StreamHandler};
+mod alerts;
+mod aspen_client;
+mod hub;
+mod protocol;
+mod trips;
+
+use actix::prelude::*;
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 use actix_web_actors::ws::Message::Text;
+use catenary::EtcdConnectionIps;
+use hub::{Connect, Disconnect, FromClient, Push, SubscriptionHub};
+use protocol::{ClientMessage, ServerMessage};
+use std::sync::Arc;
+
 /// Define HTTP actor
-pub struct SpruceWs {}
+pub struct SpruceWs {
+    session_id: usize,
+    hub: Addr<SubscriptionHub>,
+}
 
 impl Actor for SpruceWs {
     type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let recipient = ctx.address().recipient();
+
+        self.hub
+            .send(Connect { recipient })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(session_id) => act.session_id = session_id,
+                    Err(_) => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.hub.do_send(Disconnect {
+            session_id: self.session_id,
+        });
+        Running::Stop
+    }
+}
+
+impl Handler<Push> for SpruceWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: Push, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
 }
 
 /// Handler for ws::Message message
@@ -39,12 +83,18 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SpruceWs {
         match msg {
             //            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             //            Ok(ws::Message::Text(text)) => ctx.text(text),
-            //            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             Ok(ws::Message::Ping(msg)) => {
                 ctx.pong(&msg);
             }
             Ok(ws::Message::Pong(_)) => {}
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            //the protocol is json text, so binary frames are refused instead of echoed back
+            Ok(ws::Message::Binary(_)) => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Unsupported,
+                    description: Some(String::from("Only JSON text messages are accepted")),
+                }));
+                ctx.stop();
+            }
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -53,23 +103,77 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SpruceWs {
                 ctx.stop();
             }
             Ok(ws::Message::Nop) => (),
-            Ok(Text(s)) => (),
+            Ok(Text(s)) => match serde_json::from_str::<ClientMessage>(&s) {
+                Ok(message) => self.hub.do_send(FromClient {
+                    session_id: self.session_id,
+                    message,
+                }),
+                Err(err) => {
+                    let error = ServerMessage::Error {
+                        message: format!("Invalid message: {}", err),
+                    };
+
+                    if let Ok(error) = serde_json::to_string(&error) {
+                        ctx.text(error);
+                    }
+                }
+            },
             Err(e) => std::panic::panic_any(e),
             _ => (),
         }
     }
 }
 
-async fn index(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    let resp = ws::start(SpruceWs {}, &req, stream);
+async fn index(
+    req: HttpRequest,
+    stream: web::Payload,
+    hub: web::Data<Addr<SubscriptionHub>>,
+) -> Result<HttpResponse, Error> {
+    let resp = ws::start(
+        SpruceWs {
+            session_id: 0,
+            hub: hub.get_ref().clone(),
+        },
+        &req,
+        stream,
+    );
     println!("{:?}", resp);
     resp
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().route("/ws/", web::get().to(index)))
-        .bind(("127.0.0.1", 52771))?
-        .run()
-        .await
+    let etcd_urls_original =
+        std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
+    let etcd_urls = etcd_urls_original
+        .split(',')
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+
+    let etcd_connection_ips = Arc::new(EtcdConnectionIps {
+        ip_addresses: etcd_urls,
+    });
+
+    let etcd_username = std::env::var("ETCD_USERNAME");
+
+    let etcd_password = std::env::var("ETCD_PASSWORD");
+
+    let etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>> =
+        Arc::new(match (etcd_username, etcd_password) {
+            (Ok(username), Ok(password)) => {
+                Some(etcd_client::ConnectOptions::new().with_user(username, password))
+            }
+            _ => None,
+        });
+
+    let hub = SubscriptionHub::new(etcd_connection_ips, etcd_connection_options).start();
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(hub.clone()))
+            .route("/ws/", web::get().to(index))
+    })
+    .bind(("127.0.0.1", 52771))?
+    .run()
+    .await
 }
@@ -0,0 +1,111 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Messages exchanged over the Spruce websocket, as JSON text frames.
+// Clients subscribe to a chateau and category of vehicles, optionally limited to the visible bounding box.
+// Sending the same subscription again with a new bounding box moves it, and only the difference is sent.
+// Single trips can also be followed, receiving their stop times whenever Aspen has new data for them.
+// Alerts for a set of routes and stops are pushed when they are added, changed or removed.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::AlertEventKind;
+use catenary::aspen_dataset::AspenisedAlert;
+use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::aspen_dataset::AspenisedVehicleRouteCache;
+use catenary::aspen_dataset::StopTimeRefresh;
+use catenary::CategoryOfRealtimeVehicleData;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BoundingBox {
+    pub min_lon: f32,
+    pub min_lat: f32,
+    pub max_lon: f32,
+    pub max_lat: f32,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, longitude: f32, latitude: f32) -> bool {
+        longitude >= self.min_lon
+            && longitude <= self.max_lon
+            && latitude >= self.min_lat
+            && latitude <= self.max_lat
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    SubscribeVehicles {
+        chateau: String,
+        category: CategoryOfRealtimeVehicleData,
+        bbox: Option<BoundingBox>,
+    },
+    UnsubscribeVehicles {
+        chateau: String,
+        category: CategoryOfRealtimeVehicleData,
+    },
+    /// Live stop times of one trip, until it finishes
+    SubscribeTrip {
+        chateau: String,
+        trip_id: String,
+        start_date: Option<String>,
+    },
+    UnsubscribeTrip {
+        chateau: String,
+        trip_id: String,
+        start_date: Option<String>,
+    },
+    /// Alerts affecting any of the routes or stops, as soon as Aspen sees them
+    SubscribeAlerts {
+        chateau: String,
+        #[serde(default)]
+        route_ids: Vec<String>,
+        #[serde(default)]
+        stop_ids: Vec<String>,
+    },
+    UnsubscribeAlerts {
+        chateau: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Vehicles which appeared or changed since the last message, and ids of vehicles to remove from the map
+    Vehicles {
+        chateau: String,
+        category: CategoryOfRealtimeVehicleData,
+        updated: AHashMap<String, AspenisedVehiclePosition>,
+        removed: Vec<String>,
+        /// Only sent when the routes of the chateau changed
+        route_cache: Option<AHashMap<String, AspenisedVehicleRouteCache>>,
+        last_updated_time_ms: u64,
+    },
+    /// Sent whenever Aspen has new realtime data for a subscribed trip
+    TripRealtime {
+        chateau: String,
+        trip_id: String,
+        start_date: Option<String>,
+        stoptimes: Vec<StopTimeRefresh>,
+        vehicle: Option<AspenisedVehiclePosition>,
+        timestamp: Option<u64>,
+    },
+    /// The trip finished or has no realtime data anymore, and the subscription was removed
+    TripEnded {
+        chateau: String,
+        trip_id: String,
+        start_date: Option<String>,
+    },
+    /// An alert was added, changed or removed
+    Alert {
+        chateau: String,
+        kind: AlertEventKind,
+        alert_id: String,
+        alert: AspenisedAlert,
+    },
+    Error {
+        message: String,
+    },
+}
@@ -0,0 +1,173 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Live stop times for clients following a single trip.
+// Trips are fetched together per chateau, and a subscription ends once the trip has arrived at its last stop,
+// or Aspen stops publishing the trip update.
+
+use crate::aspen_client::cached_or_new_aspen_client;
+use crate::protocol::ServerMessage;
+use ahash::AHashMap;
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::aspen::lib::TripsSelectionResponse;
+use catenary::aspen_dataset::AspenisedTripUpdate;
+use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::aspen_dataset::StopTimeRefresh;
+use catenary::EtcdConnectionIps;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tarpc::context;
+
+/// Time after the last predicted arrival before the trip is considered finished
+pub const TRIP_FINISHED_GRACE_SECS: i64 = 120;
+
+/// Subscriptions to trips which never get realtime data are dropped after this long
+pub const TRIP_WITHOUT_DATA_TIMEOUT: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// (chateau, trip_id, start_date)
+pub type TripKey = (String, String, Option<String>);
+
+pub struct TripSubscription {
+    pub subscribed_at: Instant,
+    pub seen_data: bool,
+    pub last_sent: Option<String>,
+}
+
+impl TripSubscription {
+    pub fn new() -> TripSubscription {
+        TripSubscription {
+            subscribed_at: Instant::now(),
+            seen_data: false,
+            last_sent: None,
+        }
+    }
+}
+
+pub struct FetchedTrips {
+    pub trips: TripsSelectionResponse,
+    pub vehicles: AHashMap<String, AspenisedVehiclePosition>,
+}
+
+pub async fn fetch_trips(
+    chateau_id: String,
+    trip_ids: Vec<String>,
+    cached_client: Option<AspenRpcClient>,
+    etcd_connection_ips: Arc<EtcdConnectionIps>,
+    etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+) -> (Option<AspenRpcClient>, Option<FetchedTrips>) {
+    let aspen_client = match cached_or_new_aspen_client(
+        &chateau_id,
+        cached_client,
+        &etcd_connection_ips,
+        &etcd_connection_options,
+    )
+    .await
+    {
+        Some(aspen_client) => aspen_client,
+        None => return (None, None),
+    };
+
+    let (trips, vehicles) = futures::join!(
+        aspen_client.get_all_trips_with_ids(
+            context::current(),
+            chateau_id.clone(),
+            trip_ids.clone()
+        ),
+        aspen_client.get_vehicle_locations_from_trip_ids(
+            context::current(),
+            chateau_id.clone(),
+            trip_ids
+        )
+    );
+
+    match (trips, vehicles) {
+        (Ok(Some(trips)), Ok(vehicles)) => (
+            Some(aspen_client),
+            Some(FetchedTrips {
+                trips,
+                vehicles: vehicles.unwrap_or_default(),
+            }),
+        ),
+        (Ok(None), _) => (None, None),
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("Error fetching trips for {}: {}", chateau_id, err);
+            (None, None)
+        }
+    }
+}
+
+/// The trip update for the trip on the requested day, or any day if no date was given
+pub fn find_trip_update<'a>(
+    fetched: &'a FetchedTrips,
+    trip_id: &str,
+    start_date: Option<&str>,
+) -> Option<&'a AspenisedTripUpdate> {
+    fetched
+        .trips
+        .trip_id_to_trip_update_ids
+        .get(trip_id)?
+        .iter()
+        .filter_map(|trip_update_id| fetched.trips.trip_updates.get(trip_update_id))
+        .find(
+            |trip_update| match (start_date, trip_update.trip.start_date.as_deref()) {
+                (Some(start_date), Some(trip_start_date)) => start_date == trip_start_date,
+                _ => true,
+            },
+        )
+}
+
+pub fn find_vehicle<'a>(
+    fetched: &'a FetchedTrips,
+    trip_id: &str,
+    start_date: Option<&str>,
+) -> Option<&'a AspenisedVehiclePosition> {
+    fetched.vehicles.values().find(|vehicle| {
+        vehicle.trip.as_ref().is_some_and(|trip| {
+            trip.trip_id.as_deref() == Some(trip_id)
+                && match (start_date, trip.start_date.as_deref()) {
+                    (Some(start_date), Some(trip_start_date)) => start_date == trip_start_date,
+                    _ => true,
+                }
+        })
+    })
+}
+
+/// True once every stop of the trip has passed
+pub fn trip_has_finished(trip_update: &AspenisedTripUpdate, now: i64) -> bool {
+    let last_time = trip_update
+        .stop_time_update
+        .iter()
+        .flat_map(|stu| {
+            [
+                stu.arrival.as_ref().and_then(|event| event.time),
+                stu.departure.as_ref().and_then(|event| event.time),
+            ]
+        })
+        .flatten()
+        .max();
+
+    match last_time {
+        Some(last_time) => now > last_time + TRIP_FINISHED_GRACE_SECS,
+        None => false,
+    }
+}
+
+pub fn trip_message(
+    trip_key: &TripKey,
+    trip_update: &AspenisedTripUpdate,
+    vehicle: Option<&AspenisedVehiclePosition>,
+) -> ServerMessage {
+    ServerMessage::TripRealtime {
+        chateau: trip_key.0.clone(),
+        trip_id: trip_key.1.clone(),
+        start_date: trip_key.2.clone(),
+        stoptimes: trip_update
+            .stop_time_update
+            .iter()
+            .map(StopTimeRefresh::from)
+            .collect(),
+        vehicle: vehicle.cloned(),
+        timestamp: trip_update.timestamp,
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Routes inside a station over its pathways, for riders who cannot use stairs or escalators.
+
+use crate::models::Pathway;
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Steepest slope usable without assistance, 1:12
+pub const MAX_STEP_FREE_SLOPE: f32 = 0.083;
+
+/// Used when a pathway has neither a traversal time nor a length
+pub const DEFAULT_TRAVERSAL_SECS: u32 = 30;
+
+const STEP_FREE_SPEED_METRES_PER_SEC: f32 = 1.0;
+
+pub const PATHWAY_MODE_STAIRS: i16 = 2;
+pub const PATHWAY_MODE_ESCALATOR: i16 = 4;
+pub const PATHWAY_MODE_ELEVATOR: i16 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StepFreeRoute {
+    pub entrance_id: String,
+    pub platform_id: String,
+    /// Pathways in walking order
+    pub pathway_ids: Vec<String>,
+    pub traversal_time_secs: u32,
+    pub uses_elevator: bool,
+}
+
+pub fn is_step_free(pathway: &Pathway) -> bool {
+    pathway.pathway_mode != PATHWAY_MODE_STAIRS
+        && pathway.pathway_mode != PATHWAY_MODE_ESCALATOR
+        && pathway.stair_count.unwrap_or(0) == 0
+        && pathway
+            .max_slope
+            .map(|max_slope| max_slope.abs() <= MAX_STEP_FREE_SLOPE)
+            .unwrap_or(true)
+}
+
+pub fn traversal_secs(pathway: &Pathway) -> u32 {
+    match (pathway.traversal_time, pathway.length) {
+        (Some(traversal_time), _) => traversal_time.max(0) as u32,
+        (None, Some(length)) => (length / STEP_FREE_SPEED_METRES_PER_SEC).ceil() as u32,
+        (None, None) => DEFAULT_TRAVERSAL_SECS,
+    }
+}
+
+/// Fastest step-free route from each entrance to each platform it can reach.
+/// targets maps platforms, and boarding areas, to the platform they belong to.
+pub fn step_free_routes(
+    entrance_ids: &[String],
+    targets: &HashMap<String, String>,
+    pathways: &[Pathway],
+) -> Vec<StepFreeRoute> {
+    // node -> (next node, index of pathway)
+    let mut adjacency: AHashMap<&str, Vec<(&str, usize)>> = AHashMap::new();
+
+    for (pathway_index, pathway) in pathways.iter().enumerate() {
+        if !is_step_free(pathway) {
+            continue;
+        }
+
+        adjacency
+            .entry(pathway.from_stop_id.as_str())
+            .or_default()
+            .push((pathway.to_stop_id.as_str(), pathway_index));
+
+        if pathway.is_bidirectional {
+            adjacency
+                .entry(pathway.to_stop_id.as_str())
+                .or_default()
+                .push((pathway.from_stop_id.as_str(), pathway_index));
+        }
+    }
+
+    let mut routes: Vec<StepFreeRoute> = vec![];
+
+    for entrance_id in entrance_ids {
+        // node -> (seconds from the entrance, pathway used to arrive)
+        let mut best: AHashMap<&str, (u32, Option<usize>)> = AHashMap::new();
+        let mut queue: BinaryHeap<Reverse<(u32, &str)>> = BinaryHeap::new();
+
+        best.insert(entrance_id.as_str(), (0, None));
+        queue.push(Reverse((0, entrance_id.as_str())));
+
+        while let Some(Reverse((secs, node))) = queue.pop() {
+            if best
+                .get(node)
+                .map(|(best_secs, _)| secs > *best_secs)
+                .unwrap_or(false)
+            {
+                continue;
+            }
+
+            for (next_node, pathway_index) in adjacency.get(node).into_iter().flatten() {
+                let next_secs = secs + traversal_secs(&pathways[*pathway_index]);
+
+                let improves = best
+                    .get(next_node)
+                    .map(|(best_secs, _)| next_secs < *best_secs)
+                    .unwrap_or(true);
+
+                if improves {
+                    best.insert(*next_node, (next_secs, Some(*pathway_index)));
+                    queue.push(Reverse((next_secs, *next_node)));
+                }
+            }
+        }
+
+        // the fastest reached node of each platform
+        let mut platform_arrivals: AHashMap<&str, (u32, &str)> = AHashMap::new();
+
+        for (node, (secs, _)) in best.iter() {
+            if let Some(platform_id) = targets.get(*node) {
+                let faster = platform_arrivals
+                    .get(platform_id.as_str())
+                    .map(|(best_secs, _)| secs < best_secs)
+                    .unwrap_or(true);
+
+                if faster {
+                    platform_arrivals.insert(platform_id.as_str(), (*secs, *node));
+                }
+            }
+        }
+
+        for (platform_id, (secs, node)) in platform_arrivals {
+            let mut pathway_ids: Vec<String> = vec![];
+            let mut uses_elevator = false;
+            let mut current = node;
+
+            while let Some((_, Some(pathway_index))) = best.get(current) {
+                let pathway = &pathways[*pathway_index];
+
+                uses_elevator |= pathway.pathway_mode == PATHWAY_MODE_ELEVATOR;
+                pathway_ids.push(pathway.pathway_id.clone());
+
+                current = match pathway.to_stop_id == current {
+                    true => pathway.from_stop_id.as_str(),
+                    false => pathway.to_stop_id.as_str(),
+                };
+            }
+
+            pathway_ids.reverse();
+
+            routes.push(StepFreeRoute {
+                entrance_id: entrance_id.clone(),
+                platform_id: platform_id.to_string(),
+                pathway_ids,
+                traversal_time_secs: secs,
+                uses_elevator,
+            });
+        }
+    }
+
+    routes.sort_by(|a, b| {
+        a.entrance_id
+            .cmp(&b.entrance_id)
+            .then_with(|| a.platform_id.cmp(&b.platform_id))
+    });
+
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathway(pathway_id: &str, from: &str, to: &str, pathway_mode: i16, secs: i32) -> Pathway {
+        Pathway {
+            onestop_feed_id: String::from("f-test"),
+            attempt_id: String::from("f-test-1"),
+            pathway_id: pathway_id.to_string(),
+            from_stop_id: from.to_string(),
+            to_stop_id: to.to_string(),
+            pathway_mode,
+            is_bidirectional: true,
+            length: None,
+            traversal_time: Some(secs),
+            stair_count: None,
+            max_slope: None,
+            min_width: None,
+            signposted_as: None,
+            reversed_signposted_as: None,
+            chateau: String::from("test"),
+        }
+    }
+
+    #[test]
+    fn avoids_stairs_and_takes_the_elevator() {
+        let pathways = vec![
+            pathway("walk", "entrance", "concourse", 1, 20),
+            pathway("stairs", "concourse", "platform", PATHWAY_MODE_STAIRS, 10),
+            pathway("lift", "platform", "concourse", PATHWAY_MODE_ELEVATOR, 60),
+            pathway(
+                "stairs_only",
+                "entrance",
+                "platform_2",
+                PATHWAY_MODE_STAIRS,
+                10,
+            ),
+        ];
+
+        let targets = HashMap::from([
+            (String::from("platform"), String::from("platform")),
+            (String::from("platform_2"), String::from("platform_2")),
+        ]);
+
+        let routes = step_free_routes(&[String::from("entrance")], &targets, &pathways);
+
+        assert_eq!(
+            routes,
+            vec![StepFreeRoute {
+                entrance_id: String::from("entrance"),
+                platform_id: String::from("platform"),
+                pathway_ids: vec![String::from("walk"), String::from("lift")],
+                traversal_time_secs: 80,
+                uses_elevator: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn levels_behind_escalators_or_disconnected_are_unreachable() {
+        let pathways = vec![
+            pathway("walk", "entrance", "concourse", 1, 30),
+            pathway("ramp", "concourse", "upper_platform", 3, 40),
+            pathway(
+                "escalator",
+                "concourse",
+                "mezzanine",
+                PATHWAY_MODE_ESCALATOR,
+                20,
+            ),
+            pathway("mezzanine_walk", "mezzanine", "lower_platform", 1, 20),
+            pathway("island_walk", "island", "island_platform", 1, 10),
+        ];
+
+        let targets = HashMap::from([
+            (
+                String::from("upper_platform"),
+                String::from("upper_platform"),
+            ),
+            (
+                String::from("lower_platform"),
+                String::from("lower_platform"),
+            ),
+            (
+                String::from("island_platform"),
+                String::from("island_platform"),
+            ),
+        ]);
+
+        let routes = step_free_routes(&[String::from("entrance")], &targets, &pathways);
+
+        assert_eq!(
+            routes
+                .iter()
+                .map(|route| route.platform_id.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["upper_platform"]
+        );
+        assert!(!routes[0].uses_elevator);
+    }
+
+    #[test]
+    fn one_way_pathways_are_only_walked_forwards() {
+        let mut exit_only = pathway("exit_only", "platform", "entrance", 1, 10);
+        exit_only.is_bidirectional = false;
+
+        let mut entry_only = pathway("entry_only", "side_entrance", "platform", 1, 90);
+        entry_only.is_bidirectional = false;
+
+        // a two way pathway is walked from its to_stop_id here
+        let back_walk = pathway("back_walk", "platform_b", "side_entrance", 1, 15);
+
+        let pathways = vec![exit_only, entry_only, back_walk];
+
+        let targets = HashMap::from([
+            (String::from("platform"), String::from("platform")),
+            (String::from("platform_b"), String::from("platform_b")),
+        ]);
+
+        let routes = step_free_routes(
+            &[String::from("entrance"), String::from("side_entrance")],
+            &targets,
+            &pathways,
+        );
+
+        assert_eq!(
+            routes
+                .iter()
+                .map(|route| (
+                    route.entrance_id.as_str(),
+                    route.platform_id.as_str(),
+                    route.pathway_ids.clone(),
+                    route.traversal_time_secs
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "side_entrance",
+                    "platform",
+                    vec![String::from("entry_only")],
+                    90
+                ),
+                (
+                    "side_entrance",
+                    "platform_b",
+                    vec![String::from("back_walk")],
+                    15
+                ),
+            ]
+        );
+    }
+}
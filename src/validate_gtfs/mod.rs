@@ -0,0 +1,668 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Rule based validation of GTFS schedules, run by Maple on every ingestion attempt.
+// Covers what Maple would otherwise skip or accept silently: broken references, trips Maple cannot use,
+// times going backwards, impossible speeds, stops away from their shape, expired calendars and duplicate ids.
+
+use ahash::{AHashMap, AHashSet};
+use chrono::NaiveDate;
+use geo::{Closest, HaversineClosestPoint, HaversineDistance};
+use gtfs_structures::{Exception, Gtfs, RouteType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Ids kept as examples for each rule
+pub const MAX_SAMPLES: usize = 5;
+
+/// Stops further than this from the shape of their trip are misplaced, or the shape is wrong
+pub const STOP_SHAPE_DISTANCE_METRES: f64 = 150.0;
+
+/// Schedules are written to the minute, so shorter hops between stops are counted as a minute
+const MIN_HOP_SECS: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleSeverity {
+    Error,
+    Warning,
+}
+
+impl ScheduleSeverity {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScheduleSeverity::Error => "error",
+            ScheduleSeverity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleRule {
+    IngestFailed,
+    DuplicateAgencyId,
+    DuplicateStopId,
+    DuplicateRouteId,
+    DuplicateTripId,
+    DuplicateStopSequence,
+    RouteAgencyNotFound,
+    ParentStationNotFound,
+    TripRouteNotFound,
+    TripServiceNotFound,
+    TripShapeNotFound,
+    TripTooFewStopTimes,
+    TripMissingFirstOrLastTime,
+    StopTimesNotIncreasing,
+    ArrivalAfterDeparture,
+    UnreasonableSpeed,
+    StopFarFromShape,
+    ServiceExpired,
+    FeedExpired,
+}
+
+impl ScheduleRule {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScheduleRule::IngestFailed => "ingest_failed",
+            ScheduleRule::DuplicateAgencyId => "duplicate_agency_id",
+            ScheduleRule::DuplicateStopId => "duplicate_stop_id",
+            ScheduleRule::DuplicateRouteId => "duplicate_route_id",
+            ScheduleRule::DuplicateTripId => "duplicate_trip_id",
+            ScheduleRule::DuplicateStopSequence => "duplicate_stop_sequence",
+            ScheduleRule::RouteAgencyNotFound => "route_agency_not_found",
+            ScheduleRule::ParentStationNotFound => "parent_station_not_found",
+            ScheduleRule::TripRouteNotFound => "trip_route_not_found",
+            ScheduleRule::TripServiceNotFound => "trip_service_not_found",
+            ScheduleRule::TripShapeNotFound => "trip_shape_not_found",
+            ScheduleRule::TripTooFewStopTimes => "trip_too_few_stop_times",
+            ScheduleRule::TripMissingFirstOrLastTime => "trip_missing_first_or_last_time",
+            ScheduleRule::StopTimesNotIncreasing => "stop_times_not_increasing",
+            ScheduleRule::ArrivalAfterDeparture => "arrival_after_departure",
+            ScheduleRule::UnreasonableSpeed => "unreasonable_speed",
+            ScheduleRule::StopFarFromShape => "stop_far_from_shape",
+            ScheduleRule::ServiceExpired => "service_expired",
+            ScheduleRule::FeedExpired => "feed_expired",
+        }
+    }
+
+    pub fn severity(&self) -> ScheduleSeverity {
+        match self {
+            ScheduleRule::UnreasonableSpeed
+            | ScheduleRule::StopFarFromShape
+            | ScheduleRule::ServiceExpired
+            | ScheduleRule::TripShapeNotFound => ScheduleSeverity::Warning,
+            _ => ScheduleSeverity::Error,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ScheduleRule::IngestFailed => "The feed could not be ingested",
+            ScheduleRule::DuplicateAgencyId => "agency_id is used by more than one agency",
+            ScheduleRule::DuplicateStopId => "stop_id is used by more than one stop",
+            ScheduleRule::DuplicateRouteId => "route_id is used by more than one route",
+            ScheduleRule::DuplicateTripId => "trip_id is used by more than one trip",
+            ScheduleRule::DuplicateStopSequence => {
+                "stop_sequence is repeated within a trip, so one of the stop times is lost"
+            }
+            ScheduleRule::RouteAgencyNotFound => {
+                "agency_id of the route is missing or not in agency.txt"
+            }
+            ScheduleRule::ParentStationNotFound => "parent_station is not in stops.txt",
+            ScheduleRule::TripRouteNotFound => "route_id of the trip is not in routes.txt",
+            ScheduleRule::TripServiceNotFound => {
+                "service_id of the trip is not in calendar.txt or calendar_dates.txt"
+            }
+            ScheduleRule::TripShapeNotFound => "shape_id of the trip is not in shapes.txt",
+            ScheduleRule::TripTooFewStopTimes => {
+                "Trip has fewer than 2 stop times and is not shown"
+            }
+            ScheduleRule::TripMissingFirstOrLastTime => {
+                "Trip has no time at its first or last stop and is not shown"
+            }
+            ScheduleRule::StopTimesNotIncreasing => "Stop times go backwards within a trip",
+            ScheduleRule::ArrivalAfterDeparture => {
+                "arrival_time is after departure_time at the same stop"
+            }
+            ScheduleRule::UnreasonableSpeed => {
+                "Travel between consecutive stops is too fast for the route type"
+            }
+            ScheduleRule::StopFarFromShape => "Stop is far from the shape of its trip",
+            ScheduleRule::ServiceExpired => "Service has no days left to run",
+            ScheduleRule::FeedExpired => "No service in the feed runs today or later",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleNotice {
+    pub rule: ScheduleRule,
+    pub severity: ScheduleSeverity,
+    /// Number of rows breaking the rule, or 1 for rules about the whole feed
+    pub count: u32,
+    pub sample_ids: Vec<String>,
+}
+
+impl ScheduleNotice {
+    pub fn ingest_failed(message: String) -> ScheduleNotice {
+        ScheduleNotice {
+            rule: ScheduleRule::IngestFailed,
+            severity: ScheduleRule::IngestFailed.severity(),
+            count: 1,
+            sample_ids: vec![message],
+        }
+    }
+}
+
+#[derive(Default)]
+struct NoticeCollector {
+    notices: BTreeMap<ScheduleRule, (u32, Vec<String>)>,
+}
+
+impl NoticeCollector {
+    fn add(&mut self, rule: ScheduleRule, id: String) {
+        let (count, samples) = self.notices.entry(rule).or_default();
+
+        *count += 1;
+
+        if samples.len() < MAX_SAMPLES {
+            samples.push(id);
+        }
+    }
+
+    /// Rules about the whole feed have no row to point at
+    fn add_feed(&mut self, rule: ScheduleRule) {
+        self.notices.insert(rule, (1, vec![]));
+    }
+
+    fn finish(self) -> Vec<ScheduleNotice> {
+        self.notices
+            .into_iter()
+            .map(|(rule, (count, sample_ids))| ScheduleNotice {
+                rule,
+                severity: rule.severity(),
+                count,
+                sample_ids,
+            })
+            .collect()
+    }
+}
+
+/// Fastest believable speed between two stops
+fn fastest_speed_kmh(route_type: &RouteType) -> Option<f64> {
+    match route_type {
+        RouteType::Rail => Some(500.0),
+        RouteType::Ferry => Some(100.0),
+        RouteType::Air => None,
+        _ => Some(150.0),
+    }
+}
+
+pub fn validate_schedule(gtfs: &Gtfs, today: NaiveDate) -> Vec<ScheduleNotice> {
+    let mut notices = NoticeCollector::default();
+
+    let agency_ids = gtfs
+        .agencies
+        .iter()
+        .filter_map(|agency| agency.id.as_deref())
+        .collect::<AHashSet<&str>>();
+
+    for (route_id, route) in &gtfs.routes {
+        let agency_found = match &route.agency_id {
+            Some(agency_id) if !agency_id.is_empty() => agency_ids.contains(agency_id.as_str()),
+            // only required when there is more than one agency
+            _ => gtfs.agencies.len() <= 1,
+        };
+
+        if !agency_found {
+            notices.add(ScheduleRule::RouteAgencyNotFound, route_id.clone());
+        }
+    }
+
+    for (stop_id, stop) in &gtfs.stops {
+        if let Some(parent_station) = &stop.parent_station {
+            if !parent_station.is_empty() && !gtfs.stops.contains_key(parent_station) {
+                notices.add(ScheduleRule::ParentStationNotFound, stop_id.clone());
+            }
+        }
+    }
+
+    let mut shape_lines: AHashMap<&str, geo::LineString<f64>> = AHashMap::new();
+    let mut checked_stops_on_shapes: AHashSet<(&str, &str)> = AHashSet::new();
+
+    for (trip_id, trip) in &gtfs.trips {
+        let route = gtfs.routes.get(&trip.route_id);
+
+        if route.is_none() {
+            notices.add(ScheduleRule::TripRouteNotFound, trip_id.clone());
+        }
+
+        if !gtfs.calendar.contains_key(&trip.service_id)
+            && !gtfs.calendar_dates.contains_key(&trip.service_id)
+        {
+            notices.add(ScheduleRule::TripServiceNotFound, trip_id.clone());
+        }
+
+        if trip.stop_times.len() < 2 {
+            notices.add(ScheduleRule::TripTooFewStopTimes, trip_id.clone());
+            continue;
+        }
+
+        if trip.stop_times[0].arrival_time.is_none()
+            || trip.stop_times[trip.stop_times.len() - 1]
+                .departure_time
+                .is_none()
+        {
+            notices.add(ScheduleRule::TripMissingFirstOrLastTime, trip_id.clone());
+        }
+
+        let max_speed_kmh = route.and_then(|route| fastest_speed_kmh(&route.route_type));
+
+        // stop times are sorted by stop_sequence when the feed is read
+        let mut latest_time: Option<u32> = None;
+        let mut previous_timed_stop: Option<(u32, geo::Point<f64>)> = None;
+
+        for (idx, stop_time) in trip.stop_times.iter().enumerate() {
+            let stop_time_id = format!("{}:{}", trip_id, stop_time.stop_sequence);
+
+            if idx > 0 && trip.stop_times[idx - 1].stop_sequence == stop_time.stop_sequence {
+                notices.add(ScheduleRule::DuplicateStopSequence, stop_time_id.clone());
+            }
+
+            if let (Some(arrival_time), Some(departure_time)) =
+                (stop_time.arrival_time, stop_time.departure_time)
+            {
+                if arrival_time > departure_time {
+                    notices.add(ScheduleRule::ArrivalAfterDeparture, stop_time_id.clone());
+                }
+            }
+
+            let arrival_time = stop_time.arrival_time.or(stop_time.departure_time);
+            let departure_time = stop_time.departure_time.or(stop_time.arrival_time);
+
+            if let (Some(arrival_time), Some(latest_time)) = (arrival_time, latest_time) {
+                if arrival_time < latest_time {
+                    notices.add(ScheduleRule::StopTimesNotIncreasing, stop_time_id.clone());
+                }
+            }
+
+            latest_time = latest_time.max(departure_time);
+
+            let point = match (stop_time.stop.longitude, stop_time.stop.latitude) {
+                (Some(longitude), Some(latitude)) => Some(geo::Point::new(longitude, latitude)),
+                _ => None,
+            };
+
+            if let (Some(max_speed_kmh), Some(point), Some(arrival_time)) =
+                (max_speed_kmh, point, arrival_time)
+            {
+                if let Some((previous_departure_time, previous_point)) = previous_timed_stop {
+                    let hop_secs = arrival_time
+                        .saturating_sub(previous_departure_time)
+                        .max(MIN_HOP_SECS);
+
+                    let speed_kmh =
+                        previous_point.haversine_distance(&point) / hop_secs as f64 * 3.6;
+
+                    if speed_kmh > max_speed_kmh {
+                        notices.add(ScheduleRule::UnreasonableSpeed, stop_time_id.clone());
+                    }
+                }
+            }
+
+            if let (Some(point), Some(departure_time)) = (point, departure_time) {
+                previous_timed_stop = Some((departure_time, point));
+            }
+
+            let Some(shape_id) = trip.shape_id.as_deref() else {
+                continue;
+            };
+
+            let Some(point) = point else {
+                continue;
+            };
+
+            if !checked_stops_on_shapes.insert((shape_id, stop_time.stop.id.as_str())) {
+                continue;
+            }
+
+            if !shape_lines.contains_key(shape_id) {
+                let Some(shape) = gtfs.shapes.get(shape_id) else {
+                    continue;
+                };
+
+                let mut shape = shape.iter().collect::<Vec<_>>();
+                shape.sort_by_key(|shape_point| shape_point.sequence);
+
+                shape_lines.insert(
+                    shape_id,
+                    geo::LineString::new(
+                        shape
+                            .iter()
+                            .map(|shape_point| {
+                                geo::coord! { x: shape_point.longitude, y: shape_point.latitude }
+                            })
+                            .collect(),
+                    ),
+                );
+            }
+
+            let distance = match shape_lines[shape_id].haversine_closest_point(&point) {
+                Closest::SinglePoint(closest) | Closest::Intersection(closest) => {
+                    Some(point.haversine_distance(&closest))
+                }
+                Closest::Indeterminate => None,
+            };
+
+            if distance.is_some_and(|distance| distance > STOP_SHAPE_DISTANCE_METRES) {
+                notices.add(
+                    ScheduleRule::StopFarFromShape,
+                    format!("{} on {}", stop_time.stop.id, shape_id),
+                );
+            }
+        }
+
+        if let Some(shape_id) = &trip.shape_id {
+            if !gtfs.shapes.contains_key(shape_id) {
+                notices.add(ScheduleRule::TripShapeNotFound, trip_id.clone());
+            }
+        }
+    }
+
+    // last day each service runs, from calendar.txt and the dates added in calendar_dates.txt
+    let mut service_last_dates: AHashMap<&str, NaiveDate> = AHashMap::new();
+
+    for (service_id, calendar) in &gtfs.calendar {
+        service_last_dates.insert(service_id.as_str(), calendar.end_date);
+    }
+
+    for (service_id, calendar_dates) in &gtfs.calendar_dates {
+        for calendar_date in calendar_dates {
+            if matches!(calendar_date.exception_type, Exception::Added) {
+                let last_date = service_last_dates
+                    .entry(service_id.as_str())
+                    .or_insert(calendar_date.date);
+
+                *last_date = (*last_date).max(calendar_date.date);
+            }
+        }
+    }
+
+    let mut expired_service_ids = service_last_dates
+        .iter()
+        .filter(|(_, last_date)| **last_date < today)
+        .map(|(service_id, _)| *service_id)
+        .collect::<Vec<&str>>();
+
+    expired_service_ids.sort();
+
+    for service_id in expired_service_ids.iter() {
+        notices.add(ScheduleRule::ServiceExpired, service_id.to_string());
+    }
+
+    if !service_last_dates.is_empty() && expired_service_ids.len() == service_last_dates.len() {
+        notices.add_feed(ScheduleRule::FeedExpired);
+    }
+
+    notices.finish()
+}
+
+/// Ids appearing more than once in the id column of a GTFS file
+fn duplicate_ids<R: std::io::Read>(reader: R, id_column: &str) -> Vec<String> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+    let id_index = match reader.headers() {
+        Ok(headers) => headers
+            .iter()
+            .position(|header| header.trim_start_matches('\u{feff}').trim() == id_column),
+        Err(_) => None,
+    };
+
+    let Some(id_index) = id_index else {
+        return vec![];
+    };
+
+    let mut seen_ids: AHashSet<String> = AHashSet::new();
+    let mut duplicates: Vec<String> = vec![];
+
+    for record in reader.records().flatten() {
+        if let Some(id) = record.get(id_index).map(|id| id.trim()) {
+            if !id.is_empty() && !seen_ids.insert(id.to_string()) {
+                duplicates.push(id.to_string());
+            }
+        }
+    }
+
+    duplicates
+}
+
+/// Reading a feed keeps only the last row of each id, so duplicates are found in the files themselves
+pub fn find_duplicate_ids(gtfs_path: &str) -> Vec<ScheduleNotice> {
+    let mut notices = NoticeCollector::default();
+
+    for (file_name, id_column, rule) in [
+        ("agency.txt", "agency_id", ScheduleRule::DuplicateAgencyId),
+        ("stops.txt", "stop_id", ScheduleRule::DuplicateStopId),
+        ("routes.txt", "route_id", ScheduleRule::DuplicateRouteId),
+        ("trips.txt", "trip_id", ScheduleRule::DuplicateTripId),
+    ] {
+        if let Ok(file) = std::fs::File::open(format!("{}/{}", gtfs_path, file_name)) {
+            for id in duplicate_ids(file, id_column) {
+                notices.add(rule, id);
+            }
+        }
+    }
+
+    notices.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_feed() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("validate-gtfs-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = [
+            (
+                "agency.txt",
+                "agency_id,agency_name,agency_url,agency_timezone\n\
+                 a,Agency,https://example.com,America/Los_Angeles\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon,parent_station\n\
+                 s1,One,34.0,-118.0,\n\
+                 s2,Two,34.01,-118.0,\n\
+                 s3,Far Away,34.5,-118.0,\n\
+                 s4,Off The Line,34.005,-117.99,\n\
+                 child,Child,34.0,-118.0,ghost\n",
+            ),
+            (
+                "routes.txt",
+                "route_id,agency_id,route_short_name,route_type\n\
+                 r1,a,1,3\n\
+                 r2,missing,2,3\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id,shape_id\n\
+                 r1,weekdays,good,\n\
+                 r1,weekdays,backwards,\n\
+                 r1,weekdays,dwell,\n\
+                 r1,weekdays,fast,\n\
+                 r1,weekdays,lonely,\n\
+                 r9,nope,orphan,\n\
+                 r1,weekdays,untimed_end,\n\
+                 r1,weekdays,repeat,\n\
+                 r1,weekdays,shaped,line\n\
+                 r1,weekdays,unshaped,missing_shape\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 good,08:00:00,08:00:00,s1,1\n\
+                 good,08:05:00,08:05:00,s2,2\n\
+                 backwards,08:10:00,08:10:00,s1,1\n\
+                 backwards,08:05:00,08:05:00,s2,2\n\
+                 dwell,09:05:00,09:00:00,s1,1\n\
+                 dwell,09:30:00,09:30:00,s2,2\n\
+                 fast,10:00:00,10:00:00,s1,1\n\
+                 fast,10:01:00,10:01:00,s3,2\n\
+                 lonely,10:30:00,10:30:00,s1,1\n\
+                 orphan,11:00:00,11:00:00,s1,1\n\
+                 orphan,11:05:00,11:05:00,s2,2\n\
+                 untimed_end,11:30:00,11:30:00,s1,1\n\
+                 untimed_end,,,s2,2\n\
+                 repeat,11:40:00,11:40:00,s1,1\n\
+                 repeat,11:45:00,11:45:00,s2,1\n\
+                 repeat,11:50:00,11:50:00,s1,2\n\
+                 shaped,12:00:00,12:00:00,s1,1\n\
+                 shaped,12:05:00,12:05:00,s4,2\n\
+                 unshaped,13:00:00,13:00:00,s1,1\n\
+                 unshaped,13:05:00,13:05:00,s2,2\n",
+            ),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence\n\
+                 line,34.0,-118.0,1\n\
+                 line,34.02,-118.0,2\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+                 weekdays,1,1,1,1,1,0,0,20240101,20241231\n\
+                 summer2023,1,1,1,1,1,1,1,20230601,20230831\n",
+            ),
+            (
+                "calendar_dates.txt",
+                "service_id,date,exception_type\n\
+                 weekdays,20250701,1\n",
+            ),
+        ];
+
+        for (file_name, contents) in files {
+            std::fs::write(dir.join(file_name), contents).unwrap();
+        }
+
+        dir
+    }
+
+    fn samples_by_rule(notices: &[ScheduleNotice]) -> BTreeMap<ScheduleRule, Vec<String>> {
+        notices
+            .iter()
+            .map(|notice| {
+                let mut sample_ids = notice.sample_ids.clone();
+                sample_ids.sort();
+
+                (notice.rule, sample_ids)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn each_rule_points_at_the_rows_breaking_it() {
+        let dir = write_feed();
+        let gtfs = Gtfs::new(dir.to_str().unwrap()).unwrap();
+
+        let notices = validate_schedule(&gtfs, NaiveDate::from_ymd_opt(2024, 9, 20).unwrap());
+
+        let expected = [
+            (ScheduleRule::DuplicateStopSequence, vec!["repeat:1"]),
+            (ScheduleRule::RouteAgencyNotFound, vec!["r2"]),
+            (ScheduleRule::ParentStationNotFound, vec!["child"]),
+            (ScheduleRule::TripRouteNotFound, vec!["orphan"]),
+            (ScheduleRule::TripServiceNotFound, vec!["orphan"]),
+            (ScheduleRule::TripShapeNotFound, vec!["unshaped"]),
+            (ScheduleRule::TripTooFewStopTimes, vec!["lonely"]),
+            (
+                ScheduleRule::TripMissingFirstOrLastTime,
+                vec!["untimed_end"],
+            ),
+            (ScheduleRule::StopTimesNotIncreasing, vec!["backwards:2"]),
+            (ScheduleRule::ArrivalAfterDeparture, vec!["dwell:1"]),
+            (ScheduleRule::UnreasonableSpeed, vec!["fast:2"]),
+            (ScheduleRule::StopFarFromShape, vec!["s4 on line"]),
+            (ScheduleRule::ServiceExpired, vec!["summer2023"]),
+        ]
+        .into_iter()
+        .map(|(rule, sample_ids)| {
+            (
+                rule,
+                sample_ids
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<String>>(),
+            )
+        })
+        .collect::<BTreeMap<ScheduleRule, Vec<String>>>();
+
+        assert_eq!(samples_by_rule(&notices), expected);
+
+        let far_from_shape = notices
+            .iter()
+            .find(|notice| notice.rule == ScheduleRule::StopFarFromShape)
+            .unwrap();
+
+        assert_eq!(far_from_shape.severity, ScheduleSeverity::Warning);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn feed_expires_after_its_last_added_date() {
+        let dir = write_feed();
+        let gtfs = Gtfs::new(dir.to_str().unwrap()).unwrap();
+
+        // calendar.txt ends in 2024, but calendar_dates.txt adds a day in July 2025
+        let before = samples_by_rule(&validate_schedule(
+            &gtfs,
+            NaiveDate::from_ymd_opt(2025, 6, 1).unwrap(),
+        ));
+
+        assert_eq!(
+            before[&ScheduleRule::ServiceExpired],
+            vec![String::from("summer2023")]
+        );
+        assert!(!before.contains_key(&ScheduleRule::FeedExpired));
+
+        let after = validate_schedule(&gtfs, NaiveDate::from_ymd_opt(2025, 8, 1).unwrap());
+
+        let expired = after
+            .iter()
+            .find(|notice| notice.rule == ScheduleRule::ServiceExpired)
+            .unwrap();
+
+        assert_eq!(expired.count, 2);
+        assert_eq!(
+            expired.sample_ids,
+            vec![String::from("summer2023"), String::from("weekdays")]
+        );
+
+        let feed_expired = after
+            .iter()
+            .find(|notice| notice.rule == ScheduleRule::FeedExpired)
+            .unwrap();
+
+        assert_eq!(feed_expired.count, 1);
+        assert!(feed_expired.sample_ids.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_duplicate_ids() {
+        let stops = "\u{feff}stop_name,stop_id,stop_lat,stop_lon\n\
+                     A,1,34.0,-118.0\n\
+                     B,2,34.1,-118.1\n\
+                     A again,1,34.0,-118.0\n\
+                     No id,,34.0,-118.0\n\
+                     No id again,,34.0,-118.0\n";
+
+        assert_eq!(duplicate_ids(stops.as_bytes(), "stop_id"), vec!["1"]);
+        assert!(duplicate_ids(stops.as_bytes(), "trip_id").is_empty());
+    }
+}
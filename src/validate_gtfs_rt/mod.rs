@@ -1,3 +1,8 @@
+use ahash::{AHashMap, AHashSet};
+use geo::{Closest, HaversineClosestPoint, HaversineDistance};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
 pub struct GtfsRtQualityCheckResults {
     pub entities_id_using_timestamp_from_global: usize,
     pub total_entity_count: usize,
@@ -22,9 +27,7 @@ pub fn validate_gtfs_rt(input: &gtfs_realtime::FeedMessage) -> GtfsRtQualityChec
 
         if let Some(vehicle) = &entity.vehicle {
             if let Some(position) = &vehicle.position {
-                if f32::abs(0.0 - position.latitude) < 0.01
-                    && f32::abs(0.0 - position.longitude) < 0.01
-                {
+                if is_at_null_island(position) {
                     vehicles_at_null_island += 1;
                 }
             }
@@ -37,3 +40,620 @@ pub fn validate_gtfs_rt(input: &gtfs_realtime::FeedMessage) -> GtfsRtQualityChec
         vehicles_at_null_island,
     }
 }
+
+fn is_at_null_island(position: &gtfs_realtime::Position) -> bool {
+    f32::abs(0.0 - position.latitude) < 0.01 && f32::abs(0.0 - position.longitude) < 0.01
+}
+
+// Rule based validation, following the GTFS-realtime best practices
+
+/// Header timestamps older than this mean the producer stopped updating the feed
+pub const STALE_HEADER_SECS: u64 = 65;
+
+/// Header timestamps further in the future than this are wrong clocks
+pub const FUTURE_HEADER_SECS: u64 = 60;
+
+/// Vehicles further than this from the shape of their trip are on the wrong trip, or badly located
+pub const SHAPE_DISTANCE_METRES: f64 = 200.0;
+
+/// Entity ids kept as examples for each rule
+pub const MAX_SAMPLES: usize = 5;
+
+// GTFS-rt TripDescriptor.ScheduleRelationship
+const TRIP_SCHEDULED: i32 = 0;
+const TRIP_CANCELED: i32 = 3;
+
+// GTFS-rt StopTimeUpdate.ScheduleRelationship
+const STOP_SKIPPED: i32 = 1;
+const STOP_NO_DATA: i32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RtSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtRule {
+    MissingHeaderTimestamp,
+    StaleHeaderTimestamp,
+    HeaderTimestampInFuture,
+    DuplicateEntityId,
+    EntityIdContainsTimestamp,
+    VehicleAtNullIsland,
+    TripNotInSchedule,
+    TripUpdateWithoutStopTimes,
+    StopSequenceNotIncreasing,
+    StopTimesNotIncreasing,
+    ArrivalAfterDeparture,
+    VehicleFarFromShape,
+}
+
+impl RtRule {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RtRule::MissingHeaderTimestamp => "missing_header_timestamp",
+            RtRule::StaleHeaderTimestamp => "stale_header_timestamp",
+            RtRule::HeaderTimestampInFuture => "header_timestamp_in_future",
+            RtRule::DuplicateEntityId => "duplicate_entity_id",
+            RtRule::EntityIdContainsTimestamp => "entity_id_contains_timestamp",
+            RtRule::VehicleAtNullIsland => "vehicle_at_null_island",
+            RtRule::TripNotInSchedule => "trip_not_in_schedule",
+            RtRule::TripUpdateWithoutStopTimes => "trip_update_without_stop_times",
+            RtRule::StopSequenceNotIncreasing => "stop_sequence_not_increasing",
+            RtRule::StopTimesNotIncreasing => "stop_times_not_increasing",
+            RtRule::ArrivalAfterDeparture => "arrival_after_departure",
+            RtRule::VehicleFarFromShape => "vehicle_far_from_shape",
+        }
+    }
+
+    pub fn severity(&self) -> RtSeverity {
+        match self {
+            RtRule::MissingHeaderTimestamp
+            | RtRule::DuplicateEntityId
+            | RtRule::TripNotInSchedule
+            | RtRule::StopSequenceNotIncreasing
+            | RtRule::StopTimesNotIncreasing
+            | RtRule::ArrivalAfterDeparture => RtSeverity::Error,
+            _ => RtSeverity::Warning,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RtNotice {
+    pub rule: RtRule,
+    pub severity: RtSeverity,
+    /// Number of entities breaking the rule, or 1 for rules about the whole feed
+    pub count: u32,
+    pub sample_entity_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RtValidationReport {
+    pub header_timestamp: Option<u64>,
+    pub entity_count: usize,
+    pub notices: Vec<RtNotice>,
+}
+
+/// Static schedule data of the chateau, for the rules which compare against it
+pub struct ScheduleContext<'a> {
+    pub trip_ids: &'a AHashSet<String>,
+    /// trip id -> shape, in (longitude, latitude)
+    pub trip_shapes: &'a AHashMap<String, geo::LineString<f64>>,
+}
+
+#[derive(Default)]
+struct NoticeCollector {
+    notices: BTreeMap<RtRule, (u32, Vec<String>)>,
+}
+
+impl NoticeCollector {
+    fn add(&mut self, rule: RtRule, entity_id: &str) {
+        let (count, samples) = self.notices.entry(rule).or_default();
+
+        *count += 1;
+
+        if samples.len() < MAX_SAMPLES {
+            samples.push(entity_id.to_string());
+        }
+    }
+
+    /// Rules about the whole feed have no entity to point at
+    fn add_feed(&mut self, rule: RtRule) {
+        self.notices.insert(rule, (1, vec![]));
+    }
+
+    fn finish(self) -> Vec<RtNotice> {
+        self.notices
+            .into_iter()
+            .map(|(rule, (count, sample_entity_ids))| RtNotice {
+                rule,
+                severity: rule.severity(),
+                count,
+                sample_entity_ids,
+            })
+            .collect()
+    }
+}
+
+pub fn validate_feed(
+    input: &gtfs_realtime::FeedMessage,
+    now_unix_secs: u64,
+    schedule: Option<&ScheduleContext>,
+) -> RtValidationReport {
+    let mut notices = NoticeCollector::default();
+
+    match input.header.timestamp {
+        None => notices.add_feed(RtRule::MissingHeaderTimestamp),
+        Some(timestamp) => {
+            if timestamp + STALE_HEADER_SECS < now_unix_secs {
+                notices.add_feed(RtRule::StaleHeaderTimestamp);
+            }
+
+            if timestamp > now_unix_secs + FUTURE_HEADER_SECS {
+                notices.add_feed(RtRule::HeaderTimestampInFuture);
+            }
+        }
+    }
+
+    let header_timestamp_string = input
+        .header
+        .timestamp
+        .map(|timestamp| timestamp.to_string());
+
+    let mut seen_entity_ids: AHashSet<&str> = AHashSet::new();
+
+    for entity in &input.entity {
+        if !seen_entity_ids.insert(entity.id.as_str()) {
+            notices.add(RtRule::DuplicateEntityId, &entity.id);
+        }
+
+        if let Some(header_timestamp_string) = &header_timestamp_string {
+            if entity.id.contains(header_timestamp_string) {
+                notices.add(RtRule::EntityIdContainsTimestamp, &entity.id);
+            }
+        }
+
+        if let Some(trip_update) = &entity.trip_update {
+            validate_trip_update(trip_update, &entity.id, schedule, &mut notices);
+        }
+
+        if let Some(vehicle) = &entity.vehicle {
+            validate_vehicle(vehicle, &entity.id, schedule, &mut notices);
+        }
+    }
+
+    RtValidationReport {
+        header_timestamp: input.header.timestamp,
+        entity_count: input.entity.len(),
+        notices: notices.finish(),
+    }
+}
+
+fn is_scheduled_trip(trip: &gtfs_realtime::TripDescriptor) -> bool {
+    matches!(
+        trip.schedule_relationship,
+        None | Some(TRIP_SCHEDULED) | Some(TRIP_CANCELED)
+    )
+}
+
+fn validate_trip_update(
+    trip_update: &gtfs_realtime::TripUpdate,
+    entity_id: &str,
+    schedule: Option<&ScheduleContext>,
+    notices: &mut NoticeCollector,
+) {
+    if let (Some(schedule), Some(trip_id)) = (schedule, &trip_update.trip.trip_id) {
+        if is_scheduled_trip(&trip_update.trip) && !schedule.trip_ids.contains(trip_id) {
+            notices.add(RtRule::TripNotInSchedule, entity_id);
+        }
+    }
+
+    if trip_update.trip.schedule_relationship == Some(TRIP_CANCELED) {
+        return;
+    }
+
+    if trip_update.stop_time_update.is_empty() {
+        // a trip wide delay is enough on its own
+        if trip_update.delay.is_none() {
+            notices.add(RtRule::TripUpdateWithoutStopTimes, entity_id);
+        }
+        return;
+    }
+
+    let mut sequence_regressed = false;
+    let mut times_regressed = false;
+    let mut arrival_after_departure = false;
+
+    let mut previous_stop_sequence: Option<u32> = None;
+    let mut previous_time: Option<i64> = None;
+
+    for stu in trip_update.stop_time_update.iter() {
+        if let Some(stop_sequence) = stu.stop_sequence {
+            if previous_stop_sequence.is_some_and(|previous| stop_sequence <= previous) {
+                sequence_regressed = true;
+            }
+            previous_stop_sequence = Some(stop_sequence);
+        }
+
+        if matches!(
+            stu.schedule_relationship,
+            Some(STOP_SKIPPED) | Some(STOP_NO_DATA)
+        ) {
+            continue;
+        }
+
+        let arrival = stu.arrival.as_ref().and_then(|event| event.time);
+        let departure = stu.departure.as_ref().and_then(|event| event.time);
+
+        if let (Some(arrival), Some(departure)) = (arrival, departure) {
+            if arrival > departure {
+                arrival_after_departure = true;
+            }
+        }
+
+        if let Some(first_time) = arrival.or(departure) {
+            if previous_time.is_some_and(|previous| first_time < previous) {
+                times_regressed = true;
+            }
+        }
+
+        if let Some(last_time) = departure.or(arrival) {
+            previous_time = Some(last_time);
+        }
+    }
+
+    if sequence_regressed {
+        notices.add(RtRule::StopSequenceNotIncreasing, entity_id);
+    }
+
+    if times_regressed {
+        notices.add(RtRule::StopTimesNotIncreasing, entity_id);
+    }
+
+    if arrival_after_departure {
+        notices.add(RtRule::ArrivalAfterDeparture, entity_id);
+    }
+}
+
+fn validate_vehicle(
+    vehicle: &gtfs_realtime::VehiclePosition,
+    entity_id: &str,
+    schedule: Option<&ScheduleContext>,
+    notices: &mut NoticeCollector,
+) {
+    let position = match &vehicle.position {
+        Some(position) => position,
+        None => return,
+    };
+
+    if is_at_null_island(position) {
+        notices.add(RtRule::VehicleAtNullIsland, entity_id);
+        return;
+    }
+
+    let trip_id = vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_ref());
+
+    if let (Some(schedule), Some(trip_id)) = (schedule, trip_id) {
+        if let Some(shape) = schedule.trip_shapes.get(trip_id) {
+            let point = geo::Point::new(position.longitude as f64, position.latitude as f64);
+
+            let closest = match shape.haversine_closest_point(&point) {
+                Closest::Intersection(closest) | Closest::SinglePoint(closest) => closest,
+                Closest::Indeterminate => return,
+            };
+
+            if point.haversine_distance(&closest) > SHAPE_DISTANCE_METRES {
+                notices.add(RtRule::VehicleFarFromShape, entity_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_realtime::trip_update::{StopTimeEvent, StopTimeUpdate};
+    use gtfs_realtime::{
+        FeedEntity, FeedHeader, FeedMessage, Position, TripDescriptor, TripUpdate, VehiclePosition,
+    };
+
+    fn stop_time(stop_sequence: u32, arrival: i64, departure: i64) -> StopTimeUpdate {
+        StopTimeUpdate {
+            stop_sequence: Some(stop_sequence),
+            arrival: Some(StopTimeEvent {
+                time: Some(arrival),
+                ..Default::default()
+            }),
+            departure: Some(StopTimeEvent {
+                time: Some(departure),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn trip_entity(id: &str, trip_id: &str, stop_time_update: Vec<StopTimeUpdate>) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            trip_update: Some(TripUpdate {
+                trip: TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    ..Default::default()
+                },
+                stop_time_update,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn vehicle_entity(id: &str, trip_id: &str, longitude: f32, latitude: f32) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    ..Default::default()
+                }),
+                position: Some(Position {
+                    latitude,
+                    longitude,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn feed(timestamp: Option<u64>, entity: Vec<FeedEntity>) -> FeedMessage {
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: String::from("2.0"),
+                timestamp,
+                ..Default::default()
+            },
+            entity,
+            ..Default::default()
+        }
+    }
+
+    fn rules_of(report: &RtValidationReport) -> BTreeMap<RtRule, Vec<String>> {
+        report
+            .notices
+            .iter()
+            .map(|notice| (notice.rule, notice.sample_entity_ids.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn trip_update_rules() {
+        let feed = FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: String::from("2.0"),
+                timestamp: Some(1_000),
+                ..Default::default()
+            },
+            entity: vec![
+                trip_entity(
+                    "good",
+                    "t1",
+                    vec![stop_time(1, 100, 110), stop_time(2, 200, 210)],
+                ),
+                trip_entity(
+                    "backwards",
+                    "t1",
+                    vec![stop_time(2, 200, 210), stop_time(1, 100, 110)],
+                ),
+                trip_entity("early_departure", "t2", vec![stop_time(1, 120, 110)]),
+                trip_entity("unknown", "t9", vec![stop_time(1, 100, 110)]),
+            ],
+            ..Default::default()
+        };
+
+        let trip_ids = AHashSet::from_iter([String::from("t1"), String::from("t2")]);
+        let trip_shapes = AHashMap::new();
+
+        let report = validate_feed(
+            &feed,
+            1_000 + STALE_HEADER_SECS + 1,
+            Some(&ScheduleContext {
+                trip_ids: &trip_ids,
+                trip_shapes: &trip_shapes,
+            }),
+        );
+
+        let rules = report
+            .notices
+            .iter()
+            .map(|notice| (notice.rule, notice.sample_entity_ids.clone()))
+            .collect::<BTreeMap<RtRule, Vec<String>>>();
+
+        assert!(rules[&RtRule::StaleHeaderTimestamp].is_empty());
+        assert_eq!(rules[&RtRule::StopSequenceNotIncreasing], vec!["backwards"]);
+        assert_eq!(rules[&RtRule::StopTimesNotIncreasing], vec!["backwards"]);
+        assert_eq!(
+            rules[&RtRule::ArrivalAfterDeparture],
+            vec!["early_departure"]
+        );
+        assert_eq!(rules[&RtRule::TripNotInSchedule], vec!["unknown"]);
+        assert_eq!(rules.len(), 5);
+    }
+
+    #[test]
+    fn unmatched_trips_are_reported_only_against_a_schedule() {
+        let feed = FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: String::from("2.0"),
+                timestamp: Some(1_000),
+                ..Default::default()
+            },
+            entity: vec![
+                trip_entity("a", "t8", vec![stop_time(1, 100, 110)]),
+                trip_entity("b", "t9", vec![stop_time(1, 100, 110)]),
+            ],
+            ..Default::default()
+        };
+
+        let trip_ids = AHashSet::new();
+        let trip_shapes = AHashMap::new();
+
+        let report = validate_feed(
+            &feed,
+            1_000,
+            Some(&ScheduleContext {
+                trip_ids: &trip_ids,
+                trip_shapes: &trip_shapes,
+            }),
+        );
+
+        let unknown = report
+            .notices
+            .iter()
+            .find(|notice| notice.rule == RtRule::TripNotInSchedule)
+            .unwrap();
+
+        assert_eq!(unknown.count, 2);
+
+        let report = validate_feed(&feed, 1_000, None);
+
+        assert!(report
+            .notices
+            .iter()
+            .all(|notice| notice.rule != RtRule::TripNotInSchedule));
+    }
+
+    #[test]
+    fn header_and_entity_id_rules() {
+        let report = validate_feed(
+            &feed(
+                None,
+                vec![
+                    trip_entity("a", "t1", vec![stop_time(1, 100, 110)]),
+                    trip_entity("a", "t2", vec![stop_time(1, 100, 110)]),
+                ],
+            ),
+            1_000,
+            None,
+        );
+
+        let rules = rules_of(&report);
+
+        assert!(rules[&RtRule::MissingHeaderTimestamp].is_empty());
+        assert_eq!(rules[&RtRule::DuplicateEntityId], vec!["a"]);
+        assert_eq!(rules.len(), 2);
+
+        let report = validate_feed(
+            &feed(
+                Some(2_000),
+                vec![trip_entity("2000-t1", "t1", vec![stop_time(1, 100, 110)])],
+            ),
+            2_000 - FUTURE_HEADER_SECS - 1,
+            None,
+        );
+
+        let rules = rules_of(&report);
+
+        assert!(rules[&RtRule::HeaderTimestampInFuture].is_empty());
+        assert_eq!(rules[&RtRule::EntityIdContainsTimestamp], vec!["2000-t1"]);
+        assert_eq!(rules.len(), 2);
+
+        // exactly at the limits is fine
+        assert!(
+            validate_feed(&feed(Some(1_000), vec![]), 1_000 + STALE_HEADER_SECS, None)
+                .notices
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn cancelled_added_and_skipped_stops_are_not_held_to_the_schedule() {
+        let mut cancelled = trip_entity("cancelled", "t1", vec![]);
+        cancelled
+            .trip_update
+            .as_mut()
+            .unwrap()
+            .trip
+            .schedule_relationship = Some(TRIP_CANCELED);
+
+        let mut delayed = trip_entity("delayed", "t1", vec![]);
+        delayed.trip_update.as_mut().unwrap().delay = Some(60);
+
+        // the skipped stop keeps stale times from before the trip was rerouted
+        let mut skipped = stop_time(2, 50, 60);
+        skipped.schedule_relationship = Some(STOP_SKIPPED);
+
+        // added trips are not in the static schedule
+        let mut added = trip_entity("added", "t7", vec![stop_time(1, 100, 110)]);
+        added
+            .trip_update
+            .as_mut()
+            .unwrap()
+            .trip
+            .schedule_relationship = Some(1);
+
+        let report = validate_feed(
+            &feed(
+                Some(1_000),
+                vec![
+                    cancelled,
+                    delayed,
+                    trip_entity("empty", "t1", vec![]),
+                    trip_entity(
+                        "skipping",
+                        "t1",
+                        vec![stop_time(1, 100, 110), skipped, stop_time(3, 200, 210)],
+                    ),
+                    added,
+                ],
+            ),
+            1_000,
+            Some(&ScheduleContext {
+                trip_ids: &AHashSet::from_iter([String::from("t1")]),
+                trip_shapes: &AHashMap::new(),
+            }),
+        );
+
+        let rules = rules_of(&report);
+
+        assert_eq!(rules[&RtRule::TripUpdateWithoutStopTimes], vec!["empty"]);
+        assert_eq!(rules.len(), 1);
+    }
+
+    #[test]
+    fn vehicles_at_null_island_or_away_from_their_shape() {
+        let trip_shapes = AHashMap::from_iter([(
+            String::from("t1"),
+            geo::LineString::from(vec![(-118.0, 34.0), (-118.0, 34.01)]),
+        )]);
+
+        let report = validate_feed(
+            &feed(
+                Some(1_000),
+                vec![
+                    vehicle_entity("null", "t1", 0.0, 0.0),
+                    vehicle_entity("on_shape", "t1", -118.0, 34.005),
+                    // about 900 metres east of the line
+                    vehicle_entity("far", "t1", -117.99, 34.005),
+                    // nothing to compare with
+                    vehicle_entity("no_shape", "t2", -117.0, 33.0),
+                ],
+            ),
+            1_000,
+            Some(&ScheduleContext {
+                trip_ids: &AHashSet::from_iter([String::from("t1"), String::from("t2")]),
+                trip_shapes: &trip_shapes,
+            }),
+        );
+
+        let rules = rules_of(&report);
+
+        assert_eq!(rules[&RtRule::VehicleAtNullIsland], vec!["null"]);
+        assert_eq!(rules[&RtRule::VehicleFarFromShape], vec!["far"]);
+        assert_eq!(rules.len(), 2);
+    }
+}